@@ -7,6 +7,9 @@ export default {
     0: DELETED_USER_INFO
   },
 
+  // Users are fetched with their nickname in this group.
+  groupId: null,
+
   createReactiveUser(name, picture) {
     const reactiveUser = reactive({
       name: name,
@@ -34,12 +37,18 @@ export default {
       const req = new XMLHttpRequest();
 
       req.onload = () => {
-        this.cache[userId].name = req.response.name;
-        this.cache[userId].picture = req.response.picture;
+        if (req.status === 200) {
+          this.cache[userId].name = req.response.name;
+          this.cache[userId].picture = req.response.picture;
+        }
       };
 
       req.responseType = "json";
-      req.open("GET", `/api/user/${userId}`);
+      if (this.groupId === null) {
+        req.open("GET", `/api/user/${userId}`);
+      } else {
+        req.open("GET", `/api/user/${userId}?group_id=${this.groupId}`);
+      }
       req.send();
     }
 
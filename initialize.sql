@@ -3,16 +3,26 @@ CREATE TABLE IF NOT EXISTS Usr (
     name TEXT NOT NULL,
     picture TEXT NOT NULL,
     google_id TEXT,
+    admin BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Only verified addresses are stored
+    email TEXT,
 
     PRIMARY KEY (user_id),
 
     UNIQUE (google_id)
 );
 
+ALTER TABLE Usr ADD COLUMN IF NOT EXISTS admin BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE Usr ADD COLUMN IF NOT EXISTS email TEXT;
+
 CREATE TABLE IF NOT EXISTS Session (
     session_id CHAR(16) COLLATE "C" NOT NULL,
     creation_time TIMESTAMPTZ NOT NULL,
+    last_active TIMESTAMPTZ NOT NULL,
     user_id INTEGER NOT NULL,
+    -- Where the user signed in from
+    ip INET,
+    user_agent TEXT,
 
     PRIMARY KEY (session_id),
 
@@ -22,6 +32,14 @@ CREATE TABLE IF NOT EXISTS Session (
         ON DELETE CASCADE
 );
 
+-- Sessions that existed before last_active was added were last active when
+-- they were created.
+ALTER TABLE Session ADD COLUMN IF NOT EXISTS last_active TIMESTAMPTZ;
+UPDATE Session SET last_active = creation_time WHERE last_active IS NULL;
+ALTER TABLE Session ALTER COLUMN last_active SET NOT NULL;
+ALTER TABLE Session ADD COLUMN IF NOT EXISTS ip INET;
+ALTER TABLE Session ADD COLUMN IF NOT EXISTS user_agent TEXT;
+
 CREATE TABLE IF NOT EXISTS Groop (
     group_id SERIAL NOT NULL,
     name TEXT NOT NULL,
@@ -36,6 +54,10 @@ CREATE TABLE IF NOT EXISTS Channel (
     channel_id SERIAL NOT NULL,
     name TEXT NOT NULL,
     group_id INTEGER NOT NULL,
+    slow_mode_seconds INTEGER NOT NULL DEFAULT 0,
+    private BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Channels are listed in ascending order of position
+    position INTEGER NOT NULL DEFAULT 0,
 
     PRIMARY KEY (channel_id),
 
@@ -45,6 +67,10 @@ CREATE TABLE IF NOT EXISTS Channel (
         ON DELETE CASCADE
 );
 
+ALTER TABLE Channel ADD COLUMN IF NOT EXISTS slow_mode_seconds INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Channel ADD COLUMN IF NOT EXISTS private BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE Channel ADD COLUMN IF NOT EXISTS position INTEGER NOT NULL DEFAULT 0;
+
 CREATE UNIQUE INDEX IF NOT EXISTS groop_channel_idx
     ON Channel (group_id, channel_id);
 
@@ -54,6 +80,15 @@ CREATE TABLE IF NOT EXISTS Message (
     author INTEGER,
     content TEXT NOT NULL,
     channel_id INTEGER NOT NULL,
+    pinned BOOLEAN NOT NULL DEFAULT FALSE,
+    version INTEGER NOT NULL DEFAULT 0,
+    -- Deleted messages are kept as tombstones with their content removed
+    deleted_at TIMESTAMPTZ,
+    reply_to INTEGER,
+    -- System messages are posted by the server rather than a user
+    system BOOLEAN NOT NULL DEFAULT FALSE,
+    -- The message that this message is a copy of
+    forwarded_from INTEGER,
 
     PRIMARY KEY (message_id),
 
@@ -65,15 +100,49 @@ CREATE TABLE IF NOT EXISTS Message (
     FOREIGN KEY (channel_id)
         REFERENCES Channel (channel_id)
         ON UPDATE NO ACTION
-        ON DELETE CASCADE
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (reply_to)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL,
+
+    FOREIGN KEY (forwarded_from)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL
 );
 
+ALTER TABLE Message ADD COLUMN IF NOT EXISTS pinned BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE Message ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Message ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+ALTER TABLE Message ADD COLUMN IF NOT EXISTS reply_to INTEGER
+    REFERENCES Message (message_id)
+    ON UPDATE NO ACTION
+    ON DELETE SET NULL;
+ALTER TABLE Message ADD COLUMN IF NOT EXISTS system BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE Message ADD COLUMN IF NOT EXISTS forwarded_from INTEGER
+    REFERENCES Message (message_id)
+    ON UPDATE NO ACTION
+    ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS message_reply_idx
+    ON Message (reply_to)
+    WHERE reply_to IS NOT NULL;
+
 CREATE UNIQUE INDEX IF NOT EXISTS channel_message_idx
     ON Message (channel_id, message_id);
 
+CREATE INDEX IF NOT EXISTS channel_pinned_idx
+    ON Message (channel_id)
+    WHERE pinned;
+
 CREATE TABLE IF NOT EXISTS Membership (
     user_id INTEGER NOT NULL,
     group_id INTEGER NOT NULL,
+    role TEXT NOT NULL DEFAULT 'member',
+
+    CHECK (role IN ('member', 'moderator', 'owner')),
 
     FOREIGN KEY (user_id)
         REFERENCES Usr (user_id)
@@ -86,6 +155,15 @@ CREATE TABLE IF NOT EXISTS Membership (
         ON DELETE CASCADE
 );
 
+-- Every member could manage their groups before roles were added. Members of
+-- groups that existed then become owners so that those groups aren't left
+-- without anyone who can manage them.
+ALTER TABLE Membership ADD COLUMN IF NOT EXISTS role TEXT
+    CHECK (role IN ('member', 'moderator', 'owner'));
+UPDATE Membership SET role = 'owner' WHERE role IS NULL;
+ALTER TABLE Membership ALTER COLUMN role SET DEFAULT 'member';
+ALTER TABLE Membership ALTER COLUMN role SET NOT NULL;
+
 -- Indexing on user then group so that getting the list of groups for a user is
 -- fast. Getting the list of users in a group would require a separate index.
 CREATE UNIQUE INDEX IF NOT EXISTS membership_user_group_idx
@@ -94,12 +172,345 @@ CREATE UNIQUE INDEX IF NOT EXISTS membership_user_group_idx
 CREATE TABLE IF NOT EXISTS Invitation (
     invite_id CHAR(16) COLLATE "C" NOT NULL,
     group_id INTEGER NOT NULL,
+    created_by INTEGER,
     creation_time TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    -- The invitation can be used any number of times if this is NULL
+    max_uses INTEGER,
+    uses INTEGER NOT NULL DEFAULT 0,
+
+    CHECK (max_uses > 0),
 
     PRIMARY KEY (invite_id),
 
     FOREIGN KEY (group_id)
         REFERENCES Groop (group_id)
         ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (created_by)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL
+);
+
+ALTER TABLE Invitation ADD COLUMN IF NOT EXISTS created_by INTEGER
+    REFERENCES Usr (user_id)
+    ON UPDATE NO ACTION
+    ON DELETE SET NULL;
+-- Invitations that existed before expires_at was added expired a day after
+-- they were created.
+ALTER TABLE Invitation ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+UPDATE Invitation SET expires_at = creation_time + INTERVAL '24 hours' WHERE expires_at IS NULL;
+ALTER TABLE Invitation ALTER COLUMN expires_at SET NOT NULL;
+ALTER TABLE Invitation ADD COLUMN IF NOT EXISTS max_uses INTEGER
+    CHECK (max_uses > 0);
+ALTER TABLE Invitation ADD COLUMN IF NOT EXISTS uses INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS Attachment (
+    attachment_id SERIAL NOT NULL,
+    uploader INTEGER NOT NULL,
+    message_id INTEGER,
+    url TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    filename TEXT NOT NULL,
+
+    PRIMARY KEY (attachment_id),
+
+    FOREIGN KEY (uploader)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS attachment_message_idx
+    ON Attachment (message_id);
+
+-- A user can react to a message with any number of different emoji.
+CREATE TABLE IF NOT EXISTS Reaction (
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    emoji TEXT NOT NULL,
+
+    PRIMARY KEY (message_id, user_id, emoji),
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- The last message that a user has read in a channel. Messages after this are
+-- unread.
+CREATE TABLE IF NOT EXISTS ChannelRead (
+    user_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+
+    PRIMARY KEY (user_id, channel_id),
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (channel_id)
+        REFERENCES Channel (channel_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS ConnectionLog (
+    user_id INTEGER NOT NULL,
+    time TIMESTAMPTZ NOT NULL,
+    ip INET,
+    user_agent TEXT,
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS connection_log_user_idx
+    ON ConnectionLog (user_id, time);
+
+-- Tokens are only stored as a SHA-256 hash.
+CREATE TABLE IF NOT EXISTS ApiToken (
+    token_id SERIAL NOT NULL,
+    token_hash BYTEA NOT NULL,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    creation_time TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (token_id),
+
+    UNIQUE (token_hash),
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- The users that can access a private channel. Public channels are accessible
+-- to every member of the group.
+CREATE TABLE IF NOT EXISTS ChannelMembership (
+    channel_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+
+    PRIMARY KEY (channel_id, user_id),
+
+    FOREIGN KEY (channel_id)
+        REFERENCES Channel (channel_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- Messages that users have reported to the moderators of the group.
+CREATE TABLE IF NOT EXISTS Report (
+    report_id SERIAL NOT NULL,
+    message_id INTEGER NOT NULL,
+    reporter INTEGER NOT NULL,
+    reason TEXT NOT NULL,
+    creation_time TIMESTAMPTZ NOT NULL,
+    resolved BOOLEAN NOT NULL DEFAULT FALSE,
+
+    PRIMARY KEY (report_id),
+
+    UNIQUE (message_id, reporter),
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (reporter)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- The preview of the first link in a message.
+CREATE TABLE IF NOT EXISTS LinkPreview (
+    message_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    title TEXT NOT NULL,
+    description TEXT,
+    image TEXT,
+
+    PRIMARY KEY (message_id),
+
+    FOREIGN KEY (message_id)
+        REFERENCES Message (message_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS CustomEmoji (
+    group_id INTEGER NOT NULL,
+    shortcode TEXT NOT NULL,
+    url TEXT NOT NULL,
+
+    PRIMARY KEY (group_id, shortcode),
+
+    FOREIGN KEY (group_id)
+        REFERENCES Groop (group_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- Groups that don't have a row here have the default settings.
+CREATE TABLE IF NOT EXISTS GroupSettings (
+    group_id INTEGER NOT NULL,
+    -- Whether a message is posted when someone joins
+    join_messages BOOLEAN NOT NULL DEFAULT TRUE,
+    link_previews BOOLEAN NOT NULL DEFAULT TRUE,
+    -- Messages older than this many days are deleted. Messages are kept
+    -- forever if this is NULL.
+    retention_days INTEGER,
+    -- Words that are masked in messages
+    word_filter TEXT[] NOT NULL DEFAULT '{}',
+
+    PRIMARY KEY (group_id),
+
+    FOREIGN KEY (group_id)
+        REFERENCES Groop (group_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+ALTER TABLE GroupSettings ADD COLUMN IF NOT EXISTS word_filter TEXT[] NOT NULL DEFAULT '{}';
+
+-- Join messages and the retention period used to be stored in Groop. They're
+-- copied here before those columns are removed.
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1
+        FROM information_schema.columns
+        WHERE table_name = 'groop'
+        AND column_name = 'join_messages'
+    ) THEN
+        INSERT INTO GroupSettings (group_id, join_messages)
+        SELECT group_id, join_messages
+        FROM Groop
+        WHERE NOT join_messages
+        ON CONFLICT (group_id) DO UPDATE
+        SET join_messages = EXCLUDED.join_messages;
+        ALTER TABLE Groop DROP COLUMN join_messages;
+    END IF;
+
+    IF EXISTS (
+        SELECT 1
+        FROM information_schema.columns
+        WHERE table_name = 'groop'
+        AND column_name = 'retention_days'
+    ) THEN
+        INSERT INTO GroupSettings (group_id, retention_days)
+        SELECT group_id, retention_days
+        FROM Groop
+        WHERE retention_days IS NOT NULL
+        ON CONFLICT (group_id) DO UPDATE
+        SET retention_days = EXCLUDED.retention_days;
+        ALTER TABLE Groop DROP COLUMN retention_days;
+    END IF;
+END
+$$;
+
+-- Moderation actions taken within a group. The target is a message, channel
+-- or user ID depending on the action.
+CREATE TABLE IF NOT EXISTS AuditLog (
+    audit_id SERIAL NOT NULL,
+    group_id INTEGER NOT NULL,
+    actor INTEGER,
+    action TEXT NOT NULL,
+    target INTEGER,
+    detail TEXT,
+    time TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (audit_id),
+
+    FOREIGN KEY (group_id)
+        REFERENCES Groop (group_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (actor)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_group_idx
+    ON AuditLog (group_id, audit_id);
+
+-- Unsent messages so that they follow a user between devices.
+CREATE TABLE IF NOT EXISTS Draft (
+    user_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (user_id, channel_id),
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (channel_id)
+        REFERENCES Channel (channel_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- Users aren't notified of mentions in channels that they have muted.
+CREATE TABLE IF NOT EXISTS ChannelMute (
+    user_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+
+    PRIMARY KEY (user_id, channel_id),
+
+    FOREIGN KEY (user_id)
+        REFERENCES Usr (user_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+
+    FOREIGN KEY (channel_id)
+        REFERENCES Channel (channel_id)
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- The name that a member goes by in a group instead of their own name.
+-- Nicknames are unique within a group.
+CREATE TABLE IF NOT EXISTS GroupNickname (
+    user_id INTEGER NOT NULL,
+    group_id INTEGER NOT NULL,
+    nickname TEXT NOT NULL,
+
+    PRIMARY KEY (user_id, group_id),
+    UNIQUE (group_id, nickname),
+
+    FOREIGN KEY (user_id, group_id)
+        REFERENCES Membership (user_id, group_id)
+        ON UPDATE NO ACTION
         ON DELETE CASCADE
 );
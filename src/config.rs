@@ -0,0 +1,331 @@
+use log::info;
+use serde::Deserialize;
+use once_cell::sync::OnceCell;
+
+/// Server configuration.
+///
+/// This is loaded from config.json when the server starts. Any fields that are
+/// missing from the file (or the whole file) take on their default values.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// Sessions expire this many seconds after they're created, regardless of
+    /// activity.
+    pub session_max_lifetime_secs: u64,
+    /// Sessions expire after this many seconds without activity.
+    pub session_idle_timeout_secs: u64,
+    /// The maximum number of messages waiting to be sent to a connection. If a
+    /// client reads too slowly and the queue fills up, it is disconnected.
+    pub send_queue_capacity: usize,
+    /// The maximum number of messages per second that can be sent to a channel
+    /// by everyone combined.
+    pub channel_message_rate_limit: u32,
+    /// When a channel exceeds its rate limit, no messages can be sent to it for
+    /// this many seconds.
+    pub channel_throttle_secs: u64,
+    /// The maximum number of database connections.
+    pub db_pool_max_size: usize,
+    /// The number of milliseconds to wait for a database connection to become
+    /// available in the pool.
+    pub db_pool_timeout_ms: u64,
+    /// The number of milliseconds to wait for a new database connection to be
+    /// established.
+    pub db_connect_timeout_ms: u64,
+    /// How connections are checked before they are reused.
+    pub db_recycling_method: RecyclingMethod,
+    /// The maximum size in bytes of a message received over a WebSocket.
+    pub ws_max_message_size: usize,
+    /// The maximum size in bytes of a single WebSocket frame.
+    pub ws_max_frame_size: usize,
+    /// A dropped connection can be resumed for this many seconds. The user
+    /// doesn't go offline until it expires.
+    pub resume_grace_secs: u64,
+    /// The origins that can make cross-origin requests to the API. If this is
+    /// empty, the API is same-origin only.
+    pub cors_allowed_origins: Vec<String>,
+    /// The methods that can be used in cross-origin requests.
+    pub cors_allowed_methods: Vec<String>,
+    /// The headers that can be used in cross-origin requests.
+    pub cors_allowed_headers: Vec<String>,
+    /// Whether cross-origin requests can include cookies.
+    pub cors_allow_credentials: bool,
+    /// Whether `:shortcode:` sequences in messages are replaced with emoji
+    /// before they're stored.
+    pub expand_shortcodes: bool,
+    /// Whether previews of links in messages are fetched. Groups can also turn
+    /// them off in their settings.
+    pub link_previews: bool,
+    /// The number of milliseconds to spend fetching a link preview before
+    /// giving up.
+    pub link_preview_timeout_ms: u64,
+    /// The maximum number of bytes of a page to read when fetching a link
+    /// preview.
+    pub link_preview_max_bytes: usize,
+    /// A host is not fetched from again for link previews for this many
+    /// seconds.
+    pub link_preview_host_interval_secs: u64,
+    /// How often messages that are past the retention period of their group
+    /// are deleted.
+    pub purge_interval_secs: u64,
+    /// Whether a user can only have one connection at a time. When the user
+    /// connects, their other connections are closed.
+    pub single_session: bool,
+    /// A user stops typing if they don't say that they're still typing within
+    /// this many seconds.
+    pub typing_timeout_secs: u64,
+    /// How often expired sessions are deleted.
+    pub session_sweep_interval_secs: u64,
+    /// How often the Google certificates are checked for expiry.
+    pub cert_refresh_interval_secs: u64,
+    /// The intervals of background tasks are randomly made longer or shorter
+    /// by up to this fraction so that multiple instances don't all run them
+    /// at the same time.
+    pub task_jitter: f64,
+    /// The maximum number of groups that a user can own. Users that own this
+    /// many groups can't create any more.
+    pub max_owned_groups: i64,
+    /// Connections that don't send anything for this many minutes are closed.
+    /// Only frames received from the client count as activity. 0 disables the
+    /// timeout.
+    pub idle_timeout_mins: u64,
+    /// How often the number of groups, connections and messages sent is
+    /// logged. 0 disables the logging.
+    pub stats_interval_secs: u64,
+    /// The maximum number of sign in attempts from an IP address per minute.
+    /// Signing in makes requests to Google so this stops them from being
+    /// made too often. 0 disables the limit.
+    pub auth_rate_limit: u32,
+    /// Queries made through the query helpers that take at least this many
+    /// milliseconds are logged. 0 disables the logging.
+    pub slow_query_ms: u64,
+    /// Presence events are posted to this URL when users come online or go
+    /// offline in a group. Nothing is posted if this is None.
+    pub presence_webhook_url: Option<String>,
+    /// The key used to sign presence events.
+    pub presence_webhook_secret: Secret,
+    /// Presence event requests are abandoned after this many milliseconds.
+    pub presence_webhook_timeout_ms: u64,
+    /// The name of the channel that new groups start with.
+    pub default_channel_name: String,
+    /// A warning is logged when more than this many groups have connections.
+    /// This is for capacity planning. 0 disables the warning.
+    pub group_warning_threshold: usize,
+    /// The warning about the number of groups is logged at most once per this
+    /// many seconds.
+    pub group_warning_interval_secs: u64,
+    /// Whether the session cookie is only sent over HTTPS. This should only be
+    /// turned off for local development.
+    pub session_cookie_secure: bool,
+    /// The SameSite attribute of the session cookie. None requires the cookie
+    /// to be secure.
+    pub session_cookie_same_site: SameSite,
+    /// The Domain attribute of the session cookie. The cookie is only sent to
+    /// the host that set it if this is None.
+    pub session_cookie_domain: Option<String>,
+    /// The Path attribute of the session cookie.
+    pub session_cookie_path: String,
+}
+
+/// A string that is left out of the log.
+#[derive(Deserialize, Default)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret")
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all="snake_case")]
+pub enum RecyclingMethod {
+    /// Only check whether the connection has been closed.
+    Fast,
+    /// Run a test query on the connection.
+    Verified,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+impl Config {
+    /// Check for settings that can't work together.
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.purge_interval_secs == 0 {
+            return Err("purge_interval_secs can't be 0");
+        }
+        // These tasks would run continuously.
+        if self.session_sweep_interval_secs == 0 {
+            return Err("session_sweep_interval_secs can't be 0");
+        }
+        if self.cert_refresh_interval_secs == 0 {
+            return Err("cert_refresh_interval_secs can't be 0");
+        }
+        // Browsers reject cookies with SameSite=None that aren't Secure.
+        if let SameSite::None = self.session_cookie_same_site {
+            if !self.session_cookie_secure {
+                return Err("session_cookie_same_site can't be None unless session_cookie_secure is true");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            session_max_lifetime_secs: 30 * 24 * 60 * 60, // 30 days
+            session_idle_timeout_secs: 7 * 24 * 60 * 60, // 7 days
+            send_queue_capacity: 256,
+            channel_message_rate_limit: 50,
+            channel_throttle_secs: 5,
+            db_pool_max_size: 16,
+            db_pool_timeout_ms: 5000,
+            db_connect_timeout_ms: 5000,
+            db_recycling_method: RecyclingMethod::Fast,
+            ws_max_message_size: 64 << 10,
+            ws_max_frame_size: 64 << 10,
+            resume_grace_secs: 30,
+            cors_allowed_origins: Vec::new(),
+            cors_allowed_methods: vec![
+                "GET".to_owned(),
+                "POST".to_owned(),
+                "PUT".to_owned(),
+                "DELETE".to_owned(),
+            ],
+            cors_allowed_headers: vec![
+                "authorization".to_owned(),
+                "content-type".to_owned(),
+            ],
+            cors_allow_credentials: false,
+            expand_shortcodes: false,
+            link_previews: false,
+            link_preview_timeout_ms: 5000,
+            link_preview_max_bytes: 256 << 10,
+            link_preview_host_interval_secs: 10,
+            purge_interval_secs: 60 * 60, // 1 hour
+            single_session: false,
+            typing_timeout_secs: 5,
+            session_sweep_interval_secs: 60 * 60, // 1 hour
+            cert_refresh_interval_secs: 60 * 60, // 1 hour
+            task_jitter: 0.1,
+            max_owned_groups: 100,
+            idle_timeout_mins: 0,
+            stats_interval_secs: 60,
+            auth_rate_limit: 10,
+            slow_query_ms: 500,
+            presence_webhook_url: None,
+            presence_webhook_secret: Secret::default(),
+            presence_webhook_timeout_ms: 5000,
+            default_channel_name: "general".to_owned(),
+            group_warning_threshold: 0,
+            group_warning_interval_secs: 300,
+            session_cookie_secure: true,
+            session_cookie_same_site: SameSite::Lax,
+            session_cookie_domain: None,
+            session_cookie_path: "/".to_owned(),
+        }
+    }
+}
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Load the configuration. This must be called once before calling `get`.
+pub fn init() {
+    let config = match std::fs::read_to_string("config.json") {
+        Ok(json) => serde_json::from_str::<Config>(json.as_str()).unwrap(),
+        Err(_) => Config::default()
+    };
+    info!("{:?}", config);
+    if let Err(e) = config.validate() {
+        panic!("Invalid config: {}", e);
+    }
+    CONFIG.set(config).unwrap();
+}
+
+#[cfg(not(test))]
+pub fn get() -> &'static Config {
+    CONFIG.get().unwrap()
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_CONFIG: std::cell::Cell<Option<&'static Config>> = std::cell::Cell::new(None);
+}
+
+/// Tests use the default configuration unless they set their own.
+#[cfg(test)]
+pub fn get() -> &'static Config {
+    TEST_CONFIG.with(|config| config.get())
+        .unwrap_or_else(|| CONFIG.get_or_init(Config::default))
+}
+
+/// Use a configuration for the rest of a test. The test runs on one thread
+/// along with the tasks that it spawns so other tests aren't affected.
+#[cfg(test)]
+pub fn set_for_test(config: Config) -> TestConfig {
+    TEST_CONFIG.with(|current| current.set(Some(Box::leak(Box::new(config)))));
+    TestConfig
+}
+
+/// Goes back to the default configuration when dropped.
+#[cfg(test)]
+pub struct TestConfig;
+
+#[cfg(test)]
+impl Drop for TestConfig {
+    fn drop(&mut self) {
+        TEST_CONFIG.with(|current| current.set(None));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn zero_purge_interval() {
+        let mut config = Config::default();
+        config.purge_interval_secs = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn zero_task_intervals() {
+        let mut config = Config::default();
+        config.session_sweep_interval_secs = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.cert_refresh_interval_secs = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn same_site_none_requires_secure() {
+        let mut config = Config::default();
+        config.session_cookie_same_site = SameSite::None;
+        assert!(config.validate().is_ok());
+        config.session_cookie_secure = false;
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use serde::Serialize;
+use crate::error::Error;
+use deadpool_postgres::{Pool, Transaction};
+use deadpool_postgres::tokio_postgres;
+use super::{MessageID, UserID, get_connection};
+
+pub type AttachmentID = i32;
+
+/// The maximum number of files that can be attached to a message.
+pub const MAX_ATTACHMENTS: usize = 10;
+
+#[derive(Serialize)]
+pub struct Attachment {
+    pub attachment_id: AttachmentID,
+    #[serde(skip)]
+    pub message_id: MessageID,
+    pub url: String,
+    pub mime: String,
+    pub size: i64,
+    pub filename: String,
+}
+
+/// Record an uploaded file.
+///
+/// The file is not attached to a message until attach_files is called.
+pub async fn create_attachment(pool: Pool, uploader: UserID, url: &String, mime: &String, size: i64, filename: &String)
+    -> Result<AttachmentID, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO Attachment (uploader, url, mime, size, filename)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING attachment_id
+    ").await?;
+    Ok(conn.query_one(&stmt, &[&uploader, url, mime, &size, filename]).await?.get(0))
+}
+
+/// Determine whether all of the given uploads belong to a user and have not
+/// been attached to a message yet.
+pub async fn valid_uploads(pool: Pool, uploader: UserID, attachment_ids: &[AttachmentID])
+    -> Result<bool, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT COUNT(*)
+        FROM Attachment
+        WHERE attachment_id = ANY($2)
+        AND uploader = $1
+        AND message_id IS NULL
+    ").await?;
+    let count: i64 = conn.query_one(&stmt, &[&uploader, &attachment_ids]).await?.get(0);
+    Ok(count as usize == attachment_ids.len())
+}
+
+/// Attach uploaded files to a message.
+///
+/// Uploads that don't belong to the uploader or are already attached are
+/// ignored. Returns the attachments that were attached.
+///
+/// This is part of the transaction that creates the message so that a message
+/// is never left without its attachments.
+pub(super) async fn attach_files(transaction: &Transaction<'_>, message_id: MessageID, uploader: UserID, attachment_ids: &[AttachmentID])
+    -> Result<Vec<Attachment>, tokio_postgres::Error>
+{
+    let stmt = transaction.prepare("
+        UPDATE Attachment
+        SET message_id = $1
+        WHERE attachment_id = ANY($3)
+        AND uploader = $2
+        AND message_id IS NULL
+        RETURNING attachment_id, message_id, url, mime, size, filename
+    ").await?;
+    let mut attachments = transaction.query(&stmt, &[&message_id, &uploader, &attachment_ids])
+        .await?
+        .iter()
+        .map(attachment_from_row)
+        .collect::<Vec<_>>();
+    attachments.sort_by_key(|a| a.attachment_id);
+    Ok(attachments)
+}
+
+/// Get the attachments of several messages.
+pub async fn message_attachments(pool: Pool, message_ids: &[MessageID])
+    -> Result<Vec<Attachment>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT attachment_id, message_id, url, mime, size, filename
+        FROM Attachment
+        WHERE message_id = ANY($1)
+        ORDER BY attachment_id
+    ").await?;
+    Ok(conn.query(&stmt, &[&message_ids]).await?.iter().map(attachment_from_row).collect())
+}
+
+fn attachment_from_row(row: &deadpool_postgres::tokio_postgres::Row) -> Attachment {
+    Attachment {
+        attachment_id: row.get(0),
+        message_id: row.get(1),
+        url: row.get(2),
+        mime: row.get(3),
+        size: row.get(4),
+        filename: row.get(5),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{testing, create_message};
+    use std::time::SystemTime;
+
+    async fn upload(pool: &Pool, uploader: UserID, filename: &str) -> AttachmentID {
+        create_attachment(
+            pool.clone(),
+            uploader,
+            &"https://example.com/file".to_owned(),
+            &"text/plain".to_owned(),
+            4,
+            &filename.to_owned(),
+        ).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn uploads_must_belong_to_the_sender() {
+        let pool = testing::pool();
+        let sender = testing::user(&pool).await;
+        let other = testing::user(&pool).await;
+        let own = upload(&pool, sender, "own.txt").await;
+        let others = upload(&pool, other, "other.txt").await;
+
+        assert!(valid_uploads(pool.clone(), sender, &[own]).await.unwrap());
+        assert!(!valid_uploads(pool.clone(), sender, &[others]).await.unwrap());
+        assert!(!valid_uploads(pool.clone(), sender, &[own, others]).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn uploads_are_attached_once() {
+        let pool = testing::pool();
+        let sender = testing::user(&pool).await;
+        let group_id = testing::group(&pool, sender).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let attachment_id = upload(&pool, sender, "file.txt").await;
+
+        let (message_id, attached) = create_message(
+            pool.clone(), SystemTime::now(), sender, &"hi".to_owned(), channel_id, None, None, &[attachment_id]
+        ).await.unwrap();
+        assert_eq!(attached.len(), 1);
+        assert_eq!(attached[0].attachment_id, attachment_id);
+        assert_eq!(attached[0].filename, "file.txt");
+
+        let stored = message_attachments(pool.clone(), &[message_id]).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].message_id, message_id);
+        assert_eq!(stored[0].mime, "text/plain");
+
+        // An attached upload can't be attached to another message.
+        assert!(!valid_uploads(pool.clone(), sender, &[attachment_id]).await.unwrap());
+    }
+}
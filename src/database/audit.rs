@@ -0,0 +1,257 @@
+use log::warn;
+use std::net::IpAddr;
+use serde::{Serialize, Deserialize};
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use crate::utils::as_timestamp;
+use super::{UserID, GroupID, ChannelID, MessageID, get_connection};
+
+/// User agents longer than this are truncated before being stored.
+pub const MAX_USER_AGENT_LENGTH: usize = 512;
+
+/// Truncate a user agent to MAX_USER_AGENT_LENGTH characters.
+pub fn truncate_user_agent(user_agent: &str) -> &str {
+    match user_agent.char_indices().nth(MAX_USER_AGENT_LENGTH) {
+        Some((end, _)) => &user_agent[..end],
+        None => user_agent
+    }
+}
+
+/// Record a socket connection for auditing.
+pub async fn log_connection(pool: Pool, user_id: UserID, ip: Option<IpAddr>, user_agent: Option<&str>)
+    -> Result<(), Error>
+{
+    let user_agent = user_agent.map(truncate_user_agent);
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO ConnectionLog (user_id, time, ip, user_agent)
+        VALUES ($1, NOW(), $2, $3)
+    ").await?;
+    conn.execute(&stmt, &[&user_id, &ip, &user_agent]).await?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct ConnectionRecord {
+    pub timestamp: u64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Get the most recent connections made by a user.
+pub async fn recent_connections(pool: Pool, user_id: UserID) -> Result<Vec<ConnectionRecord>, Error> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT time, ip, user_agent
+        FROM ConnectionLog
+        WHERE user_id = $1
+        ORDER BY time DESC
+        LIMIT 50
+    ").await?;
+    Ok(conn.query(&stmt, &[&user_id]).await?.iter().map(|row| ConnectionRecord {
+        timestamp: as_timestamp(row.get(0)),
+        ip: row.get::<_, Option<IpAddr>>(1).map(|ip| ip.to_string()),
+        user_agent: row.get(2),
+    }).collect())
+}
+
+pub type AuditID = i32;
+
+/// A moderation action recorded in the audit log.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    MessageDelete,
+    MessageEdit,
+    MessagePin,
+    MessageUnpin,
+    ChannelDelete,
+    ChannelRename,
+    ChannelReorder,
+    ChannelMemberAdd,
+    SlowModeSet,
+    SettingsUpdate,
+    WordFilterUpdate,
+    OwnershipTransfer,
+    MembersImport,
+    EmojiAdd,
+    EmojiRemove,
+    MemberRemove,
+}
+
+impl AuditAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::MessageDelete => "message_delete",
+            AuditAction::MessageEdit => "message_edit",
+            AuditAction::MessagePin => "message_pin",
+            AuditAction::MessageUnpin => "message_unpin",
+            AuditAction::ChannelDelete => "channel_delete",
+            AuditAction::ChannelRename => "channel_rename",
+            AuditAction::ChannelReorder => "channel_reorder",
+            AuditAction::ChannelMemberAdd => "channel_member_add",
+            AuditAction::SlowModeSet => "slow_mode_set",
+            AuditAction::SettingsUpdate => "settings_update",
+            AuditAction::WordFilterUpdate => "word_filter_update",
+            AuditAction::OwnershipTransfer => "ownership_transfer",
+            AuditAction::MembersImport => "members_import",
+            AuditAction::EmojiAdd => "emoji_add",
+            AuditAction::EmojiRemove => "emoji_remove",
+            AuditAction::MemberRemove => "member_remove",
+        }
+    }
+}
+
+/// The user, channel or message that a moderation action was done to.
+#[derive(Clone, Copy)]
+pub enum AuditTarget {
+    User(UserID),
+    Channel(ChannelID),
+    Message(MessageID),
+}
+
+impl AuditTarget {
+    /// The action determines which kind of ID is stored.
+    fn id(self) -> i32 {
+        match self {
+            AuditTarget::User(user_id) => user_id.0,
+            AuditTarget::Channel(channel_id) => channel_id.0,
+            AuditTarget::Message(message_id) => message_id.0,
+        }
+    }
+}
+
+async fn insert_audit(pool: Pool, group_id: GroupID, actor_id: UserID, action: AuditAction, target: Option<AuditTarget>, detail: &Option<String>)
+    -> Result<(), Error>
+{
+    let target = target.map(AuditTarget::id);
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO AuditLog (group_id, actor, action, target, detail, time)
+        VALUES ($1, $2, $3, $4, $5, NOW())
+    ").await?;
+    conn.execute(&stmt, &[&group_id, &actor_id, &action.as_str(), &target, detail]).await?;
+    Ok(())
+}
+
+/// Record a moderation action in the audit log of a group.
+///
+/// The action has already happened by the time this is called so a failure to
+/// record it is only logged.
+pub async fn log_audit(pool: Pool, group_id: GroupID, actor_id: UserID, action: AuditAction, target: Option<AuditTarget>, detail: Option<String>) {
+    if let Err(e) = insert_audit(pool, group_id, actor_id, action, target, &detail).await {
+        warn!("Failed to record {} in audit log of group {}: {}", action.as_str(), group_id, e);
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuditRecord {
+    pub audit_id: AuditID,
+    pub actor: Option<UserID>,
+    pub action: String,
+    pub target: Option<i32>,
+    pub detail: Option<String>,
+    pub timestamp: u64,
+}
+
+/// Number of audit records returned by group_audit_log.
+pub const AUDIT_PAGE_SIZE: i64 = 50;
+
+/// Get a page of the audit log of a group, newest first.
+///
+/// Only records older than `before` are returned if it is given. The records
+/// can be restricted to a single action type.
+pub async fn group_audit_log(pool: Pool, group_id: GroupID, before: Option<AuditID>, action: Option<AuditAction>)
+    -> Result<Vec<AuditRecord>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT audit_id, actor, action, target, detail, time
+        FROM AuditLog
+        WHERE group_id = $1
+        AND ($2::INTEGER IS NULL OR audit_id < $2)
+        AND ($3::TEXT IS NULL OR action = $3)
+        ORDER BY audit_id DESC
+        LIMIT $4
+    ").await?;
+    let action = action.map(AuditAction::as_str);
+    Ok(conn.query(&stmt, &[&group_id, &before, &action, &AUDIT_PAGE_SIZE]).await?.iter().map(|row| AuditRecord {
+        audit_id: row.get(0),
+        actor: row.get(1),
+        action: row.get(2),
+        target: row.get(3),
+        detail: row.get(4),
+        timestamp: as_timestamp(row.get(5)),
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+
+    const ACTIONS: &[AuditAction] = &[
+        AuditAction::MessageDelete,
+        AuditAction::MessageEdit,
+        AuditAction::MessagePin,
+        AuditAction::MessageUnpin,
+        AuditAction::ChannelDelete,
+        AuditAction::ChannelRename,
+        AuditAction::ChannelReorder,
+        AuditAction::ChannelMemberAdd,
+        AuditAction::SlowModeSet,
+        AuditAction::SettingsUpdate,
+        AuditAction::WordFilterUpdate,
+        AuditAction::OwnershipTransfer,
+        AuditAction::MembersImport,
+        AuditAction::EmojiAdd,
+        AuditAction::EmojiRemove,
+        AuditAction::MemberRemove,
+    ];
+
+    // The action filter of the audit log endpoint is deserialized and then
+    // compared with the stored string.
+    #[test]
+    fn stored_action_matches_json() {
+        for &action in ACTIONS {
+            let json = serde_json::to_value(action).unwrap();
+            assert_eq!(json, action.as_str());
+            let parsed = serde_json::from_value::<AuditAction>(json).unwrap();
+            assert_eq!(parsed.as_str(), action.as_str());
+        }
+    }
+
+    #[test]
+    fn target_id() {
+        assert_eq!(AuditTarget::User(UserID(3)).id(), 3);
+        assert_eq!(AuditTarget::Channel(ChannelID(4)).id(), 4);
+        assert_eq!(AuditTarget::Message(MessageID(5)).id(), 5);
+    }
+
+    #[test]
+    fn user_agent_is_truncated_by_characters() {
+        let short = "Mozilla/5.0";
+        assert_eq!(truncate_user_agent(short), short);
+        let long = "é".repeat(MAX_USER_AGENT_LENGTH + 1);
+        let truncated = truncate_user_agent(&long);
+        assert_eq!(truncated.chars().count(), MAX_USER_AGENT_LENGTH);
+        assert!(long.starts_with(truncated));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn connections_are_logged() {
+        let pool = testing::pool();
+        let user_id = testing::user(&pool).await;
+        let ip = "127.0.0.1".parse().unwrap();
+        log_connection(pool.clone(), user_id, Some(ip), Some("Mozilla/5.0")).await.unwrap();
+        log_connection(pool.clone(), user_id, None, None).await.unwrap();
+
+        let connections = recent_connections(pool, user_id).await.unwrap();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].ip, None);
+        assert_eq!(connections[0].user_agent, None);
+        assert_eq!(connections[1].ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(connections[1].user_agent.as_deref(), Some("Mozilla/5.0"));
+    }
+}
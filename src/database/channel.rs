@@ -1,37 +1,170 @@
-use super::GroupID;
 use serde::Serialize;
+use crate::error::Error;
+use crate::utils::as_timestamp;
+use super::{ChannelID, GroupID, UserID, MessageID, get_connection, query_map, query_opt_map};
 use deadpool_postgres::{Pool, PoolError};
 
-pub type ChannelID = i32;
-
 #[derive(Serialize)]
 pub struct Channel {
     pub channel_id: ChannelID,
     pub name: String,
+    pub slow_mode_seconds: i32,
+    pub private: bool,
+    pub position: i32,
+}
+
+#[derive(Serialize)]
+pub struct LastMessage {
+    pub message_id: MessageID,
+    pub timestamp: u64,
+    pub author: UserID,
+    /// The start of the content. Empty if the message was deleted.
+    pub preview: String,
+}
+
+#[derive(Serialize)]
+pub struct RecentChannel {
+    pub channel_id: ChannelID,
+    pub group_id: GroupID,
+    pub name: String,
+    pub last_message: Option<LastMessage>,
+}
+
+#[derive(Serialize)]
+pub struct ChannelInfo {
+    pub channel_id: ChannelID,
+    pub group_id: GroupID,
+    pub name: String,
+    /// The number of messages that haven't been deleted.
+    pub message_count: i64,
+}
+
+/// The maximum number of channels returned by recent_active_channels.
+pub const MAX_RECENT_CHANNELS: u32 = 50;
+/// The number of characters of the last message included in a
+/// RecentChannel.
+const PREVIEW_LENGTH: i32 = 100;
+
+/// Get the channels across all groups of a user that they can access, along
+/// with the last message of each. The channels with the most recent messages
+/// come first and channels without any messages come last.
+pub async fn recent_active_channels(pool: Pool, user_id: UserID, limit: u32)
+    -> Result<Vec<RecentChannel>, Error>
+{
+    let limit = limit as i64;
+    query_map(&pool, "
+        SELECT Channel.channel_id, Channel.group_id, Channel.name,
+            Last.message_id, Last.timestamp, Last.author, Last.preview
+        FROM Channel
+        JOIN Membership ON Membership.group_id = Channel.group_id
+        LEFT JOIN LATERAL (
+            SELECT
+                message_id,
+                timestamp,
+                COALESCE(author, 0) AS author,
+                CASE WHEN deleted_at IS NULL THEN LEFT(content, $3) ELSE '' END AS preview
+            FROM Message
+            WHERE Message.channel_id = Channel.channel_id
+            ORDER BY timestamp DESC, message_id DESC
+            LIMIT 1
+        ) Last ON TRUE
+        WHERE Membership.user_id = $1
+        AND (
+            NOT Channel.private
+            OR EXISTS (
+                SELECT 1
+                FROM ChannelMembership
+                WHERE ChannelMembership.channel_id = Channel.channel_id
+                AND ChannelMembership.user_id = $1
+            )
+        )
+        ORDER BY Last.timestamp DESC NULLS LAST, Last.message_id DESC, Channel.channel_id
+        LIMIT $2
+    ", &[&user_id, &limit, &PREVIEW_LENGTH], |row| RecentChannel {
+        channel_id: row.get(0),
+        group_id: row.get(1),
+        name: row.get(2),
+        last_message: row.get::<_, Option<MessageID>>(3).map(|message_id| LastMessage {
+            message_id,
+            timestamp: as_timestamp(row.get(4)),
+            author: row.get(5),
+            preview: row.get(6),
+        }),
+    }).await
 }
 
+/// The maximum slow mode interval (6 hours).
+pub const MAX_SLOW_MODE_SECONDS: i32 = 6 * 60 * 60;
+
 /// Create a new channel.
 ///
 /// Assumes that the group_id is valid (because verifying it would require an
 /// additional query). Also assumes that the name is valid
 ///
-/// Returns Ok(None) if the channel name is not unique
-pub async fn create_channel(pool: Pool, group_id: GroupID, name: &String)
-    -> Result<Option<ChannelID>, PoolError>
+/// The channel is placed after all of the other channels in the group.
+///
+/// Returns the ID and the position of the channel. Returns Ok(None) if the
+/// channel name is not unique
+pub async fn create_channel(pool: Pool, group_id: GroupID, name: &String, private: bool)
+    -> Result<Option<(ChannelID, i32)>, PoolError>
 {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
-        INSERT INTO Channel (name, group_id)
-        SELECT $1, $2
+        INSERT INTO Channel (name, group_id, private, position)
+        SELECT $1, $2, $3, (
+            SELECT COALESCE(MAX(position) + 1, 0)
+            FROM Channel
+            WHERE group_id = $2
+        )
         WHERE NOT EXISTS (
             SELECT *
             FROM Channel
             WHERE name = $1
             AND group_id = $2
         )
-        RETURNING channel_id
+        RETURNING channel_id, position
     ").await?;
-    Ok(conn.query_opt(&stmt, &[name, &group_id]).await?.map(|row| row.get(0)))
+    Ok(conn.query_opt(&stmt, &[name, &group_id, &private]).await?.map(|row| (row.get(0), row.get(1))))
+}
+
+/// Set the positions of the channels in a group.
+///
+/// The channels are given in their new order. Returns false if they are not
+/// exactly the channels in the group.
+pub async fn reorder_channels(pool: Pool, group_id: GroupID, channel_ids: &[ChannelID])
+    -> Result<bool, PoolError>
+{
+    let mut conn = get_connection(&pool).await?;
+    let transaction = conn.transaction().await?;
+
+    let stmt = transaction.prepare("
+        SELECT channel_id
+        FROM Channel
+        WHERE group_id = $1
+        ORDER BY channel_id
+        FOR UPDATE
+    ").await?;
+    let existing = transaction.query(&stmt, &[&group_id]).await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect::<Vec<ChannelID>>();
+    let mut sorted = channel_ids.to_vec();
+    sorted.sort();
+    if sorted != existing {
+        return Ok(false);
+    }
+
+    let stmt = transaction.prepare("
+        UPDATE Channel
+        SET position = $2
+        WHERE channel_id = $1
+    ").await?;
+    for (position, channel_id) in channel_ids.iter().enumerate() {
+        transaction.execute(&stmt, &[channel_id, &(position as i32)]).await?;
+    }
+
+    transaction.commit().await?;
+    Ok(true)
 }
 
 /// Delete a channel.
@@ -40,7 +173,7 @@ pub async fn create_channel(pool: Pool, group_id: GroupID, name: &String)
 pub async fn delete_channel(pool: Pool, channel_id: ChannelID)
     -> Result<bool, PoolError>
 {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
         DELETE FROM Channel
         WHERE channel_id = $1
@@ -54,7 +187,7 @@ pub async fn delete_channel(pool: Pool, channel_id: ChannelID)
 pub async fn rename_channel(pool: Pool, group_id: GroupID, channel_id: ChannelID, name: &String)
     -> Result<bool, PoolError>
 {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
         UPDATE Channel
         SET name = $3
@@ -68,3 +201,226 @@ pub async fn rename_channel(pool: Pool, group_id: GroupID, channel_id: ChannelID
     ").await?;
     Ok(conn.execute(&stmt, &[&group_id, &channel_id, name]).await? > 0)
 }
+
+/// Set the minimum number of seconds between messages from the same user in
+/// a channel. Zero disables slow mode.
+///
+/// Returns true if the channel was actually updated.
+pub async fn set_slow_mode(pool: Pool, channel_id: ChannelID, seconds: i32)
+    -> Result<bool, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        UPDATE Channel
+        SET slow_mode_seconds = $2
+        WHERE channel_id = $1
+    ").await?;
+    Ok(conn.execute(&stmt, &[&channel_id, &seconds]).await? > 0)
+}
+
+/// Determine whether a user can access a channel. The user must be a member
+/// of the group that contains the channel and, if the channel is private, a
+/// member of the channel.
+pub async fn channel_accessible(pool: Pool, user_id: UserID, channel_id: ChannelID)
+    -> Result<bool, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT 1
+        FROM Channel
+        JOIN Membership ON Membership.group_id = Channel.group_id
+        WHERE Membership.user_id = $1
+        AND Channel.channel_id = $2
+        AND (
+            NOT Channel.private
+            OR EXISTS (
+                SELECT 1
+                FROM ChannelMembership
+                WHERE ChannelMembership.channel_id = $2
+                AND ChannelMembership.user_id = $1
+            )
+        )
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&user_id, &channel_id]).await?.is_some())
+}
+
+/// Give a user access to a private channel.
+///
+/// Returns false if the user already had access.
+pub async fn add_channel_member(pool: Pool, channel_id: ChannelID, user_id: UserID)
+    -> Result<bool, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO ChannelMembership (channel_id, user_id)
+        VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+    ").await?;
+    Ok(conn.execute(&stmt, &[&channel_id, &user_id]).await? > 0)
+}
+
+/// Mute or unmute a channel for a user. Users aren't notified of mentions in
+/// channels that they have muted.
+pub async fn set_channel_mute(pool: Pool, user_id: UserID, channel_id: ChannelID, muted: bool)
+    -> Result<(), Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = if muted {
+        conn.prepare("
+            INSERT INTO ChannelMute (user_id, channel_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+        ").await?
+    } else {
+        conn.prepare("
+            DELETE FROM ChannelMute
+            WHERE user_id = $1
+            AND channel_id = $2
+        ").await?
+    };
+    conn.execute(&stmt, &[&user_id, &channel_id]).await?;
+    Ok(())
+}
+
+/// Get the users out of the given users that have muted a channel.
+pub async fn users_muting_channel(pool: Pool, channel_id: ChannelID, user_ids: &[UserID])
+    -> Result<Vec<UserID>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT user_id
+        FROM ChannelMute
+        WHERE channel_id = $1
+        AND user_id = ANY($2)
+    ").await?;
+    Ok(conn.query(&stmt, &[&channel_id, &user_ids]).await?.iter().map(|row| row.get(0)).collect())
+}
+
+/// Get the members of the private channels in a group.
+pub async fn group_channel_members(pool: Pool, group_id: GroupID)
+    -> Result<Vec<(ChannelID, UserID)>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT ChannelMembership.channel_id, ChannelMembership.user_id
+        FROM ChannelMembership
+        JOIN Channel ON Channel.channel_id = ChannelMembership.channel_id
+        WHERE Channel.group_id = $1
+    ").await?;
+    Ok(conn.query(&stmt, &[&group_id])
+        .await?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
+
+/// Determine whether a channel exists and belongs to a group.
+pub async fn channel_in_group(pool: Pool, channel_id: ChannelID, group_id: GroupID)
+    -> Result<bool, Error>
+{
+    Ok(query_opt_map(&pool, "
+        SELECT 1
+        FROM Channel
+        WHERE channel_id = $1
+        AND group_id = $2
+    ", &[&channel_id, &group_id], |_| ()).await?.is_some())
+}
+
+/// Get the ID of the group that contains a channel.
+pub async fn channel_group_id(pool: Pool, channel_id: ChannelID)
+    -> Result<Option<GroupID>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT group_id
+        FROM Channel
+        WHERE channel_id = $1
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&channel_id]).await?.map(|row| row.get(0)))
+}
+
+/// Get a summary of a channel. Returns None if the channel doesn't exist.
+pub async fn channel_info(pool: Pool, channel_id: ChannelID)
+    -> Result<Option<ChannelInfo>, Error>
+{
+    query_opt_map(&pool, "
+        SELECT Channel.group_id, Channel.name, (
+            SELECT COUNT(*)
+            FROM Message
+            WHERE Message.channel_id = Channel.channel_id
+            AND Message.deleted_at IS NULL
+        )
+        FROM Channel
+        WHERE channel_id = $1
+    ", &[&channel_id], |row| ChannelInfo {
+        channel_id,
+        group_id: row.get(0),
+        name: row.get(1),
+        message_count: row.get(2),
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{testing, group_channels};
+
+    #[tokio::test]
+    #[ignore]
+    async fn private_channels_need_membership() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let outsider = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let public = testing::channel(&pool, group_id, false).await;
+        let private = testing::channel(&pool, group_id, true).await;
+
+        assert!(channel_accessible(pool.clone(), member, public).await.unwrap());
+        assert!(!channel_accessible(pool.clone(), member, private).await.unwrap());
+        assert!(!channel_accessible(pool.clone(), outsider, public).await.unwrap());
+
+        assert!(add_channel_member(pool.clone(), private, member).await.unwrap());
+        assert!(channel_accessible(pool.clone(), member, private).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn recent_channels_order() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let general = group_channels(pool.clone(), group_id).await.unwrap()[0].channel_id;
+        let older = testing::channel(&pool, group_id, false).await;
+        let newer = testing::channel(&pool, group_id, false).await;
+        let private = testing::channel(&pool, group_id, true).await;
+        testing::message(&pool, owner, older, "older").await;
+        let last = testing::message(&pool, owner, newer, "newer").await;
+        testing::message(&pool, owner, private, "private").await;
+
+        let channels = recent_active_channels(pool.clone(), member, MAX_RECENT_CHANNELS).await.unwrap();
+        let ids = channels.iter().map(|channel| channel.channel_id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![newer, older, general]);
+        let preview = channels[0].last_message.as_ref().unwrap();
+        assert_eq!(preview.message_id, last);
+        assert_eq!(preview.preview, "newer");
+        assert!(channels[2].last_message.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn channel_in_its_group() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let other_group_id = testing::group(&pool, owner).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+
+        assert!(channel_in_group(pool.clone(), channel_id, group_id).await.unwrap());
+        assert!(!channel_in_group(pool.clone(), channel_id, other_group_id).await.unwrap());
+        assert!(!channel_in_group(pool, ChannelID(i32::MAX), group_id).await.unwrap());
+    }
+}
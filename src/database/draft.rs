@@ -0,0 +1,95 @@
+use serde::Serialize;
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use crate::utils::as_timestamp;
+use super::{UserID, GroupID, ChannelID, get_connection};
+
+/// Save the unsent message of a user in a channel. An empty draft is deleted.
+pub async fn set_draft(pool: Pool, user_id: UserID, channel_id: ChannelID, content: &String)
+    -> Result<(), Error>
+{
+    let conn = get_connection(&pool).await?;
+    if content.is_empty() {
+        let stmt = conn.prepare("
+            DELETE FROM Draft
+            WHERE user_id = $1
+            AND channel_id = $2
+        ").await?;
+        conn.execute(&stmt, &[&user_id, &channel_id]).await?;
+    } else {
+        let stmt = conn.prepare("
+            INSERT INTO Draft (user_id, channel_id, content, updated_at)
+            VALUES ($1, $2, $3, NOW())
+            ON CONFLICT (user_id, channel_id) DO UPDATE
+            SET content = EXCLUDED.content, updated_at = EXCLUDED.updated_at
+        ").await?;
+        conn.execute(&stmt, &[&user_id, &channel_id, content]).await?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct Draft {
+    pub channel_id: ChannelID,
+    pub content: String,
+    pub timestamp: u64,
+}
+
+/// Get the drafts of a user in the channels of a group that they can still
+/// access.
+pub async fn get_drafts(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<Vec<Draft>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT Draft.channel_id, content, updated_at
+        FROM Draft
+        JOIN Channel ON Channel.channel_id = Draft.channel_id
+        WHERE Draft.user_id = $1
+        AND Channel.group_id = $2
+        AND (
+            NOT Channel.private
+            OR EXISTS (
+                SELECT 1
+                FROM ChannelMembership
+                WHERE ChannelMembership.channel_id = Draft.channel_id
+                AND ChannelMembership.user_id = $1
+            )
+        )
+        ORDER BY Draft.channel_id
+    ").await?;
+    Ok(conn.query(&stmt, &[&user_id, &group_id]).await?.iter().map(|row| Draft {
+        channel_id: row.get(0),
+        content: row.get(1),
+        timestamp: as_timestamp(row.get(2)),
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+
+    #[tokio::test]
+    #[ignore]
+    async fn set_get_and_clear() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+
+        set_draft(pool.clone(), owner, channel_id, &"unfinished".to_owned()).await.unwrap();
+        let drafts = get_drafts(pool.clone(), owner, group_id).await.unwrap();
+        assert_eq!(drafts.len(), 1);
+        assert_eq!(drafts[0].channel_id, channel_id);
+        assert_eq!(drafts[0].content, "unfinished");
+
+        // Drafts are private.
+        assert!(get_drafts(pool.clone(), member, group_id).await.unwrap().is_empty());
+
+        set_draft(pool.clone(), owner, channel_id, &String::new()).await.unwrap();
+        assert!(get_drafts(pool.clone(), owner, group_id).await.unwrap().is_empty());
+    }
+}
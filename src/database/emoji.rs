@@ -0,0 +1,114 @@
+use serde::Serialize;
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use super::{GroupID, get_connection};
+
+pub const MAX_SHORTCODE_LENGTH: usize = 32;
+
+/// The maximum number of custom emoji in a group.
+pub const MAX_CUSTOM_EMOJI: i64 = 100;
+
+#[derive(Serialize)]
+pub struct CustomEmoji {
+    pub shortcode: String,
+    pub url: String,
+}
+
+/// Get the custom emoji of a group, sorted by shortcode.
+pub async fn group_emoji(pool: Pool, group_id: GroupID) -> Result<Vec<CustomEmoji>, Error> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT shortcode, url
+        FROM CustomEmoji
+        WHERE group_id = $1
+        ORDER BY shortcode
+    ").await?;
+    Ok(conn.query(&stmt, &[&group_id]).await?.iter().map(|row| CustomEmoji {
+        shortcode: row.get(0),
+        url: row.get(1),
+    }).collect())
+}
+
+/// Add a custom emoji to a group.
+///
+/// Returns false if the shortcode is already used in the group or the group
+/// has reached the emoji limit.
+pub async fn add_custom_emoji(pool: Pool, group_id: GroupID, shortcode: &String, url: &String)
+    -> Result<bool, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO CustomEmoji (group_id, shortcode, url)
+        SELECT $1, $2, $3
+        WHERE (
+            SELECT COUNT(*)
+            FROM CustomEmoji
+            WHERE group_id = $1
+        ) < $4
+        ON CONFLICT (group_id, shortcode) DO NOTHING
+    ").await?;
+    Ok(conn.execute(&stmt, &[&group_id, shortcode, url, &MAX_CUSTOM_EMOJI]).await? > 0)
+}
+
+/// Remove a custom emoji from a group.
+///
+/// Returns true if the emoji was actually removed.
+pub async fn remove_custom_emoji(pool: Pool, group_id: GroupID, shortcode: &String)
+    -> Result<bool, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        DELETE FROM CustomEmoji
+        WHERE group_id = $1
+        AND shortcode = $2
+    ").await?;
+    Ok(conn.execute(&stmt, &[&group_id, shortcode]).await? > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+
+    fn shortcodes(emoji: Vec<CustomEmoji>) -> Vec<String> {
+        emoji.into_iter().map(|emoji| emoji.shortcode).collect()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn add_and_remove() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let other_group_id = testing::group(&pool, owner).await;
+        let url = "https://example.com/emoji.png".to_owned();
+
+        assert!(add_custom_emoji(pool.clone(), group_id, &"wave_b".to_owned(), &url).await.unwrap());
+        assert!(add_custom_emoji(pool.clone(), group_id, &"wave_a".to_owned(), &url).await.unwrap());
+        assert!(!add_custom_emoji(pool.clone(), group_id, &"wave_a".to_owned(), &url).await.unwrap());
+        // Shortcodes only have to be unique within a group.
+        assert!(add_custom_emoji(pool.clone(), other_group_id, &"wave_a".to_owned(), &url).await.unwrap());
+        assert_eq!(shortcodes(group_emoji(pool.clone(), group_id).await.unwrap()), vec!["wave_a", "wave_b"]);
+
+        assert!(remove_custom_emoji(pool.clone(), group_id, &"wave_a".to_owned()).await.unwrap());
+        assert!(!remove_custom_emoji(pool.clone(), group_id, &"wave_a".to_owned()).await.unwrap());
+        assert_eq!(shortcodes(group_emoji(pool.clone(), group_id).await.unwrap()), vec!["wave_b"]);
+        assert_eq!(shortcodes(group_emoji(pool, other_group_id).await.unwrap()), vec!["wave_a"]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn emoji_limit() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let url = "https://example.com/emoji.png".to_owned();
+        for i in 0..MAX_CUSTOM_EMOJI {
+            let shortcode = format!("emoji_{}", i);
+            assert!(add_custom_emoji(pool.clone(), group_id, &shortcode, &url).await.unwrap());
+        }
+
+        let shortcode = "one_too_many".to_owned();
+        assert!(!add_custom_emoji(pool, group_id, &shortcode, &url).await.unwrap());
+    }
+}
@@ -1,19 +1,21 @@
 use serde::Serialize;
 use crate::error::Error;
-use super::{Channel, UserID};
-use deadpool_postgres::{Pool, PoolError};
+use super::{Channel, GroupID, UserID, Role, get_connection, query_map, query_opt_map, execute};
+use deadpool_postgres::Pool;
+use deadpool_postgres::tokio_postgres::error::SqlState;
 
-pub type GroupID = i32;
-
-/// Create a new group.
+/// Create a new group with one public channel. The user becomes the owner of
+/// the group.
 ///
 /// Returns Ok(None) if the name is not unique.
 /// Returns Err if a database error occurred.
-pub async fn create_group(pool: Pool, name: String, picture: String)
+pub async fn create_group(pool: Pool, owner_id: UserID, name: &String, picture: &String, channel_name: &String)
     -> Result<Option<GroupID>, Error>
 {
-    let conn = pool.get().await?;
-    let stmt = conn.prepare("
+    let mut conn = get_connection(&pool).await?;
+    let transaction = conn.transaction().await?;
+
+    let stmt = transaction.prepare("
         INSERT INTO Groop (name, picture)
         SELECT $1, $2
         WHERE NOT EXISTS (
@@ -23,7 +25,29 @@ pub async fn create_group(pool: Pool, name: String, picture: String)
         )
         RETURNING group_id
     ").await?;
-    Ok(conn.query_opt(&stmt, &[&name, &picture]).await?.map(|row| row.get(0)))
+    // Two groups with the same name can get past the NOT EXISTS check if
+    // they're created at the same time. The unique constraint catches that.
+    let group_id: GroupID = match transaction.query_opt(&stmt, &[name, picture]).await {
+        Ok(Some(row)) => row.get(0),
+        Ok(None) => return Ok(None),
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let stmt = transaction.prepare("
+        INSERT INTO Channel (name, group_id, private, position)
+        VALUES ($1, $2, FALSE, 0)
+    ").await?;
+    transaction.execute(&stmt, &[channel_name, &group_id]).await?;
+
+    let stmt = transaction.prepare("
+        INSERT INTO Membership (user_id, group_id, role)
+        VALUES ($1, $2, $3)
+    ").await?;
+    transaction.execute(&stmt, &[&owner_id, &group_id, &Role::Owner.as_str()]).await?;
+
+    transaction.commit().await?;
+    Ok(Some(group_id))
 }
 
 /// Get the channels in a group
@@ -32,21 +56,45 @@ pub async fn create_group(pool: Pool, name: String, picture: String)
 pub async fn group_channels(pool: Pool, group_id: GroupID)
     -> Result<Vec<Channel>, Error>
 {
-    let conn = pool.get().await?;
-    let stmt = conn.prepare("
-        SELECT channel_id, name
+    query_map(&pool, "
+        SELECT channel_id, name, slow_mode_seconds, private, position
         FROM Channel
         WHERE group_id = $1
-        ORDER BY channel_id
-    ").await?;
-    Ok(conn.query(&stmt, &[&group_id])
-        .await?
-        .iter()
-        .map(|row| Channel {
-            channel_id: row.get(0),
-            name: row.get(1),
-        })
-        .collect())
+        ORDER BY position, channel_id
+    ", &[&group_id], |row| Channel {
+        channel_id: row.get(0),
+        name: row.get(1),
+        slow_mode_seconds: row.get(2),
+        private: row.get(3),
+        position: row.get(4),
+    }).await
+}
+
+/// Get the channels of a group that a user can access.
+pub async fn user_group_channels(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<Vec<Channel>, Error>
+{
+    query_map(&pool, "
+        SELECT channel_id, name, slow_mode_seconds, private, position
+        FROM Channel
+        WHERE group_id = $1
+        AND (
+            NOT private
+            OR EXISTS (
+                SELECT 1
+                FROM ChannelMembership
+                WHERE ChannelMembership.channel_id = Channel.channel_id
+                AND ChannelMembership.user_id = $2
+            )
+        )
+        ORDER BY position, channel_id
+    ", &[&group_id, &user_id], |row| Channel {
+        channel_id: row.get(0),
+        name: row.get(1),
+        slow_mode_seconds: row.get(2),
+        private: row.get(3),
+        position: row.get(4),
+    }).await
 }
 
 #[derive(Serialize)]
@@ -58,52 +106,117 @@ pub struct Group {
 
 /// Get the list of groups that a user is a member of.
 pub async fn user_groups(pool: Pool, user_id: UserID) -> Result<Vec<Group>, Error> {
-    let conn = pool.get().await?;
-    let stmt = conn.prepare("
+    query_map(&pool, "
         SELECT Groop.group_id, name, picture
         FROM Groop
         JOIN Membership ON Membership.group_id = Groop.group_id
         WHERE Membership.user_id = $1
         ORDER BY Groop.group_id
-    ").await?;
-    Ok(conn.query(&stmt, &[&user_id]).await?.iter().map(|row| Group {
+    ", &[&user_id], |row| Group {
         group_id: row.get(0),
         name: row.get(1),
         picture: row.get(2),
-    }).collect())
+    }).await
+}
+
+#[derive(Serialize)]
+pub struct GroupUnread {
+    pub group_id: GroupID,
+    pub name: String,
+    pub picture: String,
+    pub unread: i64,
+}
+
+/// The maximum number of groups in a page of the group list.
+pub const MAX_GROUP_PAGE: u32 = 500;
+
+/// Get a page of the list of groups that a user is a member of, along with
+/// the number of messages that the user hasn't read in each group.
+///
+/// Only the channels that the user can access are counted. The user's own
+/// messages and deleted messages are not counted. Groups are ordered by ID.
+/// The page starts after the given group.
+pub async fn user_groups_unread(pool: Pool, user_id: UserID, after: Option<GroupID>, limit: u32)
+    -> Result<Vec<GroupUnread>, Error>
+{
+    let limit = limit as i64;
+    query_map(&pool, "
+        SELECT Groop.group_id, Groop.name, Groop.picture, COUNT(Message.message_id)
+        FROM Groop
+        JOIN Membership ON Membership.group_id = Groop.group_id
+        LEFT JOIN Channel ON Channel.group_id = Groop.group_id
+            AND (
+                NOT Channel.private
+                OR EXISTS (
+                    SELECT 1
+                    FROM ChannelMembership
+                    WHERE ChannelMembership.channel_id = Channel.channel_id
+                    AND ChannelMembership.user_id = $1
+                )
+            )
+        LEFT JOIN ChannelRead ON ChannelRead.channel_id = Channel.channel_id
+            AND ChannelRead.user_id = $1
+        LEFT JOIN Message ON Message.channel_id = Channel.channel_id
+            AND Message.message_id > COALESCE(ChannelRead.message_id, 0)
+            AND Message.author IS DISTINCT FROM $1
+            AND Message.deleted_at IS NULL
+        WHERE Membership.user_id = $1
+        AND ($2::INTEGER IS NULL OR Groop.group_id > $2)
+        GROUP BY Groop.group_id
+        ORDER BY Groop.group_id
+        LIMIT $3
+    ", &[&user_id, &after, &limit], |row| GroupUnread {
+        group_id: row.get(0),
+        name: row.get(1),
+        picture: row.get(2),
+        unread: row.get(3),
+    }).await
 }
 
 /// Get the list of group IDs that a user is a member of.
 pub async fn user_group_ids(pool: Pool, user_id: UserID) -> Result<Vec<GroupID>, Error> {
-    let conn = pool.get().await?;
-    let stmt = conn.prepare("
+    query_map(&pool, "
         SELECT Groop.group_id
         FROM Groop
         JOIN Membership ON Membership.group_id = Groop.group_id
         WHERE Membership.user_id = $1
         ORDER BY Groop.group_id
-    ").await?;
-    Ok(conn.query(&stmt, &[&user_id]).await?.iter().map(|row| row.get(0)).collect())
+    ", &[&user_id], |row| row.get(0)).await
 }
 
 /// Determine whether a user is a member of a group
 pub async fn group_member(pool: Pool, user_id: UserID, group_id: GroupID)
     -> Result<bool, Error>
 {
-    let conn = pool.get().await?;
-    let stmt = conn.prepare("
+    Ok(query_opt_map(&pool, "
         SELECT 1
         FROM Membership
         WHERE user_id = $1
         AND group_id = $2
-    ").await?;
-    Ok(conn.query_opt(&stmt, &[&user_id, &group_id]).await?.is_some())
+    ", &[&user_id, &group_id], |_| ()).await?.is_some())
+}
+
+/// Determine whether two users are members of at least one common group
+pub async fn share_group(pool: Pool, user_a: UserID, user_b: UserID)
+    -> Result<bool, Error>
+{
+    Ok(query_opt_map(&pool, "
+        SELECT 1
+        FROM Membership A
+        JOIN Membership B ON B.group_id = A.group_id
+        WHERE A.user_id = $1
+        AND B.user_id = $2
+        LIMIT 1
+    ", &[&user_a, &user_b], |_| ()).await?.is_some())
 }
 
-pub async fn rename_group(pool: Pool, group_id: GroupID, name: &String, picture: &String)
-    -> Result<bool, PoolError>
+/// Change the name and picture of a group.
+///
+/// Returns Ok(false) if the name is used by another group.
+pub async fn update_group(pool: Pool, group_id: GroupID, name: &String, picture: &String)
+    -> Result<bool, Error>
 {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
         UPDATE Groop
         SET name = $2, picture = $3
@@ -115,14 +228,77 @@ pub async fn rename_group(pool: Pool, group_id: GroupID, name: &String, picture:
             AND group_id != $1
         )
     ").await?;
-    Ok(conn.execute(&stmt, &[&group_id, name, picture]).await? > 0)
+    // Same as create_group, the unique constraint catches two groups being
+    // given the same name at the same time.
+    match conn.execute(&stmt, &[&group_id, name, picture]).await {
+        Ok(count) => Ok(count > 0),
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
 }
 
 pub async fn delete_group(pool: Pool, group_id: GroupID) -> Result<bool, Error> {
-    let conn = pool.get().await?;
-    let stmt = conn.prepare("
+    Ok(execute(&pool, "
         DELETE FROM Groop
         WHERE group_id = $1
-    ").await?;
-    Ok(conn.execute(&stmt, &[&group_id]).await? > 0)
+    ", &[&group_id]).await? > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{testing, mark_read};
+
+    #[tokio::test]
+    #[ignore]
+    async fn name_is_taken() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let name = testing::name();
+        let picture = String::new();
+        let channel_name = "general".to_owned();
+
+        // Both groups are created at the same time so that the second might get
+        // past the check for an existing name.
+        let (first, second) = tokio::join!(
+            create_group(pool.clone(), owner, &name, &picture, &channel_name),
+            create_group(pool.clone(), owner, &name, &picture, &channel_name)
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(first.is_some() != second.is_some());
+
+        let third = create_group(pool, owner, &name, &picture, &channel_name).await.unwrap();
+        assert_eq!(third, None);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn unread_counts_skip_read_channels() {
+        let pool = testing::pool();
+        let reader = testing::user(&pool).await;
+        let writer = testing::user(&pool).await;
+        let group_id = testing::group(&pool, writer).await;
+        testing::join(&pool, reader, group_id, "member").await;
+        let read = testing::channel(&pool, group_id, false).await;
+        let unread = testing::channel(&pool, group_id, false).await;
+
+        testing::message(&pool, writer, read, "read").await;
+        let last_read = testing::message(&pool, writer, read, "read").await;
+        mark_read(pool.clone(), reader, read, last_read).await.unwrap();
+        for _ in 0..3 {
+            testing::message(&pool, writer, unread, "unread").await;
+        }
+        // The reader's own messages are never unread.
+        testing::message(&pool, reader, unread, "mine").await;
+
+        let groups = user_groups_unread(pool.clone(), reader, None, MAX_GROUP_PAGE).await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].group_id, group_id);
+        assert_eq!(groups[0].unread, 3);
+
+        // Messages after the last read message are unread again.
+        testing::message(&pool, writer, read, "new").await;
+        let groups = user_groups_unread(pool.clone(), reader, None, MAX_GROUP_PAGE).await.unwrap();
+        assert_eq!(groups[0].unread, 4);
+    }
 }
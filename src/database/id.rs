@@ -0,0 +1,159 @@
+use std::fmt;
+use std::error::Error;
+use std::str::FromStr;
+use bytes::BytesMut;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, Unexpected};
+use deadpool_postgres::tokio_postgres::types::{FromSql, ToSql, Type, IsNull, to_sql_checked};
+
+/// The error from parsing an ID that isn't an integer or is out of range.
+#[derive(Debug)]
+pub struct ParseIDError;
+
+impl fmt::Display for ParseIDError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid ID")
+    }
+}
+
+impl Error for ParseIDError {}
+
+/// Define an ID type that wraps an INTEGER column.
+///
+/// The wrappers are distinct types so that the compiler catches an ID being
+/// passed where a different kind of ID is expected. They are stored in the
+/// database and serialized to JSON as plain integers.
+///
+/// IDs that come from clients are rejected if they're below the minimum, which
+/// is 1 unless another is given. IDs from the database are trusted.
+macro_rules! id_type {
+    ($(#[$attr:meta])* $name:ident) => {
+        id_type!($(#[$attr])* $name, 1);
+    };
+    ($(#[$attr:meta])* $name:ident, $min:expr) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+        pub struct $name(pub i32);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        // Needed for parsing IDs in URL paths.
+        impl FromStr for $name {
+            type Err = ParseIDError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s.parse() {
+                    Ok(id) if id >= $min => Ok($name(id)),
+                    _ => Err(ParseIDError),
+                }
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                self.0.serialize(serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let id = i32::deserialize(deserializer)?;
+                if id >= $min {
+                    Ok($name(id))
+                } else {
+                    Err(de::Error::invalid_value(Unexpected::Signed(id as i64), &"a valid ID"))
+                }
+            }
+        }
+
+        impl<'a> FromSql<'a> for $name {
+            fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+                i32::from_sql(ty, raw).map($name)
+            }
+
+            fn accepts(ty: &Type) -> bool {
+                <i32 as FromSql>::accepts(ty)
+            }
+        }
+
+        impl ToSql for $name {
+            fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+                self.0.to_sql(ty, out)
+            }
+
+            fn accepts(ty: &Type) -> bool {
+                <i32 as ToSql>::accepts(ty)
+            }
+
+            to_sql_checked!();
+        }
+    };
+}
+
+id_type!(
+    /// Messages from the system and from deleted accounts have an author ID
+    /// of 0 so clients can ask about it.
+    UserID, 0
+);
+id_type!(GroupID);
+id_type!(ChannelID);
+id_type!(MessageID);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_positive() {
+        assert_eq!("42".parse::<GroupID>().unwrap(), GroupID(42));
+        assert_eq!(serde_json::from_str::<ChannelID>("7").unwrap(), ChannelID(7));
+    }
+
+    #[test]
+    fn parse_non_positive() {
+        assert!("0".parse::<GroupID>().is_err());
+        assert!("-3".parse::<MessageID>().is_err());
+        assert!(serde_json::from_str::<ChannelID>("0").is_err());
+        assert!(serde_json::from_str::<MessageID>("-1").is_err());
+    }
+
+    #[test]
+    fn parse_not_an_id() {
+        assert!("".parse::<GroupID>().is_err());
+        assert!("abc".parse::<GroupID>().is_err());
+        assert!("99999999999".parse::<GroupID>().is_err());
+        assert!(serde_json::from_str::<GroupID>("\"1\"").is_err());
+    }
+
+    #[test]
+    fn user_id_allows_zero() {
+        assert_eq!("0".parse::<UserID>().unwrap(), UserID(0));
+        assert_eq!(serde_json::from_str::<UserID>("0").unwrap(), UserID(0));
+        assert!("-1".parse::<UserID>().is_err());
+    }
+
+    #[test]
+    fn serialize_as_integer() {
+        assert_eq!(serde_json::to_string(&MessageID(5)).unwrap(), "5");
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = serde_json::to_string(&GroupID(12)).unwrap();
+        assert_eq!(serde_json::from_str::<GroupID>(&json).unwrap(), GroupID(12));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn sql_round_trip() {
+        let pool = super::super::testing::pool();
+        let conn = pool.get().await.unwrap();
+        let row = conn.query_one("SELECT $1::INTEGER, $2::INTEGER", &[&ChannelID(34), &UserID(0)]).await.unwrap();
+        assert_eq!(row.get::<_, ChannelID>(0), ChannelID(34));
+        assert_eq!(row.get::<_, UserID>(1), UserID(0));
+    }
+}
@@ -1,6 +1,8 @@
+use log::error;
+use serde::Serialize;
 use crate::error::Error;
 use deadpool_postgres::Pool;
-use super::{UserID, GroupID};
+use super::{User, UserID, GroupID, get_connection};
 use crate::utils::generate_random_base64url;
 
 // This value is duplicated in the column type of Invitation.invite_id
@@ -8,68 +10,399 @@ pub const INVITE_ID_LENGTH: usize = 16;
 
 pub type InviteID = String;
 
-macro_rules! creation_timeout {
-    () => { "INTERVAL '24 hours'" }
-}
+/// Invitations are valid for this many hours unless another time is given.
+pub const DEFAULT_INVITE_HOURS: u32 = 24;
+/// Invitations can't be valid for longer than this many hours.
+pub const MAX_INVITE_HOURS: u32 = 30 * 24;
 
-pub async fn create_invitation(pool: Pool, group_id: GroupID)
+pub async fn create_invite(pool: Pool, group_id: GroupID, created_by: UserID, hours: u32, max_uses: Option<i32>)
     -> Result<InviteID, Error>
 {
     // This function is nearly identical to create_session
     let mut invite_id = generate_random_base64url(INVITE_ID_LENGTH);
 
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
-         INSERT INTO Invitation (invite_id, group_id, creation_time)
-         VALUES ($1, $2, NOW())
+         INSERT INTO Invitation (invite_id, group_id, created_by, creation_time, expires_at, max_uses)
+         VALUES ($1, $2, $3, NOW(), NOW() + make_interval(hours => $4), $5)
          ON CONFLICT (invite_id) DO NOTHING
     ").await?;
+    let hours = hours as i32;
 
-    while conn.execute(&stmt, &[&invite_id, &group_id]).await? == 0 {
+    while conn.execute(&stmt, &[&invite_id, &group_id, &created_by, &hours, &max_uses]).await? == 0 {
         invite_id = generate_random_base64url(INVITE_ID_LENGTH);
     }
 
     Ok(invite_id)
 }
 
-pub async fn invitation_group_id(pool: Pool, invite_id: InviteID)
-    -> Result<Option<GroupID>, Error>
+pub enum RedeemResult {
+    /// The user joined the group.
+    Joined(GroupID),
+    /// The user was already a member of the group. The invitation wasn't used
+    /// up.
+    AlreadyMember(GroupID),
+    /// The invitation doesn't exist, has expired or has been used up.
+    Invalid,
+}
+
+/// Join a group using an invitation.
+pub async fn redeem_invite(pool: Pool, invite_id: &InviteID, user_id: UserID)
+    -> Result<RedeemResult, Error>
 {
-    // This function is nearly identical to session_user_id
     if invite_id.len() != INVITE_ID_LENGTH {
-        return Ok(None);
+        return Ok(RedeemResult::Invalid);
     }
 
-    let conn = pool.get().await?;
-    let stmt = conn.prepare(concat!("
-        SELECT group_id
+    let mut conn = get_connection(&pool).await?;
+    let transaction = conn.transaction().await?;
+
+    // Locking the invitation stops concurrent redemptions from going over
+    // the use limit.
+    let stmt = transaction.prepare("
+        SELECT group_id, expires_at > NOW() AND (max_uses IS NULL OR uses < max_uses)
         FROM Invitation
         WHERE invite_id = $1
-        AND creation_time > NOW() - ", creation_timeout!()
-    )).await?;
-    Ok(conn.query_opt(&stmt, &[&invite_id]).await?.map(|row| row.get(0)))
+        FOR UPDATE
+    ").await?;
+    let (group_id, usable) = match transaction.query_opt(&stmt, &[invite_id]).await? {
+        Some(row) => (row.get(0), row.get::<_, bool>(1)),
+        None => return Ok(RedeemResult::Invalid)
+    };
+
+    // Members can still use an invitation that is no longer valid to find
+    // their way to the group.
+    let stmt = transaction.prepare("
+        SELECT 1
+        FROM Membership
+        WHERE user_id = $1
+        AND group_id = $2
+    ").await?;
+    if transaction.query_opt(&stmt, &[&user_id, &group_id]).await?.is_some() {
+        return Ok(RedeemResult::AlreadyMember(group_id));
+    }
+    if !usable {
+        return Ok(RedeemResult::Invalid);
+    }
+
+    let stmt = transaction.prepare("
+        INSERT INTO Membership (user_id, group_id, role)
+        VALUES ($1, $2, $3)
+    ").await?;
+    transaction.execute(&stmt, &[&user_id, &group_id, &Role::Member.as_str()]).await?;
+
+    let stmt = transaction.prepare("
+        UPDATE Invitation
+        SET uses = uses + 1
+        WHERE invite_id = $1
+    ").await?;
+    transaction.execute(&stmt, &[invite_id]).await?;
+
+    transaction.commit().await?;
+    Ok(RedeemResult::Joined(group_id))
 }
 
-pub async fn join_group(pool: Pool, user_id: UserID, group_id: GroupID)
-    -> Result<bool, Error>
+/// The role of a member within a group.
+///
+/// Roles are ordered so that a higher role can do everything that a lower role
+/// can.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Debug)]
+#[serde(rename_all="snake_case")]
+pub enum Role {
+    Member,
+    Moderator,
+    Owner,
+}
+
+impl Role {
+    // This must match the CHECK constraint on Membership.role
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Owner => "owner",
+        }
+    }
+
+    /// Returns None if the role is unknown. This can only happen if the
+    /// CHECK constraint is out of date.
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "member" => Some(Role::Member),
+            "moderator" => Some(Role::Moderator),
+            "owner" => Some(Role::Owner),
+            _ => {
+                error!("Unknown role: {}", role);
+                None
+            }
+        }
+    }
+}
+
+/// Get the role of a user within a group.
+///
+/// Returns Ok(None) if the user is not a member of the group. A member with an
+/// unknown role is treated as not being a member so that they can't do
+/// anything that they shouldn't.
+pub async fn member_role(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<Option<Role>, Error>
 {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
-        INSERT INTO Membership (user_id, group_id)
-        VALUES ($1, $2)
-        ON CONFLICT DO NOTHING;
+        SELECT role
+        FROM Membership
+        WHERE user_id = $1
+        AND group_id = $2
     ").await?;
-    Ok(conn.execute(&stmt, &[&user_id, &group_id]).await? > 0)
+    Ok(conn.query_opt(&stmt, &[&user_id, &group_id]).await?.and_then(|row| {
+        Role::parse(row.get(0))
+    }))
 }
 
-pub async fn leave_group(pool: Pool, user_id: UserID, group_id: GroupID)
-    -> Result<bool, Error>
+/// Get the number of groups that a user owns.
+pub async fn owned_group_count(pool: Pool, user_id: UserID)
+    -> Result<i64, Error>
 {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
+        SELECT COUNT(*)
+        FROM Membership
+        WHERE user_id = $1
+        AND role = 'owner'
+    ").await?;
+    Ok(conn.query_one(&stmt, &[&user_id]).await?.get(0))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LeaveResult {
+    Left,
+    /// The user is not a member of the group.
+    NotMember,
+    /// The user is the only owner of the group. A group must always have an
+    /// owner so they must promote someone else or delete the group instead.
+    SoleOwner,
+}
+
+pub async fn leave_group(pool: Pool, user_id: UserID, group_id: GroupID)
+    -> Result<LeaveResult, Error>
+{
+    let mut conn = get_connection(&pool).await?;
+    let transaction = conn.transaction().await?;
+
+    // Locking the owners stops two owners from both leaving at the same time
+    // because they each saw the other.
+    let stmt = transaction.prepare("
+        SELECT user_id
+        FROM Membership
+        WHERE group_id = $1
+        AND role = 'owner'
+        FOR UPDATE
+    ").await?;
+    let owners = transaction.query(&stmt, &[&group_id]).await?;
+    if owners.len() == 1 && owners[0].get::<_, UserID>(0) == user_id {
+        return Ok(LeaveResult::SoleOwner);
+    }
+
+    let stmt = transaction.prepare("
         DELETE FROM Membership
         WHERE user_id = $1
         AND group_id = $2
     ").await?;
-    Ok(conn.execute(&stmt, &[&user_id, &group_id]).await? > 0)
+    if transaction.execute(&stmt, &[&user_id, &group_id]).await? == 0 {
+        return Ok(LeaveResult::NotMember);
+    }
+
+    transaction.commit().await?;
+    Ok(LeaveResult::Left)
+}
+
+pub enum TransferResult {
+    Transferred,
+    /// The user giving away ownership is not an owner of the group.
+    NotOwner,
+    /// The user receiving ownership is not a member of the group.
+    NotMember,
+}
+
+/// Transfer ownership of a group from one member to another.
+///
+/// The previous owner becomes a moderator.
+pub async fn transfer_ownership(pool: Pool, group_id: GroupID, from_user: UserID, to_user: UserID)
+    -> Result<TransferResult, Error>
+{
+    let mut conn = get_connection(&pool).await?;
+    let transaction = conn.transaction().await?;
+
+    let stmt = transaction.prepare("
+        SELECT role
+        FROM Membership
+        WHERE user_id = $1
+        AND group_id = $2
+        FOR UPDATE
+    ").await?;
+
+    match transaction.query_opt(&stmt, &[&from_user, &group_id]).await? {
+        Some(row) if Role::parse(row.get(0)) == Some(Role::Owner) => {},
+        _ => return Ok(TransferResult::NotOwner)
+    }
+    if transaction.query_opt(&stmt, &[&to_user, &group_id]).await?.is_none() {
+        return Ok(TransferResult::NotMember);
+    }
+
+    let stmt = transaction.prepare("
+        UPDATE Membership
+        SET role = $3
+        WHERE user_id = $1
+        AND group_id = $2
+    ").await?;
+    transaction.execute(&stmt, &[&from_user, &group_id, &Role::Moderator.as_str()]).await?;
+    transaction.execute(&stmt, &[&to_user, &group_id, &Role::Owner.as_str()]).await?;
+
+    transaction.commit().await?;
+    Ok(TransferResult::Transferred)
+}
+
+pub struct MembershipImport {
+    /// The users that were not members of the group before.
+    pub added: Vec<User>,
+    /// The users that were already members of the group.
+    pub existing: Vec<UserID>,
+}
+
+/// Add many users to a group as members at once.
+///
+/// User IDs that don't belong to a user are ignored.
+pub async fn add_memberships(pool: Pool, group_id: GroupID, user_ids: &[UserID])
+    -> Result<MembershipImport, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        WITH Requested AS (
+            SELECT user_id, name, picture
+            FROM Usr
+            WHERE user_id = ANY($1)
+        ), Inserted AS (
+            INSERT INTO Membership (user_id, group_id, role)
+            SELECT user_id, $2, 'member'
+            FROM Requested
+            ON CONFLICT DO NOTHING
+            RETURNING user_id
+        )
+        SELECT user_id, name, picture, user_id IN (SELECT user_id FROM Inserted)
+        FROM Requested
+        ORDER BY user_id
+    ").await?;
+
+    let mut import = MembershipImport { added: Vec::new(), existing: Vec::new() };
+    for row in conn.query(&stmt, &[&user_ids, &group_id]).await?.iter() {
+        if row.get(3) {
+            import.added.push(User {
+                user_id: row.get(0),
+                name: row.get(1),
+                picture: row.get(2),
+            });
+        } else {
+            import.existing.push(row.get(0));
+        }
+    }
+    Ok(import)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+
+    #[tokio::test]
+    #[ignore]
+    async fn sole_owner_cannot_leave() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+
+        assert_eq!(leave_group(pool.clone(), owner, group_id).await.unwrap(), LeaveResult::SoleOwner);
+        assert_eq!(member_role(pool.clone(), owner, group_id).await.unwrap(), Some(Role::Owner));
+        assert_eq!(leave_group(pool.clone(), member, group_id).await.unwrap(), LeaveResult::Left);
+        assert_eq!(leave_group(pool.clone(), member, group_id).await.unwrap(), LeaveResult::NotMember);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn one_of_two_owners_can_leave() {
+        let pool = testing::pool();
+        let first = testing::user(&pool).await;
+        let second = testing::user(&pool).await;
+        let group_id = testing::group(&pool, first).await;
+        testing::join(&pool, second, group_id, "owner").await;
+
+        let (first_result, second_result) = futures::join!(
+            leave_group(pool.clone(), first, group_id),
+            leave_group(pool.clone(), second, group_id)
+        );
+        let mut results = vec![first_result.unwrap(), second_result.unwrap()];
+        results.sort_by_key(|result| *result == LeaveResult::SoleOwner);
+        assert_eq!(results, vec![LeaveResult::Left, LeaveResult::SoleOwner]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn transfer_to_non_member() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let outsider = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+
+        let result = transfer_ownership(pool.clone(), group_id, owner, outsider).await.unwrap();
+        assert!(matches!(result, TransferResult::NotMember));
+        assert_eq!(member_role(pool.clone(), owner, group_id).await.unwrap(), Some(Role::Owner));
+        assert_eq!(member_role(pool.clone(), outsider, group_id).await.unwrap(), None);
+
+        let result = transfer_ownership(pool.clone(), group_id, owner, member).await.unwrap();
+        assert!(matches!(result, TransferResult::Transferred));
+        assert_eq!(member_role(pool.clone(), owner, group_id).await.unwrap(), Some(Role::Moderator));
+        assert_eq!(member_role(pool.clone(), member, group_id).await.unwrap(), Some(Role::Owner));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn expired_invite() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let joiner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let invite_id = create_invite(pool.clone(), group_id, owner, 1, None).await.unwrap();
+        pool.get().await.unwrap().execute(
+            "UPDATE Invitation SET expires_at = NOW() - INTERVAL '1 minute' WHERE invite_id = $1",
+            &[&invite_id]
+        ).await.unwrap();
+
+        let result = redeem_invite(pool.clone(), &invite_id, joiner).await.unwrap();
+        assert!(matches!(result, RedeemResult::Invalid));
+        assert_eq!(member_role(pool.clone(), joiner, group_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn exhausted_invite() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let first = testing::user(&pool).await;
+        let second = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let invite_id = create_invite(pool.clone(), group_id, owner, 1, Some(1)).await.unwrap();
+
+        let result = redeem_invite(pool.clone(), &invite_id, first).await.unwrap();
+        assert!(matches!(result, RedeemResult::Joined(id) if id == group_id));
+        let result = redeem_invite(pool.clone(), &invite_id, second).await.unwrap();
+        assert!(matches!(result, RedeemResult::Invalid));
+        assert_eq!(member_role(pool.clone(), second, group_id).await.unwrap(), None);
+
+        // Members can still follow the link back to the group.
+        let result = redeem_invite(pool.clone(), &invite_id, first).await.unwrap();
+        assert!(matches!(result, RedeemResult::AlreadyMember(id) if id == group_id));
+    }
 }
@@ -1,56 +1,764 @@
-use super::{ChannelID, UserID};
+use super::{ChannelID, GroupID, UserID, MessageID, AttachmentID, Attachment, attach_files, get_connection};
+use serde::Serialize;
+use futures::{stream, Stream, StreamExt};
+use crate::utils::as_timestamp;
 use deadpool_postgres::{Pool, PoolError};
 use deadpool_postgres::tokio_postgres::Row;
 
-pub type MessageID = i32;
+/// The number of messages in a page of history.
+pub const MESSAGE_PAGE_SIZE: usize = 50;
 
-pub async fn recent_messages(pool: Pool, channel_id: ChannelID) -> Result<Vec<Row>, PoolError> {
-    let conn = pool.get().await?;
+/// A page of messages, sorted by ID.
+pub struct MessagePage {
+    pub rows: Vec<Row>,
+    /// Whether there are older messages than the ones in this page.
+    pub has_more: bool,
+}
+
+impl MessagePage {
+    /// One more row than the page size is fetched to find out whether there
+    /// are older messages without another query. That row is removed here.
+    fn from_rows(mut rows: Vec<Row>) -> Self {
+        let has_more = trim_page(&mut rows);
+        Self { rows, has_more }
+    }
+
+    /// The ID to request the next page with.
+    pub fn next_before(&self) -> Option<MessageID> {
+        if self.has_more {
+            self.rows.first().map(|row| row.get(0))
+        } else {
+            None
+        }
+    }
+}
+
+/// Remove the oldest rows beyond the page size and return whether there were
+/// any. Rows are sorted oldest first.
+fn trim_page<T>(rows: &mut Vec<T>) -> bool {
+    let has_more = rows.len() > MESSAGE_PAGE_SIZE;
+    if has_more {
+        rows.drain(..rows.len() - MESSAGE_PAGE_SIZE);
+    }
+    has_more
+}
+
+pub async fn recent_messages(pool: Pool, channel_id: ChannelID) -> Result<MessagePage, PoolError> {
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
-        SELECT message_id, timestamp, COALESCE(author, 0), content
+        SELECT message_id, timestamp, COALESCE(author, 0), content, version, deleted_at IS NOT NULL, reply_to, system, forwarded_from
         FROM (
             SELECT *
             FROM Message
             WHERE channel_id = $1
             ORDER BY message_id DESC
-            LIMIT 50
+            LIMIT $2
         ) Temp
         ORDER BY message_id ASC
     ").await?;
-    conn.query(&stmt, &[&channel_id]).await.map_err(|e| e.into())
+    let limit = MESSAGE_PAGE_SIZE as i64 + 1;
+    Ok(MessagePage::from_rows(conn.query(&stmt, &[&channel_id, &limit]).await?))
 }
 
 pub async fn old_messages(pool: Pool, channel_id: ChannelID, message_id: MessageID)
-    -> Result<Vec<Row>, PoolError>
+    -> Result<MessagePage, PoolError>
 {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
-        SELECT message_id, timestamp, COALESCE(author, 0), content
+        SELECT message_id, timestamp, COALESCE(author, 0), content, version, deleted_at IS NOT NULL, reply_to, system, forwarded_from
         FROM (
             SELECT *
             FROM Message
             WHERE channel_id = $1
             AND message_id < $2
             ORDER BY message_id DESC
-            LIMIT 50
+            LIMIT $3
+        ) Temp
+        ORDER BY message_id ASC
+    ").await?;
+    let limit = MESSAGE_PAGE_SIZE as i64 + 1;
+    Ok(MessagePage::from_rows(conn.query(&stmt, &[&channel_id, &message_id, &limit]).await?))
+}
+
+/// The maximum number of messages in a page of group activity.
+pub const MAX_GROUP_MESSAGES: u32 = 100;
+
+/// Get the most recent messages across every channel of a group that a user
+/// can access, newest first.
+///
+/// Only messages older than the message `before` are returned if it is given.
+/// Messages are ordered by time and then by ID so that paging is stable. The
+/// channel ID of each message is in column 9.
+pub async fn recent_group_messages(pool: Pool, user_id: UserID, group_id: GroupID, before: Option<MessageID>, limit: u32)
+    -> Result<Vec<Row>, PoolError>
+{
+    let limit = limit as i64;
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT message_id, timestamp, COALESCE(author, 0), content, version, deleted_at IS NOT NULL, reply_to, system, forwarded_from, Message.channel_id
+        FROM Message
+        JOIN Channel ON Channel.channel_id = Message.channel_id
+        WHERE Channel.group_id = $2
+        AND (
+            NOT Channel.private
+            OR EXISTS (
+                SELECT 1
+                FROM ChannelMembership
+                WHERE ChannelMembership.channel_id = Message.channel_id
+                AND ChannelMembership.user_id = $1
+            )
+        )
+        AND (
+            $3::INTEGER IS NULL
+            OR (Message.timestamp, Message.message_id) < (
+                SELECT timestamp, message_id
+                FROM Message
+                WHERE message_id = $3
+            )
+        )
+        ORDER BY Message.timestamp DESC, Message.message_id DESC
+        LIMIT $4
+    ").await?;
+    conn.query(&stmt, &[&user_id, &group_id, &before, &limit]).await.map_err(|e| e.into())
+}
+
+/// The maximum number of messages either side of the pivot for messages_around.
+pub const MAX_MESSAGE_RADIUS: u32 = 50;
+
+/// The maximum number of messages per channel for latest_messages_per_channel.
+pub const MAX_LATEST_PER_CHANNEL: u32 = 50;
+/// The maximum number of messages returned by latest_messages_per_channel.
+pub const MAX_LATEST_TOTAL: i64 = 1000;
+
+/// Get the latest messages of every channel in a group that a user can access.
+///
+/// Returns up to per_channel messages from each channel, sorted by ID. The
+/// channel ID of each message is in column 9.
+pub async fn latest_messages_per_channel(pool: Pool, user_id: UserID, group_id: GroupID, per_channel: u32)
+    -> Result<Vec<Row>, PoolError>
+{
+    let per_channel = per_channel.min(MAX_LATEST_PER_CHANNEL) as i64;
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT message_id, timestamp, COALESCE(author, 0), content, version, deleted_at IS NOT NULL, reply_to, system, forwarded_from, channel_id
+        FROM (
+            SELECT Message.*, ROW_NUMBER() OVER (
+                PARTITION BY Message.channel_id
+                ORDER BY Message.message_id DESC
+            ) AS row_number
+            FROM Message
+            JOIN Channel ON Channel.channel_id = Message.channel_id
+            WHERE Channel.group_id = $1
+            AND (
+                NOT Channel.private
+                OR EXISTS (
+                    SELECT 1
+                    FROM ChannelMembership
+                    WHERE ChannelMembership.channel_id = Channel.channel_id
+                    AND ChannelMembership.user_id = $2
+                )
+            )
+        ) Temp
+        WHERE row_number <= $3
+        ORDER BY message_id DESC
+        LIMIT $4
+    ").await?;
+    let mut rows = conn.query(&stmt, &[&group_id, &user_id, &per_channel, &MAX_LATEST_TOTAL]).await?;
+    // The newest messages are kept if the total limit is reached
+    rows.reverse();
+    Ok(rows)
+}
+
+/// Get the messages surrounding a message.
+///
+/// Returns up to radius messages before and after the pivot, and the pivot
+/// itself. There may be fewer messages on one side if the pivot is near the
+/// start or end of the channel.
+pub async fn messages_around(pool: Pool, channel_id: ChannelID, pivot: MessageID, radius: u32)
+    -> Result<Vec<Row>, PoolError>
+{
+    let radius = radius.min(MAX_MESSAGE_RADIUS) as i64;
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT message_id, timestamp, COALESCE(author, 0), content, version, deleted_at IS NOT NULL, reply_to, system, forwarded_from
+        FROM (
+            (
+                SELECT *
+                FROM Message
+                WHERE channel_id = $1
+                AND message_id <= $2
+                ORDER BY message_id DESC
+                LIMIT $3::BIGINT + 1
+            )
+            UNION ALL
+            (
+                SELECT *
+                FROM Message
+                WHERE channel_id = $1
+                AND message_id > $2
+                ORDER BY message_id ASC
+                LIMIT $3::BIGINT
+            )
         ) Temp
         ORDER BY message_id ASC
     ").await?;
-    conn.query(&stmt, &[&channel_id, &message_id]).await.map_err(|e| e.into())
+    conn.query(&stmt, &[&channel_id, &pivot, &radius]).await.map_err(|e| e.into())
 }
 
+/// Create a message and attach uploaded files to it. See `attach_files`.
+///
+/// Returns the ID of the message and the attachments that were attached.
 pub async fn create_message(
     pool: Pool,
     time: std::time::SystemTime,
     user_id: UserID,
     content: &String,
-    channel_id: ChannelID
-) -> Result<MessageID, PoolError> {
-    let conn = pool.get().await?;
-    let stmt = conn.prepare("
-        INSERT INTO Message (timestamp, author, content, channel_id)
-        VALUES ($1, $2, $3, $4)
+    channel_id: ChannelID,
+    reply_to: Option<MessageID>,
+    forwarded_from: Option<MessageID>,
+    attachment_ids: &[AttachmentID],
+) -> Result<(MessageID, Vec<Attachment>), PoolError> {
+    let mut conn = get_connection(&pool).await?;
+    let transaction = conn.transaction().await?;
+    let stmt = transaction.prepare("
+        INSERT INTO Message (timestamp, author, content, channel_id, reply_to, forwarded_from)
+        VALUES ($1, $2, $3, $4, $5, $6)
         RETURNING message_id
     ").await?;
-    Ok(conn.query_one(&stmt, &[&time, &user_id, content, &channel_id]).await?.get(0))
+    let message_id = transaction.query_one(&stmt, &[&time, &user_id, content, &channel_id, &reply_to, &forwarded_from]).await?.get(0);
+    let attachments = if attachment_ids.is_empty() {
+        Vec::new()
+    } else {
+        attach_files(&transaction, message_id, user_id, attachment_ids).await?
+    };
+    transaction.commit().await?;
+    Ok((message_id, attachments))
+}
+
+/// System messages have no author. They are sent as if they were written by
+/// this user.
+pub const SYSTEM_USER_ID: UserID = UserID(0);
+
+/// Post a system message to the first public channel of a group.
+///
+/// Returns the channel and the message. Returns Ok(None) if the group doesn't
+/// have any public channels.
+pub async fn insert_system_message(pool: Pool, time: std::time::SystemTime, group_id: GroupID, content: &String)
+    -> Result<Option<(ChannelID, MessageID)>, PoolError>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO Message (timestamp, content, channel_id, system)
+        SELECT $1, $2, channel_id, TRUE
+        FROM Channel
+        WHERE group_id = $3
+        AND NOT private
+        ORDER BY channel_id
+        LIMIT 1
+        RETURNING channel_id, message_id
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&time, content, &group_id]).await?.map(|row| (row.get(0), row.get(1))))
+}
+
+/// Determine whether a message is in a channel.
+pub async fn message_in_channel(pool: Pool, channel_id: ChannelID, message_id: MessageID)
+    -> Result<bool, PoolError>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT 1
+        FROM Message
+        WHERE message_id = $2
+        AND channel_id = $1
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&channel_id, &message_id]).await?.is_some())
+}
+
+/// Get a message and all of the replies to it (and replies to those replies),
+/// sorted by ID.
+pub async fn thread_messages(pool: Pool, root_id: MessageID) -> Result<Vec<Row>, PoolError> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        WITH RECURSIVE Thread AS (
+            SELECT *
+            FROM Message
+            WHERE message_id = $1
+            UNION ALL
+            SELECT Message.*
+            FROM Message
+            JOIN Thread ON Message.reply_to = Thread.message_id
+        )
+        SELECT message_id, timestamp, COALESCE(author, 0), content, version, deleted_at IS NOT NULL, reply_to, system, forwarded_from
+        FROM Thread
+        ORDER BY message_id ASC
+    ").await?;
+    conn.query(&stmt, &[&root_id]).await.map_err(|e| e.into())
+}
+
+pub enum EditResult {
+    /// The message was edited. This is the new version.
+    Edited(i32),
+    /// The message has been edited since the expected version.
+    Conflict { version: i32, content: String },
+    /// The message doesn't exist in the channel.
+    NotFound,
+}
+
+/// Get the author of a message.
+///
+/// Returns Ok(None) if the message doesn't exist in the channel or if the
+/// author has been anonymized.
+pub async fn message_author(pool: Pool, channel_id: ChannelID, message_id: MessageID)
+    -> Result<Option<UserID>, PoolError>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT author
+        FROM Message
+        WHERE message_id = $2
+        AND channel_id = $1
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&channel_id, &message_id]).await?.and_then(|row| row.get(0)))
+}
+
+/// Edit the content of a message.
+///
+/// The edit only succeeds if the message is still at the expected version.
+/// This stops two people from overwriting each other's edits.
+pub async fn edit_message(pool: Pool, channel_id: ChannelID, message_id: MessageID, content: &String, version: i32)
+    -> Result<EditResult, PoolError>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        UPDATE Message
+        SET content = $3, version = version + 1
+        WHERE message_id = $2
+        AND channel_id = $1
+        AND version = $4
+        AND deleted_at IS NULL
+        RETURNING version
+    ").await?;
+    if let Some(row) = conn.query_opt(&stmt, &[&channel_id, &message_id, content, &version]).await? {
+        return Ok(EditResult::Edited(row.get(0)));
+    }
+
+    let stmt = conn.prepare("
+        SELECT version, content
+        FROM Message
+        WHERE message_id = $2
+        AND channel_id = $1
+        AND deleted_at IS NULL
+    ").await?;
+    Ok(match conn.query_opt(&stmt, &[&channel_id, &message_id]).await? {
+        Some(row) => EditResult::Conflict { version: row.get(0), content: row.get(1) },
+        None => EditResult::NotFound
+    })
+}
+
+/// Delete a message.
+///
+/// The message is kept as a tombstone so that the messages around it (and
+/// replies to it) still make sense. The content is blanked and the message is
+/// unpinned.
+///
+/// Returns true if the message was actually deleted.
+pub async fn delete_message(pool: Pool, channel_id: ChannelID, message_id: MessageID)
+    -> Result<bool, PoolError>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        UPDATE Message
+        SET deleted_at = NOW(), content = '', pinned = FALSE
+        WHERE message_id = $2
+        AND channel_id = $1
+        AND deleted_at IS NULL
+    ").await?;
+    Ok(conn.execute(&stmt, &[&channel_id, &message_id]).await? > 0)
+}
+
+/// The maximum number of messages that can be pinned in a channel.
+///
+/// Pins are meant to highlight a few important messages. If everything is
+/// pinned then nothing is.
+pub const MAX_PINNED_MESSAGES: i64 = 50;
+
+pub enum PinResult {
+    Pinned,
+    /// The message is not in the channel, is deleted or is already pinned.
+    Invalid,
+    /// The channel has reached the pin limit.
+    LimitReached,
+}
+
+/// Pin a message.
+///
+/// The channel is locked while its pins are counted so that concurrent pins
+/// can't take it over the limit.
+pub async fn pin_message(pool: Pool, channel_id: ChannelID, message_id: MessageID)
+    -> Result<PinResult, PoolError>
+{
+    let mut conn = get_connection(&pool).await?;
+    let transaction = conn.transaction().await?;
+
+    // The count is made by a separate statement so that it sees any pins that
+    // were committed while waiting for the lock.
+    let stmt = transaction.prepare("
+        SELECT 1
+        FROM Channel
+        WHERE channel_id = $1
+        FOR UPDATE
+    ").await?;
+    if transaction.query_opt(&stmt, &[&channel_id]).await?.is_none() {
+        return Ok(PinResult::Invalid);
+    }
+
+    let stmt = transaction.prepare("
+        SELECT COUNT(*)
+        FROM Message
+        WHERE channel_id = $1
+        AND pinned
+    ").await?;
+    let count: i64 = transaction.query_one(&stmt, &[&channel_id]).await?.get(0);
+    if count >= MAX_PINNED_MESSAGES {
+        return Ok(PinResult::LimitReached);
+    }
+
+    let stmt = transaction.prepare("
+        UPDATE Message
+        SET pinned = TRUE
+        WHERE message_id = $2
+        AND channel_id = $1
+        AND NOT pinned
+        AND deleted_at IS NULL
+    ").await?;
+    if transaction.execute(&stmt, &[&channel_id, &message_id]).await? == 0 {
+        return Ok(PinResult::Invalid);
+    }
+
+    transaction.commit().await?;
+    Ok(PinResult::Pinned)
+}
+
+/// Unpin a message.
+///
+/// Returns true if the message was actually unpinned.
+pub async fn unpin_message(pool: Pool, channel_id: ChannelID, message_id: MessageID)
+    -> Result<bool, PoolError>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        UPDATE Message
+        SET pinned = FALSE
+        WHERE message_id = $2
+        AND channel_id = $1
+        AND pinned
+    ").await?;
+    Ok(conn.execute(&stmt, &[&channel_id, &message_id]).await? > 0)
+}
+
+pub async fn pinned_messages(pool: Pool, channel_id: ChannelID) -> Result<Vec<Row>, PoolError> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT message_id, timestamp, COALESCE(author, 0), content, version, deleted_at IS NOT NULL, reply_to, system, forwarded_from
+        FROM Message
+        WHERE channel_id = $1
+        AND pinned
+        ORDER BY message_id ASC
+    ").await?;
+    conn.query(&stmt, &[&channel_id]).await.map_err(|e| e.into())
+}
+
+/// Get a single message along with the channel and group that contain it.
+///
+/// The channel ID is in column 9 and the group ID is in column 10.
+pub async fn message_by_id(pool: Pool, message_id: MessageID) -> Result<Option<Row>, PoolError> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT message_id, timestamp, COALESCE(author, 0), content, version, deleted_at IS NOT NULL, reply_to, system, forwarded_from, Message.channel_id, Channel.group_id
+        FROM Message
+        JOIN Channel ON Channel.channel_id = Message.channel_id
+        WHERE message_id = $1
+    ").await?;
+    conn.query_opt(&stmt, &[&message_id]).await.map_err(|e| e.into())
+}
+
+/// Delete the messages in a group that were posted before the cutoff.
+///
+/// Pinned messages are kept. Returns the number of messages deleted.
+pub async fn purge_messages_older_than(pool: Pool, group_id: GroupID, cutoff: std::time::SystemTime)
+    -> Result<u64, PoolError>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        DELETE FROM Message
+        USING Channel
+        WHERE Channel.channel_id = Message.channel_id
+        AND Channel.group_id = $1
+        AND Message.timestamp < $2
+        AND NOT Message.pinned
+    ").await?;
+    Ok(conn.execute(&stmt, &[&group_id, &cutoff]).await?)
+}
+
+/// Mark the messages in a channel up to and including a message as read.
+///
+/// The read position never moves backwards.
+pub async fn mark_read(pool: Pool, user_id: UserID, channel_id: ChannelID, message_id: MessageID)
+    -> Result<(), PoolError>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO ChannelRead (user_id, channel_id, message_id)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, channel_id) DO UPDATE
+        SET message_id = GREATEST(ChannelRead.message_id, EXCLUDED.message_id)
+    ").await?;
+    conn.execute(&stmt, &[&user_id, &channel_id, &message_id]).await?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct ExportedMessage {
+    pub message_id: MessageID,
+    pub timestamp: u64,
+    /// None if the author deleted their account or this is a system message.
+    pub author: Option<UserID>,
+    pub content: String,
+    pub edited: bool,
+    pub deleted: bool,
+}
+
+/// The number of messages fetched at once while exporting a channel.
+const EXPORT_BATCH_SIZE: i64 = 500;
+
+/// Get every message in a channel, oldest first.
+///
+/// The messages are fetched in batches so that large channels aren't loaded
+/// into memory all at once. A connection is only taken from the pool while a
+/// batch is being fetched so that slow downloads don't hold on to one.
+pub fn export_channel_messages(pool: Pool, channel_id: ChannelID)
+    -> impl Stream<Item = Result<ExportedMessage, PoolError>> + Send
+{
+    // The ID of the last message exported, or None when there are no more.
+    stream::unfold(Some(MessageID(0)), move |after| {
+        let pool = pool.clone();
+        async move {
+            let batch = export_batch(pool, channel_id, after?).await;
+            let next = match &batch {
+                Ok(messages) if messages.len() as i64 == EXPORT_BATCH_SIZE =>
+                    messages.last().map(|message| message.message_id),
+                _ => None,
+            };
+            let messages = match batch {
+                Ok(messages) => messages.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            Some((stream::iter(messages), next))
+        }
+    }).flatten()
+}
+
+async fn export_batch(pool: Pool, channel_id: ChannelID, after: MessageID)
+    -> Result<Vec<ExportedMessage>, PoolError>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT message_id, timestamp, author, content, version > 0, deleted_at IS NOT NULL
+        FROM Message
+        WHERE channel_id = $1
+        AND message_id > $2
+        ORDER BY message_id
+        LIMIT $3
+    ").await?;
+    Ok(conn.query(&stmt, &[&channel_id, &after, &EXPORT_BATCH_SIZE]).await?.iter().map(|row| ExportedMessage {
+        message_id: row.get(0),
+        timestamp: as_timestamp(row.get(1)),
+        author: row.get(2),
+        content: row.get(3),
+        edited: row.get(4),
+        deleted: row.get(5),
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+
+    #[test]
+    fn page_at_limit() {
+        let mut rows: Vec<usize> = (0..MESSAGE_PAGE_SIZE).collect();
+        assert!(!trim_page(&mut rows));
+        assert_eq!(rows, (0..MESSAGE_PAGE_SIZE).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn page_over_limit() {
+        let mut rows: Vec<usize> = (0..MESSAGE_PAGE_SIZE + 1).collect();
+        assert!(trim_page(&mut rows));
+        // The oldest row is the extra one.
+        assert_eq!(rows, (1..MESSAGE_PAGE_SIZE + 1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn page_under_limit() {
+        let mut rows = vec![1, 2, 3];
+        assert!(!trim_page(&mut rows));
+        assert_eq!(rows, vec![1, 2, 3]);
+
+        let mut rows: Vec<usize> = Vec::new();
+        assert!(!trim_page(&mut rows));
+    }
+
+    fn ids(rows: Vec<Row>) -> Vec<MessageID> {
+        rows.iter().map(|row| row.get(0)).collect()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn messages_around_edges() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let mut messages = Vec::new();
+        for i in 0..5 {
+            messages.push(testing::message(&pool, user, channel_id, &i.to_string()).await);
+        }
+
+        let around = messages_around(pool.clone(), channel_id, messages[2], 1).await.unwrap();
+        assert_eq!(ids(around), messages[1..4]);
+
+        // There are fewer messages on the side that reaches the end.
+        let around = messages_around(pool.clone(), channel_id, messages[0], 2).await.unwrap();
+        assert_eq!(ids(around), messages[0..3]);
+        let around = messages_around(pool.clone(), channel_id, messages[4], 2).await.unwrap();
+        assert_eq!(ids(around), messages[2..5]);
+
+        let around = messages_around(pool.clone(), channel_id, messages[2], 10).await.unwrap();
+        assert_eq!(ids(around), messages);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn latest_messages_are_capped_per_channel() {
+        let pool = testing::pool();
+        let author = testing::user(&pool).await;
+        let group_id = testing::group(&pool, author).await;
+        let busy = testing::channel(&pool, group_id, false).await;
+        let quiet = testing::channel(&pool, group_id, false).await;
+        let mut busy_ids = Vec::new();
+        for _ in 0..3 {
+            busy_ids.push(testing::message(&pool, author, busy, "busy").await);
+        }
+        let quiet_id = testing::message(&pool, author, quiet, "quiet").await;
+
+        let rows = latest_messages_per_channel(pool.clone(), author, group_id, 2).await.unwrap();
+        let in_channel = |channel_id: ChannelID| rows.iter()
+            .filter(|row| row.get::<_, ChannelID>(9) == channel_id)
+            .map(|row| row.get::<_, MessageID>(0))
+            .collect::<Vec<_>>();
+        assert_eq!(in_channel(busy), busy_ids[1..].to_vec());
+        assert_eq!(in_channel(quiet), vec![quiet_id]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn deleted_message_is_a_tombstone() {
+        let pool = testing::pool();
+        let author = testing::user(&pool).await;
+        let group_id = testing::group(&pool, author).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let deleted = testing::message(&pool, author, channel_id, "deleted").await;
+        let kept = testing::message(&pool, author, channel_id, "kept").await;
+
+        assert!(delete_message(pool.clone(), channel_id, deleted).await.unwrap());
+        assert!(!delete_message(pool.clone(), channel_id, deleted).await.unwrap());
+
+        let rows = recent_messages(pool.clone(), channel_id).await.unwrap().rows;
+        assert!(rows[0].get::<_, bool>(5));
+        assert_eq!(rows[0].get::<_, String>(3), "");
+        assert!(!rows[1].get::<_, bool>(5));
+        assert_eq!(rows[1].get::<_, String>(3), "kept");
+        assert_eq!(ids(rows), vec![deleted, kept]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn pin_and_unpin() {
+        let pool = testing::pool();
+        let author = testing::user(&pool).await;
+        let group_id = testing::group(&pool, author).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let other_channel_id = testing::channel(&pool, group_id, false).await;
+        let message_id = testing::message(&pool, author, channel_id, "important").await;
+
+        assert!(matches!(pin_message(pool.clone(), other_channel_id, message_id).await.unwrap(), PinResult::Invalid));
+        assert!(matches!(pin_message(pool.clone(), channel_id, message_id).await.unwrap(), PinResult::Pinned));
+        assert!(matches!(pin_message(pool.clone(), channel_id, message_id).await.unwrap(), PinResult::Invalid));
+        assert_eq!(ids(pinned_messages(pool.clone(), channel_id).await.unwrap()), vec![message_id]);
+
+        assert!(unpin_message(pool.clone(), channel_id, message_id).await.unwrap());
+        assert!(!unpin_message(pool.clone(), channel_id, message_id).await.unwrap());
+        assert!(pinned_messages(pool, channel_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn pin_limit() {
+        let pool = testing::pool();
+        let author = testing::user(&pool).await;
+        let group_id = testing::group(&pool, author).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        for _ in 0..MAX_PINNED_MESSAGES {
+            let message_id = testing::message(&pool, author, channel_id, "important").await;
+            assert!(matches!(pin_message(pool.clone(), channel_id, message_id).await.unwrap(), PinResult::Pinned));
+        }
+
+        let message_id = testing::message(&pool, author, channel_id, "one too many").await;
+        assert!(matches!(pin_message(pool.clone(), channel_id, message_id).await.unwrap(), PinResult::LimitReached));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn purge_keeps_new_and_pinned_messages() {
+        let pool = testing::pool();
+        let author = testing::user(&pool).await;
+        let group_id = testing::group(&pool, author).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let day = std::time::Duration::from_secs(24 * 60 * 60);
+        let old_time = std::time::SystemTime::now() - 2 * day;
+        let content = "old".to_owned();
+        create_message(pool.clone(), old_time, author, &content, channel_id, None, None, &[]).await.unwrap();
+        let (pinned, _) = create_message(pool.clone(), old_time, author, &content, channel_id, None, None, &[])
+            .await.unwrap();
+        assert!(matches!(pin_message(pool.clone(), channel_id, pinned).await.unwrap(), PinResult::Pinned));
+        let new = testing::message(&pool, author, channel_id, "new").await;
+
+        let cutoff = std::time::SystemTime::now() - day;
+        assert_eq!(purge_messages_older_than(pool.clone(), group_id, cutoff).await.unwrap(), 1);
+        let rows = recent_messages(pool.clone(), channel_id).await.unwrap().rows;
+        assert_eq!(ids(rows), vec![pinned, new]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn feed_skips_private_channels() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let public = testing::channel(&pool, group_id, false).await;
+        let private = testing::channel(&pool, group_id, true).await;
+        super::super::add_channel_member(pool.clone(), private, owner).await.unwrap();
+        let first = testing::message(&pool, owner, public, "public").await;
+        let secret = testing::message(&pool, owner, private, "private").await;
+        let last = testing::message(&pool, owner, public, "public").await;
+
+        let feed = recent_group_messages(pool.clone(), member, group_id, None, 10).await.unwrap();
+        assert_eq!(ids(feed), vec![last, first]);
+        let feed = recent_group_messages(pool.clone(), owner, group_id, None, 10).await.unwrap();
+        assert_eq!(ids(feed), vec![last, secret, first]);
+    }
 }
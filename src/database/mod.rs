@@ -1,3 +1,4 @@
+mod id;
 mod channel;
 mod user;
 mod session;
@@ -5,7 +6,22 @@ mod message;
 mod group;
 mod strings;
 mod membership;
+mod retry;
+mod query;
+mod attachment;
+mod audit;
+mod token;
+mod report;
+mod preview;
+mod emoji;
+mod settings;
+mod draft;
+mod nickname;
+mod reaction;
+#[cfg(test)]
+pub mod testing;
 
+pub use id::*;
 pub use channel::*;
 pub use user::*;
 pub use message::*;
@@ -13,3 +29,15 @@ pub use session::*;
 pub use group::*;
 pub use strings::*;
 pub use membership::*;
+pub use retry::*;
+pub use query::*;
+pub use attachment::*;
+pub use audit::*;
+pub use token::*;
+pub use report::*;
+pub use preview::*;
+pub use emoji::*;
+pub use settings::*;
+pub use draft::*;
+pub use nickname::*;
+pub use reaction::*;
@@ -0,0 +1,95 @@
+use crate::error::Error;
+use super::{UserID, GroupID, get_connection, query_map};
+use deadpool_postgres::Pool;
+use deadpool_postgres::tokio_postgres::error::SqlState;
+
+/// Set the nickname of a member of a group. A nickname of None removes it
+/// so that the member goes by their own name.
+///
+/// Assumes that the user is a member of the group and that the nickname is
+/// valid.
+///
+/// Returns Ok(false) if another member of the group goes by the nickname,
+/// either as their nickname or as their own name.
+pub async fn set_nickname(pool: Pool, group_id: GroupID, user_id: UserID, nickname: Option<&String>)
+    -> Result<bool, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let nickname = match nickname {
+        Some(nickname) => nickname,
+        None => {
+            let stmt = conn.prepare("
+                DELETE FROM GroupNickname
+                WHERE user_id = $1
+                AND group_id = $2
+            ").await?;
+            conn.execute(&stmt, &[&user_id, &group_id]).await?;
+            return Ok(true);
+        }
+    };
+    let stmt = conn.prepare("
+        INSERT INTO GroupNickname (user_id, group_id, nickname)
+        SELECT $1, $2, $3
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM Membership
+            JOIN Usr ON Usr.user_id = Membership.user_id
+            LEFT JOIN GroupNickname ON GroupNickname.user_id = Membership.user_id
+                AND GroupNickname.group_id = Membership.group_id
+            WHERE Membership.group_id = $2
+            AND Membership.user_id != $1
+            AND COALESCE(GroupNickname.nickname, Usr.name) = $3
+        )
+        ON CONFLICT (user_id, group_id) DO UPDATE
+        SET nickname = EXCLUDED.nickname
+    ").await?;
+    match conn.execute(&stmt, &[&user_id, &group_id, nickname]).await {
+        Ok(count) => Ok(count > 0),
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Get the name that a user goes by in each of their groups. This is their
+/// nickname if they have one, otherwise their own name.
+pub async fn user_display_names(pool: Pool, user_id: UserID)
+    -> Result<Vec<(GroupID, String)>, Error>
+{
+    query_map(&pool, "
+        SELECT Membership.group_id, COALESCE(GroupNickname.nickname, Usr.name)
+        FROM Membership
+        JOIN Usr ON Usr.user_id = Membership.user_id
+        LEFT JOIN GroupNickname ON GroupNickname.user_id = Membership.user_id
+            AND GroupNickname.group_id = Membership.group_id
+        WHERE Membership.user_id = $1
+        ORDER BY Membership.group_id
+    ", &[&user_id], |row| (row.get(0), row.get(1))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+
+    #[tokio::test]
+    #[ignore]
+    async fn nicknames_are_unique() {
+        let pool = testing::pool();
+        let first = testing::user(&pool).await;
+        let second = testing::user(&pool).await;
+        let group_id = testing::group(&pool, first).await;
+        testing::join(&pool, second, group_id, "member").await;
+        let other_group_id = testing::group(&pool, second).await;
+        let nickname = testing::name();
+
+        assert!(set_nickname(pool.clone(), group_id, first, Some(&nickname)).await.unwrap());
+        assert!(!set_nickname(pool.clone(), group_id, second, Some(&nickname)).await.unwrap());
+        // Setting the same nickname again isn't a collision.
+        assert!(set_nickname(pool.clone(), group_id, first, Some(&nickname)).await.unwrap());
+        // Nicknames only need to be unique within a group.
+        assert!(set_nickname(pool.clone(), other_group_id, second, Some(&nickname)).await.unwrap());
+
+        assert!(set_nickname(pool.clone(), group_id, first, None).await.unwrap());
+        assert!(set_nickname(pool.clone(), group_id, second, Some(&nickname)).await.unwrap());
+    }
+}
@@ -0,0 +1,53 @@
+use serde::Serialize;
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use super::{MessageID, get_connection};
+
+pub const MAX_PREVIEW_TITLE_LENGTH: usize = 256;
+pub const MAX_PREVIEW_DESCRIPTION_LENGTH: usize = 512;
+
+/// A summary of a page linked in a message.
+#[derive(Serialize)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub image: Option<String>,
+}
+
+/// Store the preview of the link in a message.
+///
+/// A message only has one preview so any subsequent previews are ignored.
+pub async fn create_link_preview(pool: Pool, message_id: MessageID, preview: &LinkPreview)
+    -> Result<(), Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO LinkPreview (message_id, url, title, description, image)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (message_id) DO NOTHING
+    ").await?;
+    conn.execute(&stmt, &[
+        &message_id, &preview.url, &preview.title, &preview.description, &preview.image
+    ]).await?;
+    Ok(())
+}
+
+/// Get the previews of the links in several messages, sorted by message ID.
+pub async fn message_link_previews(pool: Pool, message_ids: &[MessageID])
+    -> Result<Vec<(MessageID, LinkPreview)>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT message_id, url, title, description, image
+        FROM LinkPreview
+        WHERE message_id = ANY($1)
+        ORDER BY message_id
+    ").await?;
+    Ok(conn.query(&stmt, &[&message_ids]).await?.iter().map(|row| (row.get(0), LinkPreview {
+        url: row.get(1),
+        title: row.get(2),
+        description: row.get(3),
+        image: row.get(4),
+    })).collect())
+}
@@ -0,0 +1,171 @@
+use log::warn;
+use crate::config;
+use crate::error::Error;
+use std::time::{Duration, Instant};
+use super::get_connection;
+use deadpool_postgres::Pool;
+use deadpool_postgres::tokio_postgres::Row;
+use deadpool_postgres::tokio_postgres::types::ToSql;
+
+// The prepare method of a pooled client goes through the statement cache of
+// the connection. Each query is prepared once per connection and reused after
+// that. The same goes for transactions. Statements prepared on the underlying
+// tokio_postgres client (by dereferencing) bypass the cache so that shouldn't
+// be done for queries that run often.
+
+pub type Params<'a> = &'a [&'a (dyn ToSql + Sync)];
+
+/// The number of words of the SQL that are logged for a slow query.
+const SLOW_QUERY_LABEL_WORDS: usize = 12;
+
+/// Get the warning for a query that took longer than the configured
+/// threshold. Returns None if the query wasn't slow.
+fn slow_query_warning(sql: &str, elapsed: Duration) -> Option<String> {
+    let threshold = config::get().slow_query_ms;
+    if threshold == 0 || elapsed < Duration::from_millis(threshold) {
+        return None;
+    }
+    let label = sql.split_whitespace()
+        .take(SLOW_QUERY_LABEL_WORDS)
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some(format!("Slow query ({} ms): {}", elapsed.as_millis(), label))
+}
+
+/// Log a warning if a query took longer than the configured threshold. The
+/// time includes waiting for a connection.
+fn log_if_slow(sql: &str, start: Instant) {
+    if let Some(warning) = slow_query_warning(sql, start.elapsed()) {
+        warn!("{}", warning);
+    }
+}
+
+/// Run a query and convert each of the resulting rows.
+pub async fn query_map<T, F>(pool: &Pool, sql: &str, params: Params<'_>, f: F)
+    -> Result<Vec<T>, Error>
+    where F: FnMut(&Row) -> T
+{
+    let start = Instant::now();
+    let conn = get_connection(pool).await?;
+    let stmt = conn.prepare(sql).await?;
+    let rows = conn.query(&stmt, params).await?;
+    log_if_slow(sql, start);
+    Ok(rows.iter().map(f).collect())
+}
+
+/// Run a query that returns at most one row and convert it.
+///
+/// Returns Ok(None) if there were no rows.
+pub async fn query_opt_map<T, F>(pool: &Pool, sql: &str, params: Params<'_>, f: F)
+    -> Result<Option<T>, Error>
+    where F: FnOnce(&Row) -> T
+{
+    let start = Instant::now();
+    let conn = get_connection(pool).await?;
+    let stmt = conn.prepare(sql).await?;
+    let row = conn.query_opt(&stmt, params).await?;
+    log_if_slow(sql, start);
+    Ok(row.as_ref().map(f))
+}
+
+/// Run a statement and return the number of rows that it modified.
+pub async fn execute(pool: &Pool, sql: &str, params: Params<'_>) -> Result<u64, Error> {
+    let start = Instant::now();
+    let conn = get_connection(pool).await?;
+    let stmt = conn.prepare(sql).await?;
+    let count = conn.execute(&stmt, params).await?;
+    log_if_slow(sql, start);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+    use deadpool_postgres::{Manager, ManagerConfig, RecyclingMethod};
+    use deadpool_postgres::tokio_postgres::{Config, NoTls};
+
+    // This needs the same local database as the server so it's only run when
+    // asked for with --ignored.
+    #[tokio::test]
+    #[ignore]
+    async fn prepare_reuses_cached_statement() {
+        let mut config = Config::new();
+        config.host("localhost");
+        config.user("postgres");
+        config.dbname("chat");
+        let manager_config = ManagerConfig { recycling_method: RecyclingMethod::Fast };
+        let manager = Manager::from_config(config, NoTls, manager_config);
+        let pool = Pool::new(manager, 1);
+
+        let conn = pool.get().await.unwrap();
+        let sql = "SELECT message_id FROM Message WHERE channel_id = $1";
+        conn.prepare(sql).await.unwrap();
+        let size = conn.statement_cache.size();
+        conn.prepare(sql).await.unwrap();
+        assert_eq!(conn.statement_cache.size(), size);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn helpers_map_rows() {
+        let pool = testing::pool();
+        let count = 3;
+
+        let numbers = query_map(&pool, "SELECT generate_series(1, $1)", &[&count], |row| row.get::<_, i32>(0))
+            .await.unwrap();
+        assert_eq!(numbers, vec![1, 2, 3]);
+
+        let found = query_opt_map(&pool, "SELECT $1::INTEGER * 2", &[&count], |row| row.get::<_, i32>(0))
+            .await.unwrap();
+        assert_eq!(found, Some(6));
+        let missing = query_opt_map(&pool, "SELECT 1 WHERE FALSE", &[], |row| row.get::<_, i32>(0))
+            .await.unwrap();
+        assert_eq!(missing, None);
+
+        assert_eq!(execute(&pool, "SELECT generate_series(1, $1)", &[&count]).await.unwrap(), 3);
+    }
+
+    #[test]
+    fn slow_query_is_labelled() {
+        let mut config = config::Config::default();
+        config.slow_query_ms = 100;
+        let _config = config::set_for_test(config);
+        let sql = "
+            SELECT message_id
+            FROM Message
+            WHERE channel_id = $1
+        ";
+        assert_eq!(slow_query_warning(sql, Duration::from_millis(99)), None);
+        assert_eq!(
+            slow_query_warning(sql, Duration::from_millis(150)),
+            Some("Slow query (150 ms): SELECT message_id FROM Message WHERE channel_id = $1".to_owned())
+        );
+    }
+
+    #[test]
+    fn slow_query_warning_can_be_disabled() {
+        let mut config = config::Config::default();
+        config.slow_query_ms = 0;
+        let _config = config::set_for_test(config);
+        assert_eq!(slow_query_warning("SELECT 1", Duration::from_secs(60)), None);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn slow_query_past_threshold() {
+        let mut config = config::Config::default();
+        config.slow_query_ms = 50;
+        let _config = config::set_for_test(config);
+        let pool = testing::pool();
+        // Connect first so that only the query is timed.
+        drop(pool.get().await.unwrap());
+
+        let start = Instant::now();
+        execute(&pool, "SELECT pg_sleep(0.1)", &[]).await.unwrap();
+        assert!(slow_query_warning("SELECT pg_sleep(0.1)", start.elapsed()).is_some());
+        let start = Instant::now();
+        execute(&pool, "SELECT 1", &[]).await.unwrap();
+        assert!(slow_query_warning("SELECT 1", start.elapsed()).is_none());
+    }
+}
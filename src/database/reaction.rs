@@ -0,0 +1,147 @@
+use serde::Serialize;
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use super::{MessageID, UserID, get_connection};
+
+/// The number of people that reacted to a message with an emoji.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ReactionSummary {
+    #[serde(skip)]
+    pub message_id: MessageID,
+    pub emoji: String,
+    pub count: i64,
+    /// Whether the user viewing the message is one of them.
+    pub reacted_by_me: bool,
+}
+
+/// Get the reactions to several messages.
+///
+/// The reactions are sorted by message ID. The reactions to a message are
+/// sorted by count, most popular first.
+pub async fn message_reactions_for(pool: Pool, message_ids: &[MessageID], viewer_id: UserID)
+    -> Result<Vec<ReactionSummary>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT message_id, emoji, COUNT(*), BOOL_OR(user_id = $2)
+        FROM Reaction
+        WHERE message_id = ANY($1)
+        GROUP BY message_id, emoji
+        ORDER BY message_id, COUNT(*) DESC, emoji
+    ").await?;
+    Ok(conn.query(&stmt, &[&message_ids, &viewer_id]).await?.iter().map(|row| ReactionSummary {
+        message_id: row.get(0),
+        emoji: row.get(1),
+        count: row.get(2),
+        reacted_by_me: row.get(3),
+    }).collect())
+}
+
+/// Split the reactions returned by message_reactions_for between the messages
+/// that they belong to.
+///
+/// message_ids must be sorted. The result has one list for each message.
+pub fn group_reactions(message_ids: &[MessageID], reactions: Vec<ReactionSummary>)
+    -> Vec<Vec<ReactionSummary>>
+{
+    let mut grouped = message_ids.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+    for reaction in reactions {
+        if let Ok(i) = message_ids.binary_search(&reaction.message_id) {
+            grouped[i].push(reaction);
+        }
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+
+    fn reaction(message_id: i32, emoji: &str, count: i64, reacted_by_me: bool) -> ReactionSummary {
+        ReactionSummary {
+            message_id: MessageID(message_id),
+            emoji: emoji.to_owned(),
+            count,
+            reacted_by_me,
+        }
+    }
+
+    #[test]
+    fn reactions_are_grouped_by_message() {
+        let message_ids = [MessageID(1), MessageID(2), MessageID(5)];
+        let grouped = group_reactions(&message_ids, vec![
+            reaction(1, "👍", 3, true),
+            reaction(1, "🎉", 1, false),
+            reaction(5, "👀", 12, false),
+        ]);
+        assert_eq!(grouped, vec![
+            vec![reaction(1, "👍", 3, true), reaction(1, "🎉", 1, false)],
+            vec![],
+            vec![reaction(5, "👀", 12, false)],
+        ]);
+    }
+
+    #[test]
+    fn reactions_to_other_messages_are_ignored() {
+        let message_ids = [MessageID(2)];
+        let grouped = group_reactions(&message_ids, vec![reaction(3, "👍", 1, false)]);
+        assert_eq!(grouped, vec![vec![]]);
+    }
+
+    #[test]
+    fn no_messages() {
+        assert!(group_reactions(&[], vec![reaction(1, "👍", 1, true)]).is_empty());
+    }
+
+    #[test]
+    fn summary_json() {
+        let json = serde_json::to_value(reaction(1, "👍", 2, true)).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "emoji": "👍",
+            "count": 2,
+            "reacted_by_me": true,
+        }));
+    }
+
+    async fn react(pool: &Pool, message_id: MessageID, user_id: UserID, emoji: &str) {
+        let conn = pool.get().await.unwrap();
+        conn.execute(
+            "INSERT INTO Reaction (message_id, user_id, emoji) VALUES ($1, $2, $3)",
+            &[&message_id, &user_id, &emoji]
+        ).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn reactions_are_counted() {
+        let pool = testing::pool();
+        let viewer = testing::user(&pool).await;
+        let first = testing::user(&pool).await;
+        let second = testing::user(&pool).await;
+        let group_id = testing::group(&pool, viewer).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let popular = testing::message(&pool, first, channel_id, "popular").await;
+        let ignored = testing::message(&pool, first, channel_id, "ignored").await;
+        let seen = testing::message(&pool, first, channel_id, "seen").await;
+        react(&pool, popular, viewer, "👍").await;
+        react(&pool, popular, first, "👍").await;
+        react(&pool, popular, second, "👍").await;
+        react(&pool, popular, second, "🎉").await;
+        react(&pool, seen, second, "👀").await;
+
+        let message_ids = [popular, ignored, seen];
+        let reactions = message_reactions_for(pool, &message_ids, viewer).await.unwrap();
+        let reaction = |message_id: MessageID, emoji: &str, count, reacted_by_me| ReactionSummary {
+            message_id,
+            emoji: emoji.to_owned(),
+            count,
+            reacted_by_me,
+        };
+        assert_eq!(group_reactions(&message_ids, reactions), vec![
+            vec![reaction(popular, "👍", 3, true), reaction(popular, "🎉", 1, false)],
+            vec![],
+            vec![reaction(seen, "👀", 1, false)],
+        ]);
+    }
+}
@@ -0,0 +1,57 @@
+use serde::Serialize;
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use crate::utils::as_timestamp;
+use super::{UserID, GroupID, ChannelID, MessageID, get_connection};
+
+pub const MAX_REPORT_REASON_LENGTH: usize = 512;
+
+pub type ReportID = i32;
+
+/// Report a message to the moderators of its group.
+///
+/// Returns Ok(None) if the user has already reported the message.
+pub async fn report_message(pool: Pool, reporter_id: UserID, message_id: MessageID, reason: &String)
+    -> Result<Option<ReportID>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO Report (message_id, reporter, reason, creation_time)
+        VALUES ($1, $2, $3, NOW())
+        ON CONFLICT (message_id, reporter) DO NOTHING
+        RETURNING report_id
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&message_id, &reporter_id, reason]).await?.map(|row| row.get(0)))
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub report_id: ReportID,
+    pub message_id: MessageID,
+    pub channel_id: ChannelID,
+    pub reporter: UserID,
+    pub reason: String,
+    pub timestamp: u64,
+}
+
+/// Get the unresolved reports of messages in a group, oldest first.
+pub async fn group_reports(pool: Pool, group_id: GroupID) -> Result<Vec<Report>, Error> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT report_id, Report.message_id, Message.channel_id, reporter, reason, creation_time
+        FROM Report
+        JOIN Message ON Message.message_id = Report.message_id
+        JOIN Channel ON Channel.channel_id = Message.channel_id
+        WHERE Channel.group_id = $1
+        AND NOT resolved
+        ORDER BY report_id
+    ").await?;
+    Ok(conn.query(&stmt, &[&group_id]).await?.iter().map(|row| Report {
+        report_id: row.get(0),
+        message_id: row.get(1),
+        channel_id: row.get(2),
+        reporter: row.get(3),
+        reason: row.get(4),
+        timestamp: as_timestamp(row.get(5)),
+    }).collect())
+}
@@ -0,0 +1,80 @@
+use log::warn;
+use std::time::Duration;
+use std::future::Future;
+use deadpool_postgres::{Pool, PoolError, Client};
+
+const MAX_ATTEMPTS: u32 = 3;
+const INITIAL_DELAY: Duration = Duration::from_millis(50);
+
+/// Determine whether an error from acquiring a connection is worth retrying.
+///
+/// Errors that come from the server itself (they have an SQLSTATE code) are
+/// not going to go away by trying again. Timeouts and I/O errors might.
+fn transient(error: &PoolError) -> bool {
+    match error {
+        PoolError::Timeout(_) => true,
+        PoolError::Backend(e) => e.code().is_none(),
+    }
+}
+
+/// Get a connection from the pool, retrying with exponential backoff if the
+/// failure looks transient.
+pub async fn get_connection(pool: &Pool) -> Result<Client, PoolError> {
+    retry(|| pool.get()).await
+}
+
+/// Acquire something, retrying with exponential backoff if the failure looks
+/// transient.
+async fn retry<T, F, Fut>(mut acquire: F) -> Result<T, PoolError>
+    where F: FnMut() -> Fut, Fut: Future<Output = Result<T, PoolError>>
+{
+    let mut delay = INITIAL_DELAY;
+    let mut attempt = 1;
+    loop {
+        match acquire().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < MAX_ATTEMPTS && transient(&e) => {
+                warn!("Failed to get database connection (attempt {}): {}", attempt, e);
+                tokio::time::delay_for(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool::managed::TimeoutType;
+
+    fn timeout() -> PoolError {
+        PoolError::Timeout(TimeoutType::Wait)
+    }
+
+    #[tokio::test]
+    async fn fails_twice_then_succeeds() {
+        let mut attempts = 0;
+        let result = retry(|| {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt <= 2 { Err(timeout()) } else { Ok(attempt) }
+            }
+        }).await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up() {
+        let mut attempts = 0;
+        let result = retry(|| {
+            attempts += 1;
+            async { Err::<(), _>(timeout()) }
+        }).await;
+        assert!(matches!(result, Err(PoolError::Timeout(_))));
+        assert_eq!(attempts, MAX_ATTEMPTS);
+    }
+}
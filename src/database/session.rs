@@ -1,56 +1,90 @@
+use crate::config;
+use serde::Serialize;
+use std::net::IpAddr;
 use crate::error::Error;
-use super::{User, UserID};
+use std::time::{Duration, SystemTime};
+use super::{User, UserID, get_connection, query_map, truncate_user_agent};
 use deadpool_postgres::Pool;
-use crate::utils::generate_random_base64url;
+use crate::utils::{generate_random_base64url, as_timestamp};
 
 // This value is duplicated in the column type Session.session_id
 pub const SESSION_ID_LENGTH: usize = 16;
 
 pub type SessionID = String;
 
-macro_rules! creation_timeout {
-    () => { "INTERVAL '7 days'" }
+/// Get the times that sessions must be newer than in order to be valid.
+///
+/// Returns the minimum creation time and the minimum last activity time.
+fn session_cutoffs() -> (SystemTime, SystemTime) {
+    let config = config::get();
+    let now = SystemTime::now();
+    (
+        now - Duration::from_secs(config.session_max_lifetime_secs),
+        now - Duration::from_secs(config.session_idle_timeout_secs),
+    )
 }
 
-pub async fn create_session(pool: Pool, user_id: UserID)
+/// Create a session for a user that signed in from the given address and user
+/// agent.
+pub async fn create_session(pool: Pool, user_id: UserID, ip: Option<IpAddr>, user_agent: Option<&str>)
     -> Result<SessionID, Error>
 {
-    // This function is nearly identical to create_invitation
+    // This function is nearly identical to create_invite
     let mut session_id = generate_random_base64url(SESSION_ID_LENGTH);
+    let user_agent = user_agent.map(truncate_user_agent);
 
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
-         INSERT INTO Session (session_id, creation_time, user_id)
-         VALUES ($1, NOW(), $2)
+         INSERT INTO Session (session_id, creation_time, last_active, user_id, ip, user_agent)
+         VALUES ($1, NOW(), NOW(), $2, $3, $4)
          ON CONFLICT (session_id) DO NOTHING
     ").await?;
 
-    while conn.execute(&stmt, &[&session_id, &user_id]).await? == 0 {
+    while conn.execute(&stmt, &[&session_id, &user_id, &ip, &user_agent]).await? == 0 {
         session_id = generate_random_base64url(SESSION_ID_LENGTH);
     }
 
     Ok(session_id)
 }
 
-pub async fn session_user_id(pool: Pool, session_id: &SessionID)
+/// Renew a session.
+///
+/// Sessions expire after a period of inactivity so this should be called
+/// whenever a session is used. Returns the ID of the user that the session
+/// belongs to or Ok(None) if the session is invalid or has expired.
+pub async fn touch_session(pool: Pool, session_id: &SessionID)
     -> Result<Option<UserID>, Error>
 {
-    // This function is nearly identical to invitation_group_id
     if session_id.len() != SESSION_ID_LENGTH {
         return Ok(None);
     }
 
-    let conn = pool.get().await?;
-    let stmt = conn.prepare(concat!("
-        SELECT user_id
-        FROM Session
+    let (created_after, active_after) = session_cutoffs();
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        UPDATE Session
+        SET last_active = NOW()
         WHERE session_id = $1
-        AND creation_time > NOW() - ", creation_timeout!()
-    )).await?;
+        AND creation_time > $2
+        AND last_active > $3
+        RETURNING user_id
+    ").await?;
 
-    Ok(conn.query_opt(&stmt, &[session_id]).await?.map(|row| row.get(0)))
+    Ok(conn.query_opt(&stmt, &[session_id, &created_after, &active_after]).await?.map(|row| row.get(0)))
+}
+
+/// Get the ID of the user that a session belongs to.
+///
+/// Looking up a session counts as activity so this also renews the session.
+pub async fn session_user_id(pool: Pool, session_id: &SessionID)
+    -> Result<Option<UserID>, Error>
+{
+    touch_session(pool, session_id).await
 }
 
+/// Get the user that a session belongs to.
+///
+/// Like session_user_id, this also renews the session.
 pub async fn session_user(pool: Pool, session_id: &SessionID)
     -> Result<Option<User>, Error>
 {
@@ -58,16 +92,20 @@ pub async fn session_user(pool: Pool, session_id: &SessionID)
         return Ok(None);
     }
 
-    let conn = pool.get().await?;
-    let stmt = conn.prepare(concat!("
-        SELECT Usr.user_id, name, picture
+    let (created_after, active_after) = session_cutoffs();
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        UPDATE Session
+        SET last_active = NOW()
         FROM Usr
-        JOIN Session ON Session.user_id = Usr.user_id
-        WHERE session_id = $1
-        AND creation_time > NOW() - ", creation_timeout!()
-    )).await?;
+        WHERE Usr.user_id = Session.user_id
+        AND session_id = $1
+        AND creation_time > $2
+        AND last_active > $3
+        RETURNING Usr.user_id, name, picture
+    ").await?;
 
-    Ok(conn.query_opt(&stmt, &[session_id]).await?.map(|row| {
+    Ok(conn.query_opt(&stmt, &[session_id, &created_after, &active_after]).await?.map(|row| {
         User {
             user_id: row.get(0),
             name: row.get(1),
@@ -76,14 +114,122 @@ pub async fn session_user(pool: Pool, session_id: &SessionID)
     }))
 }
 
+/// Delete the sessions that have expired.
+///
+/// Returns the number of sessions that were deleted.
+pub async fn delete_expired_sessions(pool: Pool) -> Result<u64, Error> {
+    let (created_after, active_after) = session_cutoffs();
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        DELETE FROM Session
+        WHERE creation_time <= $1
+        OR last_active <= $2
+    ").await?;
+    Ok(conn.execute(&stmt, &[&created_after, &active_after]).await?)
+}
+
 /// Delete a session
 ///
 /// Returns true if the session was actually deleted
 pub async fn delete_user_sessions(pool: Pool, user_id: UserID) -> Result<bool, Error> {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
         DELETE FROM Session
         WHERE user_id = $1
     ").await?;
     Ok(conn.execute(&stmt, &[&user_id]).await? > 0)
 }
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    /// Identifies the session without revealing the session ID.
+    pub id: String,
+    pub created: u64,
+    pub last_active: u64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Whether this is the session that the list was requested with.
+    pub current: bool,
+}
+
+/// Get the sessions of a user that haven't expired, most recently active
+/// first.
+pub async fn user_sessions(pool: Pool, user_id: UserID, current: &SessionID)
+    -> Result<Vec<SessionInfo>, Error>
+{
+    let (created_after, active_after) = session_cutoffs();
+    query_map(&pool, "
+        SELECT MD5(session_id), creation_time, last_active, ip, user_agent, session_id = $4
+        FROM Session
+        WHERE user_id = $1
+        AND creation_time > $2
+        AND last_active > $3
+        ORDER BY last_active DESC
+    ", &[&user_id, &created_after, &active_after, current], |row| SessionInfo {
+        id: row.get(0),
+        created: as_timestamp(row.get(1)),
+        last_active: as_timestamp(row.get(2)),
+        ip: row.get::<_, Option<IpAddr>>(3).map(|ip| ip.to_string()),
+        user_agent: row.get(4),
+        current: row.get(5),
+    }).await
+}
+
+/// Delete one of the sessions of a user, identified by the id of its
+/// SessionInfo.
+///
+/// Returns the ID of the session that was deleted or Ok(None) if the user has
+/// no such session.
+pub async fn delete_session(pool: Pool, user_id: UserID, id: &str)
+    -> Result<Option<SessionID>, Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        DELETE FROM Session
+        WHERE user_id = $1
+        AND MD5(session_id) = $2
+        RETURNING session_id
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&user_id, &id]).await?.map(|row| row.get(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+
+    async fn recently_active(pool: &Pool, session_id: &SessionID) -> bool {
+        let conn = pool.get().await.unwrap();
+        conn.query_one(
+            "SELECT last_active > NOW() - INTERVAL '1 minute' FROM Session WHERE session_id = $1",
+            &[session_id]
+        ).await.unwrap().get(0)
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn touching_extends_session() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let session_id = testing::session(&pool, user).await;
+        let idle = Duration::from_secs(config::get().session_idle_timeout_secs - 60);
+        testing::idle_session(&pool, &session_id, idle).await;
+        assert!(!recently_active(&pool, &session_id).await);
+
+        assert_eq!(touch_session(pool.clone(), &session_id).await.unwrap(), Some(user));
+        assert!(recently_active(&pool, &session_id).await);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn expired_session_is_not_extended() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let session_id = testing::session(&pool, user).await;
+        let idle = Duration::from_secs(config::get().session_idle_timeout_secs + 60);
+        testing::idle_session(&pool, &session_id, idle).await;
+
+        assert_eq!(touch_session(pool.clone(), &session_id).await.unwrap(), None);
+        assert!(!recently_active(&pool, &session_id).await);
+    }
+}
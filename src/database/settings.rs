@@ -0,0 +1,137 @@
+use serde::{Serialize, Deserialize};
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use super::{GroupID, get_connection};
+
+/// The longest retention period that a group can have (10 years).
+pub const MAX_RETENTION_DAYS: i32 = 10 * 365;
+pub const MAX_FILTER_WORDS: usize = 200;
+pub const MAX_FILTER_WORD_LENGTH: usize = 32;
+
+/// Features that can be turned on or off for each group.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupSettings {
+    /// Whether a message is posted when someone joins.
+    pub join_messages: bool,
+    /// Whether previews of links in messages are fetched.
+    pub link_previews: bool,
+    /// Messages older than this many days are deleted. Messages are kept
+    /// forever if this is None.
+    pub retention_days: Option<i32>,
+    /// Words that are masked in messages. This is changed separately from the
+    /// other settings.
+    #[serde(skip_deserializing)]
+    pub word_filter: Vec<String>,
+}
+
+impl Default for GroupSettings {
+    fn default() -> Self {
+        Self {
+            join_messages: true,
+            link_previews: true,
+            retention_days: None,
+            word_filter: Vec::new(),
+        }
+    }
+}
+
+impl GroupSettings {
+    pub fn valid(&self) -> bool {
+        match self.retention_days {
+            Some(days) => days > 0 && days <= MAX_RETENTION_DAYS,
+            None => true
+        }
+    }
+}
+
+/// A filter word must be a single word so that it can be matched on word
+/// boundaries.
+pub fn valid_word_filter(words: &[String]) -> bool {
+    words.len() <= MAX_FILTER_WORDS && words.iter().all(|word| {
+        !word.is_empty()
+            && word.chars().count() <= MAX_FILTER_WORD_LENGTH
+            && word.chars().all(char::is_alphanumeric)
+    })
+}
+
+/// Get the settings of a group.
+///
+/// Groups that have never changed their settings have the default settings.
+pub async fn group_settings(pool: Pool, group_id: GroupID) -> Result<GroupSettings, Error> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT join_messages, link_previews, retention_days, word_filter
+        FROM GroupSettings
+        WHERE group_id = $1
+    ").await?;
+    Ok(match conn.query_opt(&stmt, &[&group_id]).await? {
+        Some(row) => GroupSettings {
+            join_messages: row.get(0),
+            link_previews: row.get(1),
+            retention_days: row.get(2),
+            word_filter: row.get(3),
+        },
+        None => GroupSettings::default()
+    })
+}
+
+/// Replace the settings of a group. The word filter is left unchanged.
+pub async fn update_group_settings(pool: Pool, group_id: GroupID, settings: &GroupSettings)
+    -> Result<(), Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO GroupSettings (group_id, join_messages, link_previews, retention_days)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (group_id) DO UPDATE
+        SET join_messages = EXCLUDED.join_messages,
+            link_previews = EXCLUDED.link_previews,
+            retention_days = EXCLUDED.retention_days
+    ").await?;
+    conn.execute(&stmt, &[
+        &group_id, &settings.join_messages, &settings.link_previews, &settings.retention_days
+    ]).await?;
+    Ok(())
+}
+
+/// Turn the message that is posted when someone joins a group on or off.
+pub async fn set_group_join_messages(pool: Pool, group_id: GroupID, enabled: bool)
+    -> Result<(), Error>
+{
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO GroupSettings (group_id, join_messages)
+        VALUES ($1, $2)
+        ON CONFLICT (group_id) DO UPDATE
+        SET join_messages = EXCLUDED.join_messages
+    ").await?;
+    conn.execute(&stmt, &[&group_id, &enabled]).await?;
+    Ok(())
+}
+
+/// Replace the word filter of a group. Words are stored in lowercase.
+pub async fn update_word_filter(pool: Pool, group_id: GroupID, words: &[String])
+    -> Result<(), Error>
+{
+    let words = words.iter().map(|word| word.to_lowercase()).collect::<Vec<_>>();
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO GroupSettings (group_id, word_filter)
+        VALUES ($1, $2)
+        ON CONFLICT (group_id) DO UPDATE
+        SET word_filter = EXCLUDED.word_filter
+    ").await?;
+    conn.execute(&stmt, &[&group_id, &words]).await?;
+    Ok(())
+}
+
+/// Get the retention period of each group that has one.
+pub async fn group_retention_days(pool: Pool) -> Result<Vec<(GroupID, i32)>, Error> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT group_id, retention_days
+        FROM GroupSettings
+        WHERE retention_days IS NOT NULL
+    ").await?;
+    Ok(conn.query(&stmt, &[]).await?.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
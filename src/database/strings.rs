@@ -3,6 +3,8 @@ pub const MAX_GROUP_NAME_LENGTH: usize = 32;
 pub const MAX_URL_LENGTH: usize = 2048;
 pub const MAX_USER_NAME_LENGTH: usize = 64;
 pub const MAX_MESSAGE_LENGTH: usize = 1024;
+pub const MAX_FILENAME_LENGTH: usize = 255;
+pub const MAX_MIME_LENGTH: usize = 255;
 
 pub fn valid_channel_name(name: &String) -> bool {
     // A byte limit instead of a character limit is tempting...
@@ -42,6 +44,39 @@ pub fn valid_user_name(name: &String) -> bool {
     !name.is_empty() && within_char_limit(name, MAX_USER_NAME_LENGTH)
 }
 
+pub fn valid_nickname(nickname: &String) -> bool {
+    valid_user_name(nickname)
+}
+
 pub fn valid_message(message: &String) -> bool {
     !message.is_empty() && within_char_limit(message, MAX_MESSAGE_LENGTH)
 }
+
+pub fn valid_filename(filename: &String) -> bool {
+    !filename.is_empty() && within_char_limit(filename, MAX_FILENAME_LENGTH)
+}
+
+pub fn valid_mime(mime: &String) -> bool {
+    // Not attempting to validate the full syntax. Just type/subtype.
+    within_char_limit(mime, MAX_MIME_LENGTH) && match mime.find('/') {
+        Some(slash) => slash > 0 && slash < mime.len() - 1,
+        None => false
+    }
+}
+
+pub fn valid_api_token_name(name: &String) -> bool {
+    !name.is_empty() && within_char_limit(name, super::MAX_API_TOKEN_NAME_LENGTH)
+}
+
+pub fn valid_report_reason(reason: &String) -> bool {
+    !reason.is_empty() && within_char_limit(reason, super::MAX_REPORT_REASON_LENGTH)
+}
+
+/// Shortcodes that are built-in can't be used for custom emoji because they
+/// are expanded before the message is stored.
+pub fn valid_shortcode(shortcode: &String) -> bool {
+    shortcode.len() >= 2
+        && shortcode.len() <= super::MAX_SHORTCODE_LENGTH
+        && shortcode.chars().all(crate::utils::shortcode_char)
+        && crate::utils::shortcode_emoji(shortcode).is_none()
+}
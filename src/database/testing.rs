@@ -0,0 +1,79 @@
+use std::time::{Duration, SystemTime};
+use deadpool_postgres::{Pool, Manager, ManagerConfig, RecyclingMethod};
+use deadpool_postgres::tokio_postgres::{Config, NoTls};
+use crate::utils::generate_random_base64url;
+use super::{UserID, GroupID, ChannelID, MessageID, SessionID, GoogleUser};
+use super::{upsert_user, create_group, create_session, create_channel, create_message};
+
+// Tests that use these need the same local database as the server so they're
+// only run when asked for with --ignored. Every fixture gets a random name so
+// that the tests can share the database and be run more than once.
+
+pub fn pool() -> Pool {
+    let mut config = Config::new();
+    config.host("localhost");
+    config.user("postgres");
+    config.dbname("chat");
+    let manager_config = ManagerConfig { recycling_method: RecyclingMethod::Fast };
+    let manager = Manager::from_config(config, NoTls, manager_config);
+    Pool::new(manager, 4)
+}
+
+pub fn name() -> String {
+    format!("test {}", generate_random_base64url(16))
+}
+
+pub async fn user(pool: &Pool) -> UserID {
+    let user = GoogleUser {
+        google_id: generate_random_base64url(16),
+        name: name(),
+        picture: String::new(),
+        email: None,
+    };
+    upsert_user(pool.clone(), &user).await.unwrap()
+}
+
+pub async fn session(pool: &Pool, user_id: UserID) -> SessionID {
+    create_session(pool.clone(), user_id, None, None).await.unwrap()
+}
+
+/// Make a session look like it was last used some time ago.
+pub async fn idle_session(pool: &Pool, session_id: &SessionID, idle: Duration) {
+    let conn = pool.get().await.unwrap();
+    conn.execute(
+        "UPDATE Session SET last_active = $2 WHERE session_id = $1",
+        &[session_id, &(SystemTime::now() - idle)]
+    ).await.unwrap();
+}
+
+/// Create a group owned by a user.
+pub async fn group(pool: &Pool, owner: UserID) -> GroupID {
+    create_group(pool.clone(), owner, &name(), &String::new(), &"general".to_owned())
+        .await.unwrap().unwrap()
+}
+
+/// Add a user to a group with a role.
+pub async fn join(pool: &Pool, user_id: UserID, group_id: GroupID, role: &str) {
+    let conn = pool.get().await.unwrap();
+    conn.execute(
+        "INSERT INTO Membership (user_id, group_id, role) VALUES ($1, $2, $3)",
+        &[&user_id, &group_id, &role]
+    ).await.unwrap();
+}
+
+/// Make a user a server administrator.
+pub async fn admin(pool: &Pool, user_id: UserID) {
+    let conn = pool.get().await.unwrap();
+    conn.execute("UPDATE Usr SET admin = TRUE WHERE user_id = $1", &[&user_id]).await.unwrap();
+}
+
+/// Create a channel in a group.
+pub async fn channel(pool: &Pool, group_id: GroupID, private: bool) -> ChannelID {
+    create_channel(pool.clone(), group_id, &name(), private).await.unwrap().unwrap().0
+}
+
+/// Post a message to a channel.
+pub async fn message(pool: &Pool, author: UserID, channel_id: ChannelID, content: &str) -> MessageID {
+    create_message(pool.clone(), SystemTime::now(), author, &content.to_owned(), channel_id, None, None, &[])
+        .await.unwrap().0
+}
@@ -0,0 +1,146 @@
+use serde::Serialize;
+use sha2::{Sha256, Digest};
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use crate::utils::{as_timestamp, generate_random_base64url};
+use super::{SessionID, UserID, session_user_id, get_connection};
+
+pub const API_TOKEN_LENGTH: usize = 32;
+pub const MAX_API_TOKEN_NAME_LENGTH: usize = 64;
+
+pub type ApiTokenID = i32;
+pub type ApiToken = String;
+
+/// The ways that a request can identify a user.
+pub enum Credentials {
+    Session(SessionID),
+    ApiToken(ApiToken),
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Create a new API token for a user.
+///
+/// The token itself is returned but only its hash is stored so this is the
+/// only time that the token is available.
+pub async fn create_api_token(pool: Pool, user_id: UserID, name: &String)
+    -> Result<(ApiTokenID, ApiToken), Error>
+{
+    let mut token = generate_random_base64url(API_TOKEN_LENGTH);
+
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO ApiToken (token_hash, user_id, name, creation_time)
+        VALUES ($1, $2, $3, NOW())
+        ON CONFLICT (token_hash) DO NOTHING
+        RETURNING token_id
+    ").await?;
+
+    loop {
+        if let Some(row) = conn.query_opt(&stmt, &[&hash_token(&token), &user_id, name]).await? {
+            return Ok((row.get(0), token));
+        }
+        token = generate_random_base64url(API_TOKEN_LENGTH);
+    }
+}
+
+/// Get the user that an API token belongs to.
+pub async fn verify_api_token(pool: Pool, token: &str) -> Result<Option<UserID>, Error> {
+    if token.len() != API_TOKEN_LENGTH {
+        return Ok(None);
+    }
+
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT user_id
+        FROM ApiToken
+        WHERE token_hash = $1
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&hash_token(token)]).await?.map(|row| row.get(0)))
+}
+
+/// Get the user identified by some credentials.
+pub async fn credentials_user_id(pool: Pool, credentials: &Credentials) -> Result<Option<UserID>, Error> {
+    match credentials {
+        Credentials::Session(session_id) => session_user_id(pool, session_id).await,
+        Credentials::ApiToken(token) => verify_api_token(pool, token).await,
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiTokenInfo {
+    pub token_id: ApiTokenID,
+    pub name: String,
+    pub timestamp: u64,
+}
+
+pub async fn user_api_tokens(pool: Pool, user_id: UserID) -> Result<Vec<ApiTokenInfo>, Error> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT token_id, name, creation_time
+        FROM ApiToken
+        WHERE user_id = $1
+        ORDER BY token_id
+    ").await?;
+    Ok(conn.query(&stmt, &[&user_id]).await?.iter().map(|row| ApiTokenInfo {
+        token_id: row.get(0),
+        name: row.get(1),
+        timestamp: as_timestamp(row.get(2)),
+    }).collect())
+}
+
+/// Delete an API token.
+///
+/// Returns true if the token was actually deleted.
+pub async fn delete_api_token(pool: Pool, user_id: UserID, token_id: ApiTokenID) -> Result<bool, Error> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        DELETE FROM ApiToken
+        WHERE token_id = $1
+        AND user_id = $2
+    ").await?;
+    Ok(conn.execute(&stmt, &[&token_id, &user_id]).await? > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+
+    #[tokio::test]
+    #[ignore]
+    async fn create_verify_and_delete() {
+        let pool = testing::pool();
+        let user_id = testing::user(&pool).await;
+        let other_user_id = testing::user(&pool).await;
+        let (token_id, token) = create_api_token(pool.clone(), user_id, &"bot".to_owned()).await.unwrap();
+        assert_eq!(token.len(), API_TOKEN_LENGTH);
+        assert_eq!(verify_api_token(pool.clone(), &token).await.unwrap(), Some(user_id));
+        let tokens = user_api_tokens(pool.clone(), user_id).await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].token_id, token_id);
+        assert_eq!(tokens[0].name, "bot");
+
+        // Only the hash is stored.
+        let conn = pool.get().await.unwrap();
+        let stored: Vec<u8> = conn.query_one("SELECT token_hash FROM ApiToken WHERE token_id = $1", &[&token_id])
+            .await.unwrap().get(0);
+        assert_eq!(stored, hash_token(&token));
+        assert_ne!(stored, token.as_bytes());
+
+        assert!(!delete_api_token(pool.clone(), other_user_id, token_id).await.unwrap());
+        assert!(delete_api_token(pool.clone(), user_id, token_id).await.unwrap());
+        assert_eq!(verify_api_token(pool, &token).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn unknown_token() {
+        let pool = testing::pool();
+        let token = generate_random_base64url(API_TOKEN_LENGTH);
+        assert_eq!(verify_api_token(pool.clone(), &token).await.unwrap(), None);
+        assert_eq!(verify_api_token(pool, "short").await.unwrap(), None);
+    }
+}
@@ -1,10 +1,8 @@
-use super::GroupID;
+use super::{UserID, GroupID, get_connection};
 use serde::Serialize;
 use crate::error::Error;
 use deadpool_postgres::{Pool, PoolError};
 
-pub type UserID = i32;
-
 #[derive(Serialize)]
 pub struct User {
     pub user_id: UserID,
@@ -12,57 +10,117 @@ pub struct User {
     pub picture: String,
 }
 
-#[derive(Serialize)]
-pub struct AnonUser {
-    pub name: String,
-    pub picture: String,
+/// The name given to authors whose accounts no longer exist.
+pub const DELETED_USER_NAME: &str = "Deleted User";
+
+impl User {
+    /// A placeholder for a user that doesn't exist anymore. Messages from
+    /// deleted accounts have an author ID of 0.
+    pub fn deleted(user_id: UserID) -> Self {
+        Self {
+            user_id,
+            name: DELETED_USER_NAME.to_owned(),
+            picture: String::new(),
+        }
+    }
 }
 
 pub struct GoogleUser {
     pub google_id: String,
     pub name: String,
     pub picture: String,
+    /// None if the user doesn't have a verified email address.
+    pub email: Option<String>,
 }
 
-pub async fn user(pool: Pool, user_id: UserID) -> Result<Option<AnonUser>, Error> {
-    let conn = pool.get().await?;
+/// Get a user. Returns None if the user doesn't exist. See `User::deleted`.
+///
+/// If a group is given and the user has a nickname in it, the user goes by
+/// their nickname.
+pub async fn user_by_id(pool: Pool, user_id: UserID, group_id: Option<GroupID>) -> Result<Option<User>, Error> {
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
-        SELECT name, picture
+        SELECT Usr.user_id, COALESCE(GroupNickname.nickname, Usr.name), picture
         FROM Usr
-        WHERE user_id = $1
+        LEFT JOIN GroupNickname ON GroupNickname.user_id = Usr.user_id
+            AND GroupNickname.group_id = $2
+        WHERE Usr.user_id = $1
     ").await?;
-    Ok(conn.query_opt(&stmt, &[&user_id]).await?.map(|row| {
-        AnonUser {
-            name: row.get(0),
-            picture: row.get(1)
-        }
+    Ok(conn.query_opt(&stmt, &[&user_id, &group_id]).await?.map(|row| User {
+        user_id: row.get(0),
+        name: row.get(1),
+        picture: row.get(2),
     }))
 }
 
-pub async fn user_id_from_google(pool: Pool, user: &GoogleUser) -> Result<UserID, Error> {
-    let conn = pool.get().await?;
-    // https://stackoverflow.com/a/6722460/4093378
+/// Get the users with the given IDs that share at least one group with the
+/// requester. Unknown IDs are ignored.
+///
+/// If a group is given, users that have a nickname in it go by their nickname.
+pub async fn users_by_ids(pool: Pool, requester_id: UserID, user_ids: &[UserID], group_id: Option<GroupID>)
+    -> Result<Vec<User>, Error>
+{
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
-        WITH Temp AS (
-            INSERT INTO Usr (google_id, name, picture)
-            SELECT $1, $2, $3
-            WHERE NOT EXISTS (SELECT * FROM Usr WHERE google_id = $1)
-            RETURNING user_id
-        )
-        SELECT user_id FROM Temp
-        UNION ALL
-        SELECT user_id FROM Usr WHERE google_id = $1
-        LIMIT 1
+        SELECT Usr.user_id, COALESCE(GroupNickname.nickname, Usr.name), picture
+        FROM Usr
+        LEFT JOIN GroupNickname ON GroupNickname.user_id = Usr.user_id
+            AND GroupNickname.group_id = $3
+        WHERE Usr.user_id = ANY($1)
+        AND (Usr.user_id = $2 OR EXISTS (
+            SELECT 1
+            FROM Membership A
+            JOIN Membership B ON B.group_id = A.group_id
+            WHERE A.user_id = $2
+            AND B.user_id = Usr.user_id
+        ))
     ").await?;
-    Ok(conn.query_one(&stmt, &[&user.google_id, &user.name, &user.picture]).await?.get(0))
+    Ok(conn.query(&stmt, &[&user_ids, &requester_id, &group_id]).await?.iter().map(|row| User {
+        user_id: row.get(0),
+        name: row.get(1),
+        picture: row.get(2),
+    }).collect())
+}
+
+/// Determine whether a user is a server administrator
+pub async fn user_admin(pool: Pool, user_id: UserID) -> Result<bool, Error> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        SELECT 1
+        FROM Usr
+        WHERE user_id = $1
+        AND admin
+    ").await?;
+    Ok(conn.query_opt(&stmt, &[&user_id]).await?.is_some())
+}
+
+/// Create a user for a Google account if there isn't one already and get its
+/// ID.
+///
+/// The email address of an existing user is updated. The name and picture are
+/// only used for new users because users can change them.
+pub async fn upsert_user(pool: Pool, user: &GoogleUser) -> Result<UserID, Error> {
+    let conn = get_connection(&pool).await?;
+    let stmt = conn.prepare("
+        INSERT INTO Usr (google_id, name, picture, email)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (google_id) DO UPDATE
+        SET email = EXCLUDED.email
+        RETURNING user_id
+    ").await?;
+    Ok(conn.query_one(&stmt, &[&user.google_id, &user.name, &user.picture, &user.email]).await?.get(0))
 }
 
+/// Get the members of a group. Members that have a nickname in the group go by
+/// their nickname.
 pub async fn group_users(pool: Pool, group_id: GroupID) -> Result<Vec<User>, PoolError> {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
-        SELECT Usr.user_id, name, picture
+        SELECT Usr.user_id, COALESCE(GroupNickname.nickname, Usr.name), picture
         FROM Usr
         JOIN Membership ON Membership.user_id = Usr.user_id
+        LEFT JOIN GroupNickname ON GroupNickname.user_id = Membership.user_id
+            AND GroupNickname.group_id = Membership.group_id
         WHERE Membership.group_id = $1
         ORDER BY Usr.user_id
     ").await?;
@@ -74,7 +132,7 @@ pub async fn group_users(pool: Pool, group_id: GroupID) -> Result<Vec<User>, Poo
 }
 
 pub async fn group_user_ids(pool: Pool, group_id: GroupID) -> Result<Vec<UserID>, PoolError> {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
         SELECT Usr.user_id
         FROM Usr
@@ -86,7 +144,7 @@ pub async fn group_user_ids(pool: Pool, group_id: GroupID) -> Result<Vec<UserID>
 }
 
 pub async fn rename_user(pool: Pool, user_id: UserID, name: &String, picture: &String) -> Result<bool, Error> {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
         UPDATE Usr
         SET name = $2, picture = $3
@@ -102,7 +160,7 @@ pub async fn rename_user(pool: Pool, user_id: UserID, name: &String, picture: &S
 }
 
 pub async fn delete_user(pool: Pool, user_id: UserID) -> Result<bool, Error> {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
         DELETE FROM Usr
         WHERE user_id = $1
@@ -111,7 +169,7 @@ pub async fn delete_user(pool: Pool, user_id: UserID) -> Result<bool, Error> {
 }
 
 pub async fn anonymize_messages(pool: Pool, user_id: UserID, group_id: GroupID) -> Result<bool, Error> {
-    let conn = pool.get().await?;
+    let conn = get_connection(&pool).await?;
     let stmt = conn.prepare("
         UPDATE Message
         SET author = NULL
@@ -124,3 +182,37 @@ pub async fn anonymize_messages(pool: Pool, user_id: UserID, group_id: GroupID)
     ").await?;
     Ok(conn.execute(&stmt, &[&user_id, &group_id]).await? > 0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing;
+    use crate::utils::generate_random_base64url;
+
+    async fn email(pool: &Pool, user_id: UserID) -> Option<String> {
+        let conn = pool.get().await.unwrap();
+        conn.query_one("SELECT email FROM Usr WHERE user_id = $1", &[&user_id]).await.unwrap().get(0)
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn insert_then_update() {
+        let pool = testing::pool();
+        let name = testing::name();
+        let mut user = GoogleUser {
+            google_id: generate_random_base64url(16),
+            name: name.clone(),
+            picture: String::new(),
+            email: None,
+        };
+        let user_id = upsert_user(pool.clone(), &user).await.unwrap();
+        assert_eq!(email(&pool, user_id).await, None);
+
+        user.name = testing::name();
+        user.email = Some("jane@example.com".to_owned());
+        assert_eq!(upsert_user(pool.clone(), &user).await.unwrap(), user_id);
+        assert_eq!(email(&pool, user_id).await.as_deref(), Some("jane@example.com"));
+        // The name might have been changed since the user was created.
+        assert_eq!(user_by_id(pool, user_id, None).await.unwrap().unwrap().name, name);
+    }
+}
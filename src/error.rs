@@ -4,23 +4,47 @@ pub type JWTError = jsonwebtoken::errors::Error;
 pub type HeaderError = headers::Error;
 pub type JSONError = serde_json::error::Error;
 
+#[derive(Debug)]
+pub enum AuthError {
+    /// The nonce claim of the id token didn't match the nonce cookie.
+    InvalidNonce,
+    /// The user didn't grant all of the scopes that were requested.
+    InsufficientScope,
+    /// The token endpoint refused to exchange the authorization code. This
+    /// holds the error code and description that it gave.
+    TokenRejected(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::InvalidNonce => write!(f, "Invalid nonce in id token"),
+            AuthError::InsufficientScope => write!(f, "Required scope not granted"),
+            AuthError::TokenRejected(reason) => write!(f, "Token request rejected: {}", reason),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Database(DatabaseError),
     Request(RequestError),
     JWT(JWTError),
     Header(HeaderError),
-    JSON(JSONError)
+    JSON(JSONError),
+    Auth(AuthError),
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            Error::Database(DatabaseError::Timeout(t)) => write!(f, "Database pool timeout ({:?})", t),
             Error::Database(e) => e.fmt(f),
             Error::Request(e) => e.fmt(f),
             Error::JWT(e) => e.fmt(f),
             Error::Header(e) => e.fmt(f),
-            Error::JSON(e) => e.fmt(f)
+            Error::JSON(e) => e.fmt(f),
+            Error::Auth(e) => e.fmt(f),
         }
     }
 }
@@ -74,3 +98,9 @@ impl From<JSONError> for Error {
         Error::JSON(e)
     }
 }
+
+impl From<AuthError> for Error {
+    fn from(e: AuthError) -> Error {
+        Error::Auth(e)
+    }
+}
@@ -1,28 +1,107 @@
 use warp::Filter;
 use log::{debug, error};
-use crate::error::Error;
+use crate::error::{Error, DatabaseError};
 use deadpool_postgres::Pool;
 use std::convert::Infallible;
-use crate::utils::cache_long;
+use std::str::FromStr;
+use crate::config;
+use crate::utils::{cache_long, RateLimiter};
+use std::net::SocketAddr;
 use super::{handlers, socket};
-use crate::database::{ChannelID, UserID, GroupID, InviteID, SessionID};
+use crate::database as db;
+use crate::database::{ChannelID, UserID, GroupID, InviteID, SessionID, ApiTokenID, MessageID};
 
 fn with_state<S: Clone + Send>(state: S) -> impl Filter<Extract = (S,), Error = Infallible> + Clone {
     warp::any().map(move || state.clone())
 }
 
+#[derive(Debug)]
+struct InvalidID;
+
+impl warp::reject::Reject for InvalidID {}
+
+/// Extract a path segment like `warp::path::param`. Integers that can't be
+/// parsed are IDs that are out of range so they're rejected as a bad request
+/// instead of not matching the route.
+fn param<T: FromStr + Send + 'static>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Copy {
+    warp::path::param::<String>()
+        .and_then(|segment: String| async move {
+            match segment.parse::<T>() {
+                Ok(param) => Ok(param),
+                Err(_) if segment.parse::<i64>().is_ok() => Err(warp::reject::custom(InvalidID)),
+                Err(_) => Err(warp::reject::not_found()),
+            }
+        })
+}
+
+/// The same as `warp::path!` except that the parameters are extracted with
+/// `param`.
+macro_rules! id_path {
+    (@segment $segment:literal) => { warp::path($segment) };
+    (@segment $param:ident) => { param::<$param>() };
+    ($first:tt $(/ $segment:tt)*) => {
+        id_path!(@segment $first)
+            $(.and(id_path!(@segment $segment)))*
+            .and(warp::path::end())
+    };
+}
+
+/// The CORS policy of the API. Returns None if the API is same-origin only.
+pub fn cors(config: &config::Config) -> Option<warp::cors::Builder> {
+    if config.cors_allowed_origins.is_empty() {
+        return None;
+    }
+    Some(warp::cors()
+        .allow_origins(config.cors_allowed_origins.iter().map(String::as_str))
+        .allow_methods(config.cors_allowed_methods.iter().map(String::as_str))
+        .allow_headers(config.cors_allowed_headers.iter().map(String::as_str))
+        .allow_credentials(config.cors_allow_credentials))
+}
+
 fn with_session_id() -> impl Filter<Extract = (SessionID,), Error = Infallible> + Clone {
     warp::any()
         .and(warp::cookie::optional("session_id"))
         .map(|session_id: Option<String>| session_id.unwrap_or(String::new()))
 }
 
+/// Get the credentials of the request. An API token in the Authorization header
+/// takes precedence over the session cookie.
+fn with_credentials() -> impl Filter<Extract = (db::Credentials,), Error = Infallible> + Clone {
+    warp::any()
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_session_id())
+        .map(|authorization: Option<String>, session_id: SessionID| {
+            match authorization.as_ref().and_then(|auth| auth.strip_prefix("Bearer ")) {
+                Some(token) => db::Credentials::ApiToken(token.to_owned()),
+                None => db::Credentials::Session(session_id)
+            }
+        })
+}
+
+#[derive(Debug)]
+struct RateLimited;
+
+impl warp::reject::Reject for RateLimited {}
+
+/// Reject the request if the address it came from has made too many requests.
+fn rate_limit(limiter: RateLimiter) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::addr::remote()
+        .and(with_state(limiter))
+        .and_then(|addr: Option<SocketAddr>, limiter: RateLimiter| async move {
+            match addr {
+                Some(addr) if !limiter.allow(addr.ip()) => Err(warp::reject::custom(RateLimited)),
+                _ => Ok(())
+            }
+        })
+        .untuple_one()
+}
+
 pub fn root(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path::end()
         .and(warp::get())
         .and(with_session_id())
         .and(with_state(pool))
-        .map(|session_id, pool| (0, 0, session_id, pool))
+        .map(|session_id, pool| (GroupID(0), ChannelID(0), session_id, pool))
         .untuple_one()
         .and_then(handlers::channel)
         .recover(rejection)
@@ -49,7 +128,7 @@ pub fn logout(pool: Pool, socket_ctx: socket::Context)
 }
 
 pub fn channel(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("channel" / GroupID / ChannelID)
+    id_path!("channel" / GroupID / ChannelID)
         .and(warp::get())
         .and(with_session_id())
         .and(with_state(pool))
@@ -57,11 +136,12 @@ pub fn channel(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = wa
         .recover(rejection)
 }
 
-pub fn invite(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn invite(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("invite" / InviteID)
         .and(warp::get())
         .and(with_session_id())
         .and(with_state(pool))
+        .and(with_state(socket_ctx))
         .and_then(handlers::accept_invite)
         .recover(rejection)
 }
@@ -77,8 +157,30 @@ pub fn create_group(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error
         .recover(rejection)
 }
 
+pub fn user_groups(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "group")
+        .and(warp::get())
+        .and(warp::query::<handlers::GroupListQuery>())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::user_groups)
+        .recover(rejection)
+}
+
+pub fn update_group(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID)
+        .and(warp::put())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::UPDATE_GROUP_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::update_group)
+        .recover(rejection)
+}
+
 pub fn delete_group(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("api" / "group" / GroupID)
+    id_path!("api" / "group" / GroupID)
         .and(warp::delete())
         .and(warp::cookie("session_id"))
         .and(with_state(pool))
@@ -87,6 +189,64 @@ pub fn delete_group(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extr
         .recover(rejection)
 }
 
+pub fn group_settings(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "settings")
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::group_settings)
+        .recover(rejection)
+}
+
+pub fn update_group_settings(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "settings")
+        .and(warp::put())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::UPDATE_SETTINGS_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::update_group_settings)
+        .recover(rejection)
+}
+
+pub fn update_word_filter(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "settings" / "word_filter")
+        .and(warp::put())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::UPDATE_WORD_FILTER_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::update_word_filter)
+        .recover(rejection)
+}
+
+pub fn transfer_ownership(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "owner")
+        .and(warp::put())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::TRANSFER_OWNERSHIP_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::transfer_ownership)
+        .recover(rejection)
+}
+
+pub fn import_members(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "member")
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::IMPORT_MEMBERS_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::import_members)
+        .recover(rejection)
+}
+
 pub fn create_invite(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("api" / "invite")
         .and(warp::post())
@@ -99,7 +259,7 @@ pub fn create_invite(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Erro
 }
 
 pub fn leave_group(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("api" / "leave" / GroupID)
+    id_path!("api" / "leave" / GroupID)
         .and(warp::post())
         .and(warp::cookie("session_id"))
         .and(with_state(pool))
@@ -109,13 +269,62 @@ pub fn leave_group(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extra
 }
 
 pub fn user(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("api" / "user" / UserID)
+    id_path!("api" / "user" / UserID)
         .and(warp::get())
+        .and(warp::query::<handlers::UserQuery>())
+        .and(warp::cookie("session_id"))
         .and(with_state(pool))
         .and_then(handlers::user)
         .recover(rejection)
 }
 
+pub fn users(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "users")
+        .and(warp::get())
+        .and(warp::query::<handlers::UsersQuery>())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::users)
+        .recover(rejection)
+}
+
+pub fn whoami(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "whoami")
+        .and(warp::get())
+        .and(with_session_id())
+        .and(with_state(pool))
+        .and_then(handlers::whoami)
+        .recover(rejection)
+}
+
+pub fn sessions(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "user" / "session")
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::sessions)
+        .recover(rejection)
+}
+
+pub fn revoke_session(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "user" / "session" / String)
+        .and(warp::delete())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::revoke_session)
+        .recover(rejection)
+}
+
+pub fn recent_connections(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "user" / "connections")
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::recent_connections)
+        .recover(rejection)
+}
+
 pub fn rename_user(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("api" / "user")
         .and(warp::put())
@@ -128,6 +337,18 @@ pub fn rename_user(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extra
         .recover(rejection)
 }
 
+pub fn set_nickname(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "nickname")
+        .and(warp::put())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::SET_NICKNAME_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::set_nickname)
+        .recover(rejection)
+}
+
 pub fn delete_user(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("api" / "user")
         .and(warp::delete())
@@ -139,20 +360,262 @@ pub fn delete_user(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extra
 }
 
 pub fn socket(socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("api" / "socket" / GroupID)
+    id_path!("api" / "socket" / GroupID)
         .and(warp::ws())
         .and(warp::cookie("session_id"))
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::query::<socket::ResumeQuery>())
         .and(with_state(socket_ctx))
         .and_then(socket::Context::upgrade)
         .recover(rejection)
 }
 
-pub fn auth_success(pool: Pool, client: reqwest::Client, cert_cache: handlers::CertificateCache)
+pub fn pinned_messages(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "pinned" / ChannelID)
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::pinned_messages)
+        .recover(rejection)
+}
+
+pub fn group_activity(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "activity")
+        .and(warp::get())
+        .and(warp::query::<handlers::ActivityQuery>())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::group_activity)
+        .recover(rejection)
+}
+
+pub fn message(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "message" / MessageID)
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::message)
+        .recover(rejection)
+}
+
+pub fn post_message(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "message" / ChannelID)
+        .and(warp::post())
+        .and(with_credentials())
+        .and(warp::body::content_length_limit(handlers::POST_MESSAGE_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::post_message)
+        .recover(rejection)
+}
+
+pub fn create_attachment(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "attachment")
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::CREATE_ATTACHMENT_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and_then(handlers::create_attachment)
+        .recover(rejection)
+}
+
+pub fn create_token(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "token")
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::CREATE_TOKEN_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and_then(handlers::create_token)
+        .recover(rejection)
+}
+
+pub fn list_tokens(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "token")
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::list_tokens)
+        .recover(rejection)
+}
+
+pub fn delete_token(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "token" / ApiTokenID)
+        .and(warp::delete())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::delete_token)
+        .recover(rejection)
+}
+
+pub fn report_message(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "report" / MessageID)
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::REPORT_MESSAGE_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and_then(handlers::report_message)
+        .recover(rejection)
+}
+
+pub fn list_reports(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "report")
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::list_reports)
+        .recover(rejection)
+}
+
+pub fn audit_log(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "audit")
+        .and(warp::get())
+        .and(warp::query::<handlers::AuditQuery>())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::audit_log)
+        .recover(rejection)
+}
+
+pub fn group_emoji(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "emoji")
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::group_emoji)
+        .recover(rejection)
+}
+
+pub fn add_emoji(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "emoji")
+        .and(warp::post())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::ADD_EMOJI_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and_then(handlers::add_emoji)
+        .recover(rejection)
+}
+
+pub fn remove_emoji(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "emoji" / String)
+        .and(warp::delete())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::remove_emoji)
+        .recover(rejection)
+}
+
+pub fn recent_channels(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "channel" / "recent")
+        .and(warp::get())
+        .and(warp::query::<handlers::RecentChannelsQuery>())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::recent_channels)
+        .recover(rejection)
+}
+
+pub fn channel_info(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "channel" / ChannelID)
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::channel_info)
+        .recover(rejection)
+}
+
+pub fn export_channel(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "channel" / ChannelID / "export")
+        .and(warp::get())
+        .and(warp::query::<handlers::ExportQuery>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::export_channel)
+        .recover(rejection)
+}
+
+pub fn mute_channel(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "channel" / ChannelID / "mute")
+        .and(warp::put())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::mute_channel)
+        .recover(rejection)
+}
+
+pub fn unmute_channel(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "channel" / ChannelID / "mute")
+        .and(warp::delete())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::unmute_channel)
+        .recover(rejection)
+}
+
+pub fn drafts(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "group" / GroupID / "draft")
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and_then(handlers::drafts)
+        .recover(rejection)
+}
+
+pub fn set_draft(pool: Pool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    id_path!("api" / "draft" / ChannelID)
+        .and(warp::put())
+        .and(warp::cookie("session_id"))
+        .and(warp::body::content_length_limit(handlers::SET_DRAFT_LIMIT))
+        .and(warp::body::json())
+        .and(with_state(pool))
+        .and_then(handlers::set_draft)
+        .recover(rejection)
+}
+
+pub fn capabilities() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "capabilities")
+        .and(warp::get())
+        .and_then(handlers::capabilities)
+        .recover(rejection)
+}
+
+pub fn list_connections(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "admin" / "connection")
+        .and(warp::get())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::list_connections)
+        .recover(rejection)
+}
+
+pub fn disconnect_connection(pool: Pool, socket_ctx: socket::Context) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "admin" / "connection" / socket::ConnID)
+        .and(warp::delete())
+        .and(warp::cookie("session_id"))
+        .and(with_state(pool))
+        .and(with_state(socket_ctx))
+        .and_then(handlers::disconnect_connection)
+        .recover(rejection)
+}
+
+pub fn auth_success(pool: Pool, client: reqwest::Client, cert_cache: handlers::CertificateCache, limiter: RateLimiter)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
     warp::path!("api" / "auth")
         .and(warp::get())
         .and(warp::query::<handlers::AuthSuccess>())
+        .and(rate_limit(limiter))
+        .and(warp::cookie::optional("auth_nonce"))
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("user-agent"))
         .and(with_state(pool))
         .and(with_state(client))
         .and(with_state(cert_cache))
@@ -160,10 +623,11 @@ pub fn auth_success(pool: Pool, client: reqwest::Client, cert_cache: handlers::C
         .recover(rejection)
 }
 
-pub fn auth_fail() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn auth_fail(limiter: RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("api" / "auth")
         .and(warp::get())
         .and(warp::query::<handlers::AuthFail>())
+        .and(rate_limit(limiter))
         .and_then(handlers::auth_fail)
         .recover(rejection)
 }
@@ -196,7 +660,16 @@ pub fn css() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection>
 async fn rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
     if let Some(error) = rejection.find::<Error>() {
         error!("{}", error);
-        Ok(warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+        match error {
+            // The database isn't broken, it's just busy
+            Error::Database(DatabaseError::Timeout(_)) => Ok(warp::http::StatusCode::SERVICE_UNAVAILABLE),
+            Error::Auth(_) => Ok(warp::http::StatusCode::BAD_REQUEST),
+            _ => Ok(warp::http::StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    } else if rejection.find::<RateLimited>().is_some() {
+        Ok(warp::http::StatusCode::TOO_MANY_REQUESTS)
+    } else if rejection.find::<InvalidID>().is_some() {
+        Ok(warp::http::StatusCode::BAD_REQUEST)
     } else {
         Err(rejection)
     }
@@ -206,3 +679,62 @@ pub async fn leaked_rejection(rejection: warp::Rejection) -> Result<warp::http::
     debug!("Leaked: {:?}", rejection);
     Err(rejection)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing;
+    use warp::http::StatusCode;
+
+    // The pool doesn't connect until it's used and these requests are rejected
+    // before it would be.
+
+    #[tokio::test]
+    async fn non_positive_id_is_bad_request() {
+        let filter = audit_log(testing::pool());
+        for path in &["/api/group/0/audit", "/api/group/-2/audit", "/api/group/99999999999/audit"] {
+            let response = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn non_integer_id_does_not_match() {
+        let response = warp::test::request()
+            .path("/api/group/abc/audit")
+            .reply(&audit_log(testing::pool()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // The sessions route is next to this one.
+        let response = warp::test::request()
+            .path("/api/user/session")
+            .reply(&user(testing::pool()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn preflight(origin: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("OPTIONS")
+            .header("origin", origin)
+            .header("access-control-request-method", "GET")
+    }
+
+    #[tokio::test]
+    async fn cors_preflight() {
+        assert!(cors(&config::Config::default()).is_none());
+
+        let mut config = config::Config::default();
+        config.cors_allowed_origins = vec!["http://localhost:3000".to_owned()];
+        let filter = warp::any().map(warp::reply).with(cors(&config).unwrap());
+
+        let response = preflight("http://localhost:3000").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["access-control-allow-origin"], "http://localhost:3000");
+
+        let response = preflight("http://evil.example").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response.headers().get("access-control-allow-origin").is_none());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::socket;
+use crate::database as db;
+use deadpool_postgres::Pool;
+
+pub async fn list_connections(session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::user_admin(pool, user_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::FORBIDDEN));
+    }
+
+    Ok(Box::new(warp::reply::json(&socket_ctx.list_connections().await)))
+}
+
+pub async fn disconnect_connection(conn_id: socket::ConnID, session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    if !db::user_admin(pool, user_id).await? {
+        return Ok(warp::http::StatusCode::FORBIDDEN);
+    }
+
+    if socket_ctx.disconnect_connection(conn_id).await {
+        Ok(warp::http::StatusCode::NO_CONTENT)
+    } else {
+        Ok(warp::http::StatusCode::NOT_FOUND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filters;
+    use crate::socket;
+    use crate::database::testing;
+    use warp::http::StatusCode;
+
+    #[tokio::test]
+    #[ignore]
+    async fn list_connections() {
+        let pool = testing::pool();
+        let admin = testing::user(&pool).await;
+        testing::admin(&pool, admin).await;
+        let session_id = testing::session(&pool, admin).await;
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+        let first = socket_ctx.connect(owner, group_id).await;
+        let second = socket_ctx.connect(owner, group_id).await;
+
+        let response = warp::test::request()
+            .path("/api/admin/connection")
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::list_connections(pool.clone(), socket_ctx))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let groups: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(groups, serde_json::json!([{
+            "group_id": group_id,
+            "connections": [
+                { "conn_id": first.conn_id, "user_id": owner },
+                { "conn_id": second.conn_id, "user_id": owner },
+            ]
+        }]));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn only_admins_can_list_connections() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let session_id = testing::session(&pool, user).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+
+        let response = warp::test::request()
+            .path("/api/admin/connection")
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::list_connections(pool, socket_ctx))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn disconnect_connection() {
+        let pool = testing::pool();
+        let admin = testing::user(&pool).await;
+        testing::admin(&pool, admin).await;
+        let session_id = testing::session(&pool, admin).await;
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+        let mut target = socket_ctx.connect(owner, group_id).await;
+        let mut other = socket_ctx.connect(owner, group_id).await;
+        let filter = filters::disconnect_connection(pool.clone(), socket_ctx.clone());
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path(&format!("/api/admin/connection/{}", target.conn_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(target.received_type("close")[0]["code"], 4000);
+        assert!(other.received_type("close").is_empty());
+
+        // The connection is gone once the socket finishes closing.
+        let conn_id = target.conn_id;
+        socket_ctx.disconnect(target).await;
+        let response = warp::test::request()
+            .method("DELETE")
+            .path(&format!("/api/admin/connection/{}", conn_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
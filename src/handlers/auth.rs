@@ -1,13 +1,15 @@
 use log::error;
-use crate::error::Error;
+use crate::error::{Error, AuthError};
 use crate::database as db;
+use crate::utils::session_cookie;
 use deadpool_postgres::Pool;
 use jsonwebtoken::errors::Error as JWTError;
 use jsonwebtoken::errors::ErrorKind as JWTErrorKind;
 
 use headers::Header;
 use headers::CacheControl;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+use std::net::SocketAddr;
 use std::convert::Infallible;
 use serde::{Serialize, Deserialize};
 use jsonwebtoken::{decode, decode_header, Algorithm, Validation, DecodingKey};
@@ -21,7 +23,8 @@ https://accounts.google.com/o/oauth2/v2/auth?
   client_id=xxx.apps.googleusercontent.com&
   redirect_uri=https://localhost/api/auth&
   response_type=code&
-  scope=profile
+  scope=profile%20email&
+  nonce=xxx
 
 If the user accepts signs in, they'll be redirected to (AuthSuccess)
 https://localhost/api/auth?code=xxx&scope=xxx
@@ -35,7 +38,8 @@ https://oauth2.googleapis.com/token
 
 From this, we obtain a (TokenResponse) containing the id token. The id token is
 a JWT (json web token). The JWT is decoded to obtain the profile info. In order
-to verify it, a certificate must be obtained.
+to verify it, a certificate must be obtained. The nonce claim of the JWT must
+match the nonce that was put in the initial link.
 
 Certificates are obtained from
 https://www.googleapis.com/oauth2/v3/certs
@@ -44,6 +48,21 @@ is inspected so that the certificate is only requested when the cached
 certificate expires.
 */
 
+/// The scopes that must be granted. Google might report a scope by its short
+/// name or by its URL.
+const REQUIRED_SCOPES: &[(&str, &str)] = &[
+    ("profile", "https://www.googleapis.com/auth/userinfo.profile"),
+    ("email", "https://www.googleapis.com/auth/userinfo.email"),
+];
+
+/// Determine whether the space separated list of scopes includes all of the
+/// required scopes.
+fn has_required_scopes(scope: &str) -> bool {
+    REQUIRED_SCOPES.iter().all(|(name, url)| {
+        scope.split_whitespace().any(|granted| granted == *name || granted == *url)
+    })
+}
+
 #[derive(Deserialize)]
 pub struct AuthSuccess {
     code: String,
@@ -75,6 +94,14 @@ struct TokenResponse {
     // refresh_token: String,
 }
 
+/// The body of an error response from the token endpoint.
+#[derive(Deserialize)]
+struct TokenErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
 async fn request_id_token(client: &reqwest::Client, authorization_code: String)
     -> Result<TokenResponse, Error>
 {
@@ -85,13 +112,30 @@ async fn request_id_token(client: &reqwest::Client, authorization_code: String)
         grant_type: "authorization_code",
         redirect_uri: "https://localhost/api/auth"
     };
-    Ok(client.post("https://oauth2.googleapis.com/token")
+    let response = client.post("https://oauth2.googleapis.com/token")
         .form(&request)
         .send()
-        .await?
-        .json::<TokenResponse>()
-        .await?
-    )
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.bytes().await?;
+        return Err(AuthError::TokenRejected(token_error_reason(status, &body)).into());
+    }
+
+    Ok(response.json::<TokenResponse>().await?)
+}
+
+/// Get the reason that the token endpoint gave for rejecting a request. Falls
+/// back to the status if the body isn't in the expected shape.
+fn token_error_reason(status: reqwest::StatusCode, body: &[u8]) -> String {
+    match serde_json::from_slice::<TokenErrorResponse>(body) {
+        Ok(TokenErrorResponse { error, error_description: Some(description) }) => {
+            format!("{} ({})", error, description)
+        }
+        Ok(TokenErrorResponse { error, error_description: None }) => error,
+        Err(_) => status.to_string(),
+    }
 }
 
 #[derive(Deserialize)]
@@ -102,6 +146,32 @@ struct Certificate {
     // alg: String,
     // kty: String,
     // r#use: String,
+
+    /// When this key should be forgotten. None while Google publishes it.
+    /// Keys are kept after they stop being published because tokens signed
+    /// with them are still valid for a while.
+    #[serde(skip_deserializing)]
+    retire: Option<SystemTime>,
+}
+
+/// How long a key is kept after Google stops publishing it. This is the
+/// lifetime of an id token.
+const RETIRED_KEY_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Combine the published keys with the cached keys that are no longer
+/// published. Keys that disappear are given until `now + RETIRED_KEY_GRACE`
+/// and then forgotten.
+fn merge_certs(cached: Vec<Certificate>, mut published: Vec<Certificate>, now: SystemTime) -> Vec<Certificate> {
+    for mut cert in cached {
+        if published.iter().any(|new| new.kid == cert.kid) {
+            continue;
+        }
+        let retire = *cert.retire.get_or_insert(now + RETIRED_KEY_GRACE);
+        if retire > now {
+            published.push(cert);
+        }
+    }
+    published
 }
 
 #[derive(Deserialize)]
@@ -123,11 +193,13 @@ impl Default for Certs {
 
 pub type CertificateCache = std::sync::Arc<tokio::sync::Mutex<Certs>>;
 
-async fn update_cert_cache(client: &reqwest::Client, cached_certs: &mut Certs)
+/// Fetch the certificates if the cached ones have expired, or if force is
+/// true.
+async fn update_cert_cache(client: &reqwest::Client, cached_certs: &mut Certs, force: bool)
     -> Result<(), Error>
 {
     let now = SystemTime::now();
-    if cached_certs.expire > now {
+    if !force && cached_certs.expire > now {
         return Ok(());
     }
 
@@ -141,12 +213,24 @@ async fn update_cert_cache(client: &reqwest::Client, cached_certs: &mut Certs)
     let cache_control = CacheControl::decode(&mut iter)?;
     let certs = response.json::<Certs>().await?;
 
-    cached_certs.keys = certs.keys;
-    cached_certs.expire = now + cache_control.max_age().unwrap();
+    // Google stops publishing the old key before the tokens signed with it
+    // expire so those tokens can still be checked for a while.
+    let cached = std::mem::take(&mut cached_certs.keys);
+    cached_certs.keys = merge_certs(cached, certs.keys, now);
+    cached_certs.expire = now + cache_control.max_age().unwrap_or_default();
 
     Ok(())
 }
 
+/// Fill the certificate cache so that sign ins don't have to wait for it.
+/// Does nothing if the cached certificates haven't expired.
+pub async fn prefetch_certs(client: reqwest::Client, cache: CertificateCache) {
+    let mut certs = cache.lock().await;
+    if let Err(e) = update_cert_cache(&client, &mut *certs, false).await {
+        error!("Failed to prefetch Google certificates: {}", e);
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Claims {
     iss: String, // Issuer
@@ -158,9 +242,47 @@ pub struct Claims {
     pub picture: String,
     pub given_name: String,
     pub family_name: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+
+    nonce: Option<String>,
+}
+
+/// Check the claims of an id token that the JWT validation doesn't cover.
+fn check_claims(claims: &Claims, nonce: &str) -> Result<(), Error> {
+    // We can't set the iss field of Validation because it only accepts one
+    // value but the issuer can be one of two values.
+    match claims.iss.as_str() {
+        "accounts.google.com" | "https://accounts.google.com" => {},
+        _ => return Err(JWTError::from(JWTErrorKind::InvalidIssuer).into())
+    };
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(AuthError::InvalidNonce.into());
+    }
+
+    Ok(())
 }
 
-fn decode_id_token(certs: &Certs, id_token: &str) -> Result<Claims, Error> {
+/// Get the details of the user that an id token belongs to.
+fn google_user(claims: Claims) -> db::GoogleUser {
+    // Anyone can put any address on a Google account so it isn't stored
+    // until Google has verified it.
+    db::GoogleUser {
+        google_id: claims.sub,
+        name: claims.name,
+        picture: claims.picture,
+        email: if claims.email_verified { claims.email } else { None },
+    }
+}
+
+/// Decode and verify an id token.
+///
+/// Returns Ok(None) if none of the certificates match the key ID of the token.
+/// The nonce claim of the token must match the given nonce.
+fn decode_id_token(certs: &Certs, id_token: &str, nonce: &str) -> Result<Option<Claims>, Error> {
     let header = decode_header(id_token)?;
 
     // The header contains a kid (key ID) field that identifies the key to use
@@ -182,44 +304,56 @@ fn decode_id_token(certs: &Certs, id_token: &str) -> Result<Claims, Error> {
             validation.set_audience(&[include_str!("../../api/client_id.txt")]);
             let key = DecodingKey::from_rsa_components(&cert.n, &cert.e);
             let token_data = decode::<Claims>(id_token, &key, &validation)?;
-
-            // We can't set the iss field of Validation because it only accepts
-            // one value but the issuer can be one of two values.
-            match token_data.claims.iss.as_str() {
-                "accounts.google.com" | "https://accounts.google.com" => {},
-                _ => return Err(JWTError::from(JWTErrorKind::InvalidIssuer).into())
-            };
-
-            return Ok(token_data.claims);
+            check_claims(&token_data.claims, nonce)?;
+            return Ok(Some(token_data.claims));
         }
     }
 
-    Err(JWTError::from(JWTErrorKind::InvalidAlgorithmName).into())
+    Ok(None)
 }
 
-pub async fn auth_success(res: AuthSuccess, pool: Pool, client: reqwest::Client, cache: CertificateCache)
-    -> Result<impl warp::Reply, warp::Rejection>
-{
-    if res.scope != "profile https://www.googleapis.com/auth/userinfo.profile" {
-        return Err(warp::reject::not_found());
+pub async fn auth_success(
+    res: AuthSuccess,
+    nonce: Option<String>,
+    addr: Option<SocketAddr>,
+    user_agent: Option<String>,
+    pool: Pool,
+    client: reqwest::Client,
+    cache: CertificateCache
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !has_required_scopes(&res.scope) {
+        return Err(Error::from(AuthError::InsufficientScope).into());
     }
+    // The nonce cookie is set by the login page. Without it, there's nothing to
+    // check the token against.
+    let nonce = match nonce {
+        Some(nonce) => nonce,
+        None => return Err(Error::from(AuthError::InvalidNonce).into())
+    };
     let token = request_id_token(&client, res.code).await?;
     let mut certs = cache.lock().await;
-    update_cert_cache(&client, &mut *certs).await?;
-    let claims = decode_id_token(&certs, token.id_token.as_str())?;
-
-    let user = db::GoogleUser {
-        google_id: claims.sub,
-        name: claims.name,
-        picture: claims.picture,
+    update_cert_cache(&client, &mut *certs, false).await?;
+    let claims = match decode_id_token(&certs, token.id_token.as_str(), &nonce)? {
+        Some(claims) => claims,
+        None => {
+            // Google rotates its keys so the token might be signed with a key
+            // that was published after the certificates were cached.
+            update_cert_cache(&client, &mut *certs, true).await?;
+            match decode_id_token(&certs, token.id_token.as_str(), &nonce)? {
+                Some(claims) => claims,
+                None => return Err(Error::from(JWTError::from(JWTErrorKind::InvalidAlgorithmName)).into())
+            }
+        }
     };
-    let user_id = db::user_id_from_google(pool.clone(), &user).await?;
-    let session_id = db::create_session(pool, user_id).await?;
+
+    let user_id = db::upsert_user(pool.clone(), &google_user(claims)).await?;
+    let ip = addr.map(|addr| addr.ip());
+    let session_id = db::create_session(pool, user_id, ip, user_agent.as_deref()).await?;
 
     Ok(warp::reply::with_header(
         warp::redirect(res.state.parse::<warp::http::Uri>().unwrap()),
         "Set-Cookie",
-        format!("session_id={};Path=/;HttpOnly;Secure", session_id)
+        session_cookie(&session_id)
     ))
 }
 
@@ -227,3 +361,174 @@ pub async fn auth_fail(res: AuthFail) -> Result<impl warp::Reply, Infallible> {
     error!("Google auth error: {}", res.error);
     Ok(warp::redirect(warp::http::Uri::from_static("/")))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert(kid: &str) -> Certificate {
+        Certificate {
+            kid: kid.to_owned(),
+            n: String::new(),
+            e: String::new(),
+            retire: None,
+        }
+    }
+
+    fn kids(certs: &[Certificate]) -> Vec<&str> {
+        certs.iter().map(|cert| cert.kid.as_str()).collect()
+    }
+
+    #[test]
+    fn unpublished_key_is_kept_during_grace() {
+        let now = SystemTime::now();
+        let merged = merge_certs(vec![cert("old"), cert("current")], vec![cert("current"), cert("new")], now);
+        assert_eq!(kids(&merged), vec!["current", "new", "old"]);
+        assert_eq!(merged[2].retire, Some(now + RETIRED_KEY_GRACE));
+        assert_eq!(merged[0].retire, None);
+    }
+
+    #[test]
+    fn retired_key_keeps_its_deadline() {
+        let now = SystemTime::now();
+        let first = merge_certs(vec![cert("old")], vec![cert("new")], now);
+        let later = now + RETIRED_KEY_GRACE / 2;
+        let second = merge_certs(first, vec![cert("new")], later);
+        assert_eq!(kids(&second), vec!["new", "old"]);
+        assert_eq!(second[1].retire, Some(now + RETIRED_KEY_GRACE));
+    }
+
+    #[test]
+    fn retired_key_is_dropped_after_grace() {
+        let now = SystemTime::now();
+        let first = merge_certs(vec![cert("old")], vec![cert("new")], now);
+        let second = merge_certs(first, vec![cert("new")], now + RETIRED_KEY_GRACE);
+        assert_eq!(kids(&second), vec!["new"]);
+    }
+
+    #[test]
+    fn republished_key_is_no_longer_retired() {
+        let now = SystemTime::now();
+        let first = merge_certs(vec![cert("old")], vec![cert("new")], now);
+        let second = merge_certs(first, vec![cert("old"), cert("new")], now);
+        assert_eq!(kids(&second), vec!["old", "new"]);
+        assert!(second.iter().all(|cert| cert.retire.is_none()));
+    }
+
+    // Tokens with the header {"alg":"RS256","kid":"unknown","typ":"JWT"} and
+    // {"alg":"RS256","typ":"JWT"}. Neither has to be signed because decoding
+    // stops at the header.
+    const UNKNOWN_KID_TOKEN: &str = "eyJhbGciOiJSUzI1NiIsImtpZCI6InVua25vd24iLCJ0eXAiOiJKV1QifQ.e30.c2ln";
+    const NO_KID_TOKEN: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.e30.c2ln";
+
+    #[test]
+    fn unknown_key_needs_refetch() {
+        let certs = Certs { keys: vec![cert("current")], expire: SystemTime::now() };
+        assert!(matches!(decode_id_token(&certs, UNKNOWN_KID_TOKEN, "abc"), Ok(None)));
+    }
+
+    #[test]
+    fn missing_key_id() {
+        let certs = Certs { keys: vec![cert("current")], expire: SystemTime::now() };
+        assert!(decode_id_token(&certs, NO_KID_TOKEN, "abc").is_err());
+    }
+
+    fn claims(nonce: Option<&str>) -> Claims {
+        serde_json::from_value(serde_json::json!({
+            "iss": "https://accounts.google.com",
+            "sub": "1234",
+            "name": "Jane Doe",
+            "picture": "",
+            "given_name": "Jane",
+            "family_name": "Doe",
+            "nonce": nonce,
+        })).unwrap()
+    }
+
+    #[test]
+    fn matching_nonce() {
+        assert!(check_claims(&claims(Some("abc")), "abc").is_ok());
+    }
+
+    #[test]
+    fn mismatched_nonce() {
+        assert!(matches!(check_claims(&claims(Some("abc")), "xyz"), Err(Error::Auth(AuthError::InvalidNonce))));
+        assert!(matches!(check_claims(&claims(None), "xyz"), Err(Error::Auth(AuthError::InvalidNonce))));
+    }
+
+    fn email_claims(email_verified: Option<bool>) -> Claims {
+        let mut claims = serde_json::json!({
+            "iss": "https://accounts.google.com",
+            "sub": "1234",
+            "name": "Jane Doe",
+            "picture": "",
+            "given_name": "Jane",
+            "family_name": "Doe",
+            "email": "jane@example.com",
+        });
+        if let Some(verified) = email_verified {
+            claims["email_verified"] = verified.into();
+        }
+        serde_json::from_value(claims).unwrap()
+    }
+
+    #[test]
+    fn verified_email() {
+        let claims = email_claims(Some(true));
+        assert_eq!(claims.email.as_deref(), Some("jane@example.com"));
+        assert!(claims.email_verified);
+        let user = google_user(claims);
+        assert_eq!(user.google_id, "1234");
+        assert_eq!(user.email.as_deref(), Some("jane@example.com"));
+    }
+
+    #[test]
+    fn unverified_email() {
+        assert_eq!(google_user(email_claims(Some(false))).email, None);
+        assert_eq!(google_user(email_claims(None)).email, None);
+        assert_eq!(google_user(claims(None)).email, None);
+    }
+
+    #[test]
+    fn invalid_grant() {
+        let body = br#"{ "error": "invalid_grant", "error_description": "Bad Request" }"#;
+        assert_eq!(token_error_reason(reqwest::StatusCode::BAD_REQUEST, body), "invalid_grant (Bad Request)");
+        let body = br#"{ "error": "invalid_grant" }"#;
+        assert_eq!(token_error_reason(reqwest::StatusCode::BAD_REQUEST, body), "invalid_grant");
+    }
+
+    #[test]
+    fn unexpected_token_error() {
+        let reason = token_error_reason(reqwest::StatusCode::BAD_GATEWAY, b"<html></html>");
+        assert_eq!(reason, "502 Bad Gateway");
+    }
+
+    #[test]
+    fn short_scope_names() {
+        assert!(has_required_scopes("openid profile email"));
+        assert!(has_required_scopes("email profile"));
+    }
+
+    #[test]
+    fn scope_urls() {
+        assert!(has_required_scopes(
+            "openid https://www.googleapis.com/auth/userinfo.email https://www.googleapis.com/auth/userinfo.profile"
+        ));
+        assert!(has_required_scopes("profile  https://www.googleapis.com/auth/userinfo.email"));
+    }
+
+    #[test]
+    fn missing_scopes() {
+        assert!(!has_required_scopes(""));
+        assert!(!has_required_scopes("openid"));
+        assert!(!has_required_scopes("openid profile"));
+        assert!(!has_required_scopes("https://www.googleapis.com/auth/userinfo.email"));
+    }
+
+    #[test]
+    fn scopes_must_match_exactly() {
+        assert!(!has_required_scopes("profiles emails"));
+        assert!(!has_required_scopes("profile,email"));
+        assert!(!has_required_scopes("Profile Email"));
+    }
+}
@@ -0,0 +1,81 @@
+use serde::Serialize;
+use crate::config;
+use crate::database as db;
+use crate::utils::cache_short;
+
+/// The optional features and limits of this server so that clients can adapt
+/// to different deployments.
+#[derive(Serialize)]
+struct Capabilities {
+    reactions: bool,
+    threads: bool,
+    link_previews: bool,
+    custom_emoji: bool,
+    expand_shortcodes: bool,
+    single_session: bool,
+    max_message_length: usize,
+    max_attachments: usize,
+    max_pinned_messages: i64,
+    max_custom_emoji: i64,
+    max_owned_groups: i64,
+    typing_timeout_secs: u64,
+}
+
+pub async fn capabilities() -> Result<impl warp::Reply, warp::Rejection> {
+    let config = config::get();
+    Ok(cache_short(warp::reply::json(&Capabilities {
+        // Message history includes reaction summaries but there is no way to
+        // add a reaction yet.
+        reactions: false,
+        threads: true,
+        link_previews: config.link_previews,
+        custom_emoji: true,
+        expand_shortcodes: config.expand_shortcodes,
+        single_session: config.single_session,
+        max_message_length: db::MAX_MESSAGE_LENGTH,
+        max_attachments: db::MAX_ATTACHMENTS,
+        max_pinned_messages: db::MAX_PINNED_MESSAGES,
+        max_custom_emoji: db::MAX_CUSTOM_EMOJI,
+        max_owned_groups: config.max_owned_groups,
+        typing_timeout_secs: config.typing_timeout_secs,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn reflects_config() {
+        let mut config = config::Config::default();
+        config.link_previews = false;
+        config.single_session = true;
+        config.max_owned_groups = 3;
+        config.typing_timeout_secs = 9;
+        let _config = config::set_for_test(config);
+
+        let response = warp::test::request()
+            .path("/api/capabilities")
+            .reply(&filters::capabilities())
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        assert_eq!(response.headers()["cache-control"], "public,max-age=3600");
+        let capabilities: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(capabilities, json!({
+            "reactions": false,
+            "threads": true,
+            "link_previews": false,
+            "custom_emoji": true,
+            "expand_shortcodes": config::get().expand_shortcodes,
+            "single_session": true,
+            "max_message_length": db::MAX_MESSAGE_LENGTH,
+            "max_attachments": db::MAX_ATTACHMENTS,
+            "max_pinned_messages": db::MAX_PINNED_MESSAGES,
+            "max_custom_emoji": db::MAX_CUSTOM_EMOJI,
+            "max_owned_groups": 3,
+            "typing_timeout_secs": 9,
+        }));
+    }
+}
@@ -1,7 +1,8 @@
 use askama::Template;
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 use crate::database as db;
 use deadpool_postgres::Pool;
+use crate::utils::cache_none;
 
 #[derive(Template)]
 #[template(path = "channel.html")]
@@ -41,8 +42,8 @@ pub async fn channel(mut group_id: db::GroupID, mut channel_id: db::ChannelID, s
         return Ok(Box::new(ChannelTemplate {
             title: "Chat".to_owned(),
             preload_images,
-            group_id: 0,
-            channel_id: 0,
+            group_id: db::GroupID(0),
+            channel_id: db::ChannelID(0),
             user_id,
             user_list: ser_json(&user_list),
             group_list: "[]".to_owned(),
@@ -55,13 +56,13 @@ pub async fn channel(mut group_id: db::GroupID, mut channel_id: db::ChannelID, s
         Some(group) => group.name.clone(),
         None => {
             group_id = group_list[0].group_id;
-            channel_id = 0;
+            channel_id = db::ChannelID(0);
             group_list[0].name.clone()
         }
     };
 
     let (channel_list, user_list) = futures::future::join(
-        db::group_channels(pool.clone(), group_id),
+        db::user_group_channels(pool.clone(), user.user_id, group_id),
         db::group_users(pool.clone(), group_id)
     ).await;
 
@@ -97,3 +98,144 @@ pub async fn channel(mut group_id: db::GroupID, mut channel_id: db::ChannelID, s
         url: format!("/channel/{}/{}", group_id, channel_id),
     }))
 }
+
+#[derive(Deserialize)]
+pub struct RecentChannelsQuery {
+    limit: Option<u32>,
+}
+
+/// Get the channels that the user can access across all of their groups,
+/// most recently active first.
+pub async fn recent_channels(query: RecentChannelsQuery, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let limit = query.limit.unwrap_or(20).min(db::MAX_RECENT_CHANNELS);
+    let channels = db::recent_active_channels(pool, user_id, limit).await?;
+    Ok(Box::new(cache_none(warp::reply::json(&channels))))
+}
+
+/// Get the name, group and message count of a channel.
+pub async fn channel_info(channel_id: db::ChannelID, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let info = match db::channel_info(pool.clone(), channel_id).await? {
+        Some(info) => info,
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    };
+
+    if !db::channel_accessible(pool, user_id, channel_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::FORBIDDEN));
+    }
+
+    Ok(Box::new(cache_none(warp::reply::json(&info))))
+}
+
+pub async fn mute_channel(channel_id: db::ChannelID, session_id: db::SessionID, pool: Pool)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    set_channel_mute(channel_id, true, session_id, pool).await
+}
+
+pub async fn unmute_channel(channel_id: db::ChannelID, session_id: db::SessionID, pool: Pool)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    set_channel_mute(channel_id, false, session_id, pool).await
+}
+
+async fn set_channel_mute(channel_id: db::ChannelID, muted: bool, session_id: db::SessionID, pool: Pool)
+    -> Result<warp::http::StatusCode, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    if !db::channel_accessible(pool.clone(), user_id, channel_id).await? {
+        return Ok(warp::http::StatusCode::NOT_FOUND);
+    }
+
+    db::set_channel_mute(pool, user_id, channel_id, muted).await?;
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filters;
+    use crate::database as db;
+    use db::testing;
+    use deadpool_postgres::Pool;
+    use bytes::Bytes;
+    use serde_json::Value;
+    use warp::http::StatusCode;
+
+    async fn channel_info(pool: &Pool, session_id: &db::SessionID, channel_id: db::ChannelID)
+        -> warp::http::Response<Bytes>
+    {
+        warp::test::request()
+            .path(&format!("/api/channel/{}", channel_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::channel_info(pool.clone()))
+            .await
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn accessible_channel() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        testing::message(&pool, owner, channel_id, "first").await;
+        testing::message(&pool, owner, channel_id, "second").await;
+        let session_id = testing::session(&pool, owner).await;
+
+        let response = channel_info(&pool, &session_id, channel_id).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let info: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(info["channel_id"], channel_id.0);
+        assert_eq!(info["group_id"], group_id.0);
+        assert_eq!(info["message_count"], 2);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn unknown_channel() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let session_id = testing::session(&pool, user).await;
+
+        let response = channel_info(&pool, &session_id, db::ChannelID(i32::MAX)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn inaccessible_channel() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let outsider = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let public_id = testing::channel(&pool, group_id, false).await;
+        let private_id = testing::channel(&pool, group_id, true).await;
+
+        let session_id = testing::session(&pool, member).await;
+        let response = channel_info(&pool, &session_id, private_id).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let session_id = testing::session(&pool, outsider).await;
+        let response = channel_info(&pool, &session_id, public_id).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,48 @@
+use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::utils::cache_none;
+use serde::Deserialize;
+
+pub async fn drafts(group_id: db::GroupID, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::group_member(pool.clone(), user_id, group_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    Ok(Box::new(cache_none(warp::reply::json(&db::get_drafts(pool, user_id, group_id).await?))))
+}
+
+#[derive(Deserialize)]
+pub struct SetDraftRequest {
+    content: String,
+}
+
+pub const SET_DRAFT_LIMIT: u64 =
+    ("{'content':''}".len() + 4 * db::MAX_MESSAGE_LENGTH) as u64;
+
+pub async fn set_draft(channel_id: db::ChannelID, session_id: db::SessionID, request: SetDraftRequest, pool: Pool)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    // An empty draft is allowed. It clears the draft.
+    if !request.content.is_empty() && !db::valid_message(&request.content) {
+        return Ok(warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    if !db::channel_accessible(pool.clone(), user_id, channel_id).await? {
+        return Ok(warp::http::StatusCode::NOT_FOUND);
+    }
+
+    db::set_draft(pool, user_id, channel_id, &request.content).await?;
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,156 @@
+use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::utils::cache_none;
+use serde::Deserialize;
+
+pub async fn group_emoji(group_id: db::GroupID, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::group_member(pool.clone(), user_id, group_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    Ok(Box::new(cache_none(warp::reply::json(&db::group_emoji(pool, group_id).await?))))
+}
+
+#[derive(Deserialize)]
+pub struct AddEmojiRequest {
+    shortcode: String,
+    url: String,
+}
+
+pub const ADD_EMOJI_LIMIT: u64 =
+    ("{'shortcode':'','url':''}".len() + 4 * db::MAX_SHORTCODE_LENGTH + 4 * db::MAX_URL_LENGTH) as u64;
+
+pub async fn add_emoji(group_id: db::GroupID, session_id: db::SessionID, request: AddEmojiRequest, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    match db::member_role(pool.clone(), user_id, group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        _ => return Ok(Box::new(warp::http::StatusCode::FORBIDDEN))
+    }
+
+    if !db::valid_shortcode(&request.shortcode) || !db::valid_url(&request.url) {
+        return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+    }
+
+    if db::add_custom_emoji(pool.clone(), group_id, &request.shortcode, &request.url).await? {
+        db::log_audit(
+            pool, group_id, user_id, db::AuditAction::EmojiAdd, None, Some(request.shortcode)
+        ).await;
+        Ok(Box::new(warp::http::StatusCode::CREATED))
+    } else {
+        Ok(Box::new(warp::http::StatusCode::CONFLICT))
+    }
+}
+
+pub async fn remove_emoji(group_id: db::GroupID, shortcode: String, session_id: db::SessionID, pool: Pool)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    match db::member_role(pool.clone(), user_id, group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        _ => return Ok(warp::http::StatusCode::FORBIDDEN)
+    }
+
+    if db::remove_custom_emoji(pool.clone(), group_id, &shortcode).await? {
+        db::log_audit(
+            pool, group_id, user_id, db::AuditAction::EmojiRemove, None, Some(shortcode)
+        ).await;
+        Ok(warp::http::StatusCode::NO_CONTENT)
+    } else {
+        Ok(warp::http::StatusCode::NOT_FOUND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filters;
+    use crate::database as db;
+    use db::testing;
+    use deadpool_postgres::Pool;
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    async fn add_emoji(pool: &Pool, session_id: &db::SessionID, group_id: db::GroupID, shortcode: &str) -> StatusCode {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/api/group/{}/emoji", group_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .json(&json!({ "shortcode": shortcode, "url": "https://example.com/emoji.png" }))
+            .reply(&filters::add_emoji(pool.clone()))
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn only_moderators_add_emoji() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let outsider = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let owner_session = testing::session(&pool, owner).await;
+        let member_session = testing::session(&pool, member).await;
+        let outsider_session = testing::session(&pool, outsider).await;
+
+        assert_eq!(add_emoji(&pool, &member_session, group_id, "party_blob").await, StatusCode::FORBIDDEN);
+        assert_eq!(add_emoji(&pool, &owner_session, group_id, "no spaces").await, StatusCode::BAD_REQUEST);
+        assert_eq!(add_emoji(&pool, &owner_session, group_id, "party_blob").await, StatusCode::CREATED);
+        assert_eq!(add_emoji(&pool, &owner_session, group_id, "party_blob").await, StatusCode::CONFLICT);
+
+        let filter = filters::group_emoji(pool.clone());
+        let response = warp::test::request()
+            .path(&format!("/api/group/{}/emoji", group_id))
+            .header("cookie", format!("session_id={}", member_session))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let emoji: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(emoji, json!([{ "shortcode": "party_blob", "url": "https://example.com/emoji.png" }]));
+
+        let response = warp::test::request()
+            .path(&format!("/api/group/{}/emoji", group_id))
+            .header("cookie", format!("session_id={}", outsider_session))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn remove_emoji() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let session_id = testing::session(&pool, owner).await;
+        assert_eq!(add_emoji(&pool, &session_id, group_id, "party_blob").await, StatusCode::CREATED);
+        let filter = filters::remove_emoji(pool.clone());
+
+        for expected in [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND].iter() {
+            let response = warp::test::request()
+                .method("DELETE")
+                .path(&format!("/api/group/{}/emoji/party_blob", group_id))
+                .header("cookie", format!("session_id={}", session_id))
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), *expected);
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use crate::socket;
+use crate::config;
+use lexical_core::Number;
 use crate::database as db;
 use deadpool_postgres::Pool;
 use serde::{Serialize, Deserialize};
@@ -48,27 +50,94 @@ pub async fn create_group(session_id: String, request: CreateGroupRequest, pool:
         None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
     };
 
-    let group_id = match db::create_group(pool.clone(), request.name, request.picture).await? {
+    if db::owned_group_count(pool.clone(), user_id).await? >= config::get().max_owned_groups {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&Response::Error { message: "group_limit" }),
+            warp::http::StatusCode::FORBIDDEN
+        )));
+    }
+
+    let channel_name = &config::get().default_channel_name;
+    let group_id = match db::create_group(pool.clone(), user_id, &request.name, &request.picture, channel_name).await? {
         Some(id) => id,
         None => return Ok(error_response("name_exists"))
     };
 
-    let (channel_id, joined) = futures::future::join(
-        db::create_channel(pool.clone(), group_id, &"general".to_owned()),
-        db::join_group(pool.clone(), user_id, group_id)
-    ).await;
-
-    // Unwrapping the Option returned by create_channel because it is None if
-    // the channel name is not unique within the group. We just created the
-    // group so it must be unique.
-    channel_id.map_err(|e| crate::error::Error::Database(e))?.unwrap();
-    joined?;
-
     Ok(Box::new(warp::reply::json(
         &Response::Success { group_id }
     )))
 }
 
+#[derive(Deserialize)]
+pub struct UpdateGroupRequest {
+    name: String,
+    picture: String,
+}
+
+pub const UPDATE_GROUP_LIMIT: u64 = CREATE_GROUP_LIMIT;
+
+/// Change the name and picture of a group.
+pub async fn update_group(group_id: db::GroupID, session_id: db::SessionID, request: UpdateGroupRequest, pool: Pool, socket_ctx: socket::Context)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    match db::member_role(pool.clone(), user_id, group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        _ => return Ok(warp::http::StatusCode::FORBIDDEN)
+    }
+
+    if !db::valid_group_name(&request.name) || !db::valid_url(&request.picture) {
+        return Ok(warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    if !db::update_group(pool.clone(), group_id, &request.name, &request.picture).await? {
+        return Ok(warp::http::StatusCode::CONFLICT);
+    }
+
+    let users = db::group_user_ids(pool, group_id).await.map_err(|e| crate::error::Error::Database(e))?;
+    socket_ctx.group_updated(users, group_id, &request.name, &request.picture).await;
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct GroupListQuery {
+    after: Option<db::GroupID>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct GroupListResponse {
+    groups: Vec<db::GroupUnread>,
+    next_after: Option<db::GroupID>,
+}
+
+pub async fn user_groups(query: GroupListQuery, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    // One more group is fetched to find out whether there is another page.
+    let limit = query.limit.unwrap_or(db::MAX_GROUP_PAGE).min(db::MAX_GROUP_PAGE);
+    let mut groups = db::user_groups_unread(pool, user_id, query.after, limit + 1).await?;
+    let has_more = groups.len() > limit as usize;
+    groups.truncate(limit as usize);
+
+    let next_after = if has_more {
+        groups.last().map(|group| group.group_id)
+    } else {
+        None
+    };
+
+    Ok(Box::new(warp::reply::json(&GroupListResponse { groups, next_after })))
+}
+
 pub async fn delete_group(group_id: db::GroupID, session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
     -> Result<impl warp::Reply, warp::Rejection>
 {
@@ -86,3 +155,355 @@ pub async fn delete_group(group_id: db::GroupID, session_id: db::SessionID, pool
     socket_ctx.delete_group(users, group_id).await;
     Ok(warp::http::StatusCode::NO_CONTENT)
 }
+
+pub async fn group_settings(group_id: db::GroupID, session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::group_member(pool.clone(), user_id, group_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    Ok(Box::new(warp::reply::json(&socket_ctx.group_settings(group_id).await?)))
+}
+
+pub const UPDATE_SETTINGS_LIMIT: u64 = 256;
+
+pub async fn update_group_settings(group_id: db::GroupID, session_id: db::SessionID, settings: db::GroupSettings, pool: Pool, socket_ctx: socket::Context)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    match db::member_role(pool.clone(), user_id, group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        _ => return Ok(warp::http::StatusCode::FORBIDDEN)
+    }
+
+    if !settings.valid() {
+        return Ok(warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    socket_ctx.update_group_settings(group_id, &settings).await?;
+    db::log_audit(
+        pool, group_id, user_id, db::AuditAction::SettingsUpdate, None,
+        serde_json::to_string(&settings).ok()
+    ).await;
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct WordFilterRequest {
+    words: Vec<String>,
+}
+
+pub const UPDATE_WORD_FILTER_LIMIT: u64 =
+    ("{'words':[]}".len() + db::MAX_FILTER_WORDS * ("'',".len() + 4 * db::MAX_FILTER_WORD_LENGTH)) as u64;
+
+pub async fn update_word_filter(group_id: db::GroupID, session_id: db::SessionID, request: WordFilterRequest, pool: Pool, socket_ctx: socket::Context)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    match db::member_role(pool.clone(), user_id, group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        _ => return Ok(warp::http::StatusCode::FORBIDDEN)
+    }
+
+    if !db::valid_word_filter(&request.words) {
+        return Ok(warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    socket_ctx.update_word_filter(group_id, &request.words).await?;
+    db::log_audit(
+        pool, group_id, user_id, db::AuditAction::WordFilterUpdate, None,
+        Some(request.words.len().to_string())
+    ).await;
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct TransferOwnershipRequest {
+    user_id: db::UserID,
+}
+
+pub const TRANSFER_OWNERSHIP_LIMIT: u64 =
+    ("{'user_id':}".len() + i32::FORMATTED_SIZE_DECIMAL) as u64;
+
+pub async fn transfer_ownership(group_id: db::GroupID, session_id: db::SessionID, request: TransferOwnershipRequest, pool: Pool, socket_ctx: socket::Context)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    if request.user_id == user_id {
+        return Ok(warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    match db::transfer_ownership(pool.clone(), group_id, user_id, request.user_id).await? {
+        db::TransferResult::Transferred => {
+            db::log_audit(
+                pool, group_id, user_id, db::AuditAction::OwnershipTransfer, Some(db::AuditTarget::User(request.user_id)), None
+            ).await;
+            socket_ctx.ownership_transferred(group_id, user_id, request.user_id).await;
+            Ok(warp::http::StatusCode::NO_CONTENT)
+        }
+        db::TransferResult::NotOwner => Ok(warp::http::StatusCode::FORBIDDEN),
+        db::TransferResult::NotMember => Ok(warp::http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// The maximum number of users that can be added to a group in one request.
+pub const MAX_IMPORT_MEMBERS: usize = 500;
+
+pub const IMPORT_MEMBERS_LIMIT: u64 =
+    ("[]".len() + MAX_IMPORT_MEMBERS * (i32::FORMATTED_SIZE_DECIMAL + 1)) as u64;
+
+#[derive(Serialize)]
+struct ImportMembersResponse {
+    added: Vec<db::UserID>,
+    existing: Vec<db::UserID>,
+}
+
+pub async fn import_members(group_id: db::GroupID, session_id: db::SessionID, user_ids: Vec<db::UserID>, pool: Pool, socket_ctx: socket::Context)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    match db::member_role(pool.clone(), user_id, group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        _ => return Ok(Box::new(warp::http::StatusCode::FORBIDDEN))
+    }
+
+    if user_ids.len() > MAX_IMPORT_MEMBERS {
+        return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+    }
+
+    let import = db::add_memberships(pool.clone(), group_id, &user_ids).await?;
+    socket_ctx.add_members(group_id, &import.added).await;
+    db::log_audit(
+        pool, group_id, user_id, db::AuditAction::MembersImport, None,
+        Some(format!("{} added", import.added.len()))
+    ).await;
+
+    Ok(Box::new(warp::reply::json(&ImportMembersResponse {
+        added: import.added.iter().map(|user| user.user_id).collect(),
+        existing: import.existing,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config;
+    use crate::filters;
+    use crate::socket;
+    use crate::database as db;
+    use db::testing;
+    use deadpool_postgres::Pool;
+    use serde_json::{json, Value};
+
+    async fn create_group(pool: &Pool, session_id: &db::SessionID) -> (warp::http::StatusCode, Value) {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/group")
+            .header("cookie", format!("session_id={}", session_id))
+            .json(&json!({ "name": testing::name(), "picture": "" }))
+            .reply(&filters::create_group(pool.clone()))
+            .await;
+        (response.status(), serde_json::from_slice(response.body()).unwrap())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn transfer_is_audited() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let session_id = testing::session(&pool, owner).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path(&format!("/api/group/{}/owner", group_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .json(&json!({ "user_id": member }))
+            .reply(&filters::transfer_ownership(pool.clone(), socket_ctx))
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::NO_CONTENT);
+
+        let log = db::group_audit_log(pool, group_id, None, Some(db::AuditAction::OwnershipTransfer))
+            .await.unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].actor, Some(owner));
+        assert_eq!(log[0].target, Some(member.0));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn import_new_and_existing_members() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let existing = testing::user(&pool).await;
+        let new = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, existing, group_id, "member").await;
+        let session_id = testing::session(&pool, owner).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+        let mut conn = socket_ctx.connect(owner, group_id).await;
+        conn.received();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/api/group/{}/member", group_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .json(&vec![existing, new])
+            .reply(&filters::import_members(pool.clone(), socket_ctx.clone()))
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        let import: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(import["added"], json!([new]));
+        assert_eq!(import["existing"], json!([existing]));
+
+        let added = conn.received_type("member_added");
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0]["user_id"], new.0);
+        assert_eq!(db::member_role(pool, new, group_id).await.unwrap(), Some(db::Role::Member));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn owned_group_limit() {
+        let mut config = config::Config::default();
+        config.max_owned_groups = 1;
+        let _config = config::set_for_test(config);
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let session_id = testing::session(&pool, owner).await;
+
+        let (status, response) = create_group(&pool, &session_id).await;
+        assert_eq!(status, warp::http::StatusCode::OK);
+        assert_eq!(response["type"], "success");
+
+        let (status, response) = create_group(&pool, &session_id).await;
+        assert_eq!(status, warp::http::StatusCode::FORBIDDEN);
+        assert_eq!(response, json!({ "type": "error", "message": "group_limit" }));
+    }
+
+    async fn update_group(pool: &Pool, socket_ctx: &socket::Context, session_id: &db::SessionID, group_id: db::GroupID, name: &str)
+        -> warp::http::StatusCode
+    {
+        warp::test::request()
+            .method("PUT")
+            .path(&format!("/api/group/{}", group_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .json(&json!({ "name": name, "picture": "" }))
+            .reply(&filters::update_group(pool.clone(), socket_ctx.clone()))
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn update_group_name() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let taken = testing::name();
+        db::create_group(pool.clone(), owner, &taken, &String::new(), &"general".to_owned())
+            .await.unwrap().unwrap();
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let session_id = testing::session(&pool, owner).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+        let mut conn = socket_ctx.connect(member, group_id).await;
+        conn.received();
+
+        let status = update_group(&pool, &socket_ctx, &session_id, group_id, &taken).await;
+        assert_eq!(status, warp::http::StatusCode::CONFLICT);
+        assert!(conn.received().is_empty());
+
+        let name = testing::name();
+        let status = update_group(&pool, &socket_ctx, &session_id, group_id, &name).await;
+        assert_eq!(status, warp::http::StatusCode::NO_CONTENT);
+        let updated = conn.received_type("group_updated");
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0]["group_id"], group_id.0);
+        assert_eq!(updated[0]["name"], name);
+    }
+
+    async fn list_groups(pool: &Pool, session_id: &db::SessionID, query: &str) -> Value {
+        let response = warp::test::request()
+            .path(&format!("/api/group?{}", query))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::user_groups(pool.clone()))
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn group_list_cursor() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let mut group_ids = Vec::new();
+        for _ in 0..5 {
+            group_ids.push(testing::group(&pool, user).await);
+        }
+        let session_id = testing::session(&pool, user).await;
+
+        let page = list_groups(&pool, &session_id, "limit=2").await;
+        assert_eq!(page["groups"][0]["group_id"], group_ids[0].0);
+        assert_eq!(page["groups"][1]["group_id"], group_ids[1].0);
+        assert_eq!(page["groups"].as_array().unwrap().len(), 2);
+        assert_eq!(page["next_after"], group_ids[1].0);
+
+        let page = list_groups(&pool, &session_id, &format!("limit=2&after={}", group_ids[1])).await;
+        assert_eq!(page["groups"][0]["group_id"], group_ids[2].0);
+        assert_eq!(page["groups"][1]["group_id"], group_ids[3].0);
+        assert_eq!(page["next_after"], group_ids[3].0);
+
+        let page = list_groups(&pool, &session_id, &format!("limit=2&after={}", group_ids[3])).await;
+        assert_eq!(page["groups"].as_array().unwrap().len(), 1);
+        assert_eq!(page["groups"][0]["group_id"], group_ids[4].0);
+        assert_eq!(page["next_after"], Value::Null);
+
+        let page = list_groups(&pool, &session_id, "").await;
+        assert_eq!(page["groups"].as_array().unwrap().len(), 5);
+        assert_eq!(page["next_after"], Value::Null);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn default_channel_from_config() {
+        let mut config = config::Config::default();
+        config.default_channel_name = "lobby".to_owned();
+        let _config = config::set_for_test(config);
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let session_id = testing::session(&pool, owner).await;
+
+        let (status, response) = create_group(&pool, &session_id).await;
+        assert_eq!(status, warp::http::StatusCode::OK);
+        let group_id = serde_json::from_value(response["group_id"].clone()).unwrap();
+        let channels = db::group_channels(pool, group_id).await.unwrap();
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].name, "lobby");
+    }
+}
@@ -1,9 +1,13 @@
+use crate::socket;
 use lexical_core::Number;
+use std::time::SystemTime;
+use crate::error::Error;
 use crate::database as db;
 use deadpool_postgres::Pool;
+use crate::utils::as_timestamp;
 use serde::{Serialize, Deserialize};
 
-pub async fn accept_invite(invite_id: db::InviteID, session_id: db::SessionID, pool: Pool)
+pub async fn accept_invite(invite_id: db::InviteID, session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
     -> Result<Box<dyn warp::Reply>, warp::Rejection>
 {
     let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
@@ -14,16 +18,39 @@ pub async fn accept_invite(invite_id: db::InviteID, session_id: db::SessionID, p
         )))
     };
 
-    let group_id = match db::invitation_group_id(pool.clone(), invite_id).await? {
-        Some(id) => id,
-        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    // Either way, we should take the user to the group.
+    let group_id = match db::redeem_invite(pool.clone(), &invite_id, user_id).await? {
+        db::RedeemResult::Joined(group_id) => {
+            if let Some(user) = db::user_by_id(pool.clone(), user_id, None).await? {
+                socket_ctx.add_members(group_id, std::slice::from_ref(&user)).await;
+                post_join_message(pool.clone(), &socket_ctx, &user, group_id).await?;
+            }
+            group_id
+        }
+        db::RedeemResult::AlreadyMember(group_id) => group_id,
+        db::RedeemResult::Invalid => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
     };
 
-    // This returns false if the user is already a member of the group but that
-    // doesn't matter because either way, we should take the user to the group.
-    db::join_group(pool.clone(), user_id, group_id).await?;
+    super::channel(group_id, db::ChannelID(0), session_id, pool).await
+}
+
+/// Announce that a user joined a group if the group has join messages enabled.
+async fn post_join_message(pool: Pool, socket_ctx: &socket::Context, user: &db::User, group_id: db::GroupID)
+    -> Result<(), Error>
+{
+    if !socket_ctx.group_settings(group_id).await?.join_messages {
+        return Ok(());
+    }
+
+    let content = format!("{} joined the group", user.name);
+    let time = SystemTime::now();
+    if let Some((channel_id, message_id)) = db::insert_system_message(pool, time, group_id, &content).await? {
+        socket_ctx.send_recent_message(
+            group_id, message_id, as_timestamp(time), db::SYSTEM_USER_ID, content, channel_id, true
+        ).await;
+    }
 
-    super::channel(group_id, 0, session_id, pool).await
+    Ok(())
 }
 
 #[derive(Serialize)]
@@ -33,11 +60,17 @@ struct Response {
 
 #[derive(Deserialize)]
 pub struct CreateInviteRequest {
-    group_id: db::GroupID
+    group_id: db::GroupID,
+    /// The number of hours that the invitation is valid for.
+    #[serde(default)]
+    hours: Option<u32>,
+    /// The number of times that the invitation can be used. Unlimited if None.
+    #[serde(default)]
+    max_uses: Option<i32>,
 }
 
 pub const CREATE_INVITE_LIMIT: u64 =
-    ("{'group_id':}".len() + db::GroupID::FORMATTED_SIZE_DECIMAL) as u64;
+    ("{'group_id':,'hours':,'max_uses':}".len() + 3 * i32::FORMATTED_SIZE_DECIMAL) as u64;
 
 pub async fn create_invite(session_id: db::SessionID, request: CreateInviteRequest, pool: Pool)
     -> Result<Box<dyn warp::Reply>, warp::Rejection>
@@ -47,11 +80,64 @@ pub async fn create_invite(session_id: db::SessionID, request: CreateInviteReque
         None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
     };
 
-    if !db::group_member(pool.clone(), user_id, request.group_id).await? {
-        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    match db::member_role(pool.clone(), user_id, request.group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        Some(_) => return Ok(Box::new(warp::http::StatusCode::FORBIDDEN)),
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    }
+
+    let hours = request.hours.unwrap_or(db::DEFAULT_INVITE_HOURS);
+    if hours == 0 || hours > db::MAX_INVITE_HOURS {
+        return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+    }
+    if let Some(max_uses) = request.max_uses {
+        if max_uses <= 0 {
+            return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+        }
     }
 
     Ok(Box::new(warp::reply::json(&Response {
-        invite_id: db::create_invitation(pool.clone(), request.group_id).await?
+        invite_id: db::create_invite(pool.clone(), request.group_id, user_id, hours, request.max_uses).await?
     })))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters;
+    use crate::database::testing;
+
+    #[tokio::test]
+    #[ignore]
+    async fn joining_posts_system_message() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let joiner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let invite_id = db::create_invite(pool.clone(), group_id, owner, 1, None).await.unwrap();
+        let session_id = testing::session(&pool, joiner).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+        let mut conn = socket_ctx.connect(owner, group_id).await;
+        conn.received();
+
+        warp::test::request()
+            .path(&format!("/invite/{}", invite_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::invite(pool.clone(), socket_ctx.clone()))
+            .await;
+
+        let received = conn.received_type("recent_message");
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["system"], true);
+        assert_eq!(received[0]["author"], db::SYSTEM_USER_ID.0);
+        assert!(received[0]["content"].as_str().unwrap().ends_with(" joined the group"));
+
+        // The message is kept so that it's in the history too.
+        let channel_id = serde_json::from_value(received[0]["channel_id"].clone()).unwrap();
+        let message_id: db::MessageID = serde_json::from_value(received[0]["message_id"].clone()).unwrap();
+        let history = db::recent_messages(pool.clone(), channel_id).await.unwrap();
+        let last = history.rows.last().unwrap();
+        assert_eq!(last.get::<_, db::MessageID>(0), message_id);
+        assert!(last.get::<_, bool>(7));
+    }
+}
@@ -2,7 +2,8 @@ use askama::Template;
 use serde::Deserialize;
 use crate::database as db;
 use deadpool_postgres::Pool;
-use crate::{utils::cache_long, socket};
+use crate::{utils::{cache_none, generate_random_base64url, clear_session_cookie}, socket};
+use warp::Reply;
 
 #[derive(Template)]
 #[template(path = "login.html")]
@@ -16,16 +17,29 @@ pub struct LoginQuery {
     redirect: String,
 }
 
+/// The nonce binds the id token to the browser that started the sign in. It's
+/// stored in a cookie that is only sent back to the auth endpoint and is
+/// checked against the nonce claim of the id token.
+const NONCE_LENGTH: usize = 32;
+const NONCE_MAX_AGE: u32 = 600; // 10 minutes
+
 pub async fn login(query: LoginQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    let nonce = generate_random_base64url(NONCE_LENGTH);
     let mut google_auth_url = format!(
-        "https://accounts.google.com/o/oauth2/v2/auth?redirect_uri=https://localhost/api/auth&response_type=code&scope=profile&client_id={}&state=",
-        include_str!("../../api/client_id.txt")
+        "https://accounts.google.com/o/oauth2/v2/auth?redirect_uri=https://localhost/api/auth&response_type=code&scope=profile%20email&client_id={}&nonce={}&state=",
+        include_str!("../../api/client_id.txt"),
+        nonce
     );
     google_auth_url.extend(form_urlencoded::byte_serialize(query.redirect.as_bytes()));
-    Ok(cache_long(LoginTemplate {
-        redirect_url: query.redirect,
-        google_auth_url,
-    }))
+    // The page can't be cached because every sign in needs a fresh nonce.
+    Ok(warp::reply::with_header(
+        cache_none(LoginTemplate {
+            redirect_url: query.redirect,
+            google_auth_url,
+        }),
+        "Set-Cookie",
+        format!("auth_nonce={};Path=/api/auth;Max-Age={};HttpOnly;Secure;SameSite=Lax", nonce, NONCE_MAX_AGE)
+    ))
 }
 
 pub async fn logout(pool: Pool, socket_ctx: socket::Context, session_id: db::SessionID)
@@ -33,7 +47,13 @@ pub async fn logout(pool: Pool, socket_ctx: socket::Context, session_id: db::Ses
 {
     if let Some(user_id) = db::session_user_id(pool.clone(), &session_id).await? {
         db::delete_user_sessions(pool, user_id).await?;
-        socket_ctx.kick_user(user_id).await;
+        socket_ctx.kick_user(user_id, socket::CloseReason::SessionExpired).await;
     }
-    Ok(login(LoginQuery { redirect: "/".to_owned() }).await?)
+    let mut response = login(LoginQuery { redirect: "/".to_owned() }).await?.into_response();
+    // The login page sets the nonce cookie so this has to be appended.
+    response.headers_mut().append(
+        warp::http::header::SET_COOKIE,
+        warp::http::HeaderValue::from_str(&clear_session_cookie()).unwrap()
+    );
+    Ok(response)
 }
@@ -0,0 +1,529 @@
+use crate::socket;
+use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::utils::{as_timestamp, cache_none};
+use serde::{Serialize, Deserialize};
+use deadpool_postgres::tokio_postgres::Row;
+use futures::StreamExt;
+use bytes::Bytes;
+
+#[derive(Serialize)]
+struct Message {
+    message_id: db::MessageID,
+    timestamp: u64,
+    author: db::UserID,
+    content: String,
+    deleted: bool,
+    reply_to: Option<db::MessageID>,
+    system: bool,
+    forwarded_from: Option<db::MessageID>,
+}
+
+fn message_from_row(row: &Row) -> Message {
+    Message {
+        message_id: row.get(0),
+        timestamp: as_timestamp(row.get(1)),
+        author: row.get(2),
+        content: row.get(3),
+        deleted: row.get(5),
+        reply_to: row.get(6),
+        system: row.get(7),
+        forwarded_from: row.get(8),
+    }
+}
+
+pub async fn pinned_messages(channel_id: db::ChannelID, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::channel_accessible(pool.clone(), user_id, channel_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    let rows = db::pinned_messages(pool, channel_id).await
+        .map_err(|e| crate::error::Error::Database(e))?;
+    let messages = rows.iter().map(message_from_row).collect::<Vec<_>>();
+
+    Ok(Box::new(warp::reply::json(&messages)))
+}
+
+#[derive(Serialize)]
+struct LinkedMessage {
+    #[serde(flatten)]
+    message: Message,
+    channel_id: db::ChannelID,
+    group_id: db::GroupID,
+}
+
+/// Get a single message for a link to it.
+pub async fn message(message_id: db::MessageID, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let row = match db::message_by_id(pool.clone(), message_id).await
+        .map_err(|e| crate::error::Error::Database(e))?
+    {
+        Some(row) => row,
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    };
+
+    let channel_id = row.get(9);
+    if !db::channel_accessible(pool, user_id, channel_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::FORBIDDEN));
+    }
+
+    Ok(Box::new(warp::reply::json(&LinkedMessage {
+        message: message_from_row(&row),
+        channel_id,
+        group_id: row.get(10),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct CreateAttachmentRequest {
+    url: String,
+    mime: String,
+    size: i64,
+    filename: String,
+}
+
+#[derive(Serialize)]
+struct CreateAttachmentResponse {
+    attachment_id: db::AttachmentID,
+}
+
+pub const CREATE_ATTACHMENT_LIMIT: u64 = (
+    "{'url':'','mime':'','size':,'filename':''}".len()
+        + 4 * db::MAX_URL_LENGTH
+        + 4 * db::MAX_MIME_LENGTH
+        + 20
+        + 4 * db::MAX_FILENAME_LENGTH
+) as u64;
+
+pub async fn create_attachment(session_id: db::SessionID, request: CreateAttachmentRequest, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::valid_url(&request.url)
+        || !db::valid_mime(&request.mime)
+        || !db::valid_filename(&request.filename)
+        || request.size < 0
+    {
+        return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+    }
+
+    let attachment_id = db::create_attachment(
+        pool, user_id, &request.url, &request.mime, request.size, &request.filename
+    ).await?;
+
+    Ok(Box::new(warp::reply::json(&CreateAttachmentResponse { attachment_id })))
+}
+
+#[derive(Deserialize)]
+pub struct PostMessageRequest {
+    content: String,
+}
+
+#[derive(Serialize)]
+struct PostMessageResponse {
+    message_id: db::MessageID,
+    timestamp: u64,
+}
+
+pub const POST_MESSAGE_LIMIT: u64 =
+    ("{'content':''}".len() + 4 * db::MAX_MESSAGE_LENGTH) as u64;
+
+/// Post a message without a socket.
+///
+/// Bots can use an API token instead of a session.
+pub async fn post_message(channel_id: db::ChannelID, credentials: db::Credentials, request: PostMessageRequest, pool: Pool, socket_ctx: socket::Context)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::credentials_user_id(pool.clone(), &credentials).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let group_id = match db::channel_group_id(pool.clone(), channel_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    };
+
+    let message = socket::NewMessage {
+        author: user_id,
+        group_id,
+        channel_id,
+        content: request.content,
+        attachments: Vec::new(),
+        reply_to: None,
+        forwarded_from: None,
+    };
+    let (message_id, timestamp) = match socket_ctx.send_message(message).await? {
+        Ok(sent) => sent,
+        Err(socket::Refusal::ChannelIdInvalid) => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND)),
+        Err(socket::Refusal::SlowMode(remaining)) => return Ok(Box::new(warp::reply::with_header(
+            warp::http::StatusCode::TOO_MANY_REQUESTS, "Retry-After", remaining.to_string()
+        ))),
+        Err(socket::Refusal::ChannelThrottled) => return Ok(Box::new(warp::http::StatusCode::TOO_MANY_REQUESTS)),
+        Err(_) => return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST)),
+    };
+
+    Ok(Box::new(warp::reply::json(&PostMessageResponse { message_id, timestamp })))
+}
+
+#[derive(Deserialize)]
+pub struct ActivityQuery {
+    before: Option<db::MessageID>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ActivityMessage {
+    #[serde(flatten)]
+    message: Message,
+    channel_id: db::ChannelID,
+}
+
+#[derive(Serialize)]
+struct ActivityResponse {
+    messages: Vec<ActivityMessage>,
+    next_before: Option<db::MessageID>,
+}
+
+/// Get the recent messages of every channel in a group that the user can
+/// access.
+pub async fn group_activity(group_id: db::GroupID, query: ActivityQuery, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::group_member(pool.clone(), user_id, group_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    // One more message is fetched to find out whether there is another page.
+    let limit = query.limit.unwrap_or(50).min(db::MAX_GROUP_MESSAGES);
+    let mut rows = db::recent_group_messages(pool, user_id, group_id, query.before, limit + 1).await
+        .map_err(|e| crate::error::Error::Database(e))?;
+    let has_more = rows.len() > limit as usize;
+    rows.truncate(limit as usize);
+
+    let messages = rows.iter().map(|row| ActivityMessage {
+        message: message_from_row(row),
+        channel_id: row.get(9),
+    }).collect::<Vec<_>>();
+    let next_before = if has_more {
+        messages.last().map(|message| message.message.message_id)
+    } else {
+        None
+    };
+
+    Ok(Box::new(cache_none(warp::reply::json(&ActivityResponse { messages, next_before }))))
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ExportFormat {
+    Json,
+    Csv,
+}
+
+/// Quote a CSV field if it contains anything special.
+///
+/// Spreadsheets treat a field that starts with `=`, `+`, `-` or `@` as a
+/// formula so those fields are prefixed with a `'` to keep them as text.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(|ch| matches!(ch, '=' | '+' | '-' | '@')) {
+        format!("'{}", field)
+    } else {
+        field.to_owned()
+    };
+    if field.contains(|ch| ch == ',' || ch == '"' || ch == '\n' || ch == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn export_line(format: ExportFormat, message: &db::ExportedMessage) -> String {
+    match format {
+        ExportFormat::Json => serde_json::to_string(message).unwrap() + "\n",
+        ExportFormat::Csv => format!(
+            "{},{},{},{},{},{}\r\n",
+            message.message_id,
+            message.timestamp,
+            message.author.map(|id| id.to_string()).unwrap_or_default(),
+            csv_field(&message.content),
+            message.edited,
+            message.deleted,
+        ),
+    }
+}
+
+/// Download every message in a channel as newline-delimited JSON or CSV.
+///
+/// The format is chosen by the format query parameter, falling back to the
+/// Accept header. Only moderators can export a channel.
+pub async fn export_channel(channel_id: db::ChannelID, query: ExportQuery, accept: Option<String>, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let format = match query.format.as_deref() {
+        Some("json") => ExportFormat::Json,
+        Some("csv") => ExportFormat::Csv,
+        Some(_) => return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST)),
+        None if accept.map_or(false, |accept| accept.contains("text/csv")) => ExportFormat::Csv,
+        None => ExportFormat::Json,
+    };
+
+    let group_id = match db::channel_group_id(pool.clone(), channel_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    };
+
+    match db::member_role(pool.clone(), user_id, group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        _ => return Ok(Box::new(warp::http::StatusCode::FORBIDDEN))
+    }
+
+    if !db::channel_accessible(pool.clone(), user_id, channel_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::FORBIDDEN));
+    }
+
+    let messages = db::export_channel_messages(pool, channel_id);
+    let lines = messages.map(move |message| {
+        message.map(|message| Bytes::from(export_line(format, &message)))
+    });
+
+    let (content_type, extension) = match format {
+        ExportFormat::Json => ("application/x-ndjson", "ndjson"),
+        ExportFormat::Csv => ("text/csv", "csv"),
+    };
+    let body = match format {
+        ExportFormat::Json => warp::hyper::Body::wrap_stream(lines),
+        ExportFormat::Csv => {
+            let header = futures::stream::once(async {
+                Ok(Bytes::from_static(b"message_id,timestamp,author,content,edited,deleted\r\n"))
+            });
+            warp::hyper::Body::wrap_stream(header.chain(lines))
+        }
+    };
+
+    Ok(Box::new(cache_none(warp::http::Response::builder()
+        .header(warp::http::header::CONTENT_TYPE, content_type)
+        .header(
+            warp::http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"channel-{}.{}\"", channel_id, extension)
+        )
+        .body(body)
+        .unwrap())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters;
+    use crate::database::testing;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn plain_field() {
+        assert_eq!(csv_field("hello there"), "hello there");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn special_characters_are_quoted() {
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("two\r\nlines"), "\"two\r\nlines\"");
+    }
+
+    #[test]
+    fn formulas_are_neutralised() {
+        assert_eq!(csv_field("=1+1"), "'=1+1");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+    }
+
+    #[test]
+    fn formula_characters_later_in_the_field() {
+        assert_eq!(csv_field("1+1=2"), "1+1=2");
+        assert_eq!(csv_field("me@example.com"), "me@example.com");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn posted_message_reaches_socket() {
+        let pool = testing::pool();
+        let poster = testing::user(&pool).await;
+        let reader = testing::user(&pool).await;
+        let group_id = testing::group(&pool, poster).await;
+        testing::join(&pool, reader, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let session_id = testing::session(&pool, poster).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+        let mut conn = socket_ctx.connect(reader, group_id).await;
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/api/message/{}", channel_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .json(&json!({ "content": "hello from http" }))
+            .reply(&filters::post_message(pool.clone(), socket_ctx.clone()))
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        let posted: Value = serde_json::from_slice(response.body()).unwrap();
+
+        let received = conn.received_type("recent_message");
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["message_id"], posted["message_id"]);
+        assert_eq!(received[0]["channel_id"], channel_id.0);
+        assert_eq!(received[0]["author"], poster.0);
+        assert_eq!(received[0]["content"], "hello from http");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn api_token_posts_message() {
+        let pool = testing::pool();
+        let bot = testing::user(&pool).await;
+        let group_id = testing::group(&pool, bot).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let session_id = testing::session(&pool, bot).await;
+        let (_, token) = db::create_api_token(pool.clone(), bot, &"bot".to_owned()).await.unwrap();
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+        let filter = filters::post_message(pool.clone(), socket_ctx);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/api/message/{}", channel_id))
+            .header("authorization", format!("Bearer {}", token))
+            .json(&json!({ "content": "beep" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+
+        // The token takes precedence over the session.
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/api/message/{}", channel_id))
+            .header("authorization", "Bearer not a token")
+            .header("cookie", format!("session_id={}", session_id))
+            .json(&json!({ "content": "beep" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::UNAUTHORIZED);
+    }
+
+    async fn get_message(pool: &Pool, session_id: &db::SessionID, message_id: db::MessageID)
+        -> warp::http::Response<Bytes>
+    {
+        warp::test::request()
+            .path(&format!("/api/message/{}", message_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::message(pool.clone()))
+            .await
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn linked_message_needs_access() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let public = testing::channel(&pool, group_id, false).await;
+        let private = testing::channel(&pool, group_id, true).await;
+        let visible = testing::message(&pool, owner, public, "visible").await;
+        let hidden = testing::message(&pool, owner, private, "hidden").await;
+        let session_id = testing::session(&pool, member).await;
+
+        let response = get_message(&pool, &session_id, visible).await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        let message: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(message["message_id"], visible.0);
+        assert_eq!(message["content"], "visible");
+        assert_eq!(message["channel_id"], public.0);
+        assert_eq!(message["group_id"], group_id.0);
+
+        let response = get_message(&pool, &session_id, hidden).await;
+        assert_eq!(response.status(), warp::http::StatusCode::FORBIDDEN);
+        let response = get_message(&pool, &session_id, db::MessageID(i32::MAX)).await;
+        assert_eq!(response.status(), warp::http::StatusCode::NOT_FOUND);
+    }
+
+    async fn export(pool: &Pool, session_id: &db::SessionID, channel_id: db::ChannelID, format: &str)
+        -> warp::http::Response<Bytes>
+    {
+        warp::test::request()
+            .path(&format!("/api/channel/{}/export?format={}", channel_id, format))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::export_channel(pool.clone()))
+            .await
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn export_has_a_row_per_message() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let mut message_ids = Vec::new();
+        for content in ["first", "second, with a comma", "third"].iter() {
+            message_ids.push(testing::message(&pool, owner, channel_id, content).await);
+        }
+        let session_id = testing::session(&pool, owner).await;
+
+        let response = export(&pool, &session_id, channel_id, "json").await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        let body = std::str::from_utf8(response.body()).unwrap();
+        let rows = body.lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), message_ids.len());
+        for (row, message_id) in rows.iter().zip(message_ids.iter()) {
+            assert_eq!(row["message_id"], message_id.0);
+        }
+
+        let response = export(&pool, &session_id, channel_id, "csv").await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        let body = std::str::from_utf8(response.body()).unwrap();
+        // One line for the header.
+        assert_eq!(body.split_terminator("\r\n").count(), message_ids.len() + 1);
+
+        let session_id = testing::session(&pool, member).await;
+        let response = export(&pool, &session_id, channel_id, "json").await;
+        assert_eq!(response.status(), warp::http::StatusCode::FORBIDDEN);
+    }
+}
@@ -4,6 +4,13 @@ mod channel;
 mod login;
 mod group;
 mod invite;
+mod admin;
+mod message;
+mod token;
+mod report;
+mod emoji;
+mod draft;
+mod capabilities;
 
 pub use auth::*;
 pub use user::*;
@@ -11,3 +18,10 @@ pub use channel::*;
 pub use login::*;
 pub use group::*;
 pub use invite::*;
+pub use admin::*;
+pub use message::*;
+pub use token::*;
+pub use report::*;
+pub use emoji::*;
+pub use draft::*;
+pub use capabilities::*;
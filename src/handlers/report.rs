@@ -0,0 +1,153 @@
+use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::utils::cache_none;
+use serde::{Serialize, Deserialize};
+
+#[derive(Deserialize)]
+pub struct ReportMessageRequest {
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct ReportMessageResponse {
+    report_id: db::ReportID,
+}
+
+pub const REPORT_MESSAGE_LIMIT: u64 =
+    ("{'reason':''}".len() + 4 * db::MAX_REPORT_REASON_LENGTH) as u64;
+
+pub async fn report_message(message_id: db::MessageID, session_id: db::SessionID, request: ReportMessageRequest, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::valid_report_reason(&request.reason) {
+        return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+    }
+
+    let row = match db::message_by_id(pool.clone(), message_id).await
+        .map_err(|e| crate::error::Error::Database(e))?
+    {
+        Some(row) => row,
+        None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+    };
+
+    if !db::channel_accessible(pool.clone(), user_id, row.get(9)).await? {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    match db::report_message(pool, user_id, message_id, &request.reason).await? {
+        Some(report_id) => Ok(Box::new(warp::reply::json(&ReportMessageResponse { report_id }))),
+        None => Ok(Box::new(warp::http::StatusCode::CONFLICT))
+    }
+}
+
+pub async fn list_reports(group_id: db::GroupID, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    match db::member_role(pool.clone(), user_id, group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        _ => return Ok(Box::new(warp::http::StatusCode::FORBIDDEN))
+    }
+
+    Ok(Box::new(cache_none(warp::reply::json(&db::group_reports(pool, group_id).await?))))
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    before: Option<db::AuditID>,
+    action: Option<db::AuditAction>,
+}
+
+pub async fn audit_log(group_id: db::GroupID, query: AuditQuery, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    match db::member_role(pool.clone(), user_id, group_id).await? {
+        Some(role) if role >= db::Role::Moderator => {},
+        _ => return Ok(Box::new(warp::http::StatusCode::FORBIDDEN))
+    }
+
+    let records = db::group_audit_log(pool, group_id, query.before, query.action).await?;
+    Ok(Box::new(cache_none(warp::reply::json(&records))))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filters;
+    use crate::database::testing;
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    #[tokio::test]
+    #[ignore]
+    async fn duplicate_report() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let message_id = testing::message(&pool, owner, channel_id, "abuse").await;
+        let session_id = testing::session(&pool, member).await;
+        let filter = filters::report_message(pool.clone());
+
+        for expected in [StatusCode::OK, StatusCode::CONFLICT].iter() {
+            let response = warp::test::request()
+                .method("POST")
+                .path(&format!("/api/report/{}", message_id))
+                .header("cookie", format!("session_id={}", session_id))
+                .json(&json!({ "reason": "rude" }))
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), *expected);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn only_moderators_list_reports() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let message_id = testing::message(&pool, owner, channel_id, "abuse").await;
+        crate::database::report_message(pool.clone(), member, message_id, &"rude".to_owned()).await.unwrap();
+        let filter = filters::list_reports(pool.clone());
+
+        let session_id = testing::session(&pool, member).await;
+        let response = warp::test::request()
+            .path(&format!("/api/group/{}/report", group_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let session_id = testing::session(&pool, owner).await;
+        let response = warp::test::request()
+            .path(&format!("/api/group/{}/report", group_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let reports: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(reports.as_array().unwrap().len(), 1);
+        assert_eq!(reports[0]["message_id"], message_id.0);
+        assert_eq!(reports[0]["reporter"], member.0);
+        assert_eq!(reports[0]["reason"], "rude");
+    }
+}
@@ -0,0 +1,61 @@
+use crate::database as db;
+use deadpool_postgres::Pool;
+use crate::utils::cache_none;
+use serde::{Serialize, Deserialize};
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct CreateTokenResponse {
+    token_id: db::ApiTokenID,
+    token: db::ApiToken,
+}
+
+pub const CREATE_TOKEN_LIMIT: u64 =
+    ("{'name':''}".len() + 4 * db::MAX_API_TOKEN_NAME_LENGTH) as u64;
+
+pub async fn create_token(session_id: db::SessionID, request: CreateTokenRequest, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::valid_api_token_name(&request.name) {
+        return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+    }
+
+    let (token_id, token) = db::create_api_token(pool, user_id, &request.name).await?;
+
+    Ok(Box::new(cache_none(warp::reply::json(&CreateTokenResponse { token_id, token }))))
+}
+
+pub async fn list_tokens(session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    Ok(Box::new(cache_none(warp::reply::json(&db::user_api_tokens(pool, user_id).await?))))
+}
+
+pub async fn delete_token(token_id: db::ApiTokenID, session_id: db::SessionID, pool: Pool)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    if db::delete_api_token(pool, user_id, token_id).await? {
+        Ok(warp::http::StatusCode::NO_CONTENT)
+    } else {
+        Ok(warp::http::StatusCode::NOT_FOUND)
+    }
+}
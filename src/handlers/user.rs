@@ -1,17 +1,148 @@
 use crate::socket;
 use serde::Deserialize;
+use std::collections::HashMap;
 use crate::database as db;
 use deadpool_postgres::Pool;
-use crate::utils::cache_short;
+use crate::utils::{cache_private, cache_none};
 
-pub async fn user(user_id: db::UserID, pool: Pool)
+#[derive(Deserialize)]
+pub struct UserQuery {
+    /// The group to get the nickname of the user in.
+    group_id: Option<db::GroupID>,
+}
+
+pub async fn user(user_id: db::UserID, query: UserQuery, session_id: db::SessionID, pool: Pool)
     -> Result<Box<dyn warp::Reply>, warp::Rejection>
 {
-    let user = match db::user(pool, user_id).await? {
-        Some(info) => info,
+    let requester_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    // Nicknames are only visible to the members of the group.
+    if let Some(group_id) = query.group_id {
+        if !db::group_member(pool.clone(), requester_id, group_id).await? {
+            return Ok(Box::new(warp::http::StatusCode::FORBIDDEN));
+        }
+    }
+
+    // Messages from deleted accounts are shown as written by the system user,
+    // which has no account of its own.
+    if user_id == db::SYSTEM_USER_ID {
+        return Ok(Box::new(cache_private(warp::reply::json(&db::User::deleted(user_id)))));
+    }
+
+    let user = match db::user_by_id(pool.clone(), user_id, query.group_id).await? {
+        Some(user) => user,
         None => return Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
     };
-    Ok(Box::new(cache_short(warp::reply::json(&user))))
+
+    // Users can only see the profiles of people that they share a group with.
+    if user_id != requester_id && !db::share_group(pool, requester_id, user_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::FORBIDDEN));
+    }
+
+    Ok(Box::new(cache_private(warp::reply::json(&user))))
+}
+
+/// The maximum number of users that can be requested at once.
+pub const MAX_USERS_PER_REQUEST: usize = 100;
+
+#[derive(Deserialize)]
+pub struct UsersQuery {
+    /// Comma separated list of user IDs.
+    ids: String,
+    /// The group to get the nicknames of the users in.
+    group_id: Option<db::GroupID>,
+}
+
+pub async fn users(query: UsersQuery, session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let requester_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    let user_ids = match query.ids.split(',').map(|id| id.parse()).collect::<Result<Vec<db::UserID>, _>>() {
+        Ok(ids) => ids,
+        Err(_) => return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST))
+    };
+    if user_ids.len() > MAX_USERS_PER_REQUEST {
+        return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+    }
+
+    // Nicknames are only visible to the members of the group.
+    if let Some(group_id) = query.group_id {
+        if !db::group_member(pool.clone(), requester_id, group_id).await? {
+            return Ok(Box::new(warp::http::StatusCode::FORBIDDEN));
+        }
+    }
+
+    // Users that don't exist or don't share a group with the requester are
+    // left out. The system user stands in for deleted accounts.
+    let mut users = db::users_by_ids(pool, requester_id, &user_ids, query.group_id).await?
+        .into_iter()
+        .map(|user| (user.user_id, user))
+        .collect::<HashMap<_, _>>();
+    if user_ids.contains(&db::SYSTEM_USER_ID) {
+        users.insert(db::SYSTEM_USER_ID, db::User::deleted(db::SYSTEM_USER_ID));
+    }
+
+    Ok(Box::new(cache_private(warp::reply::json(&users))))
+}
+
+pub async fn whoami(session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user = match db::session_user(pool, &session_id).await? {
+        Some(user) => user,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+    // The response depends on the session cookie so it must not be cached.
+    Ok(Box::new(cache_none(warp::reply::json(&user))))
+}
+
+pub async fn recent_connections(session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+    let connections = db::recent_connections(pool, user_id).await?;
+    Ok(Box::new(cache_none(warp::reply::json(&connections))))
+}
+
+/// List the sessions of the user that haven't expired.
+pub async fn sessions(session_id: db::SessionID, pool: Pool)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+    let sessions = db::user_sessions(pool, user_id, &session_id).await?;
+    Ok(Box::new(cache_none(warp::reply::json(&sessions))))
+}
+
+/// Sign out of one of the sessions of the user. Connections made with the
+/// session are closed.
+pub async fn revoke_session(id: String, session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
+    -> Result<impl warp::Reply, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
+    };
+
+    match db::delete_session(pool, user_id, &id).await? {
+        Some(revoked) => {
+            socket_ctx.kick_session(&revoked).await;
+            Ok(warp::http::StatusCode::NO_CONTENT)
+        }
+        None => Ok(warp::http::StatusCode::NOT_FOUND)
+    }
 }
 
 #[derive(Deserialize)]
@@ -21,7 +152,7 @@ pub struct RenameUserRequest {
 }
 
 pub const RENAME_USER_LIMIT: u64 =
-    ("{'name':'','picture':''}".len() + db::MAX_USER_NAME_LENGTH + db::MAX_URL_LENGTH) as u64;
+    ("{'name':'','picture':''}".len() + 4 * db::MAX_USER_NAME_LENGTH + 4 * db::MAX_URL_LENGTH) as u64;
 
 pub async fn rename_user(session_id: db::SessionID, request: RenameUserRequest, pool: Pool, socket_ctx: socket::Context)
     -> Result<Box<dyn warp::Reply>, warp::Rejection>
@@ -43,12 +174,54 @@ pub async fn rename_user(session_id: db::SessionID, request: RenameUserRequest,
         return Ok(Box::new("name_exists"));
     }
 
-    let groups = db::user_group_ids(pool, user_id).await?;
-    socket_ctx.rename_user(groups, user_id, &request.name, &request.picture).await;
+    let groups = db::user_display_names(pool, user_id).await?;
+    socket_ctx.rename_user(groups, user_id, &request.picture).await;
 
     return Ok(Box::new(warp::http::StatusCode::NO_CONTENT))
 }
 
+#[derive(Deserialize)]
+pub struct SetNicknameRequest {
+    nickname: Option<String>,
+}
+
+pub const SET_NICKNAME_LIMIT: u64 =
+    ("{'nickname':''}".len() + 4 * db::MAX_USER_NAME_LENGTH) as u64;
+
+/// Set or remove the nickname of the user in a group.
+pub async fn set_nickname(group_id: db::GroupID, session_id: db::SessionID, request: SetNicknameRequest, pool: Pool, socket_ctx: socket::Context)
+    -> Result<Box<dyn warp::Reply>, warp::Rejection>
+{
+    let user_id = match db::session_user_id(pool.clone(), &session_id).await? {
+        Some(id) => id,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if let Some(nickname) = &request.nickname {
+        if !db::valid_nickname(nickname) {
+            return Ok(Box::new("nickname_invalid"));
+        }
+    }
+
+    let user = match db::user_by_id(pool.clone(), user_id, None).await? {
+        Some(user) => user,
+        None => return Ok(Box::new(warp::http::StatusCode::UNAUTHORIZED))
+    };
+
+    if !db::group_member(pool.clone(), user_id, group_id).await? {
+        return Ok(Box::new(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    if !db::set_nickname(pool, group_id, user_id, request.nickname.as_ref()).await? {
+        return Ok(Box::new("nickname_taken"));
+    }
+
+    let name = request.nickname.unwrap_or(user.name);
+    socket_ctx.rename_user(vec![(group_id, name)], user_id, &user.picture).await;
+
+    Ok(Box::new(warp::http::StatusCode::NO_CONTENT))
+}
+
 pub async fn delete_user(session_id: db::SessionID, pool: Pool, socket_ctx: socket::Context)
     -> Result<impl warp::Reply, warp::Rejection>
 {
@@ -59,7 +232,7 @@ pub async fn delete_user(session_id: db::SessionID, pool: Pool, socket_ctx: sock
 
     let groups = db::user_group_ids(pool.clone(), user_id).await?;
     db::delete_user(pool, user_id).await?;
-    socket_ctx.kick_user(user_id).await;
+    socket_ctx.kick_user(user_id, socket::CloseReason::Kick).await;
     socket_ctx.delete_user(groups, user_id).await;
 
     Ok(warp::http::StatusCode::NO_CONTENT)
@@ -73,10 +246,227 @@ pub async fn leave_group(group_id: db::GroupID, session_id: db::SessionID, pool:
         None => return Ok(warp::http::StatusCode::UNAUTHORIZED)
     };
 
-    db::leave_group(pool.clone(), user_id, group_id).await?;
-    db::anonymize_messages(pool, user_id, group_id).await?;
+    match db::leave_group(pool.clone(), user_id, group_id).await? {
+        db::LeaveResult::Left => {},
+        db::LeaveResult::NotMember => return Ok(warp::http::StatusCode::NOT_FOUND),
+        db::LeaveResult::SoleOwner => return Ok(warp::http::StatusCode::CONFLICT),
+    }
+    db::anonymize_messages(pool.clone(), user_id, group_id).await?;
     socket_ctx.kick_user_from_group(user_id, group_id).await;
-    socket_ctx.delete_user(vec![group_id], user_id).await;
+    socket_ctx.remove_member(group_id, user_id).await;
+    db::log_audit(
+        pool, group_id, user_id, db::AuditAction::MemberRemove, Some(db::AuditTarget::User(user_id)), None
+    ).await;
 
     Ok(warp::http::StatusCode::NO_CONTENT)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config;
+    use crate::filters;
+    use crate::socket;
+    use crate::database as db;
+    use crate::database::testing;
+    use crate::utils::generate_random_base64url;
+    use deadpool_postgres::Pool;
+    use bytes::Bytes;
+    use warp::http::StatusCode;
+    use std::time::Duration;
+
+    #[tokio::test]
+    #[ignore]
+    async fn whoami() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let session_id = testing::session(&pool, user).await;
+
+        let response = warp::test::request()
+            .path("/api/whoami")
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::whoami(pool))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        let json: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(json["user_id"], user.0);
+        assert!(json["name"].is_string());
+        assert!(json["picture"].is_string());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn whoami_expired_session() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let session_id = testing::session(&pool, user).await;
+        let idle = Duration::from_secs(config::get().session_idle_timeout_secs + 60);
+        testing::idle_session(&pool, &session_id, idle).await;
+
+        let response = warp::test::request()
+            .path("/api/whoami")
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::whoami(pool))
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    async fn rename(pool: &Pool, socket_ctx: &socket::Context, session_id: &db::SessionID, name: &str)
+        -> warp::http::Response<Bytes>
+    {
+        warp::test::request()
+            .method("PUT")
+            .path("/api/user")
+            .header("cookie", format!("session_id={}", session_id))
+            .json(&serde_json::json!({ "name": name, "picture": "" }))
+            .reply(&filters::rename_user(pool.clone(), socket_ctx.clone()))
+            .await
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn multi_byte_name() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let friend = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user).await;
+        testing::join(&pool, friend, group_id, "member").await;
+        let session_id = testing::session(&pool, user).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+        let mut conn = socket_ctx.connect(friend, group_id).await;
+        conn.received();
+
+        // Every character takes four bytes.
+        let suffix = "🙂".repeat(db::MAX_USER_NAME_LENGTH - 16);
+        let name = format!("{}{}", generate_random_base64url(16), suffix);
+        let response = rename(&pool, &socket_ctx, &session_id, &name).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let renamed = conn.received_type("user_renamed");
+        assert_eq!(renamed.len(), 1);
+        assert_eq!(renamed[0]["user_id"], user.0);
+        assert_eq!(renamed[0]["name"], name);
+
+        let name = format!("{}🙂", name);
+        let response = rename(&pool, &socket_ctx, &session_id, &name).await;
+        assert_eq!(response.body(), "name_invalid");
+        let response = rename(&pool, &socket_ctx, &session_id, "").await;
+        assert_eq!(response.body(), "name_invalid");
+        assert!(conn.received().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn profiles_are_private() {
+        let pool = testing::pool();
+        let requester = testing::user(&pool).await;
+        let friend = testing::user(&pool).await;
+        let stranger = testing::user(&pool).await;
+        let group_id = testing::group(&pool, requester).await;
+        testing::join(&pool, friend, group_id, "member").await;
+        testing::group(&pool, stranger).await;
+        let session_id = testing::session(&pool, requester).await;
+        let filter = filters::user(pool.clone());
+
+        for (user_id, status) in [
+            (friend, StatusCode::OK),
+            (stranger, StatusCode::FORBIDDEN),
+            (db::UserID(i32::MAX), StatusCode::NOT_FOUND),
+        ].iter() {
+            let response = warp::test::request()
+                .path(&format!("/api/user/{}", user_id))
+                .header("cookie", format!("session_id={}", session_id))
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), *status);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn batch_leaves_out_strangers() {
+        let pool = testing::pool();
+        let requester = testing::user(&pool).await;
+        let friend = testing::user(&pool).await;
+        let stranger = testing::user(&pool).await;
+        let group_id = testing::group(&pool, requester).await;
+        testing::join(&pool, friend, group_id, "member").await;
+        let session_id = testing::session(&pool, requester).await;
+
+        let response = warp::test::request()
+            .path(&format!("/api/users?ids={},{}", friend, stranger))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::users(pool))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let users: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let users = users.as_object().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[&friend.to_string()]["user_id"], friend.0);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn deleted_author_placeholder() {
+        let pool = testing::pool();
+        let requester = testing::user(&pool).await;
+        let friend = testing::user(&pool).await;
+        let group_id = testing::group(&pool, requester).await;
+        testing::join(&pool, friend, group_id, "member").await;
+        let session_id = testing::session(&pool, requester).await;
+
+        let response = warp::test::request()
+            .path(&format!("/api/user/{}", db::SYSTEM_USER_ID))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::user(pool.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let user: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(user, serde_json::json!({
+            "user_id": db::SYSTEM_USER_ID,
+            "name": db::DELETED_USER_NAME,
+            "picture": "",
+        }));
+
+        let response = warp::test::request()
+            .path(&format!("/api/users?ids={},{}", db::SYSTEM_USER_ID, friend))
+            .header("cookie", format!("session_id={}", session_id))
+            .reply(&filters::users(pool))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let users: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let users = users.as_object().unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[&db::SYSTEM_USER_ID.to_string()]["name"], db::DELETED_USER_NAME);
+        assert_eq!(users[&friend.to_string()]["user_id"], friend.0);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn revoked_session_is_kicked() {
+        let pool = testing::pool();
+        let user = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user).await;
+        let current = testing::session(&pool, user).await;
+        let other = testing::session(&pool, user).await;
+        let socket_ctx = socket::Context::new(pool.clone(), reqwest::Client::new());
+        let mut conn = socket_ctx.connect_with_session(user, group_id, other.clone()).await;
+        conn.received();
+
+        let sessions = db::user_sessions(pool.clone(), user, &current).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        let id = &sessions.iter().find(|session| !session.current).unwrap().id;
+        let response = warp::test::request()
+            .method("DELETE")
+            .path(&format!("/api/user/session/{}", id))
+            .header("cookie", format!("session_id={}", current))
+            .reply(&filters::revoke_session(pool.clone(), socket_ctx.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let closed = conn.received_type("close");
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0]["code"], socket::CloseReason::SessionExpired.code());
+        assert_eq!(db::session_user_id(pool.clone(), &other).await.unwrap(), None);
+        assert_eq!(db::user_sessions(pool, user, &current).await.unwrap().len(), 1);
+    }
+}
@@ -4,23 +4,50 @@ mod error;
 mod database;
 mod utils;
 mod socket;
+mod config;
+mod tasks;
 
+use log::info;
 use warp::Filter;
-use deadpool_postgres::{Pool, Manager};
+use std::time::Duration;
+use deadpool::managed::{PoolConfig, Timeouts};
+use deadpool_postgres::{Pool, Manager, ManagerConfig, RecyclingMethod};
 use deadpool_postgres::tokio_postgres::{Config, NoTls};
 
 // Why are strings not fixed size?
 // let _a: &[u8; 5] = b"hello";
 // let _b: &str = "hello";
 
-fn create_pool() -> Pool {
+fn create_pool(settings: &config::Config) -> Pool {
     let mut config = Config::new();
     config.host("localhost");
     config.user("postgres");
     config.dbname("chat");
 
-    let manager = Manager::new(config, NoTls);
-    Pool::new(manager, 16)
+    let manager_config = ManagerConfig {
+        recycling_method: match settings.db_recycling_method {
+            config::RecyclingMethod::Fast => RecyclingMethod::Fast,
+            config::RecyclingMethod::Verified => RecyclingMethod::Verified,
+        }
+    };
+    let pool_config = PoolConfig {
+        max_size: settings.db_pool_max_size,
+        timeouts: Timeouts {
+            wait: Some(Duration::from_millis(settings.db_pool_timeout_ms)),
+            create: Some(Duration::from_millis(settings.db_connect_timeout_ms)),
+            recycle: Some(Duration::from_millis(settings.db_connect_timeout_ms)),
+        },
+    };
+    info!(
+        "Database pool: max size {}, wait timeout {}ms, connect timeout {}ms, {:?} recycling",
+        settings.db_pool_max_size,
+        settings.db_pool_timeout_ms,
+        settings.db_connect_timeout_ms,
+        settings.db_recycling_method,
+    );
+
+    let manager = Manager::from_config(config, NoTls, manager_config);
+    Pool::from_config(manager, pool_config)
 }
 
 async fn print_message_count(pool: &Pool) {
@@ -38,29 +65,89 @@ async fn print_message_count(pool: &Pool) {
 
 #[tokio::main]
 async fn main() {
-    let pool = create_pool();
+    // Records from the log crate are forwarded to tracing so that they pick up
+    // the fields of the current span.
+    tracing_subscriber::fmt::init();
+    config::init();
+
+    let pool = create_pool(config::get());
     print_message_count(&pool).await;
-    let socket_ctx = crate::socket::Context::new(pool.clone());
-    let client = reqwest::Client::new();
+    // Redirects aren't followed so that the address of a link preview can't
+    // change after it has been checked.
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let socket_ctx = crate::socket::Context::new(pool.clone(), client.clone());
     let cert_cache = handlers::CertificateCache::default();
+    let auth_limiter = utils::RateLimiter::new(config::get().auth_rate_limit, Duration::from_secs(60));
+    tokio::spawn(tasks::refresh_certs(client.clone(), cert_cache.clone()));
+    tokio::spawn(tasks::purge_messages(pool.clone()));
+    tokio::spawn(tasks::sweep_sessions(pool.clone()));
+    if config::get().stats_interval_secs != 0 {
+        tokio::spawn(tasks::log_stats(socket_ctx.clone()));
+    }
 
-    pretty_env_logger::init();
-
-    let routes = filters::root(pool.clone())
-        .or(filters::login())
-        .or(filters::logout(pool.clone(), socket_ctx.clone()))
-        .or(filters::channel(pool.clone()))
-        .or(filters::invite(pool.clone()))
-        .or(filters::create_group(pool.clone()))
+    // The JSON API can be called from other origins if the CORS policy
+    // allows it.
+    let api = filters::create_group(pool.clone())
+        .or(filters::user_groups(pool.clone()))
+        .or(filters::update_group(pool.clone(), socket_ctx.clone()))
         .or(filters::delete_group(pool.clone(), socket_ctx.clone()))
+        .or(filters::group_settings(pool.clone(), socket_ctx.clone()))
+        .or(filters::update_group_settings(pool.clone(), socket_ctx.clone()))
+        .or(filters::update_word_filter(pool.clone(), socket_ctx.clone()))
+        .or(filters::transfer_ownership(pool.clone(), socket_ctx.clone()))
+        .or(filters::import_members(pool.clone(), socket_ctx.clone()))
         .or(filters::create_invite(pool.clone()))
         .or(filters::leave_group(pool.clone(), socket_ctx.clone()))
+        .or(filters::recent_connections(pool.clone()))
+        .or(filters::sessions(pool.clone()))
+        .or(filters::revoke_session(pool.clone(), socket_ctx.clone()))
         .or(filters::user(pool.clone()))
+        .or(filters::users(pool.clone()))
+        .or(filters::whoami(pool.clone()))
         .or(filters::rename_user(pool.clone(), socket_ctx.clone()))
+        .or(filters::set_nickname(pool.clone(), socket_ctx.clone()))
         .or(filters::delete_user(pool.clone(), socket_ctx.clone()))
+        .or(filters::pinned_messages(pool.clone()))
+        .or(filters::message(pool.clone()))
+        .or(filters::group_activity(pool.clone()))
+        .or(filters::post_message(pool.clone(), socket_ctx.clone()))
+        .or(filters::create_attachment(pool.clone()))
+        .or(filters::create_token(pool.clone()))
+        .or(filters::list_tokens(pool.clone()))
+        .or(filters::delete_token(pool.clone()))
+        .or(filters::report_message(pool.clone()))
+        .or(filters::list_reports(pool.clone()))
+        .or(filters::audit_log(pool.clone()))
+        .or(filters::group_emoji(pool.clone()))
+        .or(filters::add_emoji(pool.clone()))
+        .or(filters::remove_emoji(pool.clone()))
+        .or(filters::drafts(pool.clone()))
+        .or(filters::set_draft(pool.clone()))
+        .or(filters::recent_channels(pool.clone()))
+        .or(filters::channel_info(pool.clone()))
+        .or(filters::export_channel(pool.clone()))
+        .or(filters::mute_channel(pool.clone()))
+        .or(filters::unmute_channel(pool.clone()))
+        .or(filters::capabilities())
+        .or(filters::list_connections(pool.clone(), socket_ctx.clone()))
+        .or(filters::disconnect_connection(pool.clone(), socket_ctx.clone()));
+    let api = match filters::cors(config::get()) {
+        Some(cors) => api.with(cors).map(|reply| Box::new(reply) as Box<dyn warp::Reply>).boxed(),
+        None => api.map(|reply| Box::new(reply) as Box<dyn warp::Reply>).boxed(),
+    };
+
+    let routes = filters::root(pool.clone())
+        .or(filters::login())
+        .or(filters::logout(pool.clone(), socket_ctx.clone()))
+        .or(filters::channel(pool.clone()))
+        .or(filters::invite(pool.clone(), socket_ctx.clone()))
+        .or(api)
         .or(filters::socket(socket_ctx))
-        .or(filters::auth_success(pool.clone(), client, cert_cache))
-        .or(filters::auth_fail())
+        .or(filters::auth_success(pool.clone(), client, cert_cache, auth_limiter.clone()))
+        .or(filters::auth_fail(auth_limiter))
         .or(filters::favicon())
         .or(filters::js())
         .or(filters::css())
@@ -73,3 +160,27 @@ async fn main() {
         .run(([0, 0, 0, 0], 443))
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool_postgres::PoolError;
+
+    #[tokio::test]
+    #[ignore]
+    async fn contended_pool_times_out() {
+        let mut settings = config::Config::default();
+        settings.db_pool_max_size = 1;
+        settings.db_pool_timeout_ms = 50;
+        let pool = create_pool(&settings);
+
+        let _held = pool.get().await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_secs(5), pool.get()).await;
+        match waiting {
+            Ok(Err(PoolError::Timeout(_))) => {}
+            Ok(Err(e)) => panic!("expected a pool timeout, got {}", e),
+            Ok(Ok(_)) => panic!("expected a pool timeout, got a connection"),
+            Err(_) => panic!("waiting for a connection hung"),
+        }
+    }
+}
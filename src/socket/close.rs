@@ -0,0 +1,107 @@
+use warp::ws::Message;
+
+/// The reason that the server closed a connection. The codes and reasons are
+/// part of the protocol so the client must be kept in sync with them.
+///
+/// Codes in the 4000-4999 range are reserved for applications.
+#[derive(Clone, Copy)]
+pub enum CloseReason {
+    /// The client asked to be disconnected.
+    Normal,
+    /// The client sent too many malformed messages.
+    ProtocolError,
+    /// The client isn't reading messages as fast as they're being sent.
+    TooSlow,
+    /// The connection was closed by the server or an administrator.
+    Kick,
+    /// The user is no longer a member of the group.
+    RemovedFromGroup,
+    /// The user connected again and only one connection is allowed.
+    Replaced,
+    /// The session of the user is no longer valid.
+    SessionExpired,
+    /// The client hasn't sent anything for too long.
+    Idle,
+}
+
+impl CloseReason {
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::Normal => 1000,
+            CloseReason::ProtocolError => 1002,
+            CloseReason::TooSlow => 1008,
+            CloseReason::Kick => 4000,
+            CloseReason::RemovedFromGroup => 4001,
+            CloseReason::Replaced => 4009,
+            CloseReason::Idle => 4010,
+            CloseReason::SessionExpired => 4401,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseReason::Normal => "normal",
+            CloseReason::ProtocolError => "protocol error",
+            CloseReason::TooSlow => "too slow",
+            CloseReason::Kick => "kick",
+            CloseReason::RemovedFromGroup => "removed from group",
+            CloseReason::Replaced => "replaced by newer session",
+            CloseReason::SessionExpired => "session expired",
+            CloseReason::Idle => "idle",
+        }
+    }
+
+    pub fn message(self) -> Message {
+        Message::close_with(self.code(), self.reason())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [CloseReason; 8] = [
+        CloseReason::Normal,
+        CloseReason::ProtocolError,
+        CloseReason::TooSlow,
+        CloseReason::Kick,
+        CloseReason::RemovedFromGroup,
+        CloseReason::Replaced,
+        CloseReason::SessionExpired,
+        CloseReason::Idle,
+    ];
+
+    #[test]
+    fn codes_are_unique() {
+        for (i, a) in ALL.iter().enumerate() {
+            for b in ALL[i + 1..].iter() {
+                assert_ne!(a.code(), b.code(), "{} and {}", a.reason(), b.reason());
+            }
+        }
+    }
+
+    #[test]
+    fn application_codes_are_in_range() {
+        for reason in ALL.iter() {
+            let code = reason.code();
+            assert!(
+                code == 1000 || code == 1002 || code == 1008 || (4000..5000).contains(&code),
+                "{} has code {}", reason.reason(), code
+            );
+        }
+    }
+
+    #[test]
+    fn client_codes() {
+        // The client doesn't reconnect after these
+        assert_eq!(CloseReason::Replaced.code(), 4009);
+        assert_eq!(CloseReason::Idle.code(), 4010);
+    }
+
+    #[test]
+    fn message_has_code_and_reason() {
+        let message = CloseReason::Idle.message();
+        assert!(message.is_close());
+        assert_eq!(message.close_frame(), Some((4010, "idle")));
+    }
+}
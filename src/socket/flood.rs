@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq)]
+pub enum Flood {
+    /// The message can be sent.
+    Allowed,
+    /// This message pushed the channel over the limit. The channel is now
+    /// being throttled.
+    Tripped,
+    /// The channel is being throttled.
+    Throttled,
+}
+
+/// Tracks the rate of messages being sent to a channel by everyone.
+pub struct ChannelRate {
+    window_start: Instant,
+    count: u32,
+    throttled_until: Instant,
+}
+
+impl ChannelRate {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            count: 0,
+            throttled_until: now,
+        }
+    }
+
+    /// Record a message being sent to the channel.
+    ///
+    /// If more than limit messages are sent within a second, the channel is
+    /// throttled for the given duration.
+    pub fn record(&mut self, now: Instant, limit: u32, throttle: Duration) -> Flood {
+        if now < self.throttled_until {
+            return Flood::Throttled;
+        }
+
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.count = 0;
+        }
+
+        self.count += 1;
+
+        if self.count > limit {
+            self.throttled_until = now + throttle;
+            self.count = 0;
+            Flood::Tripped
+        } else {
+            Flood::Allowed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: u32 = 3;
+    const THROTTLE: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn trips_after_limit() {
+        let start = Instant::now();
+        let mut rate = ChannelRate::new(start);
+        for _ in 0..LIMIT {
+            assert_eq!(rate.record(start, LIMIT, THROTTLE), Flood::Allowed);
+        }
+        assert_eq!(rate.record(start, LIMIT, THROTTLE), Flood::Tripped);
+        assert_eq!(rate.record(start + THROTTLE / 2, LIMIT, THROTTLE), Flood::Throttled);
+    }
+
+    #[test]
+    fn throttle_ends() {
+        let start = Instant::now();
+        let mut rate = ChannelRate::new(start);
+        for _ in 0..=LIMIT {
+            rate.record(start, LIMIT, THROTTLE);
+        }
+        let later = start + THROTTLE;
+        for _ in 0..LIMIT {
+            assert_eq!(rate.record(later, LIMIT, THROTTLE), Flood::Allowed);
+        }
+        assert_eq!(rate.record(later, LIMIT, THROTTLE), Flood::Tripped);
+    }
+
+    #[test]
+    fn count_resets_each_second() {
+        let start = Instant::now();
+        let mut rate = ChannelRate::new(start);
+        for second in 0..5 {
+            let now = start + Duration::from_secs(second);
+            for _ in 0..LIMIT {
+                assert_eq!(rate.record(now, LIMIT, THROTTLE), Flood::Allowed);
+            }
+        }
+    }
+}
@@ -1,24 +1,92 @@
-use log::error;
+use log::{debug, error, warn};
 use warp::ws::Message;
 use std::time::SystemTime;
 use crate::database as db;
+use crate::utils::{as_timestamp, parse_mentions, expand_shortcodes, apply_word_filter};
 use serde::{Serialize, Deserialize};
-use deadpool_postgres::{Pool, PoolError};
-use super::upgrade::{ConnID, Sender, Group, Groups, UserGroups};
+use crate::error::Error;
+use deadpool_postgres::Pool;
+use deadpool_postgres::tokio_postgres::Row;
+use crate::config;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::time::{Duration, Instant};
+use super::flood::{Flood, ChannelRate};
+use super::upgrade::{ConnID, Sender, Group, Groups, UserGroups, load_channels};
+use super::close::CloseReason;
+use super::preview::LinkPreviews;
+use super::settings::SettingsCache;
+use tracing_futures::Instrument;
 
 #[derive(Deserialize)]
 #[serde(tag="type")]
 #[serde(rename_all="snake_case")]
 enum ClientMessage {
-    CreateMessage { content: String, channel_id: db::ChannelID },
+    CreateMessage {
+        content: String,
+        channel_id: db::ChannelID,
+        #[serde(default)]
+        attachments: Vec<db::AttachmentID>,
+        #[serde(default)]
+        reply_to: Option<db::MessageID>,
+    },
+    Forward { message_id: db::MessageID, channel_id: db::ChannelID },
     RequestRecentMessages { channel_id: db::ChannelID },
     RequestOldMessages { channel_id: db::ChannelID, message_id: db::MessageID },
-    CreateChannel { name: String },
+    RequestMessagesAround { channel_id: db::ChannelID, message_id: db::MessageID, radius: u32 },
+    RequestLatestMessages { per_channel: u32 },
+    RequestThread { channel_id: db::ChannelID, message_id: db::MessageID },
+    CreateChannel {
+        name: String,
+        #[serde(default)]
+        private: bool,
+    },
     RequestChannels,
     DeleteChannel { channel_id: db::ChannelID },
     RenameChannel { channel_id: db::ChannelID, name: String },
+    ReorderChannels { channel_ids: Vec<db::ChannelID> },
     RequestUsers,
     RenameGroup { name: String, picture: String },
+    SetJoinMessages { enabled: bool },
+    PinMessage { channel_id: db::ChannelID, message_id: db::MessageID },
+    UnpinMessage { channel_id: db::ChannelID, message_id: db::MessageID },
+    MarkRead { channel_id: db::ChannelID, message_id: db::MessageID },
+    SetSlowMode { channel_id: db::ChannelID, seconds: i32 },
+    EditMessage { channel_id: db::ChannelID, message_id: db::MessageID, content: String, version: i32 },
+    DeleteMessage { channel_id: db::ChannelID, message_id: db::MessageID },
+    SetStatus { status: UserStatus },
+    AddChannelMember { channel_id: db::ChannelID, user_id: db::UserID },
+    StartTyping { channel_id: db::ChannelID },
+    StopTyping { channel_id: db::ChannelID },
+    Disconnect,
+    #[serde(other)]
+    Unknown,
+}
+
+impl ClientMessage {
+    /// The channel that the message refers to, if any.
+    fn channel_id(&self) -> Option<db::ChannelID> {
+        match self {
+            ClientMessage::CreateMessage { channel_id, .. }
+            | ClientMessage::Forward { channel_id, .. }
+            | ClientMessage::RequestRecentMessages { channel_id }
+            | ClientMessage::RequestOldMessages { channel_id, .. }
+            | ClientMessage::RequestMessagesAround { channel_id, .. }
+            | ClientMessage::RequestThread { channel_id, .. }
+            | ClientMessage::DeleteChannel { channel_id }
+            | ClientMessage::RenameChannel { channel_id, .. }
+            | ClientMessage::PinMessage { channel_id, .. }
+            | ClientMessage::UnpinMessage { channel_id, .. }
+            | ClientMessage::MarkRead { channel_id, .. }
+            | ClientMessage::SetSlowMode { channel_id, .. }
+            | ClientMessage::EditMessage { channel_id, .. }
+            | ClientMessage::DeleteMessage { channel_id, .. }
+            | ClientMessage::AddChannelMember { channel_id, .. }
+            | ClientMessage::StartTyping { channel_id }
+            | ClientMessage::StopTyping { channel_id } => Some(*channel_id),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -28,6 +96,12 @@ struct RecentMessage {
     author: db::UserID,
     content: String,
     channel_id: db::ChannelID,
+    version: i32,
+    attachments: Vec<db::Attachment>,
+    reply_to: Option<db::MessageID>,
+    system: bool,
+    forwarded_from: Option<db::MessageID>,
+    seq: u64,
 }
 
 #[derive(Serialize)]
@@ -36,12 +110,21 @@ struct GenericRecentMessage {
     timestamp: u64,
     author: db::UserID,
     content: String,
+    version: i32,
+    attachments: Vec<db::Attachment>,
+    deleted: bool,
+    reply_to: Option<db::MessageID>,
+    system: bool,
+    forwarded_from: Option<db::MessageID>,
+    reactions: Vec<db::ReactionSummary>,
+    link_preview: Option<db::LinkPreview>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all="snake_case")]
 enum UserStatus {
     Online,
+    Away,
     Offline,
 }
 
@@ -58,10 +141,20 @@ struct User {
 enum ErrorCategory {
     Application,
     Request,
+    Protocol,
     ChannelCreate,
     ChannelRename,
     ChannelDelete,
+    ChannelReorder,
     GroupRename,
+    JoinMessagesSet,
+    MessagePin,
+    MessageUnpin,
+    MessageEdit,
+    MessageDelete,
+    SlowModeSet,
+    StatusSet,
+    ChannelMemberAdd,
 }
 
 use ErrorCategory::*;
@@ -70,6 +163,8 @@ use ErrorCategory::*;
 #[serde(rename_all="snake_case")]
 enum ErrorCode {
     Json,
+    InvalidJson,
+    UnknownType,
     Database,
     ChannelIdInvalid,
     MessageInvalid,
@@ -77,6 +172,15 @@ enum ErrorCode {
     NameExists,
     LoneChannel,
     PictureInvalid,
+    AttachmentInvalid,
+    ChannelThrottled,
+    SlowModeInvalid,
+    MessageIdInvalid,
+    NotModerator,
+    NotAuthor,
+    PinLimit,
+    StatusInvalid,
+    UserIdInvalid,
 }
 
 use ErrorCode::*;
@@ -85,77 +189,297 @@ use ErrorCode::*;
 #[serde(tag="type")]
 #[serde(rename_all="snake_case")]
 enum ServerMessage<'a> {
-    Error { category: ErrorCategory, code: ErrorCode },
-    MessageReceipt { message_id: db::MessageID, timestamp: u64, channel_id: db::ChannelID },
-    RecentMessage(RecentMessage),
-    RecentMessageList { channel_id: db::ChannelID, messages: Vec<GenericRecentMessage> },
-    OldMessageList { channel_id: db::ChannelID, messages: Vec<GenericRecentMessage> },
-    ChannelCreated { channel_id: db::ChannelID, name: &'a String },
-    ChannelList { channels: &'a Vec<db::Channel> },
+    Error {
+        category: ErrorCategory,
+        code: ErrorCode,
+        #[serde(skip_serializing_if="Option::is_none")]
+        message: Option<String>,
+    },
+    // The receipt and the broadcast of a new message are the same message so
+    // that every connection agrees on it.
+    MessageReceipt(&'a RecentMessage),
+    RecentMessage(&'a RecentMessage),
+    RecentMessageList { channel_id: db::ChannelID, messages: Vec<GenericRecentMessage>, has_more: bool, next_before: Option<db::MessageID> },
+    OldMessageList { channel_id: db::ChannelID, messages: Vec<GenericRecentMessage>, has_more: bool, next_before: Option<db::MessageID> },
+    MessagesAround { channel_id: db::ChannelID, message_id: db::MessageID, messages: Vec<GenericRecentMessage> },
+    LatestMessages { channels: HashMap<db::ChannelID, Vec<GenericRecentMessage>> },
+    Thread { channel_id: db::ChannelID, message_id: db::MessageID, messages: Vec<GenericRecentMessage> },
+    ChannelCreated { channel_id: db::ChannelID, name: &'a String, private: bool, position: i32 },
+    ChannelsReordered { channel_ids: Vec<db::ChannelID> },
+    ChannelList { channels: Vec<&'a db::Channel> },
     ChannelDeleted { channel_id: db::ChannelID },
-    ChannelRenamed { channel_id: db::ChannelID, name: &'a String },
+    ChannelRenamed { channel_id: db::ChannelID, name: &'a String, seq: u64 },
     UserList { users: Vec<User> },
     UserStatusChanged { user_id: db::UserID, status: UserStatus },
     UserRenamed { user_id: db::UserID, name: &'a String, picture: &'a String },
     UserDeleted { user_id: db::UserID },
-    GroupRenamed { group_id: db::GroupID, name: String, picture: String },
+    MemberAdded { user_id: db::UserID, name: &'a String, picture: &'a String },
+    MemberRemoved { user_id: db::UserID },
+    GroupUpdated { group_id: db::GroupID, name: &'a String, picture: &'a String },
+    SettingsUpdated { settings: &'a db::GroupSettings },
+    OwnershipTransferred { from: db::UserID, to: db::UserID },
     GroupDeleted { group_id: db::GroupID },
+    MessagePinned { channel_id: db::ChannelID, message_id: db::MessageID, seq: u64 },
+    MessageUnpinned { channel_id: db::ChannelID, message_id: db::MessageID, seq: u64 },
+    Mention { message_id: db::MessageID, channel_id: db::ChannelID, author: db::UserID },
+    SlowMode { channel_id: db::ChannelID, seconds: u64, seq: u64 },
+    SlowModeActive { channel_id: db::ChannelID, remaining: u64 },
+    SlowModeChanged { channel_id: db::ChannelID, seconds: i32, seq: u64 },
+    MessageEdited { channel_id: db::ChannelID, message_id: db::MessageID, content: &'a String, version: i32, seq: u64 },
+    EditConflict { channel_id: db::ChannelID, message_id: db::MessageID, content: String, version: i32 },
+    MessageDeleted { channel_id: db::ChannelID, message_id: db::MessageID, seq: u64 },
+    LinkPreview { channel_id: db::ChannelID, message_id: db::MessageID, preview: &'a db::LinkPreview, seq: u64 },
+    TypingStarted { channel_id: db::ChannelID, user_id: db::UserID },
+    TypingStopped { channel_id: db::ChannelID, user_id: db::UserID },
+    ResumeToken { token: &'a String },
 }
 
-fn as_timestamp(time: SystemTime) -> u64 {
-    time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
-}
+impl ServerMessage<'_> {
+    /// Serialize the message into a frame that can be sent over a socket.
+    /// Every message sent to clients goes through here.
+    fn to_ws_message(&self) -> Message {
+        Message::text(serde_json::to_string(self).unwrap())
+    }
+
+    fn user_online(user_id: db::UserID) -> Self {
+        ServerMessage::UserStatusChanged { user_id, status: UserStatus::Online }
+    }
 
-fn send_message(ch_tx: &Sender, message: String) {
-    if ch_tx.send(Ok(Message::text(message))).is_err() {
-        // the connection handler will handle the possible error
+    fn user_offline(user_id: db::UserID) -> Self {
+        ServerMessage::UserStatusChanged { user_id, status: UserStatus::Offline }
     }
 }
 
+/// Prepare the content of a message before it's stored.
+fn expand_content(content: String, settings: &db::GroupSettings) -> String {
+    let content = if config::get().expand_shortcodes {
+        expand_shortcodes(&content)
+    } else {
+        content
+    };
+    apply_word_filter(&content, &settings.word_filter)
+}
+
 impl Group {
-    fn find_channel(&self, channel_id: db::ChannelID) -> usize {
+    /// Find a channel that a user can access.
+    ///
+    /// Returns usize::MAX if the channel doesn't exist or is private and the
+    /// user is not a member of it.
+    fn find_channel(&self, user_id: db::UserID, channel_id: db::ChannelID) -> usize {
         match self.channels.binary_search_by(|ch| ch.channel_id.cmp(&channel_id)) {
-            Ok(i) => i,
-            Err(_) => usize::MAX
+            Ok(i) if self.can_access(user_id, channel_id) => i,
+            _ => usize::MAX
+        }
+    }
+
+    fn contains_channel(&self, user_id: db::UserID, channel_id: db::ChannelID) -> bool {
+        self.find_channel(user_id, channel_id) != usize::MAX
+    }
+
+    /// Add a channel to the list, keeping it sorted by ID.
+    fn insert_channel(&mut self, channel: db::Channel) {
+        let index = match self.channels.binary_search_by(|ch| ch.channel_id.cmp(&channel.channel_id)) {
+            Ok(i) | Err(i) => i,
+        };
+        self.channels.insert(index, channel);
+    }
+
+    /// Determine whether a user can access a channel. Assumes that the channel
+    /// exists.
+    fn can_access(&self, user_id: db::UserID, channel_id: db::ChannelID) -> bool {
+        match self.channel_members.get(&channel_id) {
+            Some(members) => members.contains(&user_id),
+            None => true,
+        }
+    }
+
+    /// Check whether a user has waited long enough since their last message to
+    /// send another message to a channel in slow mode.
+    ///
+    /// Returns the number of seconds remaining or zero if the message can be
+    /// sent.
+    fn check_slow_mode(&self, user_id: db::UserID, channel_index: usize) -> u64 {
+        let channel = &self.channels[channel_index];
+        let interval = Duration::from_secs(channel.slow_mode_seconds as u64);
+        let last_messages = self.last_messages.lock().unwrap();
+
+        if let Some(last) = last_messages.get(&(user_id, channel.channel_id)) {
+            let elapsed = last.elapsed();
+            if elapsed < interval {
+                // Rounding up so that we never say zero seconds remain
+                let remaining = interval - elapsed;
+                return remaining.as_secs() + (remaining.subsec_nanos() > 0) as u64;
+            }
+        }
+
+        0
+    }
+
+    /// Record that a user sent a message to a channel in slow mode. This
+    /// starts the wait for their next message.
+    fn record_slow_mode(&self, user_id: db::UserID, channel_id: db::ChannelID) {
+        self.last_messages.lock().unwrap().insert((user_id, channel_id), Instant::now());
+    }
+
+    /// Record a message being sent to a channel and check whether the channel
+    /// is being flooded.
+    fn record_channel_message(&self, channel_id: db::ChannelID) -> Flood {
+        let config = config::get();
+        let now = Instant::now();
+        self.channel_rates.lock().unwrap()
+            .entry(channel_id)
+            .or_insert_with(|| ChannelRate::new(now))
+            .record(now, config.channel_message_rate_limit, Duration::from_secs(config.channel_throttle_secs))
+    }
+
+    /// Record that a user is typing in a channel until the deadline.
+    ///
+    /// Returns whether they weren't typing already and whether a timer needs
+    /// to be started to stop them at the deadline. There is only one timer for
+    /// each user in each channel.
+    fn start_typing(&self, user_id: db::UserID, channel_id: db::ChannelID, deadline: Instant) -> (bool, bool) {
+        match self.typing.lock().unwrap().entry((user_id, channel_id)) {
+            Entry::Occupied(mut entry) => (entry.insert(Some(deadline)).is_none(), false),
+            Entry::Vacant(entry) => {
+                entry.insert(Some(deadline));
+                (true, true)
+            }
         }
     }
 
-    fn contains_channel(&self, channel_id: db::ChannelID) -> bool {
-        self.find_channel(channel_id) != usize::MAX
+    /// Tell everyone in a channel that a user stopped typing if they were
+    /// typing.
+    fn stop_typing(&self, user_id: db::UserID, channel_id: db::ChannelID) {
+        let stopped = match self.typing.lock().unwrap().get_mut(&(user_id, channel_id)) {
+            Some(deadline) => deadline.take().is_some(),
+            None => false
+        };
+        if stopped {
+            self.send_channel(channel_id, ServerMessage::TypingStopped { channel_id, user_id });
+        }
+    }
+
+    /// Called by the timer of a user in a channel when it reaches the deadline.
+    /// Stops the user from typing if they haven't said that they're still
+    /// typing since the deadline was set.
+    ///
+    /// Returns the new deadline if they have. The timer stops otherwise.
+    pub fn expire_typing(&self, user_id: db::UserID, channel_id: db::ChannelID) -> Option<Instant> {
+        let expired = {
+            let mut typing = self.typing.lock().unwrap();
+            match typing.get(&(user_id, channel_id)).copied() {
+                Some(Some(deadline)) if deadline > Instant::now() => return Some(deadline),
+                Some(deadline) => {
+                    typing.remove(&(user_id, channel_id));
+                    deadline.is_some()
+                }
+                None => false
+            }
+        };
+        if expired && self.channels.binary_search_by(|ch| ch.channel_id.cmp(&channel_id)).is_ok() {
+            self.send_channel(channel_id, ServerMessage::TypingStopped { channel_id, user_id });
+        }
+        None
+    }
+
+    /// Stop a user from typing in every channel. This is used when the user
+    /// goes offline.
+    pub fn stop_typing_everywhere(&self, user_id: db::UserID) {
+        let channel_ids = self.typing.lock().unwrap()
+            .iter_mut()
+            .filter_map(|((id, channel_id), deadline)| {
+                if *id == user_id && deadline.take().is_some() {
+                    Some(*channel_id)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        for channel_id in channel_ids {
+            if self.channels.binary_search_by(|ch| ch.channel_id.cmp(&channel_id)).is_ok() {
+                self.send_channel(channel_id, ServerMessage::TypingStopped { channel_id, user_id });
+            }
+        }
+    }
+
+    /// Assign the next sequence number of a channel and call the given
+    /// function with it. Messages sent from within the function are queued
+    /// while the sequence is locked so every connection receives the events
+    /// of a channel in sequence order.
+    fn send_sequenced<F: FnOnce(u64)>(&self, channel_id: db::ChannelID, send: F) {
+        let mut sequences = self.channel_sequences.lock().unwrap();
+        let seq = sequences.entry(channel_id).or_insert(self.sequence_base);
+        *seq += 1;
+        send(*seq);
     }
 
     /// Send a message to all connections.
     fn send_all(&self, message: ServerMessage) {
-        let response = serde_json::to_string(&message).unwrap();
+        let response = message.to_ws_message();
         for (_, ch_tx) in self.connections.iter() {
-            send_message(ch_tx, response.clone());
+            ch_tx.send(response.clone());
+        }
+    }
+
+    /// Call a function for each connection that can access a channel.
+    fn for_each_channel_connection<F: FnMut(ConnID, &Sender)>(&self, channel_id: db::ChannelID, mut f: F) {
+        match self.channel_members.get(&channel_id) {
+            Some(members) => {
+                for user_id in members.iter() {
+                    if let Some(conn_ids) = self.online_users.get(user_id) {
+                        for conn_id in conn_ids.iter() {
+                            f(*conn_id, &self.connections[conn_id]);
+                        }
+                    }
+                }
+            }
+            None => {
+                for (&conn_id, ch_tx) in self.connections.iter() {
+                    f(conn_id, ch_tx);
+                }
+            }
         }
     }
 
-    /// Send a peer message to all connections but the current connection.
+    /// Send a message to all connections that can access a channel.
+    fn send_channel(&self, channel_id: db::ChannelID, message: ServerMessage) {
+        let response = message.to_ws_message();
+        self.for_each_channel_connection(channel_id, |_, ch_tx| {
+            ch_tx.send(response.clone());
+        });
+    }
+
+    /// Send a peer message to all connections that can access a channel but
+    /// the current connection.
     /// Send a reply message to the current connection.
-    fn send_peer_reply(&self, conn_id: ConnID, peer: ServerMessage, reply: ServerMessage) {
-        let peer_response = serde_json::to_string(&peer).unwrap();
-        let reply_response = serde_json::to_string(&reply).unwrap();
-        for (&other_conn_id, ch_tx) in self.connections.iter() {
+    fn send_peer_reply(&self, conn_id: ConnID, channel_id: db::ChannelID, peer: ServerMessage, reply: ServerMessage) {
+        let peer_response = peer.to_ws_message();
+        let reply_response = reply.to_ws_message();
+        self.for_each_channel_connection(channel_id, |other_conn_id, ch_tx| {
             if other_conn_id == conn_id {
-                send_message(ch_tx, reply_response.clone());
+                ch_tx.send(reply_response.clone());
             } else {
-                send_message(ch_tx, peer_response.clone());
+                ch_tx.send(peer_response.clone());
             }
-        }
+        });
     }
 
     /// Send a reply message to the current connection.
     fn send_reply(&self, conn_id: ConnID, message: ServerMessage) {
-        let sender = &self.connections[&conn_id];
-        send_message(sender, serde_json::to_string(&message).unwrap());
+        self.connections[&conn_id].send(message.to_ws_message());
     }
 
     /// Send a reply error to the current connection
     fn send_reply_error(&self, conn_id: ConnID, category: ErrorCategory, code: ErrorCode) {
         self.send_reply(conn_id, ServerMessage::Error {
-            category, code
+            category, code, message: None
+        });
+    }
+
+    /// Send a protocol error with a description to the current connection.
+    fn send_protocol_error(&self, conn_id: ConnID, code: ErrorCode, message: String) {
+        self.send_reply(conn_id, ServerMessage::Error {
+            category: Protocol, code, message: Some(message)
         });
     }
 
@@ -167,11 +491,11 @@ impl Group {
     }
 
     pub fn send_user_online(&self, user_id: db::UserID) {
-        self.send_user_status(user_id, UserStatus::Online);
+        self.send_all(ServerMessage::user_online(user_id));
     }
 
     pub fn send_user_offline(&self, user_id: db::UserID) {
-        self.send_user_status(user_id, UserStatus::Offline);
+        self.send_all(ServerMessage::user_offline(user_id));
     }
 
     pub fn send_user_renamed(&self, user_id: db::UserID, name: &String, picture: &String) {
@@ -182,27 +506,118 @@ impl Group {
         })
     }
 
-    pub fn kick_user(&self, user_id: db::UserID) {
-        let message = Message::close_with(4000u16, "kick");
+    /// Send a close frame to all connections of a user. Nothing else is sent
+    /// to them while they finish closing.
+    pub fn kick_user(&self, user_id: db::UserID, reason: CloseReason) {
         for conn_id in self.online_users[&user_id].iter() {
-            if self.connections[conn_id].send(Ok(message.clone())).is_err() {}
+            self.connections[conn_id].close(reason);
         }
     }
 
+    /// Send a close frame to a single connection. Nothing else is sent to it
+    /// while it finishes closing.
+    pub fn kick_connection(&self, conn_id: ConnID, reason: CloseReason) {
+        self.connections[&conn_id].close(reason);
+    }
+
+    /// Tell all connections of a user that a group has a new name or picture.
+    /// The group might not be this one.
+    pub fn send_group_updated(&self, user_id: db::UserID, group_id: db::GroupID, name: &String, picture: &String) {
+        self.send_user(user_id, ServerMessage::GroupUpdated { group_id, name, picture });
+    }
+
     pub fn send_delete_group(&self, user_id: db::UserID, group_id: db::GroupID) {
-        let message = serde_json::to_string(&ServerMessage::GroupDeleted {
-            group_id
-        }).unwrap();
+        let message = ServerMessage::GroupDeleted { group_id }.to_ws_message();
         for conn_id in self.online_users[&user_id].iter() {
-            send_message(&self.connections[conn_id], message.clone());
+            self.connections[conn_id].send(message.clone());
+        }
+    }
+
+    /// Send a message to all connections of a user.
+    /// Does nothing if the user is not online.
+    fn send_user(&self, user_id: db::UserID, message: ServerMessage) {
+        if let Some(conn_ids) = self.online_users.get(&user_id) {
+            let response = message.to_ws_message();
+            for conn_id in conn_ids.iter() {
+                self.connections[conn_id].send(response.clone());
+            }
+        }
+    }
+
+    /// Send a new message to all connections. This is used for messages that
+    /// didn't come from a socket.
+    pub fn send_recent_message(&self, message_id: db::MessageID, timestamp: u64, author: db::UserID, content: String, channel_id: db::ChannelID, system: bool) {
+        self.send_sequenced(channel_id, |seq| {
+            self.send_channel(channel_id, ServerMessage::RecentMessage(&RecentMessage {
+                message_id,
+                timestamp,
+                author,
+                content,
+                channel_id,
+                version: 0,
+                attachments: Vec::new(),
+                reply_to: None,
+                system,
+                forwarded_from: None,
+                seq,
+            }));
+        });
+    }
+
+    /// Send the preview of a link in a message to all connections that can
+    /// access the channel.
+    pub fn send_link_preview(&self, channel_id: db::ChannelID, message_id: db::MessageID, preview: &db::LinkPreview) {
+        // The channel might have been deleted while the preview was fetched.
+        if self.channels.binary_search_by(|ch| ch.channel_id.cmp(&channel_id)).is_err() {
+            return;
         }
+        self.send_sequenced(channel_id, |seq| {
+            self.send_channel(channel_id, ServerMessage::LinkPreview {
+                channel_id,
+                message_id,
+                preview,
+                seq,
+            });
+        });
+    }
+
+    pub fn send_resume_token(&self, conn_id: ConnID, token: &String) {
+        self.send_reply(conn_id, ServerMessage::ResumeToken { token });
     }
 
     pub fn send_delete_user(&self, user_id: db::UserID) {
         self.send_all(ServerMessage::UserDeleted { user_id });
     }
+
+    pub fn send_settings_updated(&self, settings: &db::GroupSettings) {
+        self.send_all(ServerMessage::SettingsUpdated { settings });
+    }
+
+    pub fn send_ownership_transferred(&self, from: db::UserID, to: db::UserID) {
+        self.send_all(ServerMessage::OwnershipTransferred { from, to });
+    }
+
+    pub fn send_member_added(&self, user: &db::User) {
+        self.send_all(ServerMessage::MemberAdded {
+            user_id: user.user_id,
+            name: &user.name,
+            picture: &user.picture,
+        });
+    }
+
+    pub fn send_member_removed(&self, user_id: db::UserID) {
+        self.send_all(ServerMessage::MemberRemoved { user_id });
+    }
 }
 
+/// The number of malformed messages that a connection can send before it is
+/// closed.
+const MAX_PROTOCOL_ERRORS: u32 = 5;
+
+/// The minimum time between checks of the database for channels that aren't
+/// in the cached list of a group.
+const CHANNEL_REFRESH_BACKOFF: Duration = Duration::from_secs(10);
+
 pub struct MessageContext<'a> {
     pub user_id: db::UserID,
     pub group_id: db::GroupID,
@@ -210,10 +625,18 @@ pub struct MessageContext<'a> {
     pub groups: &'a Groups,
     pub user_groups: &'a UserGroups,
     pub pool: &'a Pool,
+    pub previews: &'a LinkPreviews,
+    pub settings: &'a SettingsCache,
+    pub protocol_errors: u32,
 }
 
 impl<'a> MessageContext<'a> {
-    pub async fn handle(&self, message: Message) {
+    pub async fn handle(&mut self, message: Message) {
+        if message.is_binary() {
+            self.protocol_error(InvalidJson, "binary messages are not supported".to_owned()).await;
+            return;
+        }
+
         let message = match message.to_str() {
             Ok(m) => m,
             Err(_) => return,
@@ -222,22 +645,38 @@ impl<'a> MessageContext<'a> {
         let client_message = match serde_json::from_str::<ClientMessage>(message) {
             Ok(m) => m,
             Err(e) => {
-                error!("{}", e);
-                let group = &self.groups.read().await[&self.group_id];
-                group.send_reply_error(self.conn_id, Request, Json);
+                let code = if e.is_syntax() || e.is_eof() { InvalidJson } else { Json };
+                self.protocol_error(code, e.to_string()).await;
                 return;
             }
         };
 
+        // Every channel check uses the cached channel list so it's refreshed
+        // first if it's missing the channel.
+        if let Some(channel_id) = client_message.channel_id() {
+            if let Err(e) = self.refresh_stale_channels(channel_id).await {
+                self.application_error(e).await;
+                return;
+            }
+        }
+
         let result = match client_message {
-            ClientMessage::CreateMessage { content, channel_id } =>
-                self.create_message(content, channel_id).await,
+            ClientMessage::CreateMessage { content, channel_id, attachments, reply_to } =>
+                self.create_message(content, channel_id, attachments, reply_to, None).await,
+            ClientMessage::Forward { message_id, channel_id } =>
+                self.forward_message(message_id, channel_id).await,
             ClientMessage::RequestRecentMessages { channel_id } =>
                 self.request_recent_messages(channel_id).await,
             ClientMessage::RequestOldMessages { channel_id, message_id } =>
                 self.request_old_messages(channel_id, message_id).await,
-            ClientMessage::CreateChannel { name } =>
-                self.create_channel(name).await,
+            ClientMessage::RequestMessagesAround { channel_id, message_id, radius } =>
+                self.request_messages_around(channel_id, message_id, radius).await,
+            ClientMessage::RequestLatestMessages { per_channel } =>
+                self.request_latest_messages(per_channel).await,
+            ClientMessage::RequestThread { channel_id, message_id } =>
+                self.request_thread(channel_id, message_id).await,
+            ClientMessage::CreateChannel { name, private } =>
+                self.create_channel(name, private).await,
             ClientMessage::RequestChannels =>
                 self.request_channels().await,
             ClientMessage::DeleteChannel { channel_id } =>
@@ -246,114 +685,323 @@ impl<'a> MessageContext<'a> {
                 self.request_users().await,
             ClientMessage::RenameChannel { channel_id, name } =>
                 self.rename_channel(channel_id, name).await,
+            ClientMessage::ReorderChannels { channel_ids } =>
+                self.reorder_channels(channel_ids).await,
             ClientMessage::RenameGroup { name, picture } =>
                 self.rename_group(name, picture).await,
+            ClientMessage::SetJoinMessages { enabled } =>
+                self.set_join_messages(enabled).await,
+            ClientMessage::PinMessage { channel_id, message_id } =>
+                self.pin_message(channel_id, message_id).await,
+            ClientMessage::UnpinMessage { channel_id, message_id } =>
+                self.unpin_message(channel_id, message_id).await,
+            ClientMessage::MarkRead { channel_id, message_id } =>
+                self.mark_read(channel_id, message_id).await,
+            ClientMessage::SetSlowMode { channel_id, seconds } =>
+                self.set_slow_mode(channel_id, seconds).await,
+            ClientMessage::EditMessage { channel_id, message_id, content, version } =>
+                self.edit_message(channel_id, message_id, content, version).await,
+            ClientMessage::DeleteMessage { channel_id, message_id } =>
+                self.delete_message(channel_id, message_id).await,
+            ClientMessage::SetStatus { status } =>
+                self.set_status(status).await,
+            ClientMessage::AddChannelMember { channel_id, user_id } =>
+                self.add_channel_member(channel_id, user_id).await,
+            ClientMessage::StartTyping { channel_id } =>
+                self.start_typing(channel_id).await,
+            ClientMessage::StopTyping { channel_id } =>
+                self.stop_typing(channel_id).await,
+            ClientMessage::Disconnect =>
+                self.disconnect().await,
+            ClientMessage::Unknown => {
+                self.protocol_error(UnknownType, "unknown message type".to_owned()).await;
+                return;
+            }
         };
 
         if let Err(e) = result {
+            self.application_error(e).await;
+        }
+    }
+
+    async fn application_error(&self, e: Error) {
+        error!("{}", e);
+        let group = &self.groups.read().await[&self.group_id];
+        group.send_reply_error(self.conn_id, Application, Database);
+    }
+
+    async fn refresh_stale_channels(&self, channel_id: db::ChannelID) -> Result<(), Error> {
+        refresh_stale_channels(self.groups, self.pool, self.group_id, self.user_id, channel_id).await
+    }
+
+    /// Send the status of every member to a new connection. From then on, the
+    /// connection is kept up to date with user_status_changed events.
+    pub async fn send_presence_snapshot(&self) {
+        if let Err(e) = self.request_users().await {
             error!("{}", e);
             let group = &self.groups.read().await[&self.group_id];
             group.send_reply_error(self.conn_id, Application, Database);
         }
     }
 
-    async fn create_message(&self, content: String, channel_id: db::ChannelID)
-        -> Result<(), PoolError>
-    {
-        let time = SystemTime::now();
-        let timestamp = as_timestamp(time);
+    /// Tell the client that it sent a malformed message. The connection is
+    /// closed if it keeps sending them.
+    async fn protocol_error(&mut self, code: ErrorCode, message: String) {
+        debug!("Protocol error: {}", message);
+        self.protocol_errors += 1;
+        let group = &self.groups.read().await[&self.group_id];
+        if self.protocol_errors > MAX_PROTOCOL_ERRORS {
+            group.kick_connection(self.conn_id, CloseReason::ProtocolError);
+        } else {
+            group.send_protocol_error(self.conn_id, code, message);
+        }
+    }
 
-        let groups_guard = self.groups.read().await;
-        let group = &groups_guard[&self.group_id];
+    /// Convert rows from the message history queries into messages, including
+    /// the attachments, reactions and link previews of each message.
+    async fn messages_from_rows(&self, rows: Vec<Row>) -> Result<Vec<GenericRecentMessage>, Error> {
+        let mut messages = rows.iter()
+            .map(|row| GenericRecentMessage {
+                message_id: row.get(0),
+                timestamp: as_timestamp(row.get(1)),
+                author: row.get(2),
+                content: row.get(3),
+                version: row.get(4),
+                attachments: Vec::new(),
+                deleted: row.get(5),
+                reply_to: row.get(6),
+                system: row.get(7),
+                forwarded_from: row.get(8),
+                reactions: Vec::new(),
+                link_preview: None,
+            })
+            .collect::<Vec<_>>();
+
+        let message_ids = messages.iter().map(|m| m.message_id).collect::<Vec<_>>();
+        let attachments = db::message_attachments(self.pool.clone(), &message_ids).await?;
+
+        // Messages are sorted by ID
+        for attachment in attachments {
+            if let Ok(i) = message_ids.binary_search(&attachment.message_id) {
+                if !messages[i].deleted {
+                    messages[i].attachments.push(attachment);
+                }
+            }
+        }
 
-        if !db::valid_message(&content) {
-            group.send_reply_error(self.conn_id, Request, MessageInvalid);
-            return Ok(());
+        let reactions = db::message_reactions_for(self.pool.clone(), &message_ids, self.user_id).await?;
+        let reactions = db::group_reactions(&message_ids, reactions);
+        for (message, reactions) in messages.iter_mut().zip(reactions) {
+            if !message.deleted {
+                message.reactions = reactions;
+            }
         }
 
-        if !group.contains_channel(channel_id) {
-            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
-            return Ok(());
+        let previews = db::message_link_previews(self.pool.clone(), &message_ids).await?;
+        for (message_id, preview) in previews {
+            if let Ok(i) = message_ids.binary_search(&message_id) {
+                if !messages[i].deleted {
+                    messages[i].link_preview = Some(preview);
+                }
+            }
         }
 
-        let message_id = db::create_message(self.pool.clone(), time, self.user_id, &content, channel_id).await?;
+        Ok(messages)
+    }
 
-        let peer = ServerMessage::RecentMessage(RecentMessage {
-            message_id,
-            timestamp,
+    async fn create_message(
+        &self,
+        content: String,
+        channel_id: db::ChannelID,
+        attachments: Vec<db::AttachmentID>,
+        reply_to: Option<db::MessageID>,
+        forwarded_from: Option<db::MessageID>,
+    ) -> Result<(), Error>
+    {
+        let message = NewMessage {
             author: self.user_id,
-            content,
-            channel_id,
-        });
-
-        let echo = ServerMessage::MessageReceipt {
-            message_id,
-            timestamp,
+            group_id: self.group_id,
             channel_id,
+            content,
+            attachments,
+            reply_to,
+            forwarded_from,
+        };
+        let refusal = match send_message(self.groups, self.pool, self.previews, self.settings, Some(self.conn_id), message).await? {
+            Ok(_) => return Ok(()),
+            Err(refusal) => refusal,
         };
 
-        group.send_peer_reply(self.conn_id, peer, echo);
+        let group = &self.groups.read().await[&self.group_id];
+        match refusal {
+            Refusal::SlowMode(remaining) => group.send_reply(self.conn_id, ServerMessage::SlowModeActive {
+                channel_id,
+                remaining,
+            }),
+            Refusal::MessageInvalid => group.send_reply_error(self.conn_id, Request, MessageInvalid),
+            Refusal::ChannelIdInvalid => group.send_reply_error(self.conn_id, Request, ChannelIdInvalid),
+            Refusal::ChannelThrottled => group.send_reply_error(self.conn_id, Request, ChannelThrottled),
+            Refusal::AttachmentInvalid => group.send_reply_error(self.conn_id, Request, AttachmentInvalid),
+            Refusal::MessageIdInvalid => group.send_reply_error(self.conn_id, Request, MessageIdInvalid),
+        }
 
         Ok(())
     }
 
+    /// Copy a message into a channel of this group. The original message can
+    /// be in any channel that the user can access.
+    async fn forward_message(&self, message_id: db::MessageID, channel_id: db::ChannelID)
+        -> Result<(), Error>
+    {
+        let valid = match db::message_by_id(self.pool.clone(), message_id).await? {
+            // Deleted and system messages can't be forwarded.
+            Some(row) if !row.get::<_, bool>(5) && !row.get::<_, bool>(7) => {
+                if db::channel_accessible(self.pool.clone(), self.user_id, row.get(9)).await? {
+                    Some(row.get::<_, String>(3))
+                } else {
+                    None
+                }
+            }
+            _ => None
+        };
+
+        let content = match valid {
+            Some(content) => content,
+            None => {
+                let group = &self.groups.read().await[&self.group_id];
+                group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+                return Ok(());
+            }
+        };
+
+        // The target channel and the usual posting restrictions are checked
+        // when the copy is created.
+        self.create_message(content, channel_id, Vec::new(), None, Some(message_id)).await
+    }
+
     async fn request_recent_messages(&self, channel_id: db::ChannelID)
-        -> Result<(), PoolError>
+        -> Result<(), Error>
     {
         let groups_guard = self.groups.read().await;
         let group = &groups_guard[&self.group_id];
 
-        if !group.contains_channel(channel_id) {
+        if !group.contains_channel(self.user_id, channel_id) {
             group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
             return Ok(());
         }
 
-        let rows = db::recent_messages(self.pool.clone(), channel_id).await?;
+        let page = db::recent_messages(self.pool.clone(), channel_id).await?;
+        let next_before = page.next_before();
+        let messages = self.messages_from_rows(page.rows).await?;
 
         group.send_reply(self.conn_id, ServerMessage::RecentMessageList {
             channel_id,
-            messages: rows.iter()
-                .map(|row| GenericRecentMessage {
-                    message_id: row.get(0),
-                    timestamp: as_timestamp(row.get(1)),
-                    author: row.get(2),
-                    content: row.get(3)
-                })
-                .collect()
+            messages,
+            has_more: page.has_more,
+            next_before,
         });
 
         Ok(())
     }
 
     async fn request_old_messages(&self, channel_id: db::ChannelID, message_id: db::MessageID)
-        -> Result<(), PoolError>
+        -> Result<(), Error>
     {
         let groups_guard = self.groups.read().await;
         let group = &groups_guard[&self.group_id];
 
-        if !group.contains_channel(channel_id) {
+        if !group.contains_channel(self.user_id, channel_id) {
             group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
             return Ok(());
         }
 
-        let rows = db::old_messages(self.pool.clone(), channel_id, message_id).await?;
+        let page = db::old_messages(self.pool.clone(), channel_id, message_id).await?;
+        let next_before = page.next_before();
+        let messages = self.messages_from_rows(page.rows).await?;
 
         group.send_reply(self.conn_id, ServerMessage::OldMessageList {
             channel_id,
-            messages: rows.iter()
-                .map(|row| GenericRecentMessage {
-                    message_id: row.get(0),
-                    timestamp: as_timestamp(row.get(1)),
-                    author: row.get(2),
-                    content: row.get(3)
-                })
-                .collect()
+            messages,
+            has_more: page.has_more,
+            next_before,
+        });
+
+        Ok(())
+    }
+
+    async fn request_messages_around(&self, channel_id: db::ChannelID, message_id: db::MessageID, radius: u32)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+
+        if !group.contains_channel(self.user_id, channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        let rows = db::messages_around(self.pool.clone(), channel_id, message_id, radius).await?;
+        let messages = self.messages_from_rows(rows).await?;
+
+        group.send_reply(self.conn_id, ServerMessage::MessagesAround {
+            channel_id,
+            message_id,
+            messages,
+        });
+
+        Ok(())
+    }
+
+    async fn request_latest_messages(&self, per_channel: u32) -> Result<(), Error> {
+        let rows = db::latest_messages_per_channel(self.pool.clone(), self.user_id, self.group_id, per_channel).await?;
+        let channel_ids = rows.iter().map(|row| row.get(9)).collect::<Vec<db::ChannelID>>();
+        let messages = self.messages_from_rows(rows).await?;
+
+        let mut channels = HashMap::<_, Vec<_>>::new();
+        for (channel_id, message) in channel_ids.into_iter().zip(messages) {
+            channels.entry(channel_id).or_default().push(message);
+        }
+
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+        group.send_reply(self.conn_id, ServerMessage::LatestMessages {
+            channels,
+        });
+
+        Ok(())
+    }
+
+    async fn request_thread(&self, channel_id: db::ChannelID, message_id: db::MessageID)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+
+        if !group.contains_channel(self.user_id, channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        // Replies are always in the same channel as the message they reply
+        // to so checking the root is enough.
+        if !db::message_in_channel(self.pool.clone(), channel_id, message_id).await? {
+            group.send_reply_error(self.conn_id, Request, MessageIdInvalid);
+            return Ok(());
+        }
+
+        let rows = db::thread_messages(self.pool.clone(), message_id).await?;
+        let messages = self.messages_from_rows(rows).await?;
+
+        group.send_reply(self.conn_id, ServerMessage::Thread {
+            channel_id,
+            message_id,
+            messages,
         });
 
         Ok(())
     }
 
-    async fn create_channel(&self, name: String) -> Result<(), PoolError> {
+    async fn create_channel(&self, name: String, private: bool) -> Result<(), Error> {
         let mut groups_guard = self.groups.write().await;
         let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
 
@@ -364,50 +1012,72 @@ impl<'a> MessageContext<'a> {
             return Ok(());
         }
 
-        let channel_id = match db::create_channel(self.pool.clone(), self.group_id, &name).await? {
-            Some(id) => id,
+        let (channel_id, position) = match db::create_channel(self.pool.clone(), self.group_id, &name, private).await? {
+            Some(created) => created,
             None => {
                 group.send_reply_error(self.conn_id, ChannelCreate, NameExists);
                 return Ok(());
             }
         };
 
-        group.send_all(ServerMessage::ChannelCreated {
+        // The creator of a private channel is its first member
+        if private {
+            db::add_channel_member(self.pool.clone(), channel_id, self.user_id).await?;
+            let mut members = HashSet::new();
+            members.insert(self.user_id);
+            group.channel_members.insert(channel_id, members);
+        }
+
+        group.send_channel(channel_id, ServerMessage::ChannelCreated {
             channel_id,
             name: &name,
+            private,
+            position,
         });
 
-        group.channels.push(db::Channel {
+        group.insert_channel(db::Channel {
             channel_id,
-            name
+            name,
+            slow_mode_seconds: 0,
+            private,
+            position,
         });
 
         Ok(())
     }
 
-    async fn request_channels(&self) -> Result<(), PoolError> {
+    async fn request_channels(&self) -> Result<(), Error> {
         let groups_guard = self.groups.read().await;
         let group = &groups_guard[&self.group_id];
 
+        let mut channels = group.channels.iter()
+            .filter(|ch| group.can_access(self.user_id, ch.channel_id))
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|ch| (ch.position, ch.channel_id));
+
         group.send_reply(self.conn_id, ServerMessage::ChannelList {
-            channels: &group.channels
+            channels
         });
 
         Ok(())
     }
 
-    async fn delete_channel(&self, channel_id: db::ChannelID) -> Result<(), PoolError> {
+    async fn delete_channel(&self, channel_id: db::ChannelID) -> Result<(), Error> {
         let mut groups_guard = self.groups.write().await;
         let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
 
-        if group.channels.len() == 1 {
-            group.send_reply_error(self.conn_id, ChannelDelete, LoneChannel);
+        let channel_index = group.find_channel(self.user_id, channel_id);
+        if channel_index == usize::MAX {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
             return Ok(());
         }
 
-        let channel_index = group.find_channel(channel_id);
-        if channel_index == usize::MAX {
-            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+        // Every member of the group must be able to access at least one
+        // channel.
+        if !group.channels[channel_index].private
+            && group.channels.iter().filter(|ch| !ch.private).count() == 1
+        {
+            group.send_reply_error(self.conn_id, ChannelDelete, LoneChannel);
             return Ok(());
         }
 
@@ -417,16 +1087,82 @@ impl<'a> MessageContext<'a> {
             return Ok(());
         }
 
-        group.channels.remove(channel_index);
+        self.audit(db::AuditAction::ChannelDelete, Some(db::AuditTarget::Channel(channel_id)), Some(group.channels[channel_index].name.clone()));
 
-        group.send_all(ServerMessage::ChannelDeleted {
+        group.send_channel(channel_id, ServerMessage::ChannelDeleted {
             channel_id
         });
 
+        group.channels.remove(channel_index);
+        group.channel_members.remove(&channel_id);
+
+        Ok(())
+    }
+
+    /// Change the order of the channels that the user can see. Channels that
+    /// the user can't see keep their place.
+    async fn reorder_channels(&self, channel_ids: Vec<db::ChannelID>) -> Result<(), Error> {
+        let mut groups_guard = self.groups.write().await;
+        let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
+
+        if !self.moderator().await? {
+            group.send_reply_error(self.conn_id, ChannelReorder, NotModerator);
+            return Ok(());
+        }
+
+        // group.channels is sorted by ID
+        let mut sorted_ids = channel_ids.clone();
+        sorted_ids.sort();
+        let visible_ids = group.channels.iter()
+            .map(|ch| ch.channel_id)
+            .filter(|id| group.can_access(self.user_id, *id))
+            .collect::<Vec<_>>();
+        if sorted_ids != visible_ids {
+            group.send_reply_error(self.conn_id, ChannelReorder, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        let mut current = group.channels.iter().collect::<Vec<_>>();
+        current.sort_by_key(|ch| (ch.position, ch.channel_id));
+        let mut reordered = channel_ids.iter();
+        let order = current.iter()
+            .map(|ch| if group.can_access(self.user_id, ch.channel_id) {
+                *reordered.next().unwrap()
+            } else {
+                ch.channel_id
+            })
+            .collect::<Vec<_>>();
+
+        if !db::reorder_channels(self.pool.clone(), self.group_id, &order).await? {
+            group.send_reply_error(self.conn_id, ChannelReorder, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        self.audit(db::AuditAction::ChannelReorder, None, None);
+
+        for (position, channel_id) in order.iter().enumerate() {
+            if let Ok(i) = group.channels.binary_search_by_key(channel_id, |ch| ch.channel_id) {
+                group.channels[i].position = position as i32;
+            }
+        }
+
+        // Users are only told about the channels that they can see.
+        for (user_id, conn_ids) in group.online_users.iter() {
+            let message = ServerMessage::ChannelsReordered {
+                channel_ids: order.iter()
+                    .copied()
+                    .filter(|id| group.can_access(*user_id, *id))
+                    .collect()
+            }.to_ws_message();
+            for conn_id in conn_ids.iter() {
+                group.connections[conn_id].send(message.clone());
+            }
+        }
+
         Ok(())
     }
 
-    async fn request_users(&self) -> Result<(), PoolError> {
+    async fn request_users(&self) -> Result<(), Error> {
         let groups_guard = self.groups.read().await;
         let group = &groups_guard[&self.group_id];
 
@@ -434,10 +1170,12 @@ impl<'a> MessageContext<'a> {
         let mut users = Vec::new();
 
         for user in group_users.iter() {
-            let status = if group.online_users.contains_key(&user.user_id) {
-                UserStatus::Online
-            } else {
+            let status = if !group.online_users.contains_key(&user.user_id) {
                 UserStatus::Offline
+            } else if group.away_users.contains(&user.user_id) {
+                UserStatus::Away
+            } else {
+                UserStatus::Online
             };
             users.push(User {
                 user_id: user.user_id,
@@ -454,7 +1192,7 @@ impl<'a> MessageContext<'a> {
         Ok(())
     }
 
-    async fn rename_channel(&self, channel_id: db::ChannelID, name: String) -> Result<(), PoolError> {
+    async fn rename_channel(&self, channel_id: db::ChannelID, name: String) -> Result<(), Error> {
         let mut groups_guard = self.groups.write().await;
         let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
 
@@ -465,7 +1203,7 @@ impl<'a> MessageContext<'a> {
             return Ok(());
         }
 
-        let channel_index = group.find_channel(channel_id);
+        let channel_index = group.find_channel(self.user_id, channel_id);
         if channel_index == usize::MAX {
             group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
             return Ok(());
@@ -476,9 +1214,14 @@ impl<'a> MessageContext<'a> {
             return Ok(());
         }
 
-        group.send_all(ServerMessage::ChannelRenamed {
-            channel_id,
-            name: &name,
+        self.audit(db::AuditAction::ChannelRename, Some(db::AuditTarget::Channel(channel_id)), Some(name.clone()));
+
+        group.send_sequenced(channel_id, |seq| {
+            group.send_channel(channel_id, ServerMessage::ChannelRenamed {
+                channel_id,
+                name: &name,
+                seq,
+            });
         });
 
         group.channels[channel_index].name = name;
@@ -486,7 +1229,7 @@ impl<'a> MessageContext<'a> {
         Ok(())
     }
 
-    async fn rename_group(&self, name: String, picture: String) -> Result<(), PoolError> {
+    async fn rename_group(&self, name: String, picture: String) -> Result<(), Error> {
         let groups_guard = self.groups.read().await;
         let group = &groups_guard[&self.group_id];
 
@@ -500,19 +1243,13 @@ impl<'a> MessageContext<'a> {
             return Ok(());
         }
 
-        if !db::rename_group(self.pool.clone(), self.group_id, &name, &picture).await? {
+        if !db::update_group(self.pool.clone(), self.group_id, &name, &picture).await? {
             group.send_reply_error(self.conn_id, GroupRename, NameExists);
             return Ok(());
         }
 
         let users = db::group_user_ids(self.pool.clone(), self.group_id).await?;
 
-        let message = serde_json::to_string(&ServerMessage::GroupRenamed {
-            group_id: self.group_id,
-            name,
-            picture
-        }).unwrap();
-
         // Need to send this to all users that are members of the group.
         // They may be logged into another group.
 
@@ -521,14 +1258,1194 @@ impl<'a> MessageContext<'a> {
         for user_id in users.iter() {
             if let Some(groups) = user_groups_guard.get(&user_id) {
                 for group_id in groups.iter() {
-                    let group = &groups_guard[group_id];
-                    for conn_id in group.online_users[&user_id].iter() {
-                        send_message(&group.connections[conn_id], message.clone());
-                    }
+                    groups_guard[group_id].send_group_updated(*user_id, self.group_id, &name, &picture);
                 }
             }
         }
 
         Ok(())
     }
+
+    async fn set_join_messages(&self, enabled: bool) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+
+        if !self.moderator().await? {
+            group.send_reply_error(self.conn_id, JoinMessagesSet, NotModerator);
+            return Ok(());
+        }
+
+        db::set_group_join_messages(self.pool.clone(), self.group_id, enabled).await?;
+        self.settings.invalidate(self.group_id);
+
+        let settings = self.settings.get(self.pool, self.group_id).await?;
+        group.send_settings_updated(&settings);
+        self.audit(db::AuditAction::SettingsUpdate, None, None);
+
+        Ok(())
+    }
+
+    async fn moderator(&self) -> Result<bool, Error> {
+        Ok(match db::member_role(self.pool.clone(), self.user_id, self.group_id).await? {
+            Some(role) => role >= db::Role::Moderator,
+            None => false
+        })
+    }
+
+    /// Record an action in the audit log without holding up the request.
+    fn audit(&self, action: db::AuditAction, target: Option<db::AuditTarget>, detail: Option<String>) {
+        tokio::spawn(db::log_audit(
+            self.pool.clone(), self.group_id, self.user_id, action, target, detail
+        ));
+    }
+
+    async fn pin_message(&self, channel_id: db::ChannelID, message_id: db::MessageID)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+
+        if !group.contains_channel(self.user_id, channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        if !self.moderator().await? {
+            group.send_reply_error(self.conn_id, MessagePin, NotModerator);
+            return Ok(());
+        }
+
+        match db::pin_message(self.pool.clone(), channel_id, message_id).await? {
+            db::PinResult::Pinned => {},
+            db::PinResult::Invalid => {
+                group.send_reply_error(self.conn_id, MessagePin, MessageIdInvalid);
+                return Ok(());
+            }
+            db::PinResult::LimitReached => {
+                group.send_reply_error(self.conn_id, MessagePin, PinLimit);
+                return Ok(());
+            }
+        }
+
+        self.audit(db::AuditAction::MessagePin, Some(db::AuditTarget::Message(message_id)), None);
+
+        group.send_sequenced(channel_id, |seq| {
+            group.send_channel(channel_id, ServerMessage::MessagePinned {
+                channel_id,
+                message_id,
+                seq,
+            });
+        });
+
+        Ok(())
+    }
+
+    async fn unpin_message(&self, channel_id: db::ChannelID, message_id: db::MessageID)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+
+        if !group.contains_channel(self.user_id, channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        if !self.moderator().await? {
+            group.send_reply_error(self.conn_id, MessageUnpin, NotModerator);
+            return Ok(());
+        }
+
+        if !db::unpin_message(self.pool.clone(), channel_id, message_id).await? {
+            group.send_reply_error(self.conn_id, MessageUnpin, MessageIdInvalid);
+            return Ok(());
+        }
+
+        self.audit(db::AuditAction::MessageUnpin, Some(db::AuditTarget::Message(message_id)), None);
+
+        group.send_sequenced(channel_id, |seq| {
+            group.send_channel(channel_id, ServerMessage::MessageUnpinned {
+                channel_id,
+                message_id,
+                seq,
+            });
+        });
+
+        Ok(())
+    }
+
+    async fn mark_read(&self, channel_id: db::ChannelID, message_id: db::MessageID)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+
+        if !group.contains_channel(self.user_id, channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        db::mark_read(self.pool.clone(), self.user_id, channel_id, message_id).await?;
+
+        Ok(())
+    }
+
+    async fn edit_message(&self, channel_id: db::ChannelID, message_id: db::MessageID, content: String, version: i32)
+        -> Result<(), Error>
+    {
+        let settings = self.settings.get(self.pool, self.group_id).await?;
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+
+        let content = expand_content(content, &settings);
+        if !db::valid_message(&content) {
+            group.send_reply_error(self.conn_id, Request, MessageInvalid);
+            return Ok(());
+        }
+
+        if !group.contains_channel(self.user_id, channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        // Authors can edit their own messages. Moderators can edit anything.
+        let author = db::message_author(self.pool.clone(), channel_id, message_id).await?;
+        if author != Some(self.user_id) && !self.moderator().await? {
+            group.send_reply_error(self.conn_id, MessageEdit, NotAuthor);
+            return Ok(());
+        }
+
+        match db::edit_message(self.pool.clone(), channel_id, message_id, &content, version).await? {
+            db::EditResult::Edited(version) => {
+                if author != Some(self.user_id) {
+                    self.audit(db::AuditAction::MessageEdit, Some(db::AuditTarget::Message(message_id)), None);
+                }
+                group.send_sequenced(channel_id, |seq| {
+                    group.send_channel(channel_id, ServerMessage::MessageEdited {
+                        channel_id,
+                        message_id,
+                        content: &content,
+                        version,
+                        seq,
+                    });
+                });
+            }
+            db::EditResult::Conflict { version, content } => {
+                group.send_reply(self.conn_id, ServerMessage::EditConflict {
+                    channel_id,
+                    message_id,
+                    content,
+                    version,
+                });
+            }
+            db::EditResult::NotFound => {
+                group.send_reply_error(self.conn_id, MessageEdit, MessageIdInvalid);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn delete_message(&self, channel_id: db::ChannelID, message_id: db::MessageID)
+        -> Result<(), Error>
+    {
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+
+        if !group.contains_channel(self.user_id, channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        // Authors can delete their own messages. Moderators can delete
+        // anything.
+        let author = db::message_author(self.pool.clone(), channel_id, message_id).await?;
+        if author != Some(self.user_id) && !self.moderator().await? {
+            group.send_reply_error(self.conn_id, MessageDelete, NotAuthor);
+            return Ok(());
+        }
+
+        if !db::delete_message(self.pool.clone(), channel_id, message_id).await? {
+            group.send_reply_error(self.conn_id, MessageDelete, MessageIdInvalid);
+            return Ok(());
+        }
+
+        if author != Some(self.user_id) {
+            self.audit(db::AuditAction::MessageDelete, Some(db::AuditTarget::Message(message_id)), None);
+        }
+
+        group.send_sequenced(channel_id, |seq| {
+            group.send_channel(channel_id, ServerMessage::MessageDeleted {
+                channel_id,
+                message_id,
+                seq,
+            });
+        });
+
+        Ok(())
+    }
+
+    async fn set_slow_mode(&self, channel_id: db::ChannelID, seconds: i32) -> Result<(), Error> {
+        let mut groups_guard = self.groups.write().await;
+        let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
+
+        let channel_index = group.find_channel(self.user_id, channel_id);
+        if channel_index == usize::MAX {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        if seconds < 0 || seconds > db::MAX_SLOW_MODE_SECONDS {
+            group.send_reply_error(self.conn_id, SlowModeSet, SlowModeInvalid);
+            return Ok(());
+        }
+
+        if !self.moderator().await? {
+            group.send_reply_error(self.conn_id, SlowModeSet, NotModerator);
+            return Ok(());
+        }
+
+        if !db::set_slow_mode(self.pool.clone(), channel_id, seconds).await? {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        group.channels[channel_index].slow_mode_seconds = seconds;
+        self.audit(db::AuditAction::SlowModeSet, Some(db::AuditTarget::Channel(channel_id)), Some(seconds.to_string()));
+
+        group.send_sequenced(channel_id, |seq| {
+            group.send_channel(channel_id, ServerMessage::SlowModeChanged {
+                channel_id,
+                seconds,
+                seq,
+            });
+        });
+
+        Ok(())
+    }
+
+    /// Clients say that the user is typing every few seconds while they are.
+    /// If they stop saying so, they're assumed to have stopped after a while.
+    async fn start_typing(&self, channel_id: db::ChannelID) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+
+        if !group.contains_channel(self.user_id, channel_id) {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(config::get().typing_timeout_secs);
+        let (started, needs_timer) = group.start_typing(self.user_id, channel_id, deadline);
+        if started {
+            group.send_channel(channel_id, ServerMessage::TypingStarted {
+                channel_id,
+                user_id: self.user_id,
+            });
+        }
+
+        if needs_timer {
+            let groups = self.groups.clone();
+            let group_id = self.group_id;
+            let user_id = self.user_id;
+            tokio::spawn(async move {
+                let mut deadline = deadline;
+                loop {
+                    tokio::time::delay_until(deadline.into()).await;
+                    let next = match groups.read().await.get(&group_id) {
+                        Some(group) => group.expire_typing(user_id, channel_id),
+                        None => None
+                    };
+                    match next {
+                        Some(next) => deadline = next,
+                        None => break
+                    }
+                }
+            }.in_current_span());
+        }
+
+        Ok(())
+    }
+
+    async fn stop_typing(&self, channel_id: db::ChannelID) -> Result<(), Error> {
+        let groups_guard = self.groups.read().await;
+        let group = &groups_guard[&self.group_id];
+        group.stop_typing(self.user_id, channel_id);
+        Ok(())
+    }
+
+    /// Close the connection at the request of the client. A connection that is
+    /// closed this way can't be resumed.
+    async fn disconnect(&self) -> Result<(), Error> {
+        debug!("Socket disconnect requested: {}", self.conn_id);
+        let groups_guard = self.groups.read().await;
+        groups_guard[&self.group_id].kick_connection(self.conn_id, CloseReason::Normal);
+        Ok(())
+    }
+
+    async fn set_status(&self, status: UserStatus) -> Result<(), Error> {
+        let mut groups_guard = self.groups.write().await;
+        let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
+
+        // Users go offline by disconnecting
+        let changed = match status {
+            UserStatus::Online => group.away_users.remove(&self.user_id),
+            UserStatus::Away => group.away_users.insert(self.user_id),
+            UserStatus::Offline => {
+                group.send_reply_error(self.conn_id, StatusSet, StatusInvalid);
+                return Ok(());
+            }
+        };
+
+        if changed {
+            group.send_user_status(self.user_id, status);
+        }
+
+        Ok(())
+    }
+
+    async fn add_channel_member(&self, channel_id: db::ChannelID, user_id: db::UserID) -> Result<(), Error> {
+        let mut groups_guard = self.groups.write().await;
+        let group = &mut groups_guard.get_mut(&self.group_id).unwrap();
+
+        let channel_index = group.find_channel(self.user_id, channel_id);
+        if channel_index == usize::MAX || !group.channels[channel_index].private {
+            group.send_reply_error(self.conn_id, Request, ChannelIdInvalid);
+            return Ok(());
+        }
+
+        if !self.moderator().await? {
+            group.send_reply_error(self.conn_id, ChannelMemberAdd, NotModerator);
+            return Ok(());
+        }
+
+        if !db::group_member(self.pool.clone(), user_id, self.group_id).await? {
+            group.send_reply_error(self.conn_id, ChannelMemberAdd, UserIdInvalid);
+            return Ok(());
+        }
+
+        if !db::add_channel_member(self.pool.clone(), channel_id, user_id).await? {
+            return Ok(());
+        }
+
+        group.channel_members.get_mut(&channel_id).unwrap().insert(user_id);
+        self.audit(db::AuditAction::ChannelMemberAdd, Some(db::AuditTarget::Channel(channel_id)), Some(user_id.to_string()));
+
+        // To the new member, the channel has just been created
+        let channel = &group.channels[channel_index];
+        group.send_user(user_id, ServerMessage::ChannelCreated {
+            channel_id,
+            name: &channel.name,
+            private: true,
+            position: channel.position,
+        });
+
+        Ok(())
+    }
+}
+
+/// Reload the channels of the group if the user can access a channel in
+/// the database that isn't in the cached list. This shouldn't happen but
+/// if it does, the user shouldn't be locked out of the channel until
+/// everyone disconnects from the group.
+///
+/// The cached list is trusted when it has the channel because channels are
+/// only changed through sockets, which keep the list up to date. Only a
+/// miss goes to the database, and at most once every
+/// `CHANNEL_REFRESH_BACKOFF` for each group so that bogus channel IDs can't
+/// be used to flood the database.
+async fn refresh_stale_channels(
+    groups: &Groups,
+    pool: &Pool,
+    group_id: db::GroupID,
+    user_id: db::UserID,
+    channel_id: db::ChannelID,
+) -> Result<(), Error> {
+    // There is no list to refresh if nobody is connected to the group.
+    match groups.read().await.get(&group_id) {
+        Some(group) if !group.contains_channel(user_id, channel_id) => {
+            let mut last_refresh = group.last_channel_refresh.lock().unwrap();
+            if last_refresh.map_or(false, |last| last.elapsed() < CHANNEL_REFRESH_BACKOFF) {
+                return Ok(());
+            }
+            *last_refresh = Some(Instant::now());
+        },
+        _ => return Ok(()),
+    }
+
+    if !db::channel_in_group(pool.clone(), channel_id, group_id).await?
+        || !db::channel_accessible(pool.clone(), user_id, channel_id).await?
+    {
+        return Ok(());
+    }
+
+    let (channels, channel_members) = load_channels(pool.clone(), group_id).await?;
+    let mut groups_guard = groups.write().await;
+    if let Some(group) = groups_guard.get_mut(&group_id) {
+        warn!("Channel list of group {} was stale", group_id);
+        group.channels = channels;
+        group.channel_members = channel_members;
+    }
+
+    Ok(())
+}
+
+/// A message that a user wants to send to a channel.
+pub struct NewMessage {
+    pub author: db::UserID,
+    pub group_id: db::GroupID,
+    pub channel_id: db::ChannelID,
+    pub content: String,
+    pub attachments: Vec<db::AttachmentID>,
+    pub reply_to: Option<db::MessageID>,
+    pub forwarded_from: Option<db::MessageID>,
+}
+
+/// The reason that a new message wasn't sent.
+pub enum Refusal {
+    MessageInvalid,
+    ChannelIdInvalid,
+    /// The author has to wait this many seconds before sending another message
+    /// to the channel.
+    SlowMode(u64),
+    ChannelThrottled,
+    AttachmentInvalid,
+    MessageIdInvalid,
+}
+
+/// Store a new message and send it to the connections of its group. Messages
+/// from sockets and from the HTTP API both go through here so that they're
+/// subject to the same slow mode, flood control and mentions.
+///
+/// The connection that the message came from gets a receipt instead of the
+/// message. Returns the ID and timestamp of the message.
+///
+/// If nobody is connected to the group, there is nobody to flood or mention
+/// and the slow mode wait would have been forgotten along with the group, so
+/// only the checks that don't need the group are made.
+pub(super) async fn send_message(
+    groups: &Groups,
+    pool: &Pool,
+    previews: &LinkPreviews,
+    settings: &SettingsCache,
+    conn_id: Option<ConnID>,
+    message: NewMessage,
+) -> Result<Result<(db::MessageID, u64), Refusal>, Error>
+{
+    let NewMessage { author, group_id, channel_id, content, attachments, reply_to, forwarded_from } = message;
+    let time = SystemTime::now();
+    let timestamp = as_timestamp(time);
+
+    refresh_stale_channels(groups, pool, group_id, author, channel_id).await?;
+
+    let settings = settings.get(pool, group_id).await?;
+    let content = expand_content(content, &settings);
+
+    if !db::valid_message(&content) {
+        return Ok(Err(Refusal::MessageInvalid));
+    }
+
+    // Only members of the group are online in it so mentions of anyone else
+    // go nowhere. Muted channels are looked up before taking the lock so
+    // that writers aren't held up by it.
+    let mentioned = parse_mentions(&content);
+    let muted = if mentioned.is_empty() {
+        Vec::new()
+    } else {
+        db::users_muting_channel(pool.clone(), channel_id, &mentioned).await?
+    };
+
+    let groups_guard = groups.read().await;
+    let group = groups_guard.get(&group_id);
+
+    let slow_mode = match group {
+        Some(group) => {
+            let channel_index = group.find_channel(author, channel_id);
+            if channel_index == usize::MAX {
+                return Ok(Err(Refusal::ChannelIdInvalid));
+            }
+
+            // Moderators are exempt from slow mode
+            let slow_mode = group.channels[channel_index].slow_mode_seconds > 0
+                && db::member_role(pool.clone(), author, group_id).await?
+                    .map_or(true, |role| role < db::Role::Moderator);
+            if slow_mode {
+                let remaining = group.check_slow_mode(author, channel_index);
+                if remaining > 0 {
+                    return Ok(Err(Refusal::SlowMode(remaining)));
+                }
+            }
+
+            match group.record_channel_message(channel_id) {
+                Flood::Allowed => {},
+                Flood::Tripped => {
+                    group.send_sequenced(channel_id, |seq| {
+                        group.send_channel(channel_id, ServerMessage::SlowMode {
+                            channel_id,
+                            seconds: config::get().channel_throttle_secs,
+                            seq,
+                        });
+                    });
+                    // The message that tripped the limit isn't sent either.
+                    return Ok(Err(Refusal::ChannelThrottled));
+                }
+                Flood::Throttled => return Ok(Err(Refusal::ChannelThrottled)),
+            }
+
+            slow_mode
+        }
+        None => {
+            if !db::channel_in_group(pool.clone(), channel_id, group_id).await?
+                || !db::channel_accessible(pool.clone(), author, channel_id).await?
+            {
+                return Ok(Err(Refusal::ChannelIdInvalid));
+            }
+            false
+        }
+    };
+
+    if !attachments.is_empty() {
+        if attachments.len() > db::MAX_ATTACHMENTS
+            || !db::valid_uploads(pool.clone(), author, &attachments).await?
+        {
+            return Ok(Err(Refusal::AttachmentInvalid));
+        }
+    }
+
+    if let Some(reply_to) = reply_to {
+        if !db::message_in_channel(pool.clone(), channel_id, reply_to).await? {
+            return Ok(Err(Refusal::MessageIdInvalid));
+        }
+    }
+
+    let (message_id, attachments) = db::create_message(
+        pool.clone(), time, author, &content, channel_id, reply_to, forwarded_from, &attachments
+    ).await?;
+
+    if settings.link_previews {
+        previews.spawn(pool.clone(), groups.clone(), group_id, channel_id, message_id, &content);
+    }
+
+    let group = match group {
+        Some(group) => group,
+        None => return Ok(Ok((message_id, timestamp))),
+    };
+
+    // The wait only starts once the message has actually been sent.
+    if slow_mode {
+        group.record_slow_mode(author, channel_id);
+    }
+
+    // Sending a message means that the user isn't typing anymore.
+    group.stop_typing(author, channel_id);
+
+    group.send_sequenced(channel_id, |seq| {
+        let message = RecentMessage {
+            message_id,
+            timestamp,
+            author,
+            content,
+            channel_id,
+            version: 0,
+            attachments,
+            reply_to,
+            system: false,
+            forwarded_from,
+            seq,
+        };
+
+        match conn_id {
+            Some(conn_id) => group.send_peer_reply(
+                conn_id,
+                channel_id,
+                ServerMessage::RecentMessage(&message),
+                ServerMessage::MessageReceipt(&message),
+            ),
+            None => group.send_channel(channel_id, ServerMessage::RecentMessage(&message)),
+        }
+    });
+
+    for user_id in mentioned {
+        if user_id != author
+            && group.can_access(user_id, channel_id)
+            && !muted.contains(&user_id)
+        {
+            group.send_user(user_id, ServerMessage::Mention {
+                message_id,
+                channel_id,
+                author,
+            });
+        }
+    }
+
+    Ok(Ok((message_id, timestamp)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+    use crate::database::testing;
+    use crate::socket::Context;
+
+    fn to_json(message: ServerMessage) -> Value {
+        let message = message.to_ws_message();
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn type_is_tagged() {
+        assert_eq!(
+            to_json(ServerMessage::ChannelDeleted { channel_id: db::ChannelID(3) }),
+            json!({ "type": "channel_deleted", "channel_id": 3 })
+        );
+    }
+
+    #[test]
+    fn error_message_is_optional() {
+        assert_eq!(
+            to_json(ServerMessage::Error { category: Request, code: ChannelIdInvalid, message: None }),
+            json!({ "type": "error", "category": "request", "code": "channel_id_invalid" })
+        );
+        assert_eq!(
+            to_json(ServerMessage::Error { category: Protocol, code: InvalidJson, message: Some("oops".to_owned()) }),
+            json!({ "type": "error", "category": "protocol", "code": "invalid_json", "message": "oops" })
+        );
+    }
+
+    #[test]
+    fn user_status() {
+        assert_eq!(
+            to_json(ServerMessage::user_online(db::UserID(5))),
+            json!({ "type": "user_status_changed", "user_id": 5, "status": "online" })
+        );
+        assert_eq!(
+            to_json(ServerMessage::user_offline(db::UserID(5))),
+            json!({ "type": "user_status_changed", "user_id": 5, "status": "offline" })
+        );
+    }
+
+    #[test]
+    fn borrowed_fields() {
+        let name = "general".to_owned();
+        assert_eq!(
+            to_json(ServerMessage::ChannelRenamed { channel_id: db::ChannelID(1), name: &name, seq: 9 }),
+            json!({ "type": "channel_renamed", "channel_id": 1, "name": "general", "seq": 9 })
+        );
+    }
+
+    #[test]
+    fn sequence_increases_under_concurrent_sends() {
+        let group = Arc::new(Group::with_channels(Vec::new(), Default::default()));
+        let channel_id = db::ChannelID(1);
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let threads = (0..4).map(|_| {
+            let group = group.clone();
+            let sent = sent.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    group.send_sequenced(channel_id, |seq| sent.lock().unwrap().push(seq));
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Events are sent in the order of their sequence numbers.
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 400);
+        assert!(sent.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    }
+
+    #[test]
+    fn sequence_increases_when_group_is_created_again() {
+        let channel_id = db::ChannelID(1);
+        let mut last = 0;
+        Group::with_channels(Vec::new(), Default::default()).send_sequenced(channel_id, |seq| last = seq);
+        std::thread::sleep(Duration::from_millis(1));
+        let mut first = 0;
+        Group::with_channels(Vec::new(), Default::default()).send_sequenced(channel_id, |seq| first = seq);
+        assert!(first > last);
+    }
+
+    fn context(pool: &Pool) -> Context {
+        Context::new(pool.clone(), reqwest::Client::new())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn stale_edit_is_rejected() {
+        let pool = testing::pool();
+        let author = testing::user(&pool).await;
+        let group_id = testing::group(&pool, author).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let message_id = testing::message(&pool, author, channel_id, "first").await;
+        let ctx = context(&pool);
+        let mut conn = ctx.connect(author, group_id).await;
+
+        let edit = |content: &str| json!({
+            "type": "edit_message",
+            "channel_id": channel_id,
+            "message_id": message_id,
+            "content": content,
+            "version": 0,
+        });
+        conn.send(&ctx, edit("second")).await;
+        let edited = conn.received_type("message_edited");
+        assert_eq!(edited.len(), 1);
+        assert_eq!(edited[0]["version"], 1);
+
+        // This edit was made without seeing the one above.
+        conn.send(&ctx, edit("third")).await;
+        let conflict = conn.received();
+        assert_eq!(conflict.len(), 1);
+        assert_eq!(conflict[0]["type"], "edit_conflict");
+        assert_eq!(conflict[0]["version"], 1);
+        assert_eq!(conflict[0]["content"], "second");
+    }
+
+    fn create_message(channel_id: db::ChannelID) -> Value {
+        json!({ "type": "create_message", "channel_id": channel_id, "content": "hello" })
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn slow_mode_rejects_early_message() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        db::set_slow_mode(pool.clone(), channel_id, 60).await.unwrap();
+        let ctx = context(&pool);
+        let mut conn = ctx.connect(member, group_id).await;
+
+        conn.send(&ctx, create_message(channel_id)).await;
+        assert_eq!(conn.received_type("message_receipt").len(), 1);
+
+        conn.send(&ctx, create_message(channel_id)).await;
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["type"], "slow_mode_active");
+        assert_eq!(received[0]["channel_id"], channel_id.0);
+        let remaining = received[0]["remaining"].as_u64().unwrap();
+        assert!(remaining > 0 && remaining <= 60);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn moderators_are_exempt_from_slow_mode() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        db::set_slow_mode(pool.clone(), channel_id, 60).await.unwrap();
+        let ctx = context(&pool);
+        let mut conn = ctx.connect(owner, group_id).await;
+
+        conn.send(&ctx, create_message(channel_id)).await;
+        conn.send(&ctx, create_message(channel_id)).await;
+        assert_eq!(conn.received_type("message_receipt").len(), 2);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn away_status_is_broadcast() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let ctx = context(&pool);
+        let mut watcher = ctx.connect(owner, group_id).await;
+        let mut conn = ctx.connect(member, group_id).await;
+        watcher.received();
+
+        conn.send(&ctx, json!({ "type": "set_status", "status": "away" })).await;
+        assert_eq!(
+            watcher.received_type("user_status_changed"),
+            vec![json!({ "type": "user_status_changed", "user_id": member, "status": "away" })]
+        );
+
+        // Being away is part of the snapshot that new connections get.
+        let mut late = ctx.connect(owner, group_id).await;
+        late.send(&ctx, json!({ "type": "request_users" })).await;
+        let users = late.received_type("user_list");
+        let user = users[0]["users"].as_array().unwrap().iter()
+            .find(|user| user["user_id"] == member.0)
+            .unwrap();
+        assert_eq!(user["status"], "away");
+
+        conn.send(&ctx, json!({ "type": "set_status", "status": "online" })).await;
+        assert_eq!(
+            watcher.received_type("user_status_changed"),
+            vec![json!({ "type": "user_status_changed", "user_id": member, "status": "online" })]
+        );
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn every_receiver_gets_the_same_message() {
+        let pool = testing::pool();
+        let author = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, author).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let ctx = context(&pool);
+        let mut sender = ctx.connect(author, group_id).await;
+        let mut other_device = ctx.connect(author, group_id).await;
+        let mut first = ctx.connect(member, group_id).await;
+        let mut second = ctx.connect(member, group_id).await;
+
+        sender.send(&ctx, create_message(channel_id)).await;
+        let receipt = sender.received_type("message_receipt").remove(0);
+        assert!(receipt["message_id"].as_i64().unwrap() > 0);
+        for conn in [&mut other_device, &mut first, &mut second].iter_mut() {
+            let mut message = conn.received_type("recent_message").remove(0);
+            message["type"] = receipt["type"].clone();
+            assert_eq!(message, receipt);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn malformed_messages_are_protocol_errors() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let ctx = context(&pool);
+        let mut conn = ctx.connect(owner, group_id).await;
+
+        conn.send_message(&ctx, Message::text("{ not json")).await;
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["category"], "protocol");
+        assert_eq!(received[0]["code"], "invalid_json");
+        assert!(received[0]["message"].is_string());
+
+        conn.send(&ctx, json!({ "type": "launch_rockets" })).await;
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["category"], "protocol");
+        assert_eq!(received[0]["code"], "unknown_type");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn repeated_protocol_errors_close_the_connection() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let ctx = context(&pool);
+        let mut conn = ctx.connect(owner, group_id).await;
+
+        for _ in 0..MAX_PROTOCOL_ERRORS {
+            conn.send_message(&ctx, Message::text("{ not json")).await;
+        }
+        assert!(conn.received_type("close").is_empty());
+        conn.send_message(&ctx, Message::text("{ not json")).await;
+        assert_eq!(conn.received_type("close")[0]["code"], 1002);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn private_channel_is_hidden_from_non_members() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, true).await;
+        let ctx = context(&pool);
+        let mut conn = ctx.connect(member, group_id).await;
+
+        conn.send(&ctx, json!({ "type": "request_channels" })).await;
+        let list = conn.received_type("channel_list");
+        let channels = list[0]["channels"].as_array().unwrap();
+        assert!(!channels.is_empty());
+        assert!(channels.iter().all(|channel| channel["channel_id"] != channel_id.0));
+
+        conn.send(&ctx, create_message(channel_id)).await;
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["code"], "channel_id_invalid");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn only_moderators_pin() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let message_id = testing::message(&pool, member, channel_id, "important").await;
+        let ctx = context(&pool);
+        let mut owner_conn = ctx.connect(owner, group_id).await;
+        let mut member_conn = ctx.connect(member, group_id).await;
+        owner_conn.received();
+        member_conn.received();
+        let pin = json!({ "type": "pin_message", "channel_id": channel_id, "message_id": message_id });
+
+        member_conn.send(&ctx, pin.clone()).await;
+        let received = member_conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["category"], "message_pin");
+        assert_eq!(received[0]["code"], "not_moderator");
+        assert!(owner_conn.received().is_empty());
+
+        owner_conn.send(&ctx, pin).await;
+        let pinned = member_conn.received_type("message_pinned");
+        assert_eq!(pinned.len(), 1);
+        assert_eq!(pinned[0]["channel_id"], channel_id.0);
+        assert_eq!(pinned[0]["message_id"], message_id.0);
+        assert_eq!(owner_conn.received_type("message_pinned").len(), 1);
+
+        owner_conn.send(&ctx, json!({ "type": "unpin_message", "channel_id": channel_id, "message_id": message_id })).await;
+        assert_eq!(member_conn.received_type("message_unpinned").len(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn foreign_channel_is_rejected() {
+        let pool = testing::pool();
+        let intruder = testing::user(&pool).await;
+        let victim = testing::user(&pool).await;
+        let group_id = testing::group(&pool, intruder).await;
+        let foreign_group_id = testing::group(&pool, victim).await;
+        let foreign_channel_id = testing::channel(&pool, foreign_group_id, false).await;
+        let ctx = context(&pool);
+        let mut intruder_conn = ctx.connect(intruder, group_id).await;
+        let mut victim_conn = ctx.connect(victim, foreign_group_id).await;
+        victim_conn.received();
+
+        intruder_conn.send(&ctx, create_message(foreign_channel_id)).await;
+        let received = intruder_conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["type"], "error");
+        assert_eq!(received[0]["code"], "channel_id_invalid");
+        assert!(victim_conn.received().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn replies_must_be_in_the_same_channel() {
+        let pool = testing::pool();
+        let author = testing::user(&pool).await;
+        let group_id = testing::group(&pool, author).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let other_channel_id = testing::channel(&pool, group_id, false).await;
+        let root = testing::message(&pool, author, channel_id, "root").await;
+        let elsewhere = testing::message(&pool, author, other_channel_id, "elsewhere").await;
+        let ctx = context(&pool);
+        let mut conn = ctx.connect(author, group_id).await;
+        conn.received();
+
+        let mut reply = create_message(channel_id);
+        reply["reply_to"] = json!(root);
+        conn.send(&ctx, reply.clone()).await;
+        let receipts = conn.received_type("message_receipt");
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0]["reply_to"], root.0);
+
+        reply["reply_to"] = json!(elsewhere);
+        conn.send(&ctx, reply).await;
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["type"], "error");
+        assert_eq!(received[0]["code"], "message_id_invalid");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn added_member_is_told_about_the_channel() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, true).await;
+        db::add_channel_member(pool.clone(), channel_id, owner).await.unwrap();
+        let ctx = context(&pool);
+        let mut owner_conn = ctx.connect(owner, group_id).await;
+        let mut member_conn = ctx.connect(member, group_id).await;
+        member_conn.received();
+
+        owner_conn.send(&ctx, json!({ "type": "add_channel_member", "channel_id": channel_id, "user_id": member })).await;
+        let created = member_conn.received_type("channel_created");
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0]["channel_id"], channel_id.0);
+        assert_eq!(created[0]["private"], true);
+        assert!(created[0]["position"].is_i64());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn can_post_to_channel_created_while_connected() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let ctx = context(&pool);
+        let mut conn = ctx.connect(owner, group_id).await;
+        conn.received();
+
+        // The channel is created without going through the group's socket
+        // connections so their list of channels is stale.
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        conn.send(&ctx, create_message(channel_id)).await;
+        let receipts = conn.received_type("message_receipt");
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0]["channel_id"], channel_id.0);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn forwarded_message_references_origin() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let source_id = testing::channel(&pool, group_id, false).await;
+        let target_id = testing::channel(&pool, group_id, false).await;
+        let hidden_id = testing::channel(&pool, group_id, true).await;
+        let original = testing::message(&pool, owner, source_id, "forward me").await;
+        let hidden = testing::message(&pool, owner, hidden_id, "secret").await;
+        let ctx = context(&pool);
+        let mut forwarder = ctx.connect(member, group_id).await;
+        let mut watcher = ctx.connect(owner, group_id).await;
+        forwarder.received();
+        watcher.received();
+
+        forwarder.send(&ctx, json!({
+            "type": "forward",
+            "message_id": original,
+            "channel_id": target_id,
+        })).await;
+        let receipts = forwarder.received_type("message_receipt");
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0]["channel_id"], target_id.0);
+        assert_eq!(receipts[0]["content"], "forward me");
+        assert_eq!(receipts[0]["forwarded_from"], original.0);
+        let messages = watcher.received_type("recent_message");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["forwarded_from"], original.0);
+
+        forwarder.send(&ctx, json!({
+            "type": "forward",
+            "message_id": hidden,
+            "channel_id": target_id,
+        })).await;
+        let received = forwarder.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["type"], "error");
+        assert_eq!(received[0]["code"], "message_id_invalid");
+        assert!(watcher.received().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn wrong_group_channel_is_rejected() {
+        // The user can access both channels so only the group is wrong.
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let other_group_id = testing::group(&pool, owner).await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let other_channel_id = testing::channel(&pool, other_group_id, false).await;
+        let message_id = testing::message(&pool, owner, channel_id, "hello").await;
+        let ctx = context(&pool);
+
+        // Nobody is connected to the group so there is no channel list.
+        let sent = ctx.send_message(NewMessage {
+            author: owner,
+            group_id,
+            channel_id: other_channel_id,
+            content: "hello".to_owned(),
+            attachments: Vec::new(),
+            reply_to: None,
+            forwarded_from: None,
+        }).await.unwrap();
+        assert!(matches!(sent, Err(Refusal::ChannelIdInvalid)));
+
+        let mut conn = ctx.connect(owner, group_id).await;
+        conn.received();
+        conn.send(&ctx, create_message(other_channel_id)).await;
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["code"], "channel_id_invalid");
+
+        conn.send(&ctx, json!({
+            "type": "forward",
+            "message_id": message_id,
+            "channel_id": other_channel_id,
+        })).await;
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["code"], "channel_id_invalid");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn typing_expires() {
+        let mut config = config::Config::default();
+        config.typing_timeout_secs = 1;
+        let _config = config::set_for_test(config);
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let ctx = context(&pool);
+        let mut typist = ctx.connect(member, group_id).await;
+        let mut watcher = ctx.connect(owner, group_id).await;
+        watcher.received();
+
+        typist.send(&ctx, json!({ "type": "start_typing", "channel_id": channel_id })).await;
+        assert_eq!(watcher.received_type("typing_started").len(), 1);
+
+        // The typist never says that they stopped.
+        tokio::time::delay_for(Duration::from_millis(1500)).await;
+        let stopped = watcher.received_type("typing_stopped");
+        assert_eq!(stopped.len(), 1);
+        assert_eq!(stopped[0]["user_id"], member.0);
+        assert_eq!(stopped[0]["channel_id"], channel_id.0);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn muted_channel_has_no_mentions() {
+        let pool = testing::pool();
+        let author = testing::user(&pool).await;
+        let listener = testing::user(&pool).await;
+        let muter = testing::user(&pool).await;
+        let group_id = testing::group(&pool, author).await;
+        testing::join(&pool, listener, group_id, "member").await;
+        testing::join(&pool, muter, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        db::set_channel_mute(pool.clone(), muter, channel_id, true).await.unwrap();
+        let ctx = context(&pool);
+        let mut author_conn = ctx.connect(author, group_id).await;
+        let mut listener_conn = ctx.connect(listener, group_id).await;
+        let mut muter_conn = ctx.connect(muter, group_id).await;
+        listener_conn.received();
+        muter_conn.received();
+
+        let content = format!("hey <@{}> and <@{}>", listener, muter);
+        author_conn.send(&ctx, json!({ "type": "create_message", "channel_id": channel_id, "content": content })).await;
+
+        let mentions = listener_conn.received_type("mention");
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0]["channel_id"], channel_id.0);
+        assert_eq!(mentions[0]["author"], author.0);
+        let received = muter_conn.received();
+        assert_eq!(received.iter().filter(|message| message["type"] == "recent_message").count(), 1);
+        assert!(received.iter().all(|message| message["type"] != "mention"));
+    }
 }
@@ -1,4 +1,14 @@
 mod handler;
 mod upgrade;
+mod flood;
+mod close;
+mod preview;
+mod settings;
+mod protocol;
+mod webhook;
 
-pub use upgrade::Context;
+pub use upgrade::{Context, ConnID, ResumeQuery};
+pub use handler::{NewMessage, Refusal};
+pub use close::CloseReason;
+#[cfg(test)]
+pub use upgrade::testing;
@@ -0,0 +1,464 @@
+use log::{debug, error};
+use crate::config;
+use crate::database as db;
+use crate::utils::find_link;
+use deadpool_postgres::Pool;
+use tracing_futures::Instrument;
+use reqwest::Url;
+use reqwest::header::{CONTENT_TYPE, HOST};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+use super::upgrade::Groups;
+
+/// Fetches previews of links posted in messages.
+#[derive(Clone)]
+pub struct LinkPreviews {
+    client: reqwest::Client,
+    last_fetch: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl LinkPreviews {
+    /// The client must not follow redirects because the target address would
+    /// not be checked. Linking to an external page that redirects to an
+    /// internal address is an easy way to reach the internal network
+    /// otherwise.
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            client,
+            last_fetch: Arc::default(),
+        }
+    }
+
+    /// Fetch a preview of the first link in a message in the background and
+    /// send it to the group when it's ready.
+    pub fn spawn(&self, pool: Pool, groups: Groups, group_id: db::GroupID, channel_id: db::ChannelID, message_id: db::MessageID, content: &str) {
+        if !config::get().link_previews {
+            return;
+        }
+
+        let url = match find_link(content).and_then(|link| Url::parse(link).ok()) {
+            Some(url) => url,
+            None => return
+        };
+
+        let previews = self.clone();
+        let timeout = Duration::from_millis(config::get().link_preview_timeout_ms);
+
+        tokio::spawn(async move {
+            let preview = match tokio::time::timeout(timeout, previews.fetch(&url)).await {
+                Ok(Ok(Some(preview))) => preview,
+                Ok(Ok(None)) => return,
+                Ok(Err(e)) => {
+                    debug!("Failed to fetch {}: {}", url, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("Timed out fetching {}", url);
+                    return;
+                }
+            };
+
+            if let Err(e) = db::create_link_preview(pool, message_id, &preview).await {
+                error!("{}", e);
+                return;
+            }
+
+            if let Some(group) = groups.read().await.get(&group_id) {
+                group.send_link_preview(channel_id, message_id, &preview);
+            }
+        }.in_current_span());
+    }
+
+    async fn fetch(&self, url: &Url) -> Result<Option<db::LinkPreview>, reqwest::Error> {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Ok(None);
+        }
+
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return Ok(None)
+        };
+
+        let addr = match public_address_of(host, url.port_or_known_default().unwrap_or(80)).await {
+            Some(addr) => addr,
+            None => {
+                debug!("Not fetching {} because it isn't public", url);
+                return Ok(None);
+            }
+        };
+
+        if !self.record_fetch(host) {
+            debug!("Not fetching {} because {} was fetched recently", url, host);
+            return Ok(None);
+        }
+
+        fetch_page(&self.client, url, addr, config::get().link_preview_max_bytes).await
+    }
+
+    /// Record a fetch from a host. Returns false if the host was fetched from
+    /// too recently.
+    fn record_fetch(&self, host: &str) -> bool {
+        let interval = Duration::from_secs(config::get().link_preview_host_interval_secs);
+        let now = Instant::now();
+        let mut last_fetch = self.last_fetch.lock().unwrap();
+
+        last_fetch.retain(|_, time| now.duration_since(*time) < interval);
+
+        match last_fetch.entry(host.to_owned()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+/// Fetch a page from an address of its host that has already been checked.
+///
+/// Plain HTTP requests are sent to that address with the original Host header
+/// so that the host can't resolve to a different address by the time the
+/// request is made. HTTPS requests are sent to the host because the
+/// certificate is checked against its name. An internal server that the host
+/// resolves to the second time can't present a certificate for that name so
+/// the request is never sent. The address is checked again anyway.
+async fn fetch_page(client: &reqwest::Client, url: &Url, addr: IpAddr, max_bytes: usize)
+    -> Result<Option<db::LinkPreview>, reqwest::Error>
+{
+    let request = if url.scheme() == "http" {
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_owned()
+        };
+        let mut pinned = url.clone();
+        if pinned.set_ip_host(addr).is_err() {
+            return Ok(None);
+        }
+        client.get(pinned).header(HOST, host)
+    } else {
+        client.get(url.clone())
+    };
+
+    let mut response = request.send().await?;
+    if url.scheme() != "http" && !response.remote_addr().map_or(false, |remote| public_address(remote.ip())) {
+        debug!("Not reading {} because it was served from an address that isn't public", url);
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+
+    let html = response.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("text/html"));
+    if !html {
+        return Ok(None);
+    }
+
+    // The metadata is in the head so we don't need the whole page.
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        let remaining = max_bytes - body.len();
+        if chunk.len() >= remaining {
+            body.extend_from_slice(&chunk[..remaining]);
+            break;
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(parse_preview(url, &String::from_utf8_lossy(&body)))
+}
+
+/// Get an address of a host if all of its addresses are on the public
+/// internet.
+async fn public_address_of(host: &str, port: u16) -> Option<IpAddr> {
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(addr) = literal.parse::<IpAddr>() {
+        return Some(addr).filter(|addr| public_address(*addr));
+    }
+
+    let addrs = tokio::net::lookup_host((host, port)).await.ok()?
+        .map(|addr| addr.ip())
+        .collect::<Vec<_>>();
+    if addrs.iter().all(|addr| public_address(*addr)) {
+        addrs.first().copied()
+    } else {
+        None
+    }
+}
+
+fn public_address(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => {
+            let octets = addr.octets();
+            !(addr.is_private()
+                || addr.is_loopback()
+                || addr.is_link_local()
+                || addr.is_broadcast()
+                || addr.is_unspecified()
+                || addr.is_documentation()
+                || octets[0] == 0
+                // Carrier-grade NAT
+                || (octets[0] == 100 && octets[1] & 0xC0 == 64))
+        }
+        IpAddr::V6(addr) => {
+            let first = addr.segments()[0];
+            !(addr.is_loopback()
+                || addr.is_unspecified()
+                // Unique local
+                || first & 0xFE00 == 0xFC00
+                // Link local
+                || first & 0xFFC0 == 0xFE80)
+                && addr.to_ipv4().map_or(true, |addr| public_address(IpAddr::V4(addr)))
+        }
+    }
+}
+
+/// Extract the OpenGraph metadata from the head of a page, falling back to
+/// the title if there isn't any.
+fn parse_preview(url: &Url, html: &str) -> Option<db::LinkPreview> {
+    // Lowercasing ASCII doesn't change the length so the indices are the same
+    // in both strings.
+    let lower = html.to_ascii_lowercase();
+    let mut og_title = None;
+    let mut title = None;
+    let mut description = None;
+    let mut image = None;
+    let mut pos = 0;
+
+    while let Some(start) = lower[pos..].find('<') {
+        let start = pos + start + 1;
+        let end = match lower[start..].find('>') {
+            Some(end) => start + end,
+            None => break
+        };
+        let tag = &html[start..end];
+        let tag_lower = &lower[start..end];
+
+        if tag_lower.starts_with("meta") {
+            let property = attribute(tag, "property").or_else(|| attribute(tag, "name"));
+            if let (Some(property), Some(content)) = (property, attribute(tag, "content")) {
+                match property.to_ascii_lowercase().as_str() {
+                    "og:title" => { og_title.get_or_insert(content); },
+                    "og:description" | "description" => { description.get_or_insert(content); },
+                    "og:image" => { image.get_or_insert(content); },
+                    _ => {}
+                }
+            }
+        } else if tag_lower.starts_with("title") && title.is_none() {
+            if let Some(close) = lower[end..].find("</title") {
+                title = Some(&html[end + 1..end + close]);
+            }
+        } else if tag_lower.starts_with("/head") || tag_lower.starts_with("body") {
+            break;
+        }
+
+        pos = end + 1;
+    }
+
+    let title = clean_text(og_title.or(title)?, db::MAX_PREVIEW_TITLE_LENGTH);
+    if title.is_empty() {
+        return None;
+    }
+
+    Some(db::LinkPreview {
+        url: url.to_string(),
+        title,
+        description: description
+            .map(|description| clean_text(description, db::MAX_PREVIEW_DESCRIPTION_LENGTH))
+            .filter(|description| !description.is_empty()),
+        image: image
+            .and_then(|image| url.join(&decode_entities(image)).ok())
+            .filter(|image| image.scheme() == "http" || image.scheme() == "https")
+            .map(|image| image.to_string())
+            .filter(|image| db::valid_url(image)),
+    })
+}
+
+/// Get the value of an attribute in the contents of a tag.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let mut pos = 0;
+
+    while let Some(start) = lower[pos..].find(name) {
+        let start = pos + start;
+        pos = start + name.len();
+
+        if start == 0 || !lower.as_bytes()[start - 1].is_ascii_whitespace() {
+            continue;
+        }
+        let rest = tag[pos..].trim_start();
+        if !rest.starts_with('=') {
+            continue;
+        }
+
+        let rest = rest[1..].trim_start();
+        return if rest.starts_with('"') || rest.starts_with('\'') {
+            let quote = rest.as_bytes()[0] as char;
+            let value = &rest[1..];
+            value.find(quote).map(|end| &value[..end])
+        } else {
+            let end = rest.find(|ch: char| ch.is_whitespace() || ch == '/').unwrap_or(rest.len());
+            Some(&rest[..end])
+        };
+    }
+
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+fn clean_text(text: &str, max_chars: usize) -> String {
+    decode_entities(text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(max_chars)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serve a single response and return the request that was received.
+    async fn serve_once(content_type: &'static str, body: &'static str) -> (u16, tokio::task::JoinHandle<String>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = socket.read(&mut buf).await.unwrap();
+                if len == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..len]);
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                content_type, body.len(), body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (port, server)
+    }
+
+    #[tokio::test]
+    async fn fetches_from_the_checked_address() {
+        let (port, server) = serve_once(
+            "text/html; charset=utf-8",
+            "<html><head><title>Example</title><meta name=\"description\" content=\"A page\"></head></html>"
+        ).await;
+
+        // The host doesn't resolve so the request only succeeds if it is sent
+        // to the address that was given.
+        let url = Url::parse(&format!("http://preview.invalid:{}/page", port)).unwrap();
+        let addr = IpAddr::from([127, 0, 0, 1]);
+        let preview = fetch_page(&reqwest::Client::new(), &url, addr, 4096).await.unwrap().unwrap();
+        assert_eq!(preview.title, "Example");
+        assert_eq!(preview.description.as_deref(), Some("A page"));
+
+        let request = server.await.unwrap().to_ascii_lowercase();
+        assert!(request.starts_with("get /page "));
+        assert!(request.contains(&format!("\r\nhost: preview.invalid:{}\r\n", port)));
+    }
+
+    #[tokio::test]
+    async fn ignores_pages_that_arent_html() {
+        let (port, server) = serve_once("application/json", "{\"title\": \"Example\"}").await;
+        let url = Url::parse(&format!("http://preview.invalid:{}/", port)).unwrap();
+        let addr = IpAddr::from([127, 0, 0, 1]);
+        assert!(fetch_page(&reqwest::Client::new(), &url, addr, 4096).await.unwrap().is_none());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_at_most_max_bytes() {
+        let (port, server) = serve_once(
+            "text/html",
+            "<head><meta property=\"og:title\" content=\"First\"><meta property=\"og:description\" content=\"Too far\"></head>"
+        ).await;
+        let url = Url::parse(&format!("http://preview.invalid:{}/", port)).unwrap();
+        let addr = IpAddr::from([127, 0, 0, 1]);
+        let preview = fetch_page(&reqwest::Client::new(), &url, addr, 50).await.unwrap().unwrap();
+        assert_eq!(preview.title, "First");
+        assert_eq!(preview.description, None);
+        server.await.unwrap();
+    }
+
+    fn preview(html: &str) -> Option<db::LinkPreview> {
+        parse_preview(&Url::parse("https://example.com/a/b").unwrap(), html)
+    }
+
+    #[test]
+    fn open_graph_is_preferred_to_title() {
+        let preview = preview(r#"
+            <html><head>
+            <title>Plain title</title>
+            <META Property="og:title" Content="OG &amp; title">
+            <meta property='og:description' content='Some   words'>
+            <meta property="og:image" content="/img.png">
+            </head></html>
+        "#).unwrap();
+        assert_eq!(preview.url, "https://example.com/a/b");
+        assert_eq!(preview.title, "OG & title");
+        assert_eq!(preview.description.as_deref(), Some("Some words"));
+        assert_eq!(preview.image.as_deref(), Some("https://example.com/img.png"));
+    }
+
+    #[test]
+    fn title_is_the_fallback() {
+        let preview = preview("<head><title>  Just a\n title </title></head>").unwrap();
+        assert_eq!(preview.title, "Just a title");
+        assert_eq!(preview.description, None);
+        assert_eq!(preview.image, None);
+    }
+
+    #[test]
+    fn tags_in_the_body_are_ignored() {
+        assert!(preview("<head></head><body><title>Not this</title></body>").is_none());
+        assert!(preview("<title>   </title>").is_none());
+        assert!(preview("no tags at all").is_none());
+    }
+
+    #[test]
+    fn only_web_images_are_kept() {
+        let preview = preview(r#"<meta property="og:title" content="T"><meta property="og:image" content="javascript:alert(1)">"#).unwrap();
+        assert_eq!(preview.image, None);
+    }
+
+    #[test]
+    fn title_is_truncated() {
+        let long = "a".repeat(db::MAX_PREVIEW_TITLE_LENGTH + 10);
+        let preview = preview(&format!("<title>{}</title>", long)).unwrap();
+        assert_eq!(preview.title.len(), db::MAX_PREVIEW_TITLE_LENGTH);
+    }
+
+    #[test]
+    fn private_addresses_are_rejected() {
+        for addr in &["127.0.0.1", "10.1.2.3", "192.168.0.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:10.0.0.1"] {
+            assert!(!public_address(addr.parse().unwrap()), "{}", addr);
+        }
+        for addr in &["93.184.216.34", "2606:2800:220:1:248:1893:25c7:1946"] {
+            assert!(public_address(addr.parse().unwrap()), "{}", addr);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+/// A version of the socket protocol. The version is negotiated with the
+/// Sec-WebSocket-Protocol header when the connection is upgraded so that the
+/// format of messages can change without breaking older clients.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Protocol {
+    V1,
+}
+
+/// The supported protocols, newest first.
+const SUPPORTED: [Protocol; 1] = [Protocol::V1];
+
+impl Protocol {
+    /// Clients that don't ask for a protocol get this one.
+    pub const DEFAULT: Protocol = Protocol::V1;
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::V1 => "chat.v1",
+        }
+    }
+
+    /// Choose the newest supported protocol from a comma separated list of
+    /// protocols offered by the client. Returns None if none of them are
+    /// supported.
+    pub fn negotiate(offered: &str) -> Option<Protocol> {
+        SUPPORTED.iter()
+            .copied()
+            .find(|protocol| offered.split(',').any(|name| name.trim() == protocol.name()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_protocol() {
+        assert_eq!(Protocol::negotiate("chat.v1"), Some(Protocol::V1));
+    }
+
+    #[test]
+    fn protocol_among_others() {
+        assert_eq!(Protocol::negotiate("chat.v0, chat.v1 ,other"), Some(Protocol::V1));
+    }
+
+    #[test]
+    fn unsupported_protocols() {
+        assert_eq!(Protocol::negotiate("chat.v0, other"), None);
+        assert_eq!(Protocol::negotiate(""), None);
+        assert_eq!(Protocol::negotiate("chat.v1x"), None);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::error::Error;
+use crate::database as db;
+use deadpool_postgres::Pool;
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Default)]
+struct Cached {
+    settings: HashMap<db::GroupID, db::GroupSettings>,
+    /// The groups that have connections. The settings of other groups aren't
+    /// kept so that the cache doesn't grow with every group that is looked at.
+    loaded: HashSet<db::GroupID>,
+    /// Incremented whenever settings are invalidated.
+    version: u64,
+}
+
+/// The settings of groups with connections, loaded from the database when
+/// they're first needed.
+#[derive(Clone, Default)]
+pub struct SettingsCache {
+    cached: Arc<Mutex<Cached>>,
+}
+
+impl SettingsCache {
+    pub async fn get(&self, pool: &Pool, group_id: db::GroupID) -> Result<db::GroupSettings, Error> {
+        let version = {
+            let cached = self.cached.lock().unwrap();
+            if let Some(settings) = cached.settings.get(&group_id) {
+                return Ok(settings.clone());
+            }
+            cached.version
+        };
+
+        let settings = db::group_settings(pool.clone(), group_id).await?;
+
+        // If the settings were invalidated while they were being loaded then
+        // they may have been loaded before they were changed.
+        let mut cached = self.cached.lock().unwrap();
+        if cached.version == version && cached.loaded.contains(&group_id) {
+            cached.settings.insert(group_id, settings.clone());
+        }
+        Ok(settings)
+    }
+
+    /// Start keeping the settings of a group once it has connections.
+    pub fn group_loaded(&self, group_id: db::GroupID) {
+        self.cached.lock().unwrap().loaded.insert(group_id);
+    }
+
+    /// Forget the settings of a group once it has no connections.
+    pub fn group_unloaded(&self, group_id: db::GroupID) {
+        let mut cached = self.cached.lock().unwrap();
+        cached.loaded.remove(&group_id);
+        cached.settings.remove(&group_id);
+    }
+
+    /// This must be called after the settings of a group are changed in the
+    /// database.
+    pub fn invalidate(&self, group_id: db::GroupID) {
+        let mut cached = self.cached.lock().unwrap();
+        cached.settings.remove(&group_id);
+        cached.version += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing;
+
+    fn is_cached(cache: &SettingsCache, group_id: db::GroupID) -> bool {
+        cache.cached.lock().unwrap().settings.contains_key(&group_id)
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn only_loaded_groups_are_cached() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        let cache = SettingsCache::default();
+
+        cache.get(&pool, group_id).await.unwrap();
+        assert!(!is_cached(&cache, group_id));
+
+        cache.group_loaded(group_id);
+        cache.get(&pool, group_id).await.unwrap();
+        assert!(is_cached(&cache, group_id));
+
+        cache.group_unloaded(group_id);
+        assert!(!is_cached(&cache, group_id));
+        cache.get(&pool, group_id).await.unwrap();
+        assert!(!is_cached(&cache, group_id));
+    }
+}
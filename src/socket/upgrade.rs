@@ -1,29 +1,267 @@
-use log::{debug, error};
+use log::{debug, error, warn};
+use crate::config;
+use crate::utils::generate_random_base64url;
 use crate::error::Error;
 use crate::database as db;
+use serde::{Serialize, Deserialize};
 use deadpool_postgres::Pool;
-use tokio::sync::{RwLock, mpsc};
-use futures::{FutureExt, StreamExt};
+use tokio::sync::{RwLock, Notify, mpsc};
+use tokio::sync::mpsc::error::TrySendError;
+use futures::{future, StreamExt, SinkExt};
+use futures::stream::SplitSink;
 use warp::ws::{Ws, WebSocket, Message};
+use tracing::{info_span, debug_span};
+use tracing_futures::Instrument;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::HashSet;
 use std::collections::hash_map::{HashMap, Entry};
-use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+use super::flood::ChannelRate;
+use super::close::CloseReason;
+use super::preview::LinkPreviews;
+use super::settings::SettingsCache;
+use super::protocol::Protocol;
+use super::webhook::PresenceWebhook;
+use super::handler::{NewMessage, Refusal};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}};
 
 pub type ConnID = usize;
 pub type AtomicConnID = AtomicUsize;
 static NEXT_CONNECTION_ID: AtomicConnID = AtomicConnID::new(1);
 
-pub type Sender = mpsc::UnboundedSender<Result<Message, warp::Error>>;
+/// The number of messages queued to connections since the stats were last
+/// taken.
+static MESSAGES_SENT: AtomicUsize = AtomicUsize::new(0);
+
+/// The largest number of groups with connections since the stats were last
+/// taken.
+static PEAK_GROUPS: AtomicUsize = AtomicUsize::new(0);
+
+/// The sending end of the message queue for a connection.
+#[derive(Clone)]
+pub struct Sender {
+    queue: mpsc::Sender<Message>,
+    overflow: Arc<Notify>,
+    closing: Arc<AtomicBool>,
+}
+
+impl Sender {
+    /// Put a message onto the queue.
+    ///
+    /// If the queue is full then the client isn't reading messages as fast as
+    /// we're sending them. Rather than buffering forever, the connection is
+    /// closed.
+    ///
+    /// Does nothing if the connection is closing. The connection stays in the
+    /// group until the client finishes closing it but nothing more is sent.
+    pub fn send(&self, message: Message) {
+        if self.closing.load(Ordering::Relaxed) {
+            return;
+        }
+        self.push(message);
+    }
+
+    /// Put a close frame onto the queue. Messages sent afterwards are
+    /// dropped.
+    pub fn close(&self, reason: CloseReason) {
+        if self.closing.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.push(reason.message());
+    }
+
+    fn push(&self, message: Message) {
+        // try_send needs a mutable sender but we only have shared access to
+        // it. Senders are cheap to clone.
+        match self.queue.clone().try_send(message) {
+            Ok(()) => {
+                MESSAGES_SENT.fetch_add(1, Ordering::Relaxed);
+            },
+            Err(TrySendError::Full(_)) => self.overflow.notify(),
+            Err(TrySendError::Closed(_)) => {
+                // the connection handler will handle the possible error
+            }
+        }
+    }
+}
+
+/// The reason that forwarding messages to a socket ended.
+enum ForwardEnd {
+    /// The queue was closed or the connection was closed for being too slow.
+    Closed,
+    /// The stop signal was received.
+    Stopped,
+    /// A message couldn't be sent over the socket. The message is handed
+    /// back if the socket never took it so that it can be sent first if the
+    /// connection is resumed.
+    Failed(warp::Error, Option<Message>),
+}
+
+/// How long to spend sending the rest of the queue to a connection that the
+/// server is closing.
+const FLUSH_DEADLINE: Duration = Duration::from_secs(1);
+
+/// Send the messages that are already on the queue.
+async fn flush(ch_rx: &mut mpsc::Receiver<Message>, ws_tx: &mut SplitSink<WebSocket, Message>)
+    -> Result<(), warp::Error>
+{
+    while let Ok(message) = ch_rx.try_recv() {
+        ws_tx.send(message).await?;
+    }
+    Ok(())
+}
+
+/// Send a message over the socket.
+///
+/// The socket is checked before it's given the message so that the message
+/// can be handed back if the socket has failed. This way the message doesn't
+/// have to be copied in case it fails. Once the socket has the message, it
+/// can't be handed back.
+async fn send(ws_tx: &mut SplitSink<WebSocket, Message>, message: Message)
+    -> Result<(), (warp::Error, Option<Message>)>
+{
+    if let Err(e) = future::poll_fn(|cx| ws_tx.poll_ready_unpin(cx)).await {
+        return Err((e, Some(message)));
+    }
+    if let Err(e) = ws_tx.start_send_unpin(message) {
+        return Err((e, None));
+    }
+    ws_tx.flush().await.map_err(|e| (e, None))
+}
+
+/// Pull messages off the end of the queue and send them over the socket.
+///
+/// The queue is handed back when forwarding ends so that the connection can
+/// be resumed on another socket. A message that failed to send on the
+/// previous socket is sent before anything else on the queue.
+async fn forward(
+    mut ch_rx: mpsc::Receiver<Message>,
+    unsent: Option<Message>,
+    mut ws_tx: SplitSink<WebSocket, Message>,
+    overflow: Arc<Notify>,
+    closing: Arc<AtomicBool>,
+    stop: Arc<Notify>,
+) -> (mpsc::Receiver<Message>, ForwardEnd) {
+    if let Some(message) = unsent {
+        if let Err((e, message)) = send(&mut ws_tx, message).await {
+            return (ch_rx, ForwardEnd::Failed(e, message));
+        }
+    }
+
+    loop {
+        tokio::select! {
+            message = ch_rx.recv() => match message {
+                Some(message) => if let Err((e, message)) = send(&mut ws_tx, message).await {
+                    return (ch_rx, ForwardEnd::Failed(e, message));
+                },
+                None => return (ch_rx, ForwardEnd::Closed),
+            },
+            _ = overflow.notified() => {
+                if let Err((e, message)) = send(&mut ws_tx, CloseReason::TooSlow.message()).await {
+                    return (ch_rx, ForwardEnd::Failed(e, message));
+                }
+                return (ch_rx, ForwardEnd::Closed);
+            }
+            _ = stop.notified() => {
+                // The close frame is the last thing on the queue of a
+                // connection that the server is closing. Whatever is left is
+                // sent so that the client finds out why it was closed.
+                if closing.load(Ordering::Relaxed) {
+                    match tokio::time::timeout(FLUSH_DEADLINE, flush(&mut ch_rx, &mut ws_tx)).await {
+                        Ok(Ok(())) => {},
+                        Ok(Err(e)) => debug!("Failed to flush socket: {}", e),
+                        Err(_) => debug!("Timed out flushing socket"),
+                    }
+                }
+                return (ch_rx, ForwardEnd::Stopped);
+            }
+        }
+    }
+}
+
+pub type ResumeToken = String;
+
+const RESUME_TOKEN_LENGTH: usize = 16;
+
+/// How long an idle connection has to finish the closing handshake before it
+/// is dropped.
+const IDLE_CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+pub struct ResumeQuery {
+    resume: Option<ResumeToken>,
+}
+
+/// A connection that was dropped without being closed. It stays in its group
+/// and keeps queueing messages until it is resumed or the grace period ends.
+/// If the queue fills up in the meantime, the connection is closed for being
+/// too slow as soon as it is resumed.
+struct Suspended {
+    conn_ctx: ConnectionContext,
+    ch_rx: mpsc::Receiver<Message>,
+    // The message that failed to send when the connection was dropped.
+    unsent: Option<Message>,
+    overflow: Arc<Notify>,
+    closing: Arc<AtomicBool>,
+}
 
 struct ConnectionContext {
     user_id: db::UserID,
     group_id: db::GroupID,
     conn_id: ConnID,
+    protocol: Protocol,
+    session_id: db::SessionID,
 }
 
 pub struct Group {
     pub channels: Vec<db::Channel>,
     pub connections: HashMap<ConnID, Sender>,
     pub online_users: HashMap<db::UserID, Vec<ConnID>>,
+    // The session that each connection was made with.
+    pub sessions: HashMap<ConnID, db::SessionID>,
+    // Online users that have set their status to away.
+    pub away_users: HashSet<db::UserID>,
+    // This is modified while the group map is only locked for reading.
+    pub channel_rates: Mutex<HashMap<db::ChannelID, ChannelRate>>,
+    // The last time that each user sent a message to each channel. This is
+    // only needed for channels in slow mode.
+    pub last_messages: Mutex<HashMap<(db::UserID, db::ChannelID), Instant>>,
+    // The time that each user that is typing in each channel will be
+    // considered to have stopped. This is None if the user has stopped but
+    // the timer for the deadline hasn't finished yet.
+    pub typing: Mutex<HashMap<(db::UserID, db::ChannelID), Option<Instant>>>,
+    // The sequence number that the channels of this group started from.
+    pub sequence_base: u64,
+    // The sequence number of the last event sent to each channel. Held while
+    // the event is being queued on every connection.
+    pub channel_sequences: Mutex<HashMap<db::ChannelID, u64>>,
+    // The members of each private channel. Public channels are not in here.
+    pub channel_members: ChannelMembers,
+    // The last time that the database was checked for a channel that wasn't
+    // in the list.
+    pub last_channel_refresh: Mutex<Option<Instant>>,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionInfo {
+    pub conn_id: ConnID,
+    pub user_id: db::UserID,
+}
+
+#[derive(Serialize)]
+pub struct GroupConnections {
+    pub group_id: db::GroupID,
+    pub connections: Vec<ConnectionInfo>,
+}
+
+/// A snapshot of the activity of the server.
+pub struct Stats {
+    pub groups: usize,
+    pub connections: usize,
+    /// The number of messages sent since the previous snapshot.
+    pub messages_sent: usize,
+    /// The largest number of groups since the previous snapshot.
+    pub peak_groups: usize,
 }
 
 pub type GroupMap = HashMap<db::GroupID, Group>;
@@ -31,21 +269,72 @@ pub type Groups = Arc<RwLock<GroupMap>>;
 pub type UserGroupMap = HashMap<db::UserID, Vec<db::GroupID>>;
 pub type UserGroups = Arc<RwLock<UserGroupMap>>;
 
+pub type ChannelMembers = HashMap<db::ChannelID, HashSet<db::UserID>>;
+
+/// Load the channels of a group and the members of its private channels.
+pub async fn load_channels(pool: Pool, group_id: db::GroupID)
+    -> Result<(Vec<db::Channel>, ChannelMembers), Error>
+{
+    // Channels are kept sorted by ID so that they can be binary searched.
+    let mut channels = db::group_channels(pool.clone(), group_id).await?;
+    channels.sort_by_key(|ch| ch.channel_id);
+    let mut channel_members = ChannelMembers::new();
+    for channel in channels.iter().filter(|ch| ch.private) {
+        channel_members.insert(channel.channel_id, HashSet::new());
+    }
+    for (channel_id, user_id) in db::group_channel_members(pool, group_id).await? {
+        if let Some(members) = channel_members.get_mut(&channel_id) {
+            members.insert(user_id);
+        }
+    }
+    Ok((channels, channel_members))
+}
+
 impl Group {
+    /// Create a group without any connections.
+    pub(super) fn with_channels(channels: Vec<db::Channel>, channel_members: ChannelMembers) -> Self {
+        // Sequence numbers start from the time that the group was created so
+        // that they keep going up when a group is removed and created again.
+        // This relies on a channel getting fewer than a million events per
+        // second. Microseconds stay well within the integers that JavaScript
+        // can represent exactly.
+        let sequence_base = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_micros() as u64);
+        Self {
+            channels,
+            connections: HashMap::new(),
+            online_users: HashMap::new(),
+            sessions: HashMap::new(),
+            away_users: HashSet::new(),
+            channel_rates: Mutex::new(HashMap::new()),
+            last_messages: Mutex::new(HashMap::new()),
+            typing: Mutex::new(HashMap::new()),
+            sequence_base,
+            channel_sequences: Mutex::new(HashMap::new()),
+            channel_members,
+            last_channel_refresh: Mutex::new(None),
+        }
+    }
+
     /// Create a new group and insert a connection
     async fn new(conn_ctx: &ConnectionContext, pool: Pool, ch_tx: Sender)
         -> Result<Self, Error>
     {
-        let channels = db::group_channels(pool, conn_ctx.group_id).await?;
-        let mut connections = HashMap::new();
-        connections.insert(conn_ctx.conn_id, ch_tx);
-        let mut online_users = HashMap::new();
-        online_users.insert(conn_ctx.user_id, vec![conn_ctx.conn_id]);
-        Ok(Self { channels, connections, online_users })
+        let (channels, channel_members) = load_channels(pool, conn_ctx.group_id).await?;
+        let mut group = Self::with_channels(channels, channel_members);
+        group.connections.insert(conn_ctx.conn_id, ch_tx);
+        group.online_users.insert(conn_ctx.user_id, vec![conn_ctx.conn_id]);
+        group.sessions.insert(conn_ctx.conn_id, conn_ctx.session_id.clone());
+        Ok(group)
     }
 
     /// Insert a new connection into the group.
     /// Returns true if the user has one connection to the group.
+    ///
+    /// The rest of the group is only told about the user coming online if
+    /// this is their first connection or they were away. The new connection
+    /// isn't told because it gets a snapshot of everyone's status instead.
     fn insert_connection(&mut self, conn_ctx: &ConnectionContext, ch_tx: Sender) -> bool {
         let conn_ids = self.online_users.entry(conn_ctx.user_id).or_default();
         conn_ids.push(conn_ctx.conn_id);
@@ -53,8 +342,12 @@ impl Group {
         if conn_ids.len() == 1 {
             self.send_user_online(conn_ctx.user_id);
             joined_group = true;
+        } else if self.away_users.remove(&conn_ctx.user_id) {
+            // New connections are always online
+            self.send_user_online(conn_ctx.user_id);
         }
         self.connections.insert(conn_ctx.conn_id, ch_tx);
+        self.sessions.insert(conn_ctx.conn_id, conn_ctx.session_id.clone());
         joined_group
     }
 
@@ -62,58 +355,119 @@ impl Group {
     /// Returns true if the user has no connections to the group.
     fn remove_connection(&mut self, conn_ctx: &ConnectionContext) -> bool {
         self.connections.remove(&conn_ctx.conn_id);
+        self.sessions.remove(&conn_ctx.conn_id);
         if self.connections.is_empty() {
             return true;
         }
         let mut user_entry = match self.online_users.entry(conn_ctx.user_id) {
             Entry::Occupied(entry) => entry,
-            Entry::Vacant(_) => panic!(),
+            Entry::Vacant(_) => {
+                inconsistent(&format!("User {} is not online in group {}", conn_ctx.user_id, conn_ctx.group_id));
+                return true;
+            }
         };
         let conn_ids = user_entry.get_mut();
         if conn_ids.len() == 1 {
             user_entry.remove();
+            self.away_users.remove(&conn_ctx.user_id);
+            self.stop_typing_everywhere(conn_ctx.user_id);
             self.send_user_offline(conn_ctx.user_id);
             true
         } else {
-            let index = conn_ids.iter().position(|id| *id == conn_ctx.conn_id).unwrap();
-            conn_ids.swap_remove(index);
+            match conn_ids.iter().position(|id| *id == conn_ctx.conn_id) {
+                Some(index) => { conn_ids.swap_remove(index); },
+                None => inconsistent(&format!("Connection {} is not in the online users of group {}", conn_ctx.conn_id, conn_ctx.group_id)),
+            }
             false
         }
     }
 }
 
+/// Report a broken invariant of the group maps. The maps are repaired by
+/// treating the missing entry as already removed, but debug builds panic so
+/// that the bug is noticed.
+fn inconsistent(message: &str) {
+    error!("{}", message);
+    #[cfg(debug_assertions)]
+    panic!("{}", message);
+}
+
 #[derive(Clone)]
 pub struct Context {
     pool: Pool,
     groups: Groups,
     user_groups: UserGroups,
+    suspended: Arc<Mutex<HashMap<ResumeToken, Suspended>>>,
+    previews: LinkPreviews,
+    settings: SettingsCache,
+    presence: PresenceWebhook,
+    last_group_warning: Arc<Mutex<Option<Instant>>>,
 }
 
 impl Context {
-    pub fn new(pool: Pool) -> Self {
+    pub fn new(pool: Pool, client: reqwest::Client) -> Self {
         Self {
             pool,
             groups: Groups::default(),
             user_groups: UserGroups::default(),
+            suspended: Arc::default(),
+            previews: LinkPreviews::new(client.clone()),
+            settings: SettingsCache::default(),
+            presence: PresenceWebhook::new(client),
+            last_group_warning: Arc::default(),
         }
     }
 
+    /// Take a suspended connection if the token is valid for the user and
+    /// group.
+    fn take_suspended(&self, token: &ResumeToken, conn_ctx: &ConnectionContext) -> Option<Suspended> {
+        let mut suspended = self.suspended.lock().unwrap();
+        match suspended.get(token) {
+            Some(s) if s.conn_ctx.user_id == conn_ctx.user_id
+                && s.conn_ctx.group_id == conn_ctx.group_id
+                && s.conn_ctx.protocol == conn_ctx.protocol
+                && s.conn_ctx.session_id == conn_ctx.session_id => suspended.remove(token),
+            _ => None,
+        }
+    }
+
+    /// Keep a dropped connection in its group for a while in case the client
+    /// comes back. The connection is removed normally if it doesn't.
+    fn suspend(&self, token: ResumeToken, suspended: Suspended) {
+        debug!("Socket suspended: {}", suspended.conn_ctx.conn_id);
+        self.suspended.lock().unwrap().insert(token.clone(), suspended);
+        let ctx = self.clone();
+        tokio::task::spawn(async move {
+            tokio::time::delay_for(Duration::from_secs(config::get().resume_grace_secs)).await;
+            let expired = ctx.suspended.lock().unwrap().remove(&token);
+            if let Some(suspended) = expired {
+                ctx.remove_connection(&suspended.conn_ctx).await;
+                debug!("Socket disconnected: {}", suspended.conn_ctx.conn_id);
+            }
+        }.in_current_span());
+    }
+
     /// Insert a connection into the group map. Creates a new group if
     /// necessary, otherwise inserts into an existing group.
     async fn insert_connection(&self, conn_ctx: &ConnectionContext, ch_tx: Sender)
         -> Result<(), Error>
     {
         let joined_group;
-        match self.groups.write().await.entry(conn_ctx.group_id) {
+        let mut groups_guard = self.groups.write().await;
+        match groups_guard.entry(conn_ctx.group_id) {
             Entry::Occupied(mut entry) => {
                 joined_group = entry.get_mut().insert_connection(&conn_ctx, ch_tx);
             }
             Entry::Vacant(entry) => {
                 entry.insert(Group::new(&conn_ctx, self.pool.clone(), ch_tx).await?);
+                self.settings.group_loaded(conn_ctx.group_id);
                 joined_group = true;
+                self.group_added(groups_guard.len());
             }
         }
+        drop(groups_guard);
         if joined_group {
+            self.presence.notify(conn_ctx.group_id, conn_ctx.user_id, true);
             match self.user_groups.write().await.entry(conn_ctx.user_id) {
                 Entry::Occupied(mut entry) => {
                     entry.get_mut().push(conn_ctx.group_id);
@@ -126,6 +480,27 @@ impl Context {
         Ok(())
     }
 
+    /// Update the peak number of groups and warn if there are too many.
+    /// Returns true if the warning was logged.
+    fn group_added(&self, count: usize) -> bool {
+        PEAK_GROUPS.fetch_max(count, Ordering::Relaxed);
+
+        let config = config::get();
+        if config.group_warning_threshold == 0 || count <= config.group_warning_threshold {
+            return false;
+        }
+
+        let mut last_warning = self.last_group_warning.lock().unwrap();
+        let interval = Duration::from_secs(config.group_warning_interval_secs);
+        if last_warning.map_or(true, |last| last.elapsed() >= interval) {
+            warn!("{} groups have connections (threshold is {})", count, config.group_warning_threshold);
+            *last_warning = Some(Instant::now());
+            true
+        } else {
+            false
+        }
+    }
+
     /// Remove a connection from the group map. Also removes the group if the
     /// group becomes empty.
     async fn remove_connection(&self, conn_ctx: &ConnectionContext) {
@@ -134,31 +509,44 @@ impl Context {
             Entry::Occupied(mut entry) => {
                 if entry.get_mut().connections.len() == 1 {
                     entry.remove();
+                    self.settings.group_unloaded(conn_ctx.group_id);
                     left_group = true;
                 } else {
                     left_group = entry.get_mut().remove_connection(&conn_ctx);
                 }
             },
-            Entry::Vacant(_) => panic!()
+            Entry::Vacant(_) => {
+                inconsistent(&format!("Group {} was removed before connection {}", conn_ctx.group_id, conn_ctx.conn_id));
+                left_group = true;
+            }
         }
         if left_group {
+            self.presence.notify(conn_ctx.group_id, conn_ctx.user_id, false);
             match self.user_groups.write().await.entry(conn_ctx.user_id) {
                 Entry::Occupied(mut entry) => {
-                    if entry.get_mut().len() == 1 {
-                        entry.remove();
-                    } else {
-                        let pos = entry.get_mut().iter().position(|id| *id == conn_ctx.group_id).unwrap();
-                        entry.get_mut().swap_remove(pos);
+                    match entry.get().iter().position(|id| *id == conn_ctx.group_id) {
+                        Some(_) if entry.get().len() == 1 => { entry.remove(); },
+                        Some(pos) => { entry.get_mut().swap_remove(pos); },
+                        None => inconsistent(&format!("Group {} is not in the groups of user {}", conn_ctx.group_id, conn_ctx.user_id)),
                     }
                 },
-                Entry::Vacant(_) => panic!()
+                Entry::Vacant(_) => {
+                    inconsistent(&format!("User {} has no groups", conn_ctx.user_id));
+                }
             }
         }
     }
 
-    pub async fn upgrade(group_id: db::GroupID, ws: Ws, session_id: db::SessionID, ctx: Self)
-        -> Result<Box<dyn warp::Reply>, warp::Rejection>
-    {
+    pub async fn upgrade(
+        group_id: db::GroupID,
+        ws: Ws,
+        session_id: db::SessionID,
+        addr: Option<SocketAddr>,
+        user_agent: Option<String>,
+        offered_protocols: Option<String>,
+        query: ResumeQuery,
+        ctx: Self
+    ) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
         // The JavaScript that invokes this is only loaded when the session cookie
         // is valid. The only way that this error could happen is if the session
         // expires between loading the page and running the JavaScript. Another
@@ -174,73 +562,242 @@ impl Context {
             return Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR));
         }
 
+        // Failing to write the audit log shouldn't stop the user from connecting.
+        let ip = addr.map(|addr| addr.ip());
+        if let Err(e) = db::log_connection(ctx.pool.clone(), user_id, ip, user_agent.as_deref()).await {
+            warn!("Failed to log connection: {}", e);
+        }
+
+        // Clients that don't offer any protocols are assumed to speak the
+        // oldest one. A client that only offers protocols that we don't know
+        // about can't be understood so the upgrade is refused.
+        let negotiated = match offered_protocols {
+            Some(offered) => match Protocol::negotiate(&offered) {
+                Some(protocol) => Some(protocol),
+                None => return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST))
+            },
+            None => None
+        };
+        let protocol = negotiated.unwrap_or(Protocol::DEFAULT);
+
+        // Oversized messages are rejected while they're being read so that a
+        // client can't exhaust memory with a single huge frame.
+        let config = config::get();
+        let ws = ws.max_message_size(config.ws_max_message_size)
+            .max_frame_size(config.ws_max_frame_size);
+
         // Upgrade the HTTP connection to a WebSocket connection
-        Ok(Box::new(ws.on_upgrade(move |socket: WebSocket| {
+        let reply = ws.on_upgrade(move |socket: WebSocket| {
+            let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+            // Everything logged while handling this connection is tagged with
+            // these fields.
+            let span = info_span!("connection", conn_id, user_id = user_id.0, group_id = group_id.0);
             ctx.connected(socket, ConnectionContext {
                 user_id,
                 group_id,
-                conn_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
-            })
-        })))
-    }
+                conn_id,
+                protocol,
+                session_id,
+            }, query.resume).instrument(span)
+        });
 
-    async fn connected(self, ws: WebSocket, conn_ctx: ConnectionContext) {
-        debug!("Socket connected: {}", conn_ctx.conn_id);
+        // The selected protocol must be echoed back if the client offered any.
+        match negotiated {
+            Some(protocol) => Ok(Box::new(warp::reply::with_header(reply, "sec-websocket-protocol", protocol.name()))),
+            None => Ok(Box::new(reply))
+        }
+    }
 
+    async fn connected(self, ws: WebSocket, conn_ctx: ConnectionContext, resume: Option<ResumeToken>) {
         // Splitting the web socket into separate sinks and streams.
         // This is our means of sending and receiving messages over the socket.
         let (ws_tx, mut ws_rx) = ws.split::<Message>();
 
-        // Channel used as a queue for messages.
-        let (ch_tx, ch_rx) = mpsc::unbounded_channel::<Result<Message, warp::Error>>();
+        // A client that was disconnected recently can pick up where it left off
+        // without the rest of the group seeing it go offline and come back.
+        let resumed = resume.and_then(|token| self.take_suspended(&token, &conn_ctx));
 
-        // Pull messages off the end of the queue and send them over the socket.
-        let conn_id = conn_ctx.conn_id;
-        tokio::task::spawn(ch_rx.forward(ws_tx).map(move |result: Result<(), warp::Error>| {
-            if let Err(e) = result {
-                error!("Error sending over socket ({}): {}", conn_id, e);
-            }
-        }));
-
-        // Add the connection to the hashmap, saving the sending end of the queue.
-        // Putting messages onto the queue will cause them to eventually be
-        // processed above and sent over the socket.
-        if let Err(e) = self.insert_connection(&conn_ctx, ch_tx).await {
-            error!("{}", e);
-            return;
+        // The user might have been removed from the group while the connection
+        // was suspended.
+        if let Some(suspended) = &resumed {
+            let member = match db::group_member(self.pool.clone(), conn_ctx.user_id, conn_ctx.group_id).await {
+                Ok(member) => member,
+                Err(e) => {
+                    error!("{}", e);
+                    false
+                }
+            };
+            if !member {
+                self.remove_connection(&suspended.conn_ctx).await;
+                debug!("Socket disconnected: {}", suspended.conn_ctx.conn_id);
+                return;
+            }
         }
 
-        let message_ctx = super::handler::MessageContext {
+        let is_new = resumed.is_none();
+        let (conn_ctx, ch_rx, unsent, overflow, closing) = match resumed {
+            Some(suspended) => {
+                debug!("Socket resumed: {}", suspended.conn_ctx.conn_id);
+                (suspended.conn_ctx, suspended.ch_rx, suspended.unsent, suspended.overflow, suspended.closing)
+            }
+            None => {
+                debug!("Socket connected: {}", conn_ctx.conn_id);
+
+                if config::get().single_session {
+                    self.kick_user(conn_ctx.user_id, CloseReason::Replaced).await;
+                }
+
+                // Channel used as a queue for messages.
+                let (queue, ch_rx) = mpsc::channel::<Message>(config::get().send_queue_capacity);
+                let overflow = Arc::new(Notify::new());
+                let closing = Arc::new(AtomicBool::new(false));
+                let ch_tx = Sender { queue, overflow: overflow.clone(), closing: closing.clone() };
+
+                // Add the connection to the hashmap, saving the sending end of
+                // the queue. Putting messages onto the queue will cause them to
+                // eventually be processed below and sent over the socket.
+                if let Err(e) = self.insert_connection(&conn_ctx, ch_tx).await {
+                    error!("{}", e);
+                    return;
+                }
+
+                (conn_ctx, ch_rx, None, overflow, closing)
+            }
+        };
+
+        let token = generate_random_base64url(RESUME_TOKEN_LENGTH);
+        self.send_resume_token(&conn_ctx, &token).await;
+
+        // Pull messages off the end of the queue and send them over the socket.
+        let stop = Arc::new(Notify::new());
+        let mut forwarder = tokio::task::spawn(
+            forward(ch_rx, unsent, ws_tx, overflow.clone(), closing.clone(), stop.clone()).in_current_span()
+        );
+
+        let mut message_ctx = super::handler::MessageContext {
             user_id: conn_ctx.user_id,
             group_id: conn_ctx.group_id,
             conn_id: conn_ctx.conn_id,
             groups: &self.groups,
             user_groups: &self.user_groups,
             pool: &self.pool,
+            previews: &self.previews,
+            settings: &self.settings,
+            protocol_errors: 0,
         };
 
-        // Handle each message received from the socket.
-        while let Some(result) = ws_rx.next().await {
-            // result: Result<Message, warp::Error>
-            match result {
-                Ok(message) => message_ctx.handle(message).await,
-                Err(e) => {
-                    error!("Error receiving from socket ({}): {}", conn_ctx.conn_id, e);
-                    break;
+        // A resumed connection has been receiving status changes all along.
+        if is_new {
+            message_ctx.send_presence_snapshot().instrument(debug_span!("snapshot")).await;
+        }
+
+        // The idle timer is pushed back whenever something is received. When it
+        // fires, the connection is asked to close and then given a moment to
+        // do so.
+        let idle_timeout_mins = config::get().idle_timeout_mins;
+        let idle_timeout = Duration::from_secs(idle_timeout_mins * 60);
+        let mut idle = tokio::time::delay_for(idle_timeout);
+        let mut idle_closing = false;
+
+        // Handle each message received from the socket until either side of
+        // the socket stops working.
+        let mut closed = false;
+        let forwarded = loop {
+            tokio::select! {
+                result = ws_rx.next() => match result {
+                    Some(Ok(message)) => {
+                        if !idle_closing {
+                            idle.reset(tokio::time::Instant::now() + idle_timeout);
+                        }
+                        closed |= message.is_close();
+                        message_ctx.handle(message).instrument(debug_span!("message")).await
+                    },
+                    Some(Err(e)) => {
+                        // This includes messages that are too large. The socket
+                        // is unusable afterwards so the connection is dropped.
+                        warn!("Error receiving from socket ({}): {}", conn_ctx.conn_id, e);
+                        break None;
+                    },
+                    None => break None,
+                },
+                // If messages can't be sent then the connection is no use even
+                // if the client is still sending.
+                result = &mut forwarder => {
+                    debug!("Socket send side finished: {}", conn_ctx.conn_id);
+                    break Some(result);
+                }
+                _ = &mut idle, if idle_timeout_mins != 0 => {
+                    if idle_closing {
+                        debug!("Idle socket didn't close: {}", conn_ctx.conn_id);
+                        break None;
+                    }
+                    debug!("Socket idle: {}", conn_ctx.conn_id);
+                    self.close_connection(&conn_ctx, CloseReason::Idle).await;
+                    idle_closing = true;
+                    idle.reset(tokio::time::Instant::now() + IDLE_CLOSE_GRACE);
                 }
             }
+        };
+
+        let forwarded = match forwarded {
+            Some(result) => result,
+            None => {
+                stop.notify();
+                forwarder.await
+            }
+        };
+
+        // Connections that were closed properly are gone for good. Connections
+        // that were dropped might come back, unless the server was closing
+        // them anyway.
+        let closed = closed || closing.load(Ordering::Relaxed);
+        match forwarded {
+            Ok((ch_rx, ForwardEnd::Stopped)) if !closed => {
+                self.suspend(token, Suspended { conn_ctx, ch_rx, unsent: None, overflow, closing });
+            }
+            Ok((ch_rx, ForwardEnd::Failed(e, message))) if !closed => {
+                warn!("Error sending over socket ({}): {}", conn_ctx.conn_id, e);
+                self.suspend(token, Suspended { conn_ctx, ch_rx, unsent: message, overflow, closing });
+            }
+            _ => {
+                self.remove_connection(&conn_ctx).await;
+                debug!("Socket disconnected: {}", conn_ctx.conn_id);
+            }
         }
+    }
 
-        self.remove_connection(&conn_ctx).await;
-        debug!("Socket disconnected: {}", conn_ctx.conn_id);
+    async fn close_connection(&self, conn_ctx: &ConnectionContext, reason: CloseReason) {
+        let groups_guard = self.groups.read().await;
+        if let Some(group) = groups_guard.get(&conn_ctx.group_id) {
+            group.kick_connection(conn_ctx.conn_id, reason);
+        }
     }
 
-    pub async fn kick_user(&self, user_id: db::UserID) {
+    async fn send_resume_token(&self, conn_ctx: &ConnectionContext, token: &ResumeToken) {
+        let groups_guard = self.groups.read().await;
+        if let Some(group) = groups_guard.get(&conn_ctx.group_id) {
+            group.send_resume_token(conn_ctx.conn_id, token);
+        }
+    }
+
+    pub async fn kick_user(&self, user_id: db::UserID, reason: CloseReason) {
         let groups_guard = self.groups.read().await;
         let user_groups_guard = self.user_groups.read().await;
         if let Some(groups) = user_groups_guard.get(&user_id) {
             for group_id in groups.iter() {
-                groups_guard[group_id].kick_user(user_id);
+                groups_guard[group_id].kick_user(user_id, reason);
+            }
+        }
+    }
+
+    /// Close the connections that were made with a session.
+    pub async fn kick_session(&self, session_id: &db::SessionID) {
+        let groups_guard = self.groups.read().await;
+        for group in groups_guard.values() {
+            for (conn_id, conn_session_id) in group.sessions.iter() {
+                if conn_session_id == session_id {
+                    group.kick_connection(*conn_id, CloseReason::SessionExpired);
+                }
             }
         }
     }
@@ -248,13 +805,15 @@ impl Context {
     pub async fn kick_user_from_group(&self, user_id: db::UserID, group_id: db::GroupID) {
         let groups_guard = self.groups.read().await;
         if let Some(group) = groups_guard.get(&group_id) {
-            group.kick_user(user_id);
+            group.kick_user(user_id, CloseReason::RemovedFromGroup);
         }
     }
 
-    pub async fn rename_user(&self, groups: Vec<db::GroupID>, user_id: db::UserID, name: &String, picture: &String) {
+    /// Tell the groups of a user about their new name or picture. The name
+    /// can be different in each group because of nicknames.
+    pub async fn rename_user(&self, groups: Vec<(db::GroupID, String)>, user_id: db::UserID, picture: &String) {
         let groups_guard = self.groups.read().await;
-        for group_id in groups.iter() {
+        for (group_id, name) in groups.iter() {
             if let Some(group) = groups_guard.get(group_id) {
                 group.send_user_renamed(user_id, name, picture);
             }
@@ -269,7 +828,7 @@ impl Context {
                 for group_id in groups.iter() {
                     let group = &groups_guard[group_id];
                     if *group_id == deleted_group_id {
-                        group.kick_user(*user_id);
+                        group.kick_user(*user_id, CloseReason::RemovedFromGroup);
                     } else {
                         group.send_delete_group(*user_id, deleted_group_id);
                     }
@@ -278,6 +837,20 @@ impl Context {
         }
     }
 
+    /// Tell the members of a group that it has a new name or picture. Members
+    /// are told wherever they're connected.
+    pub async fn group_updated(&self, users: Vec<db::UserID>, updated_group_id: db::GroupID, name: &String, picture: &String) {
+        let groups_guard = self.groups.read().await;
+        let user_groups_guard = self.user_groups.read().await;
+        for user_id in users.iter() {
+            if let Some(groups) = user_groups_guard.get(&user_id) {
+                for group_id in groups.iter() {
+                    groups_guard[group_id].send_group_updated(*user_id, updated_group_id, name, picture);
+                }
+            }
+        }
+    }
+
     pub async fn delete_user(&self, groups: Vec<db::GroupID>, user_id: db::UserID) {
         let groups_guard = self.groups.read().await;
         for group_id in groups.iter() {
@@ -286,4 +859,676 @@ impl Context {
             }
         }
     }
+
+    pub async fn add_members(&self, group_id: db::GroupID, users: &[db::User]) {
+        let groups_guard = self.groups.read().await;
+        if let Some(group) = groups_guard.get(&group_id) {
+            for user in users.iter() {
+                group.send_member_added(user);
+            }
+        }
+    }
+
+    pub async fn remove_member(&self, group_id: db::GroupID, user_id: db::UserID) {
+        let groups_guard = self.groups.read().await;
+        if let Some(group) = groups_guard.get(&group_id) {
+            group.send_member_removed(user_id);
+        }
+    }
+
+    /// Get the connections of each group that has at least one connection.
+    pub async fn list_connections(&self) -> Vec<GroupConnections> {
+        let groups_guard = self.groups.read().await;
+        let mut groups = Vec::new();
+        for (&group_id, group) in groups_guard.iter() {
+            let mut connections = Vec::new();
+            for (&user_id, conn_ids) in group.online_users.iter() {
+                for &conn_id in conn_ids.iter() {
+                    connections.push(ConnectionInfo { conn_id, user_id });
+                }
+            }
+            connections.sort_by_key(|info| info.conn_id);
+            groups.push(GroupConnections { group_id, connections });
+        }
+        groups.sort_by_key(|group| group.group_id);
+        groups
+    }
+
+    /// Take a snapshot of the activity of the server. This resets the count of
+    /// messages sent.
+    pub async fn stats(&self) -> Stats {
+        let groups = self.group_count().await;
+        Stats {
+            groups,
+            connections: self.connection_count().await,
+            messages_sent: MESSAGES_SENT.swap(0, Ordering::Relaxed),
+            peak_groups: PEAK_GROUPS.swap(groups, Ordering::Relaxed).max(groups),
+        }
+    }
+
+    /// Get the number of groups that have at least one connection.
+    pub async fn group_count(&self) -> usize {
+        self.groups.read().await.len()
+    }
+
+    /// Get the number of connections across all groups.
+    pub async fn connection_count(&self) -> usize {
+        self.groups.read().await.values().map(|group| group.connections.len()).sum()
+    }
+
+    /// Send a close frame to a connection.
+    /// Returns false if the connection could not be found.
+    pub async fn disconnect_connection(&self, conn_id: ConnID) -> bool {
+        // There is no index from connections to groups so we have to search.
+        let groups_guard = self.groups.read().await;
+        for group in groups_guard.values() {
+            if group.connections.contains_key(&conn_id) {
+                group.kick_connection(conn_id, CloseReason::Kick);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Send a message that was created without a socket to the connections of
+    /// its group.
+    pub async fn send_recent_message(&self, group_id: db::GroupID, message_id: db::MessageID, timestamp: u64, author: db::UserID, content: String, channel_id: db::ChannelID, system: bool) {
+        let groups_guard = self.groups.read().await;
+        if let Some(group) = groups_guard.get(&group_id) {
+            group.send_recent_message(message_id, timestamp, author, content, channel_id, system);
+        }
+    }
+
+    /// Store a message that was created without a socket and send it to the
+    /// connections of its group.
+    pub async fn send_message(&self, message: NewMessage)
+        -> Result<Result<(db::MessageID, u64), Refusal>, Error>
+    {
+        super::handler::send_message(&self.groups, &self.pool, &self.previews, &self.settings, None, message).await
+    }
+
+    pub async fn ownership_transferred(&self, group_id: db::GroupID, from: db::UserID, to: db::UserID) {
+        let groups_guard = self.groups.read().await;
+        if let Some(group) = groups_guard.get(&group_id) {
+            group.send_ownership_transferred(from, to);
+        }
+    }
+
+    /// Get the settings of a group.
+    pub async fn group_settings(&self, group_id: db::GroupID) -> Result<db::GroupSettings, Error> {
+        self.settings.get(&self.pool, group_id).await
+    }
+
+    /// Change the settings of a group and tell its connections about it.
+    pub async fn update_group_settings(&self, group_id: db::GroupID, settings: &db::GroupSettings)
+        -> Result<(), Error>
+    {
+        db::update_group_settings(self.pool.clone(), group_id, settings).await?;
+        self.settings_changed(group_id).await
+    }
+
+    /// Change the word filter of a group and tell its connections about it.
+    pub async fn update_word_filter(&self, group_id: db::GroupID, words: &[String])
+        -> Result<(), Error>
+    {
+        db::update_word_filter(self.pool.clone(), group_id, words).await?;
+        self.settings_changed(group_id).await
+    }
+
+    async fn settings_changed(&self, group_id: db::GroupID) -> Result<(), Error> {
+        self.settings.invalidate(group_id);
+        let settings = self.settings.get(&self.pool, group_id).await?;
+
+        let groups_guard = self.groups.read().await;
+        if let Some(group) = groups_guard.get(&group_id) {
+            group.send_settings_updated(&settings);
+        }
+        Ok(())
+    }
+}
+
+/// Connections that are driven by tests instead of sockets.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use serde_json::{json, Value};
+    use super::super::handler::MessageContext;
+
+    pub struct Connection {
+        pub conn_id: ConnID,
+        conn_ctx: ConnectionContext,
+        ch_rx: mpsc::Receiver<Message>,
+        protocol_errors: u32,
+    }
+
+    impl Context {
+        /// Add a connection to a group the same way that a socket would be
+        /// added.
+        pub async fn connect(&self, user_id: db::UserID, group_id: db::GroupID) -> Connection {
+            self.connect_with_session(user_id, group_id, String::new()).await
+        }
+
+        pub async fn connect_with_session(&self, user_id: db::UserID, group_id: db::GroupID, session_id: db::SessionID)
+            -> Connection
+        {
+            let conn_ctx = ConnectionContext {
+                user_id,
+                group_id,
+                conn_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+                protocol: Protocol::DEFAULT,
+                session_id,
+            };
+            let (queue, ch_rx) = mpsc::channel(config::get().send_queue_capacity);
+            let ch_tx = Sender { queue, overflow: Arc::new(Notify::new()), closing: Arc::default() };
+            self.insert_connection(&conn_ctx, ch_tx).await.unwrap();
+            Connection { conn_id: conn_ctx.conn_id, conn_ctx, ch_rx, protocol_errors: 0 }
+        }
+
+        /// Remove a connection the same way that a closed socket would be
+        /// removed.
+        pub async fn disconnect(&self, connection: Connection) {
+            self.remove_connection(&connection.conn_ctx).await;
+        }
+
+        /// Get the number of users that are connected to a group.
+        pub async fn online_user_count(&self, group_id: db::GroupID) -> usize {
+            match self.groups.read().await.get(&group_id) {
+                Some(group) => group.online_users.len(),
+                None => 0
+            }
+        }
+    }
+
+    impl Connection {
+        /// Handle a message from the client.
+        pub async fn send(&mut self, ctx: &Context, message: Value) {
+            self.send_message(ctx, Message::text(message.to_string())).await;
+        }
+
+        pub async fn send_message(&mut self, ctx: &Context, message: Message) {
+            let mut message_ctx = MessageContext {
+                user_id: self.conn_ctx.user_id,
+                group_id: self.conn_ctx.group_id,
+                conn_id: self.conn_ctx.conn_id,
+                groups: &ctx.groups,
+                user_groups: &ctx.user_groups,
+                pool: &ctx.pool,
+                previews: &ctx.previews,
+                settings: &ctx.settings,
+                protocol_errors: self.protocol_errors,
+            };
+            message_ctx.handle(message).await;
+            self.protocol_errors = message_ctx.protocol_errors;
+        }
+
+        /// Take the messages that have been queued for the client. A close
+        /// frame is returned as a message with the type "close".
+        pub fn received(&mut self) -> Vec<Value> {
+            let mut messages = Vec::new();
+            while let Ok(message) = self.ch_rx.try_recv() {
+                messages.push(match message.close_frame() {
+                    Some((code, reason)) => json!({ "type": "close", "code": code, "reason": reason }),
+                    None => serde_json::from_str(message.to_str().unwrap()).unwrap(),
+                });
+            }
+            messages
+        }
+
+        /// Take the messages that have been queued for the client and keep the
+        /// ones with a type.
+        pub fn received_type(&mut self, message_type: &str) -> Vec<Value> {
+            self.received().into_iter().filter(|message| message["type"] == message_type).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing;
+    use crate::filters;
+    use futures::FutureExt;
+
+    fn connection(user_id: db::UserID, group_id: db::GroupID) -> (ConnectionContext, Sender, mpsc::Receiver<Message>) {
+        let conn_ctx = ConnectionContext {
+            user_id,
+            group_id,
+            conn_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: Protocol::DEFAULT,
+            session_id: String::new(),
+        };
+        let (queue, ch_rx) = mpsc::channel(16);
+        let ch_tx = Sender { queue, overflow: Arc::new(Notify::new()), closing: Arc::default() };
+        (conn_ctx, ch_tx, ch_rx)
+    }
+
+    /// Take the status changes off a queue.
+    fn status_changes(ch_rx: &mut mpsc::Receiver<Message>) -> Vec<(i64, String)> {
+        let mut changes = Vec::new();
+        while let Ok(message) = ch_rx.try_recv() {
+            let json: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+            if json["type"] == "user_status_changed" {
+                changes.push((json["user_id"].as_i64().unwrap(), json["status"].as_str().unwrap().to_owned()));
+            }
+        }
+        changes
+    }
+
+    /// Open a socket to a group through the filter that the server uses.
+    async fn open_socket(ctx: &Context, session_id: &db::SessionID, group_id: db::GroupID) -> warp::test::WsClient {
+        warp::test::ws()
+            .path(&format!("/api/socket/{}", group_id))
+            .header("cookie", format!("session_id={}", session_id))
+            .handshake(filters::socket(ctx.clone()))
+            .await
+            .unwrap()
+    }
+
+    /// Read from a socket until it's closed. Returns the close code if the
+    /// server sent one.
+    async fn wait_for_close(client: &mut warp::test::WsClient) -> Option<u16> {
+        loop {
+            match client.recv().await {
+                Ok(message) if message.is_close() => return message.close_frame().map(|(code, _)| code),
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Wait for the server to finish removing the connections of a socket.
+    async fn wait_for_removal(ctx: &Context) {
+        for _ in 0..100 {
+            if ctx.connection_count().await == 0 {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("connection was not removed");
+    }
+
+    /// Collects whatever a tracing subscriber writes.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn connection_span_has_ids() {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NEW)
+            .finish();
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+
+        let pool = testing::pool();
+        let user_id = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user_id).await;
+        let session_id = testing::session(&pool, user_id).await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        let _client = open_socket(&ctx, &session_id, group_id).await;
+        for _ in 0..100 {
+            if ctx.connection_count().await == 1 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        let conn_id = ctx.list_connections().await[0].connections[0].conn_id;
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let span = format!("connection{{conn_id={} user_id={} group_id={}}}", conn_id, user_id, group_id);
+        assert!(output.contains(&span), "{}", output);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn oversized_frame_closes_socket() {
+        let pool = testing::pool();
+        let user_id = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user_id).await;
+        let session_id = testing::session(&pool, user_id).await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        let mut client = open_socket(&ctx, &session_id, group_id).await;
+
+        let oversized = "x".repeat(config::get().ws_max_frame_size + 1);
+        client.send_text(oversized).await;
+        wait_for_close(&mut client).await;
+        wait_for_removal(&ctx).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn failed_send_side_removes_connection() {
+        let pool = testing::pool();
+        let user_id = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user_id).await;
+        let session_id = testing::session(&pool, user_id).await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        let mut client = open_socket(&ctx, &session_id, group_id).await;
+
+        // The client keeps the socket open but the send side gives up as if
+        // the client couldn't keep up.
+        ctx.groups.read().await[&group_id].connections.values().next().unwrap().overflow.notify();
+        assert_eq!(wait_for_close(&mut client).await, Some(CloseReason::TooSlow.code()));
+        wait_for_removal(&ctx).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn second_login_replaces_first() {
+        let mut config = config::Config::default();
+        config.single_session = true;
+        let _config = config::set_for_test(config);
+        let pool = testing::pool();
+        let user_id = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user_id).await;
+        let first_session = testing::session(&pool, user_id).await;
+        let second_session = testing::session(&pool, user_id).await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+
+        let mut first = open_socket(&ctx, &first_session, group_id).await;
+        let mut second = open_socket(&ctx, &second_session, group_id).await;
+        assert_eq!(wait_for_close(&mut first).await, Some(CloseReason::Replaced.code()));
+        assert!(second.recv().await.unwrap().is_text());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn second_device_is_not_announced() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        let mut watcher = ctx.connect(owner, group_id).await;
+        watcher.received();
+
+        let first = ctx.connect(member, group_id).await;
+        assert_eq!(watcher.received_type("user_status_changed").len(), 1);
+        let second = ctx.connect(member, group_id).await;
+        assert!(watcher.received_type("user_status_changed").is_empty());
+
+        ctx.disconnect(first).await;
+        assert!(watcher.received_type("user_status_changed").is_empty());
+        ctx.disconnect(second).await;
+        let changes = watcher.received_type("user_status_changed");
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0]["status"], "offline");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn idle_socket_is_closed() {
+        let mut config = config::Config::default();
+        config.idle_timeout_mins = 1;
+        let _config = config::set_for_test(config);
+        let pool = testing::pool();
+        let user_id = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user_id).await;
+        let session_id = testing::session(&pool, user_id).await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        tokio::time::pause();
+        let mut client = open_socket(&ctx, &session_id, group_id).await;
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(wait_for_close(&mut client).await, Some(CloseReason::Idle.code()));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn stats_count_connections() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let first_group = testing::group(&pool, owner).await;
+        let second_group = testing::group(&pool, owner).await;
+        testing::join(&pool, member, first_group, "member").await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+
+        ctx.connect(owner, first_group).await;
+        ctx.connect(member, first_group).await;
+        let last = ctx.connect(owner, second_group).await;
+        let stats = ctx.stats().await;
+        assert_eq!(stats.groups, 2);
+        assert_eq!(stats.connections, 3);
+        assert!(stats.peak_groups >= 2);
+
+        ctx.disconnect(last).await;
+        let stats = ctx.stats().await;
+        assert_eq!(stats.groups, 1);
+        assert_eq!(stats.connections, 2);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn accessors_count_connections() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        assert_eq!(ctx.group_count().await, 0);
+        assert_eq!(ctx.online_user_count(group_id).await, 0);
+
+        let first = ctx.connect(owner, group_id).await;
+        ctx.connect(owner, group_id).await;
+        ctx.connect(member, group_id).await;
+        assert_eq!(ctx.group_count().await, 1);
+        assert_eq!(ctx.connection_count().await, 3);
+        assert_eq!(ctx.online_user_count(group_id).await, 2);
+
+        ctx.disconnect(first).await;
+        assert_eq!(ctx.connection_count().await, 2);
+        assert_eq!(ctx.online_user_count(group_id).await, 2);
+    }
+
+    // Debug builds still panic so that the bug is noticed. Release builds
+    // treat the missing entry as already removed.
+    #[tokio::test]
+    #[ignore]
+    #[cfg_attr(debug_assertions, should_panic(expected = "is not online"))]
+    async fn missing_online_user_is_repaired() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        ctx.connect(owner, group_id).await;
+        let conn = ctx.connect(member, group_id).await;
+
+        ctx.groups.write().await.get_mut(&group_id).unwrap().online_users.remove(&member);
+        ctx.disconnect(conn).await;
+        assert_eq!(ctx.connection_count().await, 1);
+        assert_eq!(ctx.online_user_count(group_id).await, 1);
+        assert!(!ctx.user_groups.read().await.contains_key(&member));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn kicked_connection_is_skipped() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let channel_id = testing::channel(&pool, group_id, false).await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        let mut owner_conn = ctx.connect(owner, group_id).await;
+        let mut kicked = ctx.connect(member, group_id).await;
+        kicked.received();
+
+        ctx.kick_user_from_group(member, group_id).await;
+        owner_conn.send(&ctx, serde_json::json!({
+            "type": "create_message",
+            "channel_id": channel_id,
+            "content": "after the kick",
+        })).await;
+        assert_eq!(owner_conn.received_type("message_receipt").len(), 1);
+
+        // The close frame is the last thing that the kicked connection gets.
+        let received = kicked.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["type"], "close");
+        assert_eq!(received[0]["code"], CloseReason::RemovedFromGroup.code());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn disconnect_closes_normally() {
+        let pool = testing::pool();
+        let user_id = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user_id).await;
+        let session_id = testing::session(&pool, user_id).await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        let mut client = open_socket(&ctx, &session_id, group_id).await;
+
+        client.send_text(r#"{"type":"disconnect"}"#).await;
+        assert_eq!(wait_for_close(&mut client).await, Some(CloseReason::Normal.code()));
+        wait_for_removal(&ctx).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn queue_is_flushed_before_close() {
+        let pool = testing::pool();
+        let user_id = testing::user(&pool).await;
+        let group_id = testing::group(&pool, user_id).await;
+        let session_id = testing::session(&pool, user_id).await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+        let mut client = open_socket(&ctx, &session_id, group_id).await;
+        for _ in 0..100 {
+            if ctx.connection_count().await == 1 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        // The close frame is queued right behind the message so the
+        // forwarding task is told to stop before it gets to either of them.
+        {
+            let groups_guard = ctx.groups.read().await;
+            let group = &groups_guard[&group_id];
+            let conn_id = *group.connections.keys().next().unwrap();
+            group.connections[&conn_id].send(Message::text("last words"));
+            group.kick_connection(conn_id, CloseReason::Kick);
+        }
+
+        let mut last = None;
+        loop {
+            let message = client.recv().await.unwrap();
+            if message.is_close() {
+                assert_eq!(message.close_frame().map(|(code, _)| code), Some(CloseReason::Kick.code()));
+                break;
+            }
+            last = Some(message);
+        }
+        assert_eq!(last.unwrap().to_str(), Ok("last words"));
+        wait_for_removal(&ctx).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn resumed_connection_stays_online() {
+        let pool = testing::pool();
+        let owner = testing::user(&pool).await;
+        let member = testing::user(&pool).await;
+        let group_id = testing::group(&pool, owner).await;
+        testing::join(&pool, member, group_id, "member").await;
+        let ctx = Context::new(pool, reqwest::Client::new());
+
+        let (watcher, watcher_tx, mut watcher_rx) = connection(owner, group_id);
+        ctx.insert_connection(&watcher, watcher_tx).await.unwrap();
+        let (conn_ctx, ch_tx, ch_rx) = connection(member, group_id);
+        let overflow = ch_tx.overflow.clone();
+        let closing = ch_tx.closing.clone();
+        ctx.insert_connection(&conn_ctx, ch_tx).await.unwrap();
+        assert_eq!(status_changes(&mut watcher_rx), vec![(member.0 as i64, "online".to_owned())]);
+
+        let resuming = ConnectionContext {
+            user_id: conn_ctx.user_id,
+            group_id: conn_ctx.group_id,
+            conn_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: conn_ctx.protocol,
+            session_id: conn_ctx.session_id.clone(),
+        };
+        let token = generate_random_base64url(RESUME_TOKEN_LENGTH);
+        ctx.suspend(token.clone(), Suspended { conn_ctx, ch_rx, unsent: None, overflow, closing });
+        let resumed = ctx.take_suspended(&token, &resuming);
+
+        assert!(resumed.is_some());
+        assert!(status_changes(&mut watcher_rx).is_empty());
+        assert!(ctx.groups.read().await[&group_id].online_users.contains_key(&member));
+    }
+
+    #[tokio::test]
+    async fn group_warning_is_logged_once() {
+        let mut config = config::Config::default();
+        config.group_warning_threshold = 2;
+        config.group_warning_interval_secs = 300;
+        let _config = config::set_for_test(config);
+        let ctx = Context::new(testing::pool(), reqwest::Client::new());
+
+        assert!(!ctx.group_added(1));
+        assert!(!ctx.group_added(2));
+        assert!(ctx.group_added(3));
+        assert!(!ctx.group_added(4));
+        assert!(!ctx.group_added(3));
+    }
+
+    #[tokio::test]
+    async fn group_warning_is_repeated_after_interval() {
+        let mut config = config::Config::default();
+        config.group_warning_threshold = 1;
+        config.group_warning_interval_secs = 0;
+        let _config = config::set_for_test(config);
+        let ctx = Context::new(testing::pool(), reqwest::Client::new());
+
+        assert!(ctx.group_added(2));
+        assert!(ctx.group_added(3));
+    }
+
+    #[tokio::test]
+    async fn group_warning_can_be_disabled() {
+        let mut config = config::Config::default();
+        config.group_warning_threshold = 0;
+        let _config = config::set_for_test(config);
+        let ctx = Context::new(testing::pool(), reqwest::Client::new());
+
+        assert!(!ctx.group_added(1000));
+    }
+
+    #[test]
+    fn stuck_consumer_overflows() {
+        // Nothing is ever taken off this queue.
+        let (queue, mut ch_rx) = mpsc::channel(2);
+        let overflow = Arc::new(Notify::new());
+        let ch_tx = Sender { queue, overflow: overflow.clone(), closing: Arc::default() };
+
+        ch_tx.send(Message::text("1"));
+        ch_tx.send(Message::text("2"));
+        assert!(overflow.notified().now_or_never().is_none());
+        ch_tx.send(Message::text("3"));
+        assert!(overflow.notified().now_or_never().is_some());
+
+        // The message that didn't fit isn't buffered.
+        assert_eq!(ch_rx.try_recv().unwrap(), Message::text("1"));
+        assert_eq!(ch_rx.try_recv().unwrap(), Message::text("2"));
+        assert!(ch_rx.try_recv().is_err());
+    }
 }
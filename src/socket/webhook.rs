@@ -0,0 +1,130 @@
+use log::warn;
+use crate::config;
+use crate::database as db;
+use crate::utils::as_timestamp;
+use serde::Serialize;
+use sha2::Sha256;
+use hmac::{Hmac, Mac, NewMac};
+use tracing_futures::Instrument;
+use reqwest::header::CONTENT_TYPE;
+use std::time::{Duration, SystemTime};
+
+const SIGNATURE_HEADER: &str = "X-Chat-Signature";
+
+#[derive(Serialize)]
+#[serde(rename_all="snake_case")]
+enum PresenceStatus {
+    Online,
+    Offline,
+}
+
+#[derive(Serialize)]
+struct PresenceEvent {
+    group_id: db::GroupID,
+    user_id: db::UserID,
+    status: PresenceStatus,
+    timestamp: u64,
+}
+
+/// Posts presence events to an external service.
+#[derive(Clone)]
+pub struct PresenceWebhook {
+    client: reqwest::Client,
+}
+
+impl PresenceWebhook {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client }
+    }
+
+    /// Post an event in the background. Failures are logged but otherwise
+    /// ignored.
+    pub fn notify(&self, group_id: db::GroupID, user_id: db::UserID, online: bool) {
+        let config = config::get();
+        let url = match &config.presence_webhook_url {
+            Some(url) => url,
+            None => return
+        };
+
+        let body = serde_json::to_vec(&PresenceEvent {
+            group_id,
+            user_id,
+            status: if online { PresenceStatus::Online } else { PresenceStatus::Offline },
+            timestamp: as_timestamp(SystemTime::now()),
+        }).unwrap();
+
+        let request = self.client.post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, sign(&config.presence_webhook_secret.0, &body))
+            .timeout(Duration::from_millis(config.presence_webhook_timeout_ms))
+            .body(body);
+
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(res) if !res.status().is_success() => {
+                    warn!("Presence webhook responded with {}", res.status());
+                }
+                Ok(_) => {},
+                Err(e) => warn!("Presence webhook failed: {}", e),
+            }
+        }.in_current_span());
+    }
+}
+
+/// Sign the body of an event with HMAC-SHA256 so that the receiver can check
+/// that it came from us. The signature is formatted as `sha256=<hex>`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let mut signature = String::from("sha256=");
+    for byte in mac.finalize().into_bytes() {
+        signature.push_str(&format!("{:02x}", byte));
+    }
+    signature
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn posts_signed_event() {
+        // A local endpoint that passes on whatever is posted to it.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let endpoint = warp::post()
+            .and(warp::path("presence"))
+            .and(warp::header::<String>(SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |signature: String, body: Bytes| {
+                tx.send((signature, body)).unwrap();
+                warp::reply()
+            });
+        let (addr, server) = warp::serve(endpoint).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut config = config::Config::default();
+        config.presence_webhook_url = Some(format!("http://{}/presence", addr));
+        config.presence_webhook_secret = config::Secret("secret".to_owned());
+        let _config = config::set_for_test(config);
+
+        PresenceWebhook::new(reqwest::Client::new()).notify(db::GroupID(1), db::UserID(2), true);
+        let (signature, body) = rx.recv().await.unwrap();
+        assert_eq!(signature, sign("secret", &body));
+        let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(event["group_id"], 1);
+        assert_eq!(event["user_id"], 2);
+        assert_eq!(event["status"], "online");
+    }
+
+    #[test]
+    fn signature_is_hex_hmac() {
+        // From RFC 4231 test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
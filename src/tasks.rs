@@ -0,0 +1,124 @@
+use log::{info, error};
+use rand::Rng;
+use crate::config;
+use crate::handlers;
+use crate::socket;
+use crate::database as db;
+use deadpool_postgres::Pool;
+use std::time::{Duration, SystemTime};
+
+/// Randomly lengthen or shorten an interval by up to the configured jitter
+/// fraction.
+fn jittered(secs: u64) -> Duration {
+    jitter_by(secs, config::get().task_jitter)
+}
+
+fn jitter_by(secs: u64, jitter: f64) -> Duration {
+    let jitter = jitter.max(0.0).min(1.0);
+    if jitter == 0.0 {
+        return Duration::from_secs(secs);
+    }
+    let factor = 1.0 + rand::thread_rng().gen_range(-jitter, jitter);
+    Duration::from_secs_f64(secs as f64 * factor)
+}
+
+/// Periodically delete the sessions that have expired. Expired sessions are
+/// already rejected so this only stops them from piling up.
+pub async fn sweep_sessions(pool: Pool) {
+    loop {
+        tokio::time::delay_for(jittered(config::get().session_sweep_interval_secs)).await;
+
+        match db::delete_expired_sessions(pool.clone()).await {
+            Ok(0) => {},
+            Ok(count) => info!("Deleted {} expired sessions", count),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+/// Keep the Google certificates fresh so that sign ins rarely have to wait
+/// for them.
+pub async fn refresh_certs(client: reqwest::Client, cache: handlers::CertificateCache) {
+    loop {
+        handlers::prefetch_certs(client.clone(), cache.clone()).await;
+        tokio::time::delay_for(jittered(config::get().cert_refresh_interval_secs)).await;
+    }
+}
+
+/// Periodically delete the messages that are older than the retention period
+/// of their group. Groups without a retention period keep their messages
+/// forever.
+pub async fn purge_messages(pool: Pool) {
+    let mut interval = tokio::time::interval(
+        Duration::from_secs(config::get().purge_interval_secs)
+    );
+
+    loop {
+        interval.tick().await;
+
+        let groups = match db::group_retention_days(pool.clone()).await {
+            Ok(groups) => groups,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+
+        let now = SystemTime::now();
+        for (group_id, days) in groups {
+            let cutoff = now - Duration::from_secs(days as u64 * 24 * 60 * 60);
+            match db::purge_messages_older_than(pool.clone(), group_id, cutoff).await {
+                Ok(0) => {},
+                Ok(count) => info!("Purged {} messages from group {}", count, group_id),
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+}
+
+/// Periodically log how busy the server is.
+pub async fn log_stats(socket_ctx: socket::Context) {
+    let mut interval = tokio::time::interval(
+        Duration::from_secs(config::get().stats_interval_secs)
+    );
+    // The first tick completes immediately.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let stats = socket_ctx.stats().await;
+        info!(
+            "Groups: {} (peak {}), connections: {}, messages sent: {}",
+            stats.groups, stats.peak_groups, stats.connections, stats.messages_sent
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_jitter() {
+        assert_eq!(jitter_by(60, 0.0), Duration::from_secs(60));
+        assert_eq!(jitter_by(60, -0.5), Duration::from_secs(60));
+        assert_eq!(jitter_by(60, f64::NAN), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn within_bounds() {
+        for _ in 0..1000 {
+            let interval = jitter_by(100, 0.25);
+            assert!(interval >= Duration::from_secs(75), "{:?} is too short", interval);
+            assert!(interval <= Duration::from_secs(125), "{:?} is too long", interval);
+        }
+    }
+
+    #[test]
+    fn jitter_is_clamped() {
+        for _ in 0..1000 {
+            assert!(jitter_by(100, 5.0) <= Duration::from_secs(200));
+        }
+    }
+}
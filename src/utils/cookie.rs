@@ -0,0 +1,66 @@
+use crate::config::{self, Config};
+
+/// Make the value of a Set-Cookie header for the session cookie using the
+/// configured attributes. The cookie is always HttpOnly.
+pub fn session_cookie(session_id: &str) -> String {
+    format_session_cookie(config::get(), session_id)
+}
+
+/// Make the value of a Set-Cookie header that removes the session cookie.
+pub fn clear_session_cookie() -> String {
+    session_cookie("") + ";Max-Age=0"
+}
+
+fn format_session_cookie(config: &Config, session_id: &str) -> String {
+    let mut cookie = format!(
+        "session_id={};Path={};HttpOnly;SameSite={}",
+        session_id,
+        config.session_cookie_path,
+        config.session_cookie_same_site.as_str()
+    );
+    if config.session_cookie_secure {
+        cookie.push_str(";Secure");
+    }
+    if let Some(domain) = &config.session_cookie_domain {
+        cookie.push_str(";Domain=");
+        cookie.push_str(domain);
+    }
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SameSite;
+
+    #[test]
+    fn default_cookie() {
+        assert_eq!(
+            format_session_cookie(&Config::default(), "abc"),
+            "session_id=abc;Path=/;HttpOnly;SameSite=Lax;Secure"
+        );
+    }
+
+    #[test]
+    fn configured_cookie() {
+        let mut config = Config::default();
+        config.session_cookie_secure = false;
+        config.session_cookie_same_site = SameSite::Strict;
+        config.session_cookie_domain = Some("example.com".to_owned());
+        config.session_cookie_path = "/chat".to_owned();
+        assert_eq!(
+            format_session_cookie(&config, "abc"),
+            "session_id=abc;Path=/chat;HttpOnly;SameSite=Strict;Domain=example.com"
+        );
+    }
+
+    #[test]
+    fn same_site_none() {
+        let mut config = Config::default();
+        config.session_cookie_same_site = SameSite::None;
+        assert_eq!(
+            format_session_cookie(&config, "abc"),
+            "session_id=abc;Path=/;HttpOnly;SameSite=None;Secure"
+        );
+    }
+}
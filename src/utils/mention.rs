@@ -0,0 +1,63 @@
+use crate::database::UserID;
+
+/// Extract the IDs of users mentioned in a message.
+///
+/// A mention is written as `<@123>` where 123 is the ID of the user. Names
+/// aren't used because they aren't unique and can contain spaces. Anything
+/// that doesn't parse as a mention is left as plain text. Each user is
+/// returned at most once.
+pub fn parse_mentions(content: &str) -> Vec<UserID> {
+    let mut user_ids = Vec::new();
+    let mut rest = content;
+
+    while let Some(start) = rest.find("<@") {
+        rest = &rest[start + 2..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        // Only digits so that "<@+1>" and "<@ 1>" aren't mentions.
+        let id = &rest[..end];
+        if !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) {
+            if let Ok(user_id) = id.parse::<UserID>() {
+                if !user_ids.contains(&user_id) {
+                    user_ids.push(user_id);
+                }
+            }
+        }
+    }
+
+    user_ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiple_mentions() {
+        let ids = parse_mentions("<@1> and <@23>, have a look at this <@456>");
+        assert_eq!(ids, vec![UserID(1), UserID(23), UserID(456)]);
+    }
+
+    #[test]
+    fn repeated_mention() {
+        assert_eq!(parse_mentions("<@7> <@7><@7>"), vec![UserID(7)]);
+    }
+
+    #[test]
+    fn names_are_not_mentions() {
+        assert!(parse_mentions("thanks @bob and @Jane Doe").is_empty());
+    }
+
+    #[test]
+    fn malformed_mentions() {
+        assert!(parse_mentions("<@> <@abc> <@ 1> <@+1> <@-1> <@1").is_empty());
+        assert!(parse_mentions("<@99999999999>").is_empty());
+    }
+
+    #[test]
+    fn mention_after_malformed() {
+        assert_eq!(parse_mentions("<@x <@5>"), vec![UserID(5)]);
+    }
+}
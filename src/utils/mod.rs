@@ -1,6 +1,16 @@
 mod warp;
 mod random;
+mod time;
+mod mention;
+mod text;
+mod rate_limit;
+mod cookie;
 
 // Maybe I shouldn't name it warp...
 pub use crate::utils::warp::*;
 pub use random::*;
+pub use time::*;
+pub use mention::*;
+pub use text::*;
+pub use rate_limit::*;
+pub use cookie::*;
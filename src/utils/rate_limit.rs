@@ -0,0 +1,122 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Windows are only cleared out when there are at least this many of them.
+const PRUNE_THRESHOLD: usize = 1024;
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+/// Limits the number of requests from each IP address within a fixed window.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: u32,
+    period: Duration,
+    windows: Arc<Mutex<HashMap<IpAddr, Window>>>,
+}
+
+impl RateLimiter {
+    /// Allow up to limit requests from each address per period. A limit of 0
+    /// allows everything.
+    pub fn new(limit: u32, period: Duration) -> Self {
+        Self {
+            limit,
+            period,
+            windows: Arc::default(),
+        }
+    }
+
+    /// Record a request from an address. Returns false if the address has
+    /// made too many requests.
+    pub fn allow(&self, addr: IpAddr) -> bool {
+        self.allow_at(addr, Instant::now())
+    }
+
+    fn allow_at(&self, addr: IpAddr, now: Instant) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+
+        let mut windows = self.windows.lock().unwrap();
+
+        if windows.len() >= PRUNE_THRESHOLD && !windows.contains_key(&addr) {
+            let period = self.period;
+            windows.retain(|_, window| now.duration_since(window.start) < period);
+        }
+
+        let window = windows.entry(addr).or_insert(Window { start: now, count: 0 });
+        if now.duration_since(window.start) >= self.period {
+            window.start = now;
+            window.count = 0;
+        }
+        window.count += 1;
+        window.count <= self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const PERIOD: Duration = Duration::from_secs(60);
+
+    fn addr(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn allows_up_to_limit() {
+        let limiter = RateLimiter::new(3, PERIOD);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.allow_at(addr(1), now));
+        }
+        assert!(!limiter.allow_at(addr(1), now));
+        assert!(!limiter.allow_at(addr(1), now + PERIOD / 2));
+    }
+
+    #[test]
+    fn addresses_are_separate() {
+        let limiter = RateLimiter::new(1, PERIOD);
+        let now = Instant::now();
+        assert!(limiter.allow_at(addr(1), now));
+        assert!(limiter.allow_at(addr(2), now));
+        assert!(!limiter.allow_at(addr(1), now));
+    }
+
+    #[test]
+    fn window_resets_after_period() {
+        let limiter = RateLimiter::new(1, PERIOD);
+        let now = Instant::now();
+        assert!(limiter.allow_at(addr(1), now));
+        assert!(!limiter.allow_at(addr(1), now));
+        assert!(limiter.allow_at(addr(1), now + PERIOD));
+        assert!(!limiter.allow_at(addr(1), now + PERIOD));
+    }
+
+    #[test]
+    fn zero_limit_allows_everything() {
+        let limiter = RateLimiter::new(0, PERIOD);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.allow_at(addr(1), now));
+        }
+    }
+
+    #[test]
+    fn expired_windows_are_pruned() {
+        let limiter = RateLimiter::new(1, PERIOD);
+        let now = Instant::now();
+        for i in 0..PRUNE_THRESHOLD {
+            let addr = IpAddr::V4(Ipv4Addr::from(i as u32));
+            assert!(limiter.allow_at(addr, now));
+        }
+        assert!(limiter.allow_at(addr(1), now + PERIOD));
+        assert_eq!(limiter.windows.lock().unwrap().len(), 1);
+    }
+}
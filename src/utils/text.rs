@@ -0,0 +1,221 @@
+// Sorted by shortcode so that it can be binary searched.
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "\u{1F44D}"),
+    ("-1", "\u{1F44E}"),
+    ("100", "\u{1F4AF}"),
+    ("angry", "\u{1F620}"),
+    ("blush", "\u{1F60A}"),
+    ("broken_heart", "\u{1F494}"),
+    ("check", "\u{2714}\u{FE0F}"),
+    ("clap", "\u{1F44F}"),
+    ("confused", "\u{1F615}"),
+    ("cry", "\u{1F622}"),
+    ("eyes", "\u{1F440}"),
+    ("fire", "\u{1F525}"),
+    ("grin", "\u{1F601}"),
+    ("grinning", "\u{1F600}"),
+    ("heart", "\u{2764}\u{FE0F}"),
+    ("heart_eyes", "\u{1F60D}"),
+    ("joy", "\u{1F602}"),
+    ("kiss", "\u{1F48B}"),
+    ("laughing", "\u{1F606}"),
+    ("neutral_face", "\u{1F610}"),
+    ("ok_hand", "\u{1F44C}"),
+    ("party", "\u{1F389}"),
+    ("pray", "\u{1F64F}"),
+    ("rage", "\u{1F621}"),
+    ("rocket", "\u{1F680}"),
+    ("rofl", "\u{1F923}"),
+    ("scream", "\u{1F631}"),
+    ("shrug", "\u{1F937}"),
+    ("sleeping", "\u{1F634}"),
+    ("slightly_smiling_face", "\u{1F642}"),
+    ("smile", "\u{1F604}"),
+    ("smiley", "\u{1F603}"),
+    ("smirk", "\u{1F60F}"),
+    ("sob", "\u{1F62D}"),
+    ("star", "\u{2B50}"),
+    ("sunglasses", "\u{1F60E}"),
+    ("sweat_smile", "\u{1F605}"),
+    ("tada", "\u{1F389}"),
+    ("thinking", "\u{1F914}"),
+    ("thumbsdown", "\u{1F44E}"),
+    ("thumbsup", "\u{1F44D}"),
+    ("upside_down_face", "\u{1F643}"),
+    ("wave", "\u{1F44B}"),
+    ("wink", "\u{1F609}"),
+    ("x", "\u{274C}"),
+    ("yum", "\u{1F60B}"),
+];
+
+/// Look up the emoji for a shortcode (without the surrounding colons).
+pub fn shortcode_emoji(shortcode: &str) -> Option<&'static str> {
+    SHORTCODES
+        .binary_search_by(|(code, _)| (*code).cmp(shortcode))
+        .ok()
+        .map(|index| SHORTCODES[index].1)
+}
+
+/// Determine whether a character can appear in a shortcode.
+pub fn shortcode_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_' || ch == '+' || ch == '-'
+}
+
+/// Replace `:shortcode:` sequences with the emoji they stand for.
+///
+/// Unknown shortcodes are left as they are. Adjacent shortcodes such as
+/// `:wave::smile:` are both expanded.
+pub fn expand_shortcodes(content: &str) -> String {
+    let mut expanded = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find(':') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = after.find(|ch| !shortcode_char(ch)).unwrap_or(after.len());
+
+        if after[end..].starts_with(':') {
+            if let Some(emoji) = shortcode_emoji(&after[..end]) {
+                expanded.push_str(emoji);
+                rest = &after[end + 1..];
+                continue;
+            }
+        }
+
+        // The colon might be the start of the next shortcode so only skip past
+        // this one.
+        expanded.push(':');
+        rest = after;
+    }
+
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Replace each character of the filtered words in a message with `*`.
+///
+/// Only whole words are matched, ignoring case, so filtering "ass" doesn't
+/// touch "class". The filter words are expected to be in lowercase.
+pub fn apply_word_filter(content: &str, words: &[String]) -> String {
+    if words.is_empty() {
+        return content.to_owned();
+    }
+
+    let mut filtered = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find(char::is_alphanumeric) {
+        filtered.push_str(&rest[..start]);
+        let after = &rest[start..];
+        let end = after.find(|ch: char| !ch.is_alphanumeric()).unwrap_or(after.len());
+        let word = &after[..end];
+
+        let lower = word.to_lowercase();
+        if words.iter().any(|filtered_word| *filtered_word == lower) {
+            filtered.extend(word.chars().map(|_| '*'));
+        } else {
+            filtered.push_str(word);
+        }
+
+        rest = &after[end..];
+    }
+
+    filtered.push_str(rest);
+    filtered
+}
+
+/// Find the first http or https link in a message.
+///
+/// Trailing punctuation is not considered part of the link so that
+/// "see https://example.com." links to "https://example.com".
+pub fn find_link(content: &str) -> Option<&str> {
+    content
+        .split_whitespace()
+        .find(|word| word.starts_with("https://") || word.starts_with("http://"))
+        .map(|word| word.trim_end_matches(|ch: char| ".,;:!?)'\"".contains(ch)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortcodes_are_sorted() {
+        for pair in SHORTCODES.windows(2) {
+            assert!(pair[0].0 < pair[1].0, "{} should come after {}", pair[0].0, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn every_shortcode_is_found() {
+        for (code, emoji) in SHORTCODES {
+            assert!(code.chars().all(shortcode_char), "{} has an invalid character", code);
+            assert_eq!(shortcode_emoji(code), Some(*emoji));
+        }
+    }
+
+    #[test]
+    fn expand_known_shortcodes() {
+        assert_eq!(expand_shortcodes("hi :wave:"), "hi \u{1F44B}");
+        assert_eq!(expand_shortcodes(":+1: and :-1:"), "\u{1F44D} and \u{1F44E}");
+    }
+
+    #[test]
+    fn expand_adjacent_shortcodes() {
+        assert_eq!(expand_shortcodes(":wave::smile:"), "\u{1F44B}\u{1F604}");
+    }
+
+    #[test]
+    fn unknown_shortcodes_are_left() {
+        assert_eq!(expand_shortcodes(":nope: :wave"), ":nope: :wave");
+        assert_eq!(expand_shortcodes("time is 10:30:00"), "time is 10:30:00");
+    }
+
+    #[test]
+    fn colon_before_shortcode() {
+        assert_eq!(expand_shortcodes("note::fire:"), "note:\u{1F525}");
+        assert_eq!(expand_shortcodes(":not valid:fire:"), ":not valid\u{1F525}");
+    }
+
+    #[test]
+    fn no_shortcodes() {
+        assert_eq!(expand_shortcodes(""), "");
+        assert_eq!(expand_shortcodes("just text"), "just text");
+        assert_eq!(expand_shortcodes(":::"), ":::");
+    }
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn filter_whole_words() {
+        let filter = words(&["darn", "heck"]);
+        assert_eq!(apply_word_filter("darn it, what the heck", &filter), "**** it, what the ****");
+    }
+
+    #[test]
+    fn filter_ignores_case() {
+        let filter = words(&["darn"]);
+        assert_eq!(apply_word_filter("DARN Darn dArN", &filter), "**** **** ****");
+    }
+
+    #[test]
+    fn filter_only_matches_whole_words() {
+        let filter = words(&["ass"]);
+        assert_eq!(apply_word_filter("class assignment passes", &filter), "class assignment passes");
+        assert_eq!(apply_word_filter("ass-backwards (ass)", &filter), "***-backwards (***)");
+    }
+
+    #[test]
+    fn filter_masks_each_character() {
+        let filter = words(&["süß"]);
+        assert_eq!(apply_word_filter("Süß!", &filter), "***!");
+    }
+
+    #[test]
+    fn empty_filter() {
+        assert_eq!(apply_word_filter("anything goes", &[]), "anything goes");
+        assert_eq!(apply_word_filter("", &words(&["darn"])), "");
+    }
+}
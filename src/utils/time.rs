@@ -0,0 +1,6 @@
+use std::time::SystemTime;
+
+/// Convert a time to the number of seconds since the Unix epoch.
+pub fn as_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
@@ -6,10 +6,29 @@ pub fn cache_long<R: warp::Reply>(reply: R) -> impl warp::Reply {
     )
 }
 
+/// For responses that only change when the server restarts.
 pub fn cache_short<R: warp::Reply>(reply: R) -> impl warp::Reply {
     warp::reply::with_header(
         reply,
         "Cache-Control",
-        "public,max-age=86400,immutable" // 24 hours
+        "public,max-age=3600" // 1 hour
+    )
+}
+
+/// For responses that depend on who is asking so can't be stored by shared
+/// caches.
+pub fn cache_private<R: warp::Reply>(reply: R) -> impl warp::Reply {
+    warp::reply::with_header(
+        reply,
+        "Cache-Control",
+        "private,max-age=3600" // 1 hour
+    )
+}
+
+pub fn cache_none<R: warp::Reply>(reply: R) -> impl warp::Reply {
+    warp::reply::with_header(
+        reply,
+        "Cache-Control",
+        "no-store"
     )
 }